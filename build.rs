@@ -0,0 +1,40 @@
+//! Records the build details `atomic --version --verbose` prints.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=10", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_string))
+        .map(|feature| feature.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=ATOMIC_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=ATOMIC_BUILD_DATE={}", today());
+    println!(
+        "cargo:rustc-env=ATOMIC_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!("cargo:rustc-env=ATOMIC_FEATURES={}", features.join(","));
+
+    // pick up new commits; a packaged crate has no .git to watch
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/date.rs");
+}
+
+include!("src/date.rs");
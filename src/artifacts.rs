@@ -0,0 +1,432 @@
+//! `artifacts = ["target/criterion/**", "coverage/*.lcov"]`: copying the files a command leaves
+//! behind into `.atomic/artifacts/<run-id>/<step>/` once it finishes, passed or failed, so CI
+//! finds them in one place.
+//!
+//! Steps are collected as they finish, the same way [`crate::junit`] records them: nothing is
+//! kept until [`start`] is called on the current thread. Runs that collected something are
+//! listed in `.atomic/artifacts.toml` for `atomic --artifacts`.
+
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::inputs;
+use crate::last_run::{state_dir, STATE_DIR};
+use crate::util::format_size;
+use crate::{AtomicError, Result};
+
+const FILE: &str = "artifacts.toml";
+/// Version written to new files; bump it when older atomics would misread them.
+const VERSION: u32 = 1;
+
+/// `artifact_max_size` when atomic.toml doesn't set it.
+pub const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+/// A file copied from a step.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Artifact {
+    /// The directory of the step it came from, under the run's.
+    pub step: String,
+    /// Where it was, relative to the step's directory, which is also where it went.
+    pub path: String,
+    pub size: u64,
+}
+
+/// A run that collected artifacts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    pub id: String,
+    pub command: String,
+    /// Where its artifacts went.
+    pub dir: PathBuf,
+    /// Seconds since the epoch.
+    pub started: u64,
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+}
+
+/// Every run that collected artifacts, oldest first.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub runs: Vec<Run>,
+}
+
+/// What [`copy_matches`] did with the files the patterns matched.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Collected {
+    /// Copied, with their sizes.
+    pub copied: Vec<(String, u64)>,
+    /// Over the size cap, with their sizes.
+    pub skipped: Vec<(String, u64)>,
+}
+
+/// Copies the files under `root` matching any of `patterns` to the same paths under `dest`,
+/// leaving out those bigger than `max_size`. A file two patterns match is copied once.
+///
+/// # Errors
+/// Returns an error if a directory can't be read or a file can't be copied.
+pub fn copy_matches(
+    root: &Path,
+    patterns: &[String],
+    dest: &Path,
+    max_size: u64,
+) -> Result<Collected> {
+    let mut collected = Collected::default();
+    for path in inputs::expand(root, patterns)? {
+        let source = root.join(&path);
+        let size = fs::metadata(&source)
+            .map_err(|err| copy_error(&source, err))?
+            .len();
+        if size > max_size {
+            collected.skipped.push((path, size));
+            continue;
+        }
+        let target = dest.join(&path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|err| copy_error(parent, err))?;
+        }
+        fs::copy(&source, &target).map_err(|err| copy_error(&source, err))?;
+        collected.copied.push((path, size));
+    }
+    Ok(collected)
+}
+
+fn copy_error(path: &Path, err: std::io::Error) -> AtomicError {
+    AtomicError::Generic(format!(
+        "Failed to collect artifact {}: {}",
+        path.display(),
+        err
+    ))
+}
+
+/// `label` as a directory name: anything but letters, digits, `.`, `-` and `_` turned into `_`.
+fn slug(label: &str) -> String {
+    let name: String = label
+        .trim()
+        .chars()
+        .take(60)
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    match name.trim_matches(['.', '_']) {
+        "" => "step".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// A directory name for `step` under `run_dir`: its [`slug`], with a `-2`, `-3`... when a step
+/// of the same name already collected there, such as one a chain runs twice.
+pub fn step_dir_name(step: &str, run_dir: &Path) -> String {
+    let name = slug(step);
+    (1..)
+        .map(|n| match n {
+            1 => name.clone(),
+            n => format!("{}-{}", name, n),
+        })
+        .find(|candidate| !run_dir.join(candidate).exists())
+        .expect("some suffix is free")
+}
+
+/// The id of a run of `command` started at `now`, which names its directory.
+pub fn run_id(command: &str, now: u64) -> String {
+    format!("{}-{}", now, slug(command))
+}
+
+/// The run being collected on this thread.
+struct Collector {
+    atomic: PathBuf,
+    run: Run,
+    max_size: u64,
+    /// Collecting under the state directory, rather than into `--artifacts-dir`.
+    in_state_dir: bool,
+}
+
+thread_local! {
+    static COLLECTOR: RefCell<Option<Collector>> = const { RefCell::new(None) };
+}
+
+/// Starts collecting for a run of `command`, into `dir` (`--artifacts-dir`) or else
+/// `.atomic/artifacts/<run-id>` next to `atomic`. Nothing is created until a step collects.
+pub fn start(atomic: &Path, command: &str, dir: Option<&Path>, max_size: u64, now: u64) {
+    let id = run_id(command, now);
+    let in_state_dir = dir.is_none();
+    let dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => atomic
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(STATE_DIR)
+            .join("artifacts")
+            .join(&id),
+    };
+    let collector = Collector {
+        atomic: atomic.to_path_buf(),
+        run: Run {
+            id,
+            command: command.to_string(),
+            dir,
+            started: now,
+            artifacts: Vec::new(),
+        },
+        max_size,
+        in_state_dir,
+    };
+    COLLECTOR.with(|cell| *cell.borrow_mut() = Some(collector));
+}
+
+/// Collects the `artifacts` of the finished `step`, whose working directory was `root`. A no-op
+/// unless collecting is on; files that can't be copied are reported and skipped.
+pub fn collect(step: &str, root: &Path, patterns: &[String]) {
+    if patterns.is_empty() {
+        return;
+    }
+    COLLECTOR.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let Some(collector) = cell.as_mut() else {
+            return;
+        };
+        let root = match root.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => root,
+        };
+        // the state directory's .gitignore keeps collected files out of auto-commits
+        if collector.in_state_dir {
+            if let Err(err) = state_dir(&collector.atomic, None) {
+                eprintln!("warning: Failed to create {}: {}", STATE_DIR, err);
+                return;
+            }
+        }
+        let name = step_dir_name(step, &collector.run.dir);
+        let dest = collector.run.dir.join(&name);
+        let collected = match copy_matches(root, patterns, &dest, collector.max_size) {
+            Ok(collected) => collected,
+            Err(err) => {
+                eprintln!("warning: {}", err);
+                return;
+            }
+        };
+        for (path, size) in &collected.skipped {
+            eprintln!(
+                "artifacts: skipping {} ({}), over artifact_max_size ({})",
+                path,
+                format_size(*size),
+                format_size(collector.max_size)
+            );
+        }
+        if !collected.copied.is_empty() {
+            eprintln!(
+                "artifacts: {} file{} from '{}' in {}",
+                collected.copied.len(),
+                if collected.copied.len() == 1 { "" } else { "s" },
+                step,
+                dest.display()
+            );
+        }
+        collector
+            .run
+            .artifacts
+            .extend(collected.copied.into_iter().map(|(path, size)| Artifact {
+                step: name.clone(),
+                path,
+                size,
+            }));
+    });
+}
+
+/// Stops collecting, adding the run to the history when it collected anything.
+pub fn finish() {
+    let Some(collector) = COLLECTOR.with(|cell| cell.borrow_mut().take()) else {
+        return;
+    };
+    if collector.run.artifacts.is_empty() {
+        return;
+    }
+    let mut history = load(&collector.atomic);
+    history.version = VERSION;
+    history.runs.push(collector.run);
+    if let Err(err) = save(&collector.atomic, &history) {
+        eprintln!("warning: {}", err);
+    }
+}
+
+/// Where the runs with artifacts of the project around `atomic` are listed.
+pub fn path(atomic: &Path) -> PathBuf {
+    atomic
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(STATE_DIR)
+        .join(FILE)
+}
+
+/// The runs with artifacts, or none when the file is missing or can't be read.
+pub fn load(atomic: &Path) -> History {
+    fs::read_to_string(path(atomic))
+        .ok()
+        .and_then(|text| ::toml::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn save(atomic: &Path, history: &History) -> Result<()> {
+    let file = path(atomic);
+    let text = ::toml::to_string(history).map_err(|err| {
+        AtomicError::Generic(format!("Failed to serialize artifact history: {}", err))
+    })?;
+    state_dir(atomic, None)
+        .and_then(|_| fs::write(&file, text))
+        .map_err(|err| AtomicError::Generic(format!("Failed to write {}: {}", file.display(), err)))
+}
+
+/// `atomic --artifacts`: each run, newest first, with how many files it collected and their
+/// total size.
+pub fn render(history: &History) -> String {
+    history
+        .runs
+        .iter()
+        .rev()
+        .map(|run| {
+            let total: u64 = run.artifacts.iter().map(|artifact| artifact.size).sum();
+            format!(
+                "{}  {}  {} file{}, {}  {}\n",
+                run.id,
+                run.command,
+                run.artifacts.len(),
+                if run.artifacts.len() == 1 { "" } else { "s" },
+                format_size(total),
+                run.dir.display()
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn test_copy_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        for (file, contents) in [
+            ("target/criterion/fib/report.html", "<html>"),
+            ("target/criterion/fib/raw/sample.json", "{}"),
+            ("target/debug/atomic", "binary"),
+            ("coverage/lcov.lcov", "TN:"),
+            ("coverage/big.lcov", "0123456789"),
+            ("coverage/notes.txt", "notes"),
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let dest = dir.path().join("out");
+
+        // the lcov files match twice, and are copied once
+        let collected = copy_matches(
+            &root,
+            &patterns(&["target/criterion/**", "coverage/*.lcov", "coverage/**"]),
+            &dest,
+            8,
+        )
+        .unwrap();
+        assert_eq!(
+            collected.copied,
+            [
+                ("coverage/lcov.lcov".to_string(), 3),
+                ("coverage/notes.txt".to_string(), 5),
+                ("target/criterion/fib/raw/sample.json".to_string(), 2),
+                ("target/criterion/fib/report.html".to_string(), 6),
+            ]
+        );
+        assert_eq!(collected.skipped, [("coverage/big.lcov".to_string(), 10)]);
+        assert_eq!(
+            fs::read_to_string(dest.join("target/criterion/fib/raw/sample.json")).unwrap(),
+            "{}"
+        );
+        assert!(!dest.join("target/debug").exists());
+        assert!(!dest.join("coverage/big.lcov").exists());
+
+        let none = copy_matches(&root, &patterns(&["dist/*.tar.gz"]), &dest, 8).unwrap();
+        assert_eq!(none, Collected::default());
+    }
+
+    #[test]
+    fn test_step_dir_name() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(step_dir_name("bench", dir.path()), "bench");
+        assert_eq!(
+            step_dir_name("test [os=linux, flag=foo]", dir.path()),
+            "test__os_linux__flag_foo"
+        );
+        assert_eq!(
+            step_dir_name("echo hi > out.txt", dir.path()),
+            "echo_hi___out.txt"
+        );
+        assert_eq!(step_dir_name("../..", dir.path()), "step");
+        fs::create_dir(dir.path().join("bench")).unwrap();
+        fs::create_dir(dir.path().join("bench-2")).unwrap();
+        assert_eq!(step_dir_name("bench", dir.path()), "bench-3");
+        assert_eq!(run_id("bench", 1_700_000_000), "1700000000-bench");
+        // whatever happens to sit in the current directory
+        assert_eq!(run_id("src", 1_700_000_000), "1700000000-src");
+    }
+
+    #[test]
+    fn test_collect_and_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(dir.path().join("report.txt"), "report").unwrap();
+        let report = patterns(&["report.txt"]);
+
+        // off until started
+        collect("bench", dir.path(), &report);
+        finish();
+        assert_eq!(load(&atomic), History::default());
+
+        start(&atomic, "bench", None, DEFAULT_MAX_SIZE, 1_700_000_000);
+        collect("bench", dir.path(), &report);
+        collect("bench", dir.path(), &report);
+        collect("lint", dir.path(), &patterns(&["*.lcov"]));
+        finish();
+        let run_dir = dir.path().join(".atomic/artifacts/1700000000-bench");
+        assert!(run_dir.join("bench/report.txt").is_file());
+        assert!(run_dir.join("bench-2/report.txt").is_file());
+        assert!(!run_dir.join("lint").exists());
+
+        let history = load(&atomic);
+        assert_eq!(history.runs.len(), 1);
+        let steps: Vec<_> = history.runs[0]
+            .artifacts
+            .iter()
+            .map(|artifact| artifact.step.as_str())
+            .collect();
+        assert_eq!(steps, ["bench", "bench-2"]);
+        assert_eq!(
+            render(&history),
+            format!(
+                "1700000000-bench  bench  2 files, 12 B  {}\n",
+                run_dir.display()
+            )
+        );
+
+        // nothing collected, nothing recorded
+        start(&atomic, "lint", None, DEFAULT_MAX_SIZE, 1_700_000_100);
+        collect("lint", dir.path(), &patterns(&["*.lcov"]));
+        finish();
+        assert_eq!(load(&atomic).runs.len(), 1);
+    }
+}
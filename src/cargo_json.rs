@@ -0,0 +1,324 @@
+//! `cargo_json = true`: runs cargo with `--message-format=json`, shows its diagnostics the way
+//! cargo would have, and sums them up once it finishes.
+//!
+//! Cargo's stdout then mixes JSON messages with plain output from tests, build scripts and the
+//! program under `cargo run`, so it's read a line at a time and only lines that parse as cargo
+//! messages are treated as such.
+
+use std::{borrow::Cow, collections::BTreeSet};
+
+use serde::Deserialize;
+
+/// Cargo subcommands that take `--message-format`, with their aliases.
+const SUBCOMMANDS: &[&str] = &[
+    "build", "b", "check", "c", "clippy", "test", "t", "bench", "run", "r", "doc", "d", "rustc",
+    "rustdoc", "fix",
+];
+
+const FLAG: &str = "--message-format=json";
+
+/// Characters that make a command a shell script rather than one cargo invocation.
+const SHELL_OPERATORS: &[char] = &['&', '|', ';', '<', '>', '$', '`', '\n'];
+
+/// `cmd` with `--message-format=json` added, when it is a single cargo command that takes it.
+///
+/// The flag goes before a `--`, so it isn't passed on to the program or test harness. A command
+/// that already asks for JSON is kept as it is; one asking for another format isn't touched.
+pub fn with_message_format(cmd: &str) -> Option<String> {
+    if cmd.contains(SHELL_OPERATORS) {
+        return None;
+    }
+    let argv = shell_words::split(cmd).ok()?;
+    let program = argv.first()?;
+    let stem = program.rsplit(['/', '\\']).next()?;
+    if stem != "cargo" && stem != "cargo.exe" {
+        return None;
+    }
+    // `cargo +nightly --locked build`: the first word that isn't a toolchain or flag
+    let subcommand = argv[1..].iter().find(|arg| !arg.starts_with(['+', '-']))?;
+    if !SUBCOMMANDS.contains(&subcommand.as_str()) {
+        return None;
+    }
+
+    let end = argv
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(argv.len());
+    let mut format = argv[..end]
+        .iter()
+        .filter_map(|arg| arg.strip_prefix("--message-format"));
+    match format.next() {
+        Some(format) if format.trim_start_matches('=').starts_with("json") => Some(cmd.to_string()),
+        Some(_) => None,
+        // added to the text rather than rejoining the words, so the rest keeps its quoting
+        None => match cmd.find(" -- ").filter(|_| end < argv.len()) {
+            Some(at) => Some(format!("{} {}{}", &cmd[..at], FLAG, &cmd[at..])),
+            None if end < argv.len() => {
+                let at = cmd.trim_end().len() - 2;
+                Some(format!("{}{} {}", &cmd[..at], FLAG, &cmd[at..]))
+            }
+            None => Some(format!("{} {}", cmd.trim_end(), FLAG)),
+        },
+    }
+}
+
+/// One line of cargo's JSON output; only the parts atomic uses.
+#[derive(Debug, Deserialize)]
+struct Message {
+    reason: String,
+    target: Option<Target>,
+    message: Option<Diagnostic>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Target {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Diagnostic {
+    level: String,
+    code: Option<Code>,
+    rendered: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Code {
+    code: String,
+}
+
+/// What the compiler reported over a cargo run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnostics {
+    pub errors: usize,
+    pub warnings: usize,
+    /// Codes of the errors, such as `E0308`.
+    pub codes: BTreeSet<String>,
+    /// Crates with at least one error.
+    pub failing: BTreeSet<String>,
+    /// Crates with any error or warning.
+    pub crates: BTreeSet<String>,
+}
+
+impl Diagnostics {
+    /// Reads one line of cargo's output, returning what to show for it: a compiler message's
+    /// rendered text, nothing for cargo's other messages, and anything else as it is.
+    pub fn read_line<'a>(&mut self, line: &'a str) -> Option<Cow<'a, str>> {
+        let message = match line.starts_with('{') {
+            true => serde_json::from_str::<Message>(line).ok(),
+            false => None,
+        };
+        let Some(message) = message else {
+            return Some(Cow::Borrowed(line));
+        };
+        if message.reason != "compiler-message" {
+            return None;
+        }
+        let diagnostic = message.message?;
+        let krate = message.target.map(|target| target.name);
+        if diagnostic.level.starts_with("error") {
+            self.errors += 1;
+            self.codes.extend(diagnostic.code.map(|code| code.code));
+            self.failing.extend(krate.clone());
+            self.crates.extend(krate);
+        } else if diagnostic.level == "warning" {
+            self.warnings += 1;
+            self.crates.extend(krate);
+        }
+        diagnostic
+            .rendered
+            .map(|rendered| Cow::Owned(rendered.trim_end_matches('\n').to_string()))
+    }
+
+    /// `2 errors (E0308, E0502), 3 warnings across 3 crates; app and util failed`, or `None`
+    /// when the compiler had nothing to say.
+    pub fn summary(&self) -> Option<String> {
+        let mut counts = Vec::new();
+        if self.errors > 0 {
+            let mut errors = plural(self.errors, "error");
+            if !self.codes.is_empty() {
+                let codes: Vec<&str> = self.codes.iter().map(String::as_str).collect();
+                errors.push_str(&format!(" ({})", codes.join(", ")));
+            }
+            counts.push(errors);
+        }
+        if self.warnings > 0 {
+            counts.push(plural(self.warnings, "warning"));
+        }
+        if counts.is_empty() {
+            return None;
+        }
+        let mut summary = format!(
+            "{} across {}",
+            counts.join(", "),
+            plural(self.crates.len(), "crate")
+        );
+        if !self.failing.is_empty() {
+            let failing: Vec<&str> = self.failing.iter().map(String::as_str).collect();
+            summary.push_str(&format!("; {} failed", and_list(&failing)));
+        }
+        Some(summary)
+    }
+}
+
+/// Cargo's stdout as it streams in, split into lines for [`Diagnostics::read_line`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CargoJson {
+    pub diagnostics: Diagnostics,
+    /// The start of a line whose end hasn't arrived yet.
+    partial: Vec<u8>,
+}
+
+impl CargoJson {
+    /// Takes the next chunk of output, returning what to show for the lines it completes.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<u8> {
+        self.partial.extend_from_slice(bytes);
+        let Some(last) = self.partial.iter().rposition(|&byte| byte == b'\n') else {
+            return Vec::new();
+        };
+        let complete: Vec<u8> = self.partial.drain(..=last).collect();
+        let mut shown = Vec::new();
+        for line in complete[..last].split(|&byte| byte == b'\n') {
+            self.show(line, &mut shown);
+        }
+        shown
+    }
+
+    /// What to show for a last line that didn't end in a newline.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut shown = Vec::new();
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            self.show(&line, &mut shown);
+        }
+        shown
+    }
+
+    fn show(&mut self, line: &[u8], shown: &mut Vec<u8>) {
+        let text = String::from_utf8_lossy(line);
+        let text = text.strip_suffix('\r').unwrap_or(&text);
+        if let Some(out) = self.diagnostics.read_line(text) {
+            shown.extend_from_slice(out.as_bytes());
+            shown.push(b'\n');
+        }
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        n => format!("{} {}s", n, noun),
+    }
+}
+
+/// `a`, `a and b`, `a, b and c`.
+fn and_list(items: &[&str]) -> String {
+    match items {
+        [] => String::new(),
+        [one] => one.to_string(),
+        [rest @ .., last] => format!("{} and {}", rest.join(", "), last),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `cargo check --keep-going --message-format=json` over a workspace where `app` has a type
+    /// error, `util` a borrow error and a warning, and `core` two warnings.
+    const CHECK: &str = include_str!("../fixtures/cargo_check.jsonl");
+    /// `cargo test --message-format=json` with one failing test, so test output is interleaved.
+    const TEST: &str = include_str!("../fixtures/cargo_test.jsonl");
+
+    fn read(output: &str) -> (Diagnostics, String) {
+        let mut cargo = CargoJson::default();
+        let mut shown = Vec::new();
+        // in uneven chunks, the way a pipe hands them over
+        for chunk in output.as_bytes().chunks(700) {
+            shown.extend(cargo.feed(chunk));
+        }
+        shown.extend(cargo.finish());
+        (cargo.diagnostics, String::from_utf8(shown).unwrap())
+    }
+
+    #[test]
+    fn test_with_message_format() {
+        assert_eq!(
+            with_message_format("cargo build --release").as_deref(),
+            Some("cargo build --release --message-format=json")
+        );
+        assert_eq!(
+            with_message_format("cargo +nightly test -p api -- --nocapture").as_deref(),
+            Some("cargo +nightly test -p api --message-format=json -- --nocapture")
+        );
+        assert_eq!(
+            with_message_format("cargo clippy --message-format=json-diagnostic-short").as_deref(),
+            Some("cargo clippy --message-format=json-diagnostic-short")
+        );
+        assert_eq!(
+            with_message_format("cargo check --message-format short"),
+            None
+        );
+        assert_eq!(
+            with_message_format("cargo run --bin 'my app' --").as_deref(),
+            Some("cargo run --bin 'my app' --message-format=json --")
+        );
+        assert_eq!(with_message_format("cargo fmt"), None);
+        assert_eq!(with_message_format("npm test"), None);
+        assert_eq!(with_message_format("cargo build && cargo test"), None);
+        assert_eq!(
+            with_message_format("/usr/bin/cargo b").as_deref(),
+            Some("/usr/bin/cargo b --message-format=json")
+        );
+    }
+
+    #[test]
+    fn test_check_output() {
+        let (diagnostics, shown) = read(CHECK);
+        assert_eq!(diagnostics.errors, 2);
+        assert_eq!(diagnostics.warnings, 3);
+        assert_eq!(
+            diagnostics.summary().as_deref(),
+            Some("2 errors (E0308, E0502), 3 warnings across 3 crates; app and util failed")
+        );
+        assert!(!shown.contains("\"reason\""), "{}", shown);
+        assert!(shown.starts_with("warning: variable does not need to be mutable\n"));
+        assert!(
+            shown.contains("error[E0308]: mismatched types\n"),
+            "{}",
+            shown
+        );
+        assert!(
+            shown.contains("For more information about this error, try `rustc --explain E0502`.")
+        );
+    }
+
+    #[test]
+    fn test_interleaved_test_output() {
+        let (diagnostics, shown) = read(TEST);
+        assert_eq!(
+            diagnostics.summary().as_deref(),
+            Some("2 warnings across 1 crate")
+        );
+        assert!(shown.contains("warning: unused variable: `unused`"));
+        // everything that isn't one of cargo's messages comes through untouched
+        let plain: Vec<&str> = TEST.lines().filter(|line| !line.starts_with('{')).collect();
+        let tail: Vec<&str> = shown.lines().skip_while(|line| !line.is_empty()).collect();
+        assert_eq!(tail, plain);
+        assert!(shown.ends_with("finished in 0.00s\n\n"), "{:?}", shown);
+    }
+
+    #[test]
+    fn test_partial_lines() {
+        let mut cargo = CargoJson::default();
+        assert!(cargo.feed(b"running 1 te").is_empty());
+        assert_eq!(
+            cargo.feed(b"st\r\n{\"reason\":\"build-fin"),
+            b"running 1 test\n"
+        );
+        assert!(cargo.feed(b"ished\",\"success\":true}\n").is_empty());
+        assert_eq!(cargo.feed(b"{not json\nok"), b"{not json\n");
+        assert_eq!(cargo.finish(), b"ok\n");
+        assert_eq!(cargo.diagnostics.summary(), None);
+    }
+}
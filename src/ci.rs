@@ -0,0 +1,240 @@
+//! `--ci`: runs that never wait on a prompt, with output that reads well in CI logs.
+//!
+//! [`Fold`] wraps each step's output in the markers the CI log, or terminal, shows it under.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How wide [`Fold::Terminal`] draws its rule, title included.
+const RULE_WIDTH: usize = 60;
+
+/// Numbers GitLab sections, whose names must differ within a job.
+static SECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether atomic may ask questions, and how step output is framed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Interactivity {
+    /// Someone may be at a terminal to answer prompts.
+    #[default]
+    Interactive,
+    /// Nobody is there to answer: prompts take their safe default and the auto-commit is off
+    /// unless `--commit` asks for it.
+    Ci {
+        /// Running under GitHub Actions, which folds `::group::` blocks in its logs.
+        github_actions: bool,
+    },
+}
+
+impl Interactivity {
+    /// CI mode is on with `--ci`, or when the `CI` variable CI services set is present.
+    ///
+    /// `var` looks up environment variables; a variable set to `""`, `0` or `false` counts as unset.
+    pub fn detect(ci_flag: bool, var: impl Fn(&str) -> Option<String>) -> Self {
+        let set = |key: &str| is_set(&var, key);
+        if !ci_flag && !set("CI") {
+            return Interactivity::Interactive;
+        }
+        Interactivity::Ci {
+            github_actions: set("GITHUB_ACTIONS"),
+        }
+    }
+
+    pub fn is_ci(self) -> bool {
+        matches!(self, Interactivity::Ci { .. })
+    }
+}
+
+/// Whether `key` is set to something other than `""`, `0` or `false`.
+fn is_set(var: impl Fn(&str) -> Option<String>, key: &str) -> bool {
+    var(key).is_some_and(|value| {
+        !value.is_empty() && value != "0" && !value.eq_ignore_ascii_case("false")
+    })
+}
+
+/// The markers around each step's output, so the log it ends up in can fold it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fold {
+    /// `--no-fold`, or output going to a file or an unknown CI.
+    #[default]
+    None,
+    /// `::group::` and `::endgroup::`.
+    GitHubActions,
+    /// `section_start` and `section_end` lines, hidden by `\r\e[0K`.
+    GitLab,
+    /// A `--- ` header, folding everything up to the next one.
+    Buildkite,
+    /// A bold rule with the step's command, on a terminal.
+    Terminal,
+}
+
+impl Fold {
+    /// The fold for the CI service `var` says atomic runs under, or [`Fold::Terminal`] when
+    /// stdout is a `terminal`. `--no-fold` turns it off.
+    pub fn detect(no_fold: bool, terminal: bool, var: impl Fn(&str) -> Option<String>) -> Self {
+        let set = |key: &str| is_set(&var, key);
+        if no_fold {
+            Fold::None
+        } else if set("GITHUB_ACTIONS") {
+            Fold::GitHubActions
+        } else if set("GITLAB_CI") {
+            Fold::GitLab
+        } else if set("BUILDKITE") {
+            Fold::Buildkite
+        } else if terminal {
+            Fold::Terminal
+        } else {
+            Fold::None
+        }
+    }
+
+    /// A number for the next section, to pass to [`start`](Self::start) and [`end`](Self::end).
+    pub fn next_section() -> usize {
+        SECTIONS.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The line to print before a step's output. `now` is seconds since the epoch, which GitLab
+    /// times sections with.
+    pub fn start(self, title: &str, section: usize, now: u64) -> Option<String> {
+        let title = title.replace(['\r', '\n'], " ");
+        match self {
+            Fold::None => None,
+            Fold::GitHubActions => Some(format!("::group::{}", title)),
+            Fold::GitLab => Some(format!(
+                "\x1b[0Ksection_start:{}:atomic_step_{}[collapsed=true]\r\x1b[0K{}",
+                now, section, title
+            )),
+            Fold::Buildkite => Some(format!("--- {}", title)),
+            Fold::Terminal => {
+                let rule = RULE_WIDTH.saturating_sub(title.chars().count() + 4).max(3);
+                Some(format!("\x1b[1m── {} {}\x1b[0m", title, "─".repeat(rule)))
+            }
+        }
+    }
+
+    /// The line to print after a step's output, if this fold needs one. Buildkite has no end
+    /// marker, but expands the section of a step that `failed`.
+    pub fn end(self, section: usize, now: u64, failed: bool) -> Option<String> {
+        match self {
+            Fold::GitHubActions => Some("::endgroup::".to_string()),
+            Fold::GitLab => Some(format!(
+                "\x1b[0Ksection_end:{}:atomic_step_{}\r\x1b[0K",
+                now, section
+            )),
+            Fold::Buildkite if failed => Some("^^^ +++".to_string()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::env;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            Interactivity::detect(false, env(&[])),
+            Interactivity::Interactive
+        );
+        assert_eq!(
+            Interactivity::detect(false, env(&[("CI", "false")])),
+            Interactivity::Interactive
+        );
+        assert_eq!(
+            Interactivity::detect(false, env(&[("CI", "1")])),
+            Interactivity::Ci {
+                github_actions: false
+            }
+        );
+        assert_eq!(
+            Interactivity::detect(true, env(&[])),
+            Interactivity::Ci {
+                github_actions: false
+            }
+        );
+        assert_eq!(
+            Interactivity::detect(false, env(&[("CI", "true"), ("GITHUB_ACTIONS", "true")])),
+            Interactivity::Ci {
+                github_actions: true
+            }
+        );
+        // GITHUB_ACTIONS alone doesn't turn CI mode on
+        assert_eq!(
+            Interactivity::detect(false, env(&[("GITHUB_ACTIONS", "true")])),
+            Interactivity::Interactive
+        );
+    }
+
+    #[test]
+    fn test_detect_fold() {
+        let github = || env(&[("CI", "1"), ("GITHUB_ACTIONS", "true")]);
+        assert_eq!(Fold::detect(false, false, github()), Fold::GitHubActions);
+        assert_eq!(
+            Fold::detect(false, true, env(&[("GITLAB_CI", "true")])),
+            Fold::GitLab
+        );
+        assert_eq!(
+            Fold::detect(false, false, env(&[("BUILDKITE", "true")])),
+            Fold::Buildkite
+        );
+        assert_eq!(Fold::detect(false, true, env(&[])), Fold::Terminal);
+        assert_eq!(Fold::detect(false, false, env(&[("CI", "1")])), Fold::None);
+        assert_eq!(
+            Fold::detect(false, false, env(&[("GITLAB_CI", "false")])),
+            Fold::None
+        );
+        assert_eq!(Fold::detect(true, true, github()), Fold::None);
+    }
+
+    #[test]
+    fn test_github_fold() {
+        let fold = Fold::GitHubActions;
+        assert_eq!(
+            fold.start("cargo build\ncargo test", 1, 0).unwrap(),
+            "::group::cargo build cargo test"
+        );
+        assert_eq!(fold.end(1, 0, false).unwrap(), "::endgroup::");
+    }
+
+    #[test]
+    fn test_gitlab_fold() {
+        let fold = Fold::GitLab;
+        assert_eq!(
+            fold.start("cargo test", 3, 1_700_000_000).unwrap(),
+            "\x1b[0Ksection_start:1700000000:atomic_step_3[collapsed=true]\r\x1b[0Kcargo test"
+        );
+        assert_eq!(
+            fold.end(3, 1_700_000_042, true).unwrap(),
+            "\x1b[0Ksection_end:1700000042:atomic_step_3\r\x1b[0K"
+        );
+        assert_ne!(Fold::next_section(), Fold::next_section());
+    }
+
+    #[test]
+    fn test_buildkite_fold() {
+        let fold = Fold::Buildkite;
+        assert_eq!(fold.start("cargo test", 1, 0).unwrap(), "--- cargo test");
+        assert_eq!(fold.end(1, 0, false), None);
+        assert_eq!(fold.end(1, 0, true).unwrap(), "^^^ +++");
+    }
+
+    #[test]
+    fn test_terminal_fold() {
+        let header = Fold::Terminal.start("cargo test", 1, 0).unwrap();
+        assert!(
+            header.starts_with("\x1b[1m── cargo test ───"),
+            "{:?}",
+            header
+        );
+        assert!(header.ends_with("─\x1b[0m"));
+        // 60 columns, title included
+        assert_eq!(header.chars().filter(|c| *c == '─').count(), 2 + 46);
+        let long = "x".repeat(80);
+        assert!(Fold::Terminal
+            .start(&long, 1, 0)
+            .unwrap()
+            .ends_with("x ───\x1b[0m"));
+        assert_eq!(Fold::Terminal.end(1, 0, true), None);
+        assert_eq!(Fold::None.start("cargo test", 1, 0), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! `clean_env = true`: running a command with only a few of atomic's own environment variables,
+//! so stray ones such as RUSTFLAGS or NODE_OPTIONS on one machine don't change what it does.
+//!
+//! atomic's variables, `env_files` and `env_file` still reach the command; `env_allow` names more
+//! variables to keep.
+
+use std::env;
+
+/// Variables a clean environment keeps on every platform.
+const KEPT: &[&str] = &["PATH", "HOME", "TERM", "LANG"];
+
+/// Variables it also keeps on Windows, where many programs can't start without them.
+const KEPT_ON_WINDOWS: &[&str] = &[
+    "USERPROFILE",
+    "SystemRoot",
+    "SystemDrive",
+    "windir",
+    "ComSpec",
+    "PATHEXT",
+    "TEMP",
+    "TMP",
+    "APPDATA",
+    "LOCALAPPDATA",
+    "ProgramData",
+    "ProgramFiles",
+];
+
+/// The names a clean environment keeps: [`KEPT`], those Windows needs when `windows` is set, then
+/// `allowed` from `env_allow`.
+pub fn allowlist(windows: bool, allowed: &[String]) -> Vec<String> {
+    let platform = match windows {
+        true => KEPT_ON_WINDOWS,
+        false => &[],
+    };
+    let mut names: Vec<String> = KEPT
+        .iter()
+        .chain(platform)
+        .map(|name| name.to_string())
+        .collect();
+    for name in allowed {
+        if !names.iter().any(|kept| same_name(kept, name, windows)) {
+            names.push(name.clone());
+        }
+    }
+    names
+}
+
+/// The variables of `vars` that `allowlist` names. Names are compared ignoring case on Windows,
+/// like the environment itself does.
+pub fn keep(
+    vars: impl IntoIterator<Item = (String, String)>,
+    allowlist: &[String],
+    windows: bool,
+) -> Vec<(String, String)> {
+    vars.into_iter()
+        .filter(|(key, _)| allowlist.iter().any(|name| same_name(name, key, windows)))
+        .collect()
+}
+
+fn same_name(a: &str, b: &str, windows: bool) -> bool {
+    match windows {
+        true => a.eq_ignore_ascii_case(b),
+        false => a == b,
+    }
+}
+
+/// The variables of atomic's environment a command with `clean_env` starts with, `allowed`
+/// from its `env_allow` included. Variables that aren't valid unicode are left out.
+pub fn inherited(allowed: &[String]) -> Vec<(String, String)> {
+    let vars = env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)));
+    let windows = cfg!(windows);
+    keep(vars, &allowlist(windows, allowed), windows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_allowlist() {
+        assert_eq!(allowlist(false, &[]), ["PATH", "HOME", "TERM", "LANG"]);
+        let windows = allowlist(true, &["CARGO_HOME".to_string(), "path".to_string()]);
+        assert!(windows.contains(&"SystemRoot".to_string()));
+        assert_eq!(windows.last().unwrap(), "CARGO_HOME");
+        // already kept, in another case
+        assert!(!windows.contains(&"path".to_string()));
+        assert_eq!(
+            allowlist(false, &["path".to_string()]).last().unwrap(),
+            "path"
+        );
+    }
+
+    #[test]
+    fn test_keep() {
+        let env = vars(&[
+            ("PATH", "/usr/bin"),
+            ("RUSTFLAGS", "-C target-cpu=native"),
+            ("NODE_OPTIONS", "--inspect"),
+            ("CARGO_HOME", "/opt/cargo"),
+            ("Path", "C:\\Windows"),
+        ]);
+        let allowed = ["CARGO_HOME".to_string()];
+        assert_eq!(
+            keep(env.clone(), &allowlist(false, &allowed), false),
+            vars(&[("PATH", "/usr/bin"), ("CARGO_HOME", "/opt/cargo")])
+        );
+        assert_eq!(
+            keep(env.clone(), &allowlist(true, &[]), true),
+            vars(&[("PATH", "/usr/bin"), ("Path", "C:\\Windows")])
+        );
+        assert!(keep(env, &[], false).is_empty());
+    }
+}
@@ -1,127 +1,1996 @@
 use std::{
-    fs::{self, OpenOptions},
-    path::Path,
+    env, fs,
+    io::{self, BufRead, IsTerminal, Write},
+    iter,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use clap::{arg, Command};
-use toml::Value;
+use clap::{
+    arg, builder::PossibleValuesParser, parser::ValueSource, value_parser, ArgAction, ArgGroup,
+    ArgMatches, Command,
+};
+use git2::Repository;
 
-use crate::git::{commit_local_changes, send_command};
-use crate::toml::{find_key_in_tables, get_toml_content, get_toml_keys};
+use crate::artifacts;
+use crate::ci::{Fold, Interactivity};
+use crate::command::{commit_settings, run_adhoc, run_command, RunOptions};
+use crate::config::{AtomicConfig, Settings};
+use crate::context::Context;
+use crate::doctor;
+use crate::errors::{self, Style};
+use crate::events;
+use crate::exit;
+use crate::git::{
+    branch_log, commit_local_changes, commit_stats, create_snapshot, detect_base_branch,
+    diff_since_last_run, find_last_run, format_commit_row, list_snapshots, open_repository,
+    plan_restore, relative_time, render_snapshots, restore_snapshot, CommitOptions, NO_CHANGES,
+};
+use crate::gitignore;
+use crate::init;
+use crate::junit;
+use crate::last_run::{self, LastRun};
+use crate::lint;
+use crate::list::{self, LIST_SECTIONS};
+use crate::migrate;
+use crate::nesting::Nesting;
+use crate::pager;
+use crate::plan;
+use crate::repos::{self, Outcome};
+use crate::schema::{json_schema, Severity};
+use crate::share::{export_commands, ImportPlan, EXPORT_SECTIONS};
+use crate::stats;
+use crate::template;
+use crate::toml::{
+    self, apply_profile, config_files, find_config, global_config, load_and_validate_toml,
+    load_layered, load_toml, read_toml_text,
+};
+use crate::trace;
+use crate::util::{confirm, today};
+use crate::verify;
+use crate::version;
+use crate::yaml;
+use crate::{AtomicError, Result};
 
 fn cli() -> Command {
     Command::new("atomic")
         .about("run custom commands that perform git actions, so you dont have to.")
-        .arg(arg!(-l --list "list all commands found in project atomic.toml").exclusive(true))
-        .arg(arg!(-i --init "initialize atomic template in project repository").exclusive(true))
-        .arg(arg!(-t --test "tester").exclusive(true))
-        .arg(arg!([CMD] "run command listed in projects atomic.toml").exclusive(true))
-        .arg_required_else_help(true)
+        .disable_version_flag(true)
+        .arg(arg!(-V --version "print the version"))
+        .arg(arg!(--verbose "with --version, also print the commit, build date, target and features").requires("version"))
+        .arg(arg!(--"check-update" "ask crates.io whether a newer atomic has been released").exclusive(true))
+        .arg(
+            arg!(--context "print the project atomic would run in as key=value lines, for shell prompts; exits 1 outside a project")
+                .conflicts_with("CMD"),
+        )
+        .arg(arg!(--json "with --context, print JSON instead").requires("context"))
+        .arg(arg!(-l --list "list all commands found in project atomic.toml").conflicts_with("CMD"))
+        .arg(arg!(--filter <TEXT> "with --list, only show commands whose name or desc contains TEXT, or whose name has its letters in order").requires("list"))
+        .arg(
+            arg!(--stats "show how often each command has run and the time spent in it; with --list, add that to each command")
+                .conflicts_with_all(["init", "test", "log", "last-run", "diff", "stdin", "doctor", "export", "import", "again", "explain", "edit-config", "CMD"]),
+        )
+        .arg(arg!(--reset "with --stats, forget every recorded run, after asking").requires("stats").conflicts_with("list"))
+        .arg(
+            arg!(--section <SECTION> "with --list, only show commands from this section")
+                .value_parser(PossibleValuesParser::new(LIST_SECTIONS))
+                .requires("list"),
+        )
+        .arg(
+            arg!(-i --init "initialize atomic template in project repository")
+                .conflicts_with_all(["list", "test", "log", "last-run", "diff", "stdin", "doctor", "export", "import", "again", "CMD"]),
+        )
+        .arg(
+            arg!(--format <FORMAT> "with --init, write atomic.toml or atomic.yaml")
+                .value_parser(["toml", "yaml"])
+                .requires("init"),
+        )
+        .arg(
+            arg!(--from <URL> "with --init, copy a template from a git repository: URL#NAME, or just URL to list its templates")
+                .requires("init"),
+        )
+        .arg(
+            arg!(--merge [TEMPLATE] "with --init, add the commands of the template (rust or generic, by default the one that fits) that atomic.toml doesn't have yet")
+                .value_parser(["rust", "generic"])
+                .requires("init")
+                .conflicts_with_all(["from", "format"]),
+        )
+        .arg(arg!(-t --test "tester").conflicts_with("CMD"))
+        .arg(arg!(-p --profile <NAME> "overlay [profile.<NAME>] from atomic.toml (or set ATOMIC_PROFILE)"))
+        .arg(
+            arg!(--repo <PATH> "work on the repository containing PATH, using its atomic.toml (GIT_DIR / GIT_WORK_TREE also work)")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--repos <FILE> "run CMD in each repository listed in FILE, one path per line, then sum up how each went")
+                .value_parser(value_parser!(PathBuf))
+                .requires("CMD")
+                .conflicts_with_all(["repo", "dry-run", "explain", "stdin", "again"]),
+        )
+        .arg(
+            arg!(--"all-repos" "run CMD in each repository `workspace_repos` lists under [atomic]")
+                .requires("CMD")
+                .conflicts_with_all(["repos", "repo", "dry-run", "explain", "stdin", "again"]),
+        )
+        .arg(
+            arg!(--"parallel-repos" <N> "with --repos or --all-repos, run in N repositories at once, printing each one's output when it's done")
+                .value_parser(value_parser!(usize))
+                .requires("multi-repo"),
+        )
+        .group(ArgGroup::new("multi-repo").args(["repos", "all-repos"]))
+        // lets an atomic running --parallel-repos tell a failed command apart
+        .arg(arg!(--"passthrough-exit-code" "when a step fails, exit with its exit code instead of 5"))
+        .arg(arg!(--"print-exit-codes" "list the exit codes atomic uses and what each one means").exclusive(true))
+        .arg(arg!(--"show-diff" "list the files captured by the auto-commit").conflicts_with_all(["list", "init"]))
+        .arg(
+            arg!(--log [N] "show the last N commits on this branch, marking atomic auto-commits (default 15)")
+                .value_parser(value_parser!(usize))
+                .default_missing_value("15")
+                .conflicts_with_all(["list", "init", "test", "CMD"]),
+        )
+        .arg(
+            arg!(--"last-run" <CMD> "show the files changed by the most recent auto-commit of CMD")
+                .conflicts_with_all(["list", "init", "test", "log", "CMD"]),
+        )
+        .arg(
+            arg!(--diff <CMD> "show what changed since the most recent auto-commit of CMD, as a patch")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "CMD"]),
+        )
+        .arg(
+            arg!(--snapshot <NAME> "commit the work tree, even unchanged, and tag it atomic/snapshot/<name> to --restore later")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "CMD"]),
+        )
+        .arg(
+            arg!(--snapshots "list the snapshots in this repository, newest first")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "snapshot", "CMD"]),
+        )
+        .arg(
+            arg!(--restore <NAME> "reset the branch to a snapshot, keeping the commits it drops under refs/atomic/backup/")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "snapshot", "snapshots", "CMD"]),
+        )
+        .arg(
+            arg!(--verify [RANGE] "check that the auto-commits in RANGE (default: since the base branch) ran with the atomic.toml committed in them")
+                .default_missing_value("")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "snapshot", "snapshots", "restore", "CMD"]),
+        )
+        .arg(arg!(--stat "with --diff, list the changed files with line counts instead").requires("diff"))
+        .arg(arg!(--all "with --log, show the full history instead of stopping at the base branch").requires("log"))
+        .arg(
+            arg!(--stdin "read a command table from stdin, e.g. `command = [\"cargo fmt\"]`, and run it without auto-committing")
+                .conflicts_with_all(["list", "init", "test", "log", "CMD"]),
+        )
+        .arg(
+            arg!(--doctor "check git, atomic.toml and the current branch for common setup problems")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "stdin", "CMD"]),
+        )
+        .arg(
+            arg!(--lint "warn about command strings that won't do what they seem to, such as a `cd` step in a chain")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "stdin", "doctor", "CMD"]),
+        )
+        .arg(
+            arg!(--check "check atomic.toml, and the configs it's layered on, against the schema, listing errors and warnings")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "stdin", "doctor", "lint", "export", "import", "again", "explain", "CMD"]),
+        )
+        .arg(arg!(--strict "with --check, fail on warnings too").requires("check"))
+        .arg(arg!(-q --quiet "don't print warnings about atomic.toml, such as unknown keys"))
+        .arg(arg!(--schema "print a JSON Schema for atomic.toml, for editor completion and validation").exclusive(true))
+        .arg(
+            arg!(--"include-preexisting" "also auto-commit files that had uncommitted changes before the command ran")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run"]),
+        )
+        .arg(
+            arg!(--trace "print a timing tree of every step once the command finishes")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run"]),
+        )
+        .arg(
+            arg!(--"trace-json" <FILE> "write the timings as Chrome trace-event JSON, for about://tracing")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with_all(["list", "init", "test", "log", "last-run"]),
+        )
+        .arg(
+            arg!(--events <PATH> "send progress events as JSON lines to the unix socket (or named pipe) a tool listens on")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "doctor"]),
+        )
+        .arg(
+            arg!(--junit <FILE> "write each step of the run as a JUnit testcase, for CI test reports")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "doctor"]),
+        )
+        .arg(
+            arg!(--export <FILE> "write the commands in atomic.toml to FILE, to --import into another project")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "stdin", "doctor", "CMD"]),
+        )
+        .arg(
+            arg!(--only <SECTION> "with --export, only export this section (repeatable)")
+                .value_parser(PossibleValuesParser::new(EXPORT_SECTIONS))
+                .action(ArgAction::Append)
+                .requires("export"),
+        )
+        .arg(
+            arg!(--import <FILE> "merge the commands exported to FILE into atomic.toml, after asking")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "stdin", "doctor", "export", "CMD"]),
+        )
+        .arg(
+            arg!(--again "run the last command run in this project again, with the same flags and profile")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "stdin", "doctor", "export", "import", "repo", "CMD"]),
+        )
+        .arg(
+            arg!(--"dry-run" "print the shell commands CMD would run, in order, instead of running them (with --again, also the command repeated; with --init --merge, the changes as a diff)")
+                .conflicts_with_all(["list", "test", "log", "last-run", "diff", "stdin", "doctor", "export", "import"]),
+        )
+        .arg(
+            arg!(--explain <CMD> "show how CMD resolves, as a tree: sections, chain steps, hooks, environment and auto-commit, without running anything")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "stdin", "doctor", "export", "import", "again", "dry-run", "CMD"]),
+        )
+        .arg(arg!(--"explain-error" <CODE> "describe an error code such as ATM010, with common fixes").exclusive(true))
+        .arg(arg!(--force "run commands with `inputs` even when none of them changed since the last successful run"))
+        .arg(arg!(-y --yes "run commands that set `confirm`, --restore, or --template update without asking, for scripts"))
+        .arg(arg!(--ci "never prompt, and skip the auto-commit (on when CI is set)"))
+        .arg(arg!(--"no-pager" "print --list, --explain, --log, --diff and --snapshots as they are instead of through $PAGER (or set NO_PAGER)"))
+        .arg(arg!(--"no-fold" "don't wrap each step's output in GitHub Actions, GitLab or Buildkite sections, or a header on a terminal"))
+        .arg(arg!(--commit "auto-commit in CI mode too"))
+        .arg(arg!(--"allow-nested-commit" "auto-commit and run [atomic] hooks even when started by another atomic's command"))
+        .arg(arg!(--"no-wait" "fail at once instead of waiting when a command with `lock = true` is already running"))
+        .arg(
+            arg!(--"artifacts-dir" <PATH> "copy the `artifacts` of commands to PATH/<step>/ instead of .atomic/artifacts/<run>/<step>/")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--artifacts "list the runs that collected artifacts, newest first, with their file counts and sizes")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "snapshot", "snapshots", "CMD"]),
+        )
+        .arg(arg!(--"keep-tmp" "keep the ATOMIC_TMPDIR of steps with `tmpdir` set instead of removing it, and print where it is"))
+        .arg(arg!(--"no-sign" "auto-commit unsigned even when git's commit.gpgsign is set"))
+        .arg(arg!(--"no-change-notice" "don't say when atomic.toml changed since the last run (or set change_notice = false under [atomic])"))
+        .arg(arg!(--"no-git" "only run commands: no auto-commit, no branch variables, no git flags (or set git = false under [atomic])"))
+        .arg(
+            arg!(--"edit-config" "open atomic.toml in $VISUAL or $EDITOR")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "stdin", "doctor", "export", "import", "again", "explain", "CMD"]),
+        )
+        .arg(arg!(--global "with --edit-config, edit the machine-wide config every project is merged over, creating it on first use").requires("edit-config"))
+        .arg(
+            arg!(--"migrate-config" "upgrade atomic.toml to the current format, writing atomic.toml.new and listing each change")
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "stdin", "doctor", "export", "import", "again", "explain", "edit-config", "stats", "CMD"]),
+        )
+        .arg(arg!(--write "with --migrate-config, show the changes as a diff and update atomic.toml in place").requires("migrate-config"))
+        .arg(arg!(--overwrite "with --import, replace local commands that have the same name").requires("import"))
+        .arg(
+            arg!(--template <ACTION> "show NAME (rust or generic), diff atomic.toml against it, or update your saved copy from the bundled one")
+                .num_args(2)
+                .value_names(["ACTION", "NAME"])
+                .conflicts_with_all(["list", "init", "test", "log", "last-run", "diff", "stdin", "doctor", "export", "import", "again", "explain", "edit-config", "migrate-config", "stats", "CMD"]),
+        )
+        .arg(arg!([CMD] "run command listed in projects atomic.toml"))
 }
 
+/// Runs atomic with its command line, then exits with the code [`run_cli`] returns; see
+/// [`exit`] for what each one means.
 pub fn start_cli() {
-    let matches = cli().get_matches();
+    let code = run_cli();
+    if code != exit::SUCCESS {
+        process::exit(code);
+    }
+}
+
+fn run_cli() -> i32 {
+    let mut args: Vec<String> = env::args_os()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let mut matches = cli().get_matches();
+    if let Some(code) = matches.get_one::<String>("explain-error") {
+        return errors::explain_error(code);
+    }
+    if matches.get_flag("print-exit-codes") {
+        print!("{}", exit::render_table());
+        return exit::SUCCESS;
+    }
+    // --again replaces the matches with the repeated ones, which don't have it
+    let dry_run = matches.get_flag("dry-run");
+    if matches.get_flag("again") {
+        args = match again_args() {
+            Ok(args) => args,
+            Err(err) => {
+                let interactivity =
+                    Interactivity::detect(matches.get_flag("ci"), |key| env::var(key).ok());
+                errors::set_style(Style::detect(interactivity));
+                return failed(&err);
+            }
+        };
+        if dry_run {
+            println!("atomic {}", shell_words::join(&args));
+        } else {
+            eprintln!("again: atomic {}", shell_words::join(&args));
+        }
+        matches = replay(&args).unwrap_or_else(|err| err.exit());
+    }
+    let interactivity = Interactivity::detect(matches.get_flag("ci"), |key| env::var(key).ok());
+    errors::set_style(Style::detect(interactivity));
+    toml::set_quiet(matches.get_flag("quiet"));
+    pager::set_disabled(matches.get_flag("no-pager"));
+    let profile = active_profile(&matches);
+    let repo = matches.get_one::<PathBuf>("repo").map(PathBuf::as_path);
+    let atomic = match config_path(repo) {
+        Ok(atomic) => atomic,
+        Err(err) => {
+            let code = failed(&err);
+            // prompts tell "not a project" (1) apart from a problem with one
+            return if matches.get_flag("context") { 2 } else { code };
+        }
+    };
+    let trace_json = matches.get_one::<PathBuf>("trace-json");
+    let tracing = matches.get_flag("trace") || trace_json.is_some();
+    if tracing {
+        trace::enable();
+    }
+    let junit_xml = matches.get_one::<PathBuf>("junit");
+    if junit_xml.is_some() {
+        junit::enable();
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("events") {
+        events::listen_at(path);
+    }
+
+    let nesting = match Nesting::detect(|key| env::var(key).ok()) {
+        Ok(nesting) => nesting,
+        Err(err) => return failed(&err),
+    };
+
+    if let Some(reason) = git_off(&matches, &atomic, profile.as_deref()) {
+        if let Some(flag) = git_flag_given(&matches) {
+            eprintln!(
+                "--{} needs git, which {} turns off; in this mode atomic only runs commands",
+                flag, reason
+            );
+            return exit::USAGE;
+        }
+    }
 
-    match (
-        matches.get_one::<bool>("list"),
-        matches.get_one::<bool>("init"),
-        matches.get_one::<bool>("test"),
+    let mut exit_code = exit::SUCCESS;
+    let passthrough = matches.get_flag("passthrough-exit-code");
+    // what to keep for --again, once a command has run
+    let mut ran = None;
+    if matches.get_flag("version") {
+        if matches.get_flag("verbose") {
+            println!("{}", version::verbose_version());
+        } else {
+            println!("atomic {}", env!("CARGO_PKG_VERSION"));
+        }
+    } else if matches.get_flag("check-update") {
+        // an update check is a nicety, so being offline is only a warning
+        match version::check_update(&version::CratesIo) {
+            Ok(message) => println!("{}", message),
+            Err(err) => eprintln!("warning: couldn't check for updates: {}", err),
+        }
+    } else if matches.get_flag("context") {
+        exit_code = show_context(
+            &atomic,
+            profile.as_deref(),
+            repo,
+            !matches.get_flag("no-git"),
+            matches.get_flag("json"),
+        );
+    } else if matches.get_flag("list") {
+        exit_code = list_keys(
+            &atomic,
+            profile.as_deref(),
+            matches.get_one::<String>("filter").map(String::as_str),
+            matches.get_one::<String>("section").map(String::as_str),
+            matches.get_flag("stats"),
+        );
+    } else if matches.get_flag("stats") {
+        exit_code = show_stats(
+            &atomic,
+            profile.as_deref(),
+            matches.get_flag("reset"),
+            interactivity,
+        );
+    } else if let Some(limit) = matches.get_one::<usize>("log") {
+        exit_code = show_log(
+            repo,
+            &atomic,
+            *limit,
+            matches.get_flag("all"),
+            profile.as_deref(),
+        );
+    } else if let Some(cmd) = matches.get_one::<String>("explain") {
+        let options = run_options(&matches, profile, repo, interactivity, nesting);
+        exit_code = explain(cmd, &atomic, &options, false);
+    } else if let Some(cmd) = matches.get_one::<String>("last-run") {
+        exit_code = show_last_run(repo, cmd);
+    } else if let Some(cmd) = matches.get_one::<String>("diff") {
+        exit_code = show_diff_since(repo, cmd, matches.get_flag("stat"));
+    } else if let Some(name) = matches.get_one::<String>("snapshot") {
+        exit_code = take_snapshot(
+            repo,
+            &atomic,
+            profile.as_deref(),
+            name,
+            matches.get_flag("no-sign"),
+        );
+    } else if matches.get_flag("snapshots") {
+        exit_code = show_snapshots(repo);
+    } else if matches.get_flag("artifacts") {
+        exit_code = show_artifacts(&atomic);
+    } else if let Some(name) = matches.get_one::<String>("restore") {
+        exit_code = restore(repo, name, matches.get_flag("yes"), interactivity);
+    } else if let Some(range) = matches.get_one::<String>("verify") {
+        let range = Some(range.as_str()).filter(|range| !range.is_empty());
+        exit_code = verify_commits(repo, &atomic, range, profile.as_deref());
+    } else if matches.get_flag("schema") {
+        match serde_json::to_string_pretty(&json_schema()) {
+            Ok(schema) => println!("{}", schema),
+            Err(err) => {
+                eprintln!("Error rendering schema: {}", err);
+                exit_code = exit::FAILURE;
+            }
+        }
+    } else if matches.get_flag("doctor") {
+        exit_code = run_doctor(&atomic, repo, profile.as_deref());
+    } else if matches.get_flag("check") {
+        exit_code = check_config(
+            &atomic,
+            matches.get_flag("strict"),
+            matches.get_flag("quiet"),
+        );
+    } else if matches.get_flag("lint") {
+        exit_code = run_lint(&atomic, profile.as_deref());
+    } else if let Some(file) = matches.get_one::<PathBuf>("export") {
+        let sections: Vec<&str> = match matches.get_many::<String>("only") {
+            Some(only) => only.map(String::as_str).collect(),
+            None => EXPORT_SECTIONS.to_vec(),
+        };
+        if let Err(err) = export_to(&atomic, file, &sections) {
+            exit_code = failed(&err);
+        }
+    } else if let Some(file) = matches.get_one::<PathBuf>("import") {
+        if let Err(err) = import_from(&atomic, file, matches.get_flag("overwrite"), interactivity) {
+            exit_code = failed(&err);
+        }
+    } else if matches.get_flag("migrate-config") {
+        if let Err(err) = migrate_config(&atomic, matches.get_flag("write")) {
+            exit_code = failed(&err);
+        }
+    } else if matches.get_flag("edit-config") {
+        if let Err(err) = edit_config(&atomic, matches.get_flag("global")) {
+            exit_code = failed(&err);
+        }
+    } else if let Some(values) = matches.get_many::<String>("template") {
+        let values: Vec<&str> = values.map(String::as_str).collect();
+        if !template::ACTIONS.contains(&values[0]) {
+            eprintln!(
+                "--template takes show, diff or update, then a template name, not '{}'",
+                values[0]
+            );
+            exit_code = exit::USAGE;
+        } else if let Err(err) = template_action(
+            &atomic,
+            global_config().as_deref(),
+            values[0],
+            values[1],
+            matches.get_flag("yes"),
+            interactivity,
+        ) {
+            exit_code = failed(&err);
+        }
+    } else if matches.get_flag("init") && matches.contains_id("merge") {
+        let template = matches.get_one::<String>("merge").map(String::as_str);
+        if let Err(err) = merge_init(&atomic, template, matches.get_flag("dry-run")) {
+            exit_code = failed(&err);
+        }
+    } else if matches.get_flag("init") && matches.get_flag("dry-run") {
+        eprintln!(
+            "--dry-run only works with --init --merge; --init alone never overwrites anything"
+        );
+        exit_code = exit::USAGE;
+    } else if matches.get_flag("init") {
+        exit_code = start_init(
+            &atomic,
+            matches.get_one::<String>("format").map(String::as_str),
+            matches.get_one::<String>("from").map(String::as_str),
+            interactivity,
+        );
+    } else if matches.get_flag("test") {
+        let options = CommitOptions {
+            repo,
+            ..Default::default()
+        };
+        match commit_local_changes("test", &options) {
+            Ok(Some(commit)) => commit.print_summary(matches.get_flag("show-diff")),
+            Ok(None) => println!("{}", NO_CHANGES),
+            Err(err) => {
+                eprintln!("Error committing local changes: {}", err);
+                exit_code = exit::FAILURE;
+            }
+        }
+    } else if matches.get_flag("stdin") {
+        let options = run_options(&matches, profile, repo, interactivity, nesting);
+        let result = io::read_to_string(io::stdin())
+            .map_err(|err| AtomicError::Generic(format!("Failed to read stdin: {}", err)))
+            .and_then(|fragment| run_adhoc(&fragment, &atomic, &options));
+        exit_code = finish_run("--stdin", result, interactivity, passthrough);
+        ran = Some(LastRun::new(args, true));
+    } else if let (Some(cmd), Some(repos)) = (
         matches.get_one::<String>("CMD"),
+        repo_list(&matches, &atomic, profile.as_deref()),
     ) {
-        (Some(true), Some(false), Some(false), _) => {
-            list_keys();
+        let jobs = matches.get_one::<usize>("parallel-repos").copied();
+        let options = run_options(&matches, profile, None, interactivity, nesting);
+        exit_code = match repos {
+            Ok(repos) => run_in_repos(cmd, &repos, jobs, &args, &options),
+            Err(err) => failed(&err),
+        };
+    } else if let (Some(cmd), true) = (matches.get_one::<String>("CMD"), dry_run) {
+        let options = run_options(&matches, profile, repo, interactivity, nesting);
+        exit_code = explain(cmd, &atomic, &options, true);
+    } else if let Some(cmd) = matches.get_one::<String>("CMD") {
+        if first_run(&atomic, cmd, interactivity) {
+            ran = Some(LastRun::new(
+                recorded_args(&matches, args, profile.as_deref(), None),
+                false,
+            ));
+            let options = run_options(&matches, profile, repo, interactivity, nesting);
+            exit_code = finish_run(
+                cmd,
+                run_command(cmd, &atomic, &options),
+                interactivity,
+                passthrough,
+            );
+        } else {
+            exit_code = exit::FAILURE;
         }
-        (Some(false), Some(true), Some(false), _) => {
-            start_init();
+    } else if let Some(cmd) = pick_from_terminal(&atomic, profile.as_deref(), interactivity) {
+        if dry_run {
+            let options = run_options(&matches, profile, repo, interactivity, nesting);
+            exit_code = explain(&cmd, &atomic, &options, true);
+        } else {
+            ran = Some(LastRun::new(
+                recorded_args(&matches, args, profile.as_deref(), Some(&cmd)),
+                false,
+            ));
+            let options = run_options(&matches, profile, repo, interactivity, nesting);
+            exit_code = finish_run(
+                &cmd,
+                run_command(&cmd, &atomic, &options),
+                interactivity,
+                passthrough,
+            );
         }
-        (Some(false), Some(false), Some(true), _) => {
-            if let Err(err) = commit_local_changes() {
-                eprintln!("Error committing local changes: {}", err);
+    } else {
+        eprint!("{}", cli().render_help());
+        exit_code = exit::USAGE;
+    }
+
+    events::finish();
+    if tracing {
+        report_trace(matches.get_flag("trace"), trace_json);
+    }
+    if let Some(path) = junit_xml {
+        let suite = match matches.get_one::<String>("CMD") {
+            Some(cmd) => format!("atomic {}", cmd),
+            None if matches.get_flag("stdin") => "atomic --stdin".to_string(),
+            None => "atomic".to_string(),
+        };
+        if let Err(err) = fs::write(path, junit::render(&suite, &junit::finish())) {
+            eprintln!("Error writing JUnit report to {}: {}", path.display(), err);
+        }
+    }
+    // best-effort: a project atomic can't write to still runs, it just can't be repeated
+    if let Some(run) = ran {
+        let _ = last_run::save(&atomic, &run);
+    }
+    exit_code
+}
+
+/// Reports `err`, returning the code atomic exits with for it.
+fn failed(err: &AtomicError) -> i32 {
+    errors::report(err);
+    err.exit_code()
+}
+
+/// Prints what running `cmd` would do, without running it: the `--explain` tree, or with
+/// `dry_run` the `--dry-run` list. Returns the exit code, 1 when `cmd` can't be resolved or the
+/// dry run shows it would fail to start.
+fn explain(cmd: &str, atomic: &Path, options: &RunOptions, dry_run: bool) -> i32 {
+    let plan = match plan::resolve(cmd, atomic, options) {
+        Ok(plan) => plan,
+        Err(err) => {
+            return failed(&err);
+        }
+    };
+    if dry_run {
+        print!("{}", plan.render_steps());
+        i32::from(!plan.is_runnable())
+    } else {
+        pager::print_paged(&plan.render_tree());
+        0
+    }
+}
+
+/// The arguments of the last run in this project.
+///
+/// # Errors
+/// Returns an error if there's no last run there, or it can't be repeated.
+fn again_args() -> Result<Vec<String>> {
+    last_run::load(&config_path(None)?)?
+        .repeatable_args()
+        .map(<[String]>::to_vec)
+}
+
+/// Parses `args` as if atomic had been started with them.
+fn replay(args: &[String]) -> clap::error::Result<ArgMatches> {
+    cli().try_get_matches_from(iter::once("atomic").chain(args.iter().map(String::as_str)))
+}
+
+/// `args` as kept for `--again`: with the profile when it came from `ATOMIC_PROFILE`, and the
+/// command when it was picked from the menu, so the repeat doesn't depend on either.
+fn recorded_args(
+    matches: &ArgMatches,
+    mut args: Vec<String>,
+    profile: Option<&str>,
+    picked: Option<&str>,
+) -> Vec<String> {
+    if matches.value_source("profile") != Some(ValueSource::CommandLine) {
+        if let Some(profile) = profile {
+            args.extend(["--profile".to_string(), profile.to_string()]);
+        }
+    }
+    args.extend(picked.map(str::to_string));
+    args
+}
+
+/// Options for running a command, from the flags that apply to every run.
+fn run_options(
+    matches: &ArgMatches,
+    profile: Option<String>,
+    repo: Option<&Path>,
+    interactivity: Interactivity,
+    nesting: Nesting,
+) -> RunOptions {
+    RunOptions {
+        profile,
+        show_diff: matches.get_flag("show-diff"),
+        include_preexisting: matches.get_flag("include-preexisting"),
+        repo: repo.map(Path::to_path_buf),
+        yes: matches.get_flag("yes"),
+        interactivity,
+        commit: matches.get_flag("commit"),
+        nesting,
+        allow_nested_commit: matches.get_flag("allow-nested-commit"),
+        no_git: matches.get_flag("no-git"),
+        no_wait: matches.get_flag("no-wait"),
+        keep_tmp: matches.get_flag("keep-tmp"),
+        no_change_notice: matches.get_flag("no-change-notice"),
+        no_sign: matches.get_flag("no-sign"),
+        force: matches.get_flag("force"),
+        artifacts_dir: matches.get_one::<PathBuf>("artifacts-dir").cloned(),
+        fold: Fold::detect(
+            matches.get_flag("no-fold"),
+            io::stdout().is_terminal(),
+            |key| env::var(key).ok(),
+        ),
+        ..Default::default()
+    }
+}
+
+/// Flags that read or write the repository, refused while git is off.
+const GIT_FLAGS: &[&str] = &[
+    "test",
+    "commit",
+    "show-diff",
+    "include-preexisting",
+    "allow-nested-commit",
+    "log",
+    "last-run",
+    "diff",
+    "snapshot",
+    "snapshots",
+    "restore",
+    "verify",
+];
+
+/// What turned git off for this run, if anything: `--no-git`, or `git = false` under `[atomic]`.
+fn git_off(matches: &ArgMatches, atomic: &Path, profile: Option<&str>) -> Option<&'static str> {
+    if matches.get_flag("no-git") {
+        return Some("--no-git");
+    }
+    let setting = load_toml(atomic, profile)
+        .and_then(|config| Settings::from_config(&config))
+        .ok()
+        .and_then(|settings| settings.git);
+    (setting == Some(false)).then_some("`git = false` in atomic.toml")
+}
+
+/// The first of [`GIT_FLAGS`] given on the command line.
+fn git_flag_given(matches: &ArgMatches) -> Option<&'static str> {
+    GIT_FLAGS
+        .iter()
+        .copied()
+        .find(|flag| matches.value_source(flag) == Some(ValueSource::CommandLine))
+}
+
+/// Reports how a run ended and picks atomic's exit code, as [`exit::for_run`] maps it: 0 when
+/// the command succeeded, [`exit::STEP_FAILED`] (or the step's own code with `passthrough`) when
+/// a step failed, and the error's own code when the run couldn't go ahead. CI mode also ends
+/// with a one-line summary for the log.
+fn finish_run(
+    name: &str,
+    result: Result<i32>,
+    interactivity: Interactivity,
+    passthrough: bool,
+) -> i32 {
+    if let Err(err) = &result {
+        errors::report(err);
+    }
+    let code = exit::for_run(&result, passthrough);
+    if interactivity.is_ci() {
+        println!("atomic {}: exit code {}", name, code);
+    }
+    code
+}
+
+/// The repositories `--repos` or `--all-repos` asks to run in, if either was given.
+fn repo_list(
+    matches: &ArgMatches,
+    atomic: &Path,
+    profile: Option<&str>,
+) -> Option<Result<Vec<PathBuf>>> {
+    if let Some(file) = matches.get_one::<PathBuf>("repos") {
+        return Some(repos::read_list(file));
+    }
+    matches
+        .get_flag("all-repos")
+        .then(|| repos::from_setting(atomic, profile))
+}
+
+/// Runs `cmd` in each of `repos`, `jobs` at a time when given, and prints the summary. Returns 1
+/// when it failed in any of them.
+fn run_in_repos(
+    cmd: &str,
+    repos: &[PathBuf],
+    jobs: Option<usize>,
+    args: &[String],
+    options: &RunOptions,
+) -> i32 {
+    let profile = options.profile.as_deref();
+    let runs = match jobs {
+        None => repos::run_each(repos, |repo| {
+            let atomic = match repos::runnable(config_path(Some(repo)), cmd, profile) {
+                Ok(atomic) => atomic,
+                Err(outcome) => return outcome,
+            };
+            let options = RunOptions {
+                repo: Some(repo.to_path_buf()),
+                ..options.clone()
+            };
+            let result = run_command(cmd, &atomic, &options);
+            if let Err(err) = &result {
+                errors::report(err);
             }
+            Outcome::from_result(result)
+        }),
+        // each repository gets its own atomic, so its output can be kept until it's done
+        Some(jobs) => repos::run_parallel(repos, jobs, |repo| {
+            if let Err(outcome) = repos::runnable(config_path(Some(repo)), cmd, profile) {
+                return (outcome, Vec::new());
+            }
+            run_child(repo, args)
+        }),
+    };
+    print!("{}", repos::render_summary(&runs));
+    repos::exit_code(&runs)
+}
+
+/// Runs this atomic again with `args` in `repo`, keeping what it prints.
+fn run_child(repo: &Path, args: &[String]) -> (Outcome, Vec<u8>) {
+    let mut forwarded = vec![
+        "--repo".to_string(),
+        repo.display().to_string(),
+        "--passthrough-exit-code".to_string(),
+    ];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repos" | "--parallel-repos" => {
+                args.next();
+            }
+            "--all-repos" => {}
+            _ if arg.starts_with("--repos=") || arg.starts_with("--parallel-repos=") => {}
+            _ => forwarded.push(arg.clone()),
         }
-        (Some(false), Some(false), Some(false), Some(cmd)) => {
-            run_command(cmd, "atomic.toml");
+    }
+    let output = env::current_exe().and_then(|exe| {
+        process::Command::new(exe)
+            .args(&forwarded)
+            .stdin(process::Stdio::null())
+            .output()
+    });
+    match output {
+        Ok(output) => {
+            let outcome = match output.status.code() {
+                Some(0) => Outcome::Passed,
+                code => Outcome::Failed(code.unwrap_or(-1)),
+            };
+            (outcome, [output.stdout, output.stderr].concat())
         }
-        _ => {
-            // Handle invalid or no command provided
-            eprintln!("Invalid command or no command provided");
-            // You might want to print help text or show usage instructions here
+        Err(err) => (
+            Outcome::Error(format!("couldn't start atomic: {}", err)),
+            Vec::new(),
+        ),
+    }
+}
+
+/// Whether `cmd` can run: an atomic.toml here or further up the repository exists, or was just
+/// created for it.
+///
+/// With nobody at the terminal, or in CI mode, the run goes ahead and reports the missing file.
+fn first_run(atomic: &Path, cmd: &str, interactivity: Interactivity) -> bool {
+    if !config_files(atomic).is_empty() || interactivity.is_ci() || !init::can_offer() {
+        return true;
+    }
+    init::offer_init(atomic, cmd, &mut io::stdin().lock(), &mut io::stderr()).unwrap_or_else(
+        |err| {
+            errors::report(&err);
+            false
+        },
+    )
+}
+
+/// How many answers [`pick_command`] takes before giving up.
+const PICK_ATTEMPTS: usize = 3;
+
+/// Offers the commands in atomic.toml as a numbered menu when `atomic` runs with nothing to do.
+///
+/// Returns `None`, for help instead, unless someone is at the terminal, atomic.toml has commands,
+/// and `[atomic] no_picker` isn't set.
+fn pick_from_terminal(
+    atomic: &Path,
+    profile: Option<&str>,
+    interactivity: Interactivity,
+) -> Option<String> {
+    if interactivity.is_ci()
+        || config_files(atomic).is_empty()
+        || !io::stdin().is_terminal()
+        || !io::stdout().is_terminal()
+    {
+        return None;
+    }
+    let config = load_and_validate_toml(atomic, profile)
+        .and_then(AtomicConfig::from_value)
+        .map_err(|err| errors::report(&err))
+        .ok()?;
+    if config.atomic.no_picker == Some(true) {
+        return None;
+    }
+    pick_command(
+        &config.command_names(),
+        &mut io::stdin().lock(),
+        &mut io::stdout(),
+    )
+}
+
+/// Lists `names` with numbers and reads a choice: a number, a name, or an unambiguous prefix of
+/// one.
+///
+/// Asks again after an answer that matches nothing, up to [`PICK_ATTEMPTS`] times. An empty
+/// answer, end of input, or an empty list picks nothing.
+fn pick_command(
+    names: &[String],
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Option<String> {
+    if names.is_empty() {
+        return None;
+    }
+    for (i, name) in names.iter().enumerate() {
+        writeln!(output, "{:>3}) {}", i + 1, name).ok()?;
+    }
+    for _ in 0..PICK_ATTEMPTS {
+        write!(output, "run which command? ").ok()?;
+        output.flush().ok()?;
+        let mut answer = String::new();
+        if input.read_line(&mut answer).ok()? == 0 {
+            return None;
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return None;
+        }
+        if let Ok(number) = answer.parse::<usize>() {
+            match names.get(number.wrapping_sub(1)) {
+                Some(name) => return Some(name.clone()),
+                None => writeln!(output, "pick a number from 1 to {}", names.len()).ok()?,
+            }
+            continue;
+        }
+        if let Some(name) = names.iter().find(|name| *name == answer) {
+            return Some(name.clone());
+        }
+        let matching: Vec<&String> = names
+            .iter()
+            .filter(|name| name.starts_with(answer))
+            .collect();
+        match matching.as_slice() {
+            [name] => return Some((*name).clone()),
+            [] => writeln!(output, "no command matches '{}'", answer).ok()?,
+            _ => writeln!(
+                output,
+                "'{}' could be {}",
+                answer,
+                matching
+                    .iter()
+                    .map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .ok()?,
         }
     }
+    None
 }
 
-fn list_keys() {
-    match get_toml_content("atomic.toml") {
-        Some(val) => {
-            let keys = get_toml_keys(val);
-            if !keys.is_empty() {
-                for k in keys {
-                    println!("{}", k);
-                }
-            } else {
-                eprintln!("Error reading atomic.toml");
+/// Prints the recorded spans and/or writes them to `json` for `--trace` and `--trace-json`.
+fn report_trace(print: bool, json: Option<&PathBuf>) {
+    let spans = trace::finish();
+    if print {
+        eprint!("\n{}", trace::render_tree(&spans));
+    }
+    if let Some(path) = json {
+        let written = serde_json::to_string_pretty(&trace::chrome_trace(&spans))
+            .map_err(|err| err.to_string())
+            .and_then(|json| fs::write(path, json).map_err(|err| err.to_string()));
+        if let Err(err) = written {
+            eprintln!("Error writing trace to {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Returns the profile requested with `--profile`, falling back to the `ATOMIC_PROFILE` env var.
+fn active_profile(matches: &ArgMatches) -> Option<String> {
+    matches
+        .get_one::<String>("profile")
+        .cloned()
+        .or_else(|| env::var("ATOMIC_PROFILE").ok())
+        .filter(|profile| !profile.trim().is_empty())
+}
+
+/// `--context`: prints the project around `atomic`, returning 0 inside one, 1 outside any, and 2
+/// when its config can't be read.
+fn show_context(
+    atomic: &Path,
+    profile: Option<&str>,
+    repo: Option<&Path>,
+    git: bool,
+    json: bool,
+) -> i32 {
+    match Context::resolve(atomic, profile, repo, git) {
+        Ok(Some(context)) if json => match serde_json::to_string(&context) {
+            Ok(json) => {
+                println!("{}", json);
+                0
+            }
+            Err(err) => {
+                eprintln!("Error rendering context: {}", err);
+                2
             }
+        },
+        Ok(Some(context)) => {
+            print!("{}", context.render());
+            0
         }
-        _ => eprintln!("Error reading atomic.toml"),
+        Ok(None) => 1,
+        Err(err) => {
+            eprintln!("{}", err);
+            2
+        }
+    }
+}
+
+/// Where the config is, as [`find_config`] picks it: in the top of the work tree from `--repo`,
+/// or from `GIT_DIR` / `GIT_WORK_TREE` when those are set, otherwise in the current directory.
+///
+/// # Errors
+/// Returns an error if `--repo` isn't inside a repository with a work tree, or the directory has
+/// both atomic.toml and atomic.yaml.
+fn config_path(repo: Option<&Path>) -> Result<PathBuf> {
+    let here = Path::new("");
+    let git_env = env::var_os("GIT_DIR").is_some() || env::var_os("GIT_WORK_TREE").is_some();
+    if repo.is_none() && !git_env {
+        return find_config(here);
+    }
+
+    let workdir =
+        open_repository(repo).map(|repository| repository.workdir().map(Path::to_path_buf));
+    match (workdir, repo) {
+        (Ok(Some(workdir)), _) => find_config(&workdir),
+        (Ok(None), Some(path)) => Err(AtomicError::Generic(format!(
+            "{} is a bare repository; --repo needs one with a work tree",
+            path.display()
+        ))),
+        (Err(err), Some(path)) => Err(AtomicError::Generic(format!(
+            "--repo {}: {}",
+            path.display(),
+            err
+        ))),
+        // GIT_DIR / GIT_WORK_TREE are only a hint for finding atomic.toml
+        (_, None) => find_config(here),
     }
 }
 
-/// init should simply check to make sure a project folder has a atomic file created in the root.
-fn start_init() {
-    let atomic = "atomic.toml";
+/// `--list`: every command in `section` (or all of them) that `filter` matches, grouped by
+/// section. Ends with a warning for every entry a nearer file overrides.
+fn list_keys(
+    atomic: &Path,
+    profile: Option<&str>,
+    filter: Option<&str>,
+    section: Option<&str>,
+    with_stats: bool,
+) -> i32 {
+    let loaded = load_layered(atomic, true).and_then(|(val, provenance)| {
+        let config = AtomicConfig::from_value(apply_profile(val, profile)?)?;
+        Ok((config, provenance))
+    });
+    let (config, provenance) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => return failed(&err),
+    };
+    let mut entries = list::entries(&config, &provenance);
+    if with_stats {
+        let (usage, now) = (stats::load(atomic), stats::now());
+        for entry in &mut entries {
+            entry.usage = Some(stats::describe(usage.commands.get(&entry.name), now));
+        }
+    }
+    if entries.is_empty() {
+        eprintln!("No commands found in atomic.toml");
+        return exit::SUCCESS;
+    }
+    let entries: Vec<_> = entries
+        .into_iter()
+        .filter(|entry| section.is_none_or(|section| entry.section == section))
+        .filter(|entry| filter.is_none_or(|filter| entry.matches(filter)))
+        .collect();
+
+    let mut out = String::new();
+    if let Some(profile) = profile {
+        out.push_str(&format!("profile: {}\n", profile));
+    }
+    match (filter, section) {
+        _ if !entries.is_empty() => out.push_str(&list::render(&entries)),
+        (Some(filter), _) => out.push_str(&format!("no commands match '{}'\n", filter)),
+        (None, Some(section)) => out.push_str(&format!("no commands in [{}]\n", section)),
+        (None, None) => {}
+    }
+    pager::print_paged(&out);
+
+    for (name, origin) in provenance.conflicts() {
+        let overridden: Vec<String> = origin
+            .overrides
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect();
+        eprintln!(
+            "warning: {} in {} overrides {}",
+            name,
+            origin.file.display(),
+            overridden.join(", ")
+        );
+    }
+    exit::SUCCESS
+}
 
-    // if our atomic file does not exist, we create one from a template.
-    if fs::metadata(atomic).is_err() {
-        if let Ok(_created_file) = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(atomic)
-        {
-            // todo: write template to file here
+/// `--stats`: the most used commands, or with `reset` forgetting them all.
+fn show_stats(
+    atomic: &Path,
+    profile: Option<&str>,
+    reset: bool,
+    interactivity: Interactivity,
+) -> i32 {
+    if reset {
+        let file = stats::path(atomic);
+        if !file.exists() {
+            println!("No usage stats to reset.");
+            return 0;
+        }
+        let question = format!("Forget every run recorded in {}?", file.display());
+        match confirm(&question, interactivity) {
+            Ok(true) => {}
+            Ok(false) => return 0,
+            Err(err) => {
+                return failed(&err);
+            }
         }
+        return match stats::reset(atomic) {
+            Ok(()) => {
+                println!("Usage stats reset.");
+                0
+            }
+            Err(err) => failed(&err),
+        };
     }
+
+    let off = load_toml(atomic, profile)
+        .and_then(|config| Settings::from_config(&config))
+        .is_ok_and(|settings| settings.stats == Some(false));
+    if off {
+        println!("Usage stats are off (stats = false under [atomic]).");
+        return 0;
+    }
+    let usage = stats::load(atomic);
+    if usage.commands.is_empty() {
+        println!("No commands have run here yet.");
+        return 0;
+    }
+    print!("{}", stats::render(&usage, stats::now()));
+    0
 }
 
-fn run_command<P: AsRef<Path>>(cmd: &str, atomic: P) {
-    // read in atomic file and parse it out
-    let parsed_toml = get_toml_content(atomic).unwrap();
+fn show_log(
+    repo: Option<&Path>,
+    atomic: &Path,
+    limit: usize,
+    all: bool,
+    profile: Option<&str>,
+) -> i32 {
+    let repo = match open_repository(repo) {
+        Ok(repo) => repo,
+        Err(err) => {
+            eprintln!("Error opening git repository: {}", err);
+            return exit::FAILURE;
+        }
+    };
 
-    // dbg!(find_key_in_tables(parsed_toml.clone(), "check")); // Should return "cargo check"
-    // dbg!(find_key_in_tables(parsed_toml.clone(), "clippy")); // Should return "cargo clippy"
-    // dbg!(find_key_in_tables(parsed_toml.clone(), "chain")); // Should return ["check", "clippy", ...]
+    let base = if all {
+        None
+    } else {
+        base_branch(&repo, atomic, profile)
+    };
 
-    let (_, value) = find_key_in_tables(parsed_toml.clone(), cmd).unwrap_or((String::new(), None));
+    match branch_log(&repo, limit, base.as_deref()) {
+        Ok(commits) if commits.is_empty() => println!("No commits on this branch yet."),
+        Ok(commits) => {
+            let now = now_secs();
+            let mut out: String = commits
+                .iter()
+                .map(|commit| format!("{}\n", format_commit_row(commit, now)))
+                .collect();
+            if commits.iter().any(|c| c.auto) {
+                out.push_str("\n* atomic auto-commit\n");
+            }
+            pager::print_paged(&out);
+        }
+        Err(err) => {
+            eprintln!("Error reading git history: {}", err);
+            return exit::FAILURE;
+        }
+    }
+    exit::SUCCESS
+}
 
-    match value {
-        Some(Value::String(s)) => send_command(&s),
+fn show_last_run(repo: Option<&Path>, cmd: &str) -> i32 {
+    let result = open_repository(repo).and_then(|repo| {
+        let Some(commit) = find_last_run(&repo, cmd)? else {
+            println!(
+                "No auto-commit found for '{}'. Run `atomic {}` to create one.",
+                cmd, cmd
+            );
+            return Ok(());
+        };
 
-        Some(Value::Array(sub_values)) => {
-            assert!(!sub_values.is_empty(), "Array of sub-values is empty");
+        let age = now_secs() - commit.time().seconds();
+        println!(
+            "Last run of '{}' {}: {}",
+            cmd,
+            relative_time(age),
+            commit.summary().unwrap_or_default()
+        );
+        commit_stats(&repo, &commit)?.print_summary(true);
+        Ok(())
+    });
 
-            for v in sub_values {
-                // Resolve sub-command value
-                let inner_value = match v {
-                    Value::String(ref s) => {
-                        // Perform lookup in nested "custom" table
-                        find_key_in_tables(parsed_toml.clone(), s)
-                            .and_then(|(_, val)| val) // Get the value if found
-                            .unwrap_or(v.clone()) // Use the original value if not found
-                    }
-                    _ => v.clone(),
-                };
+    match result {
+        Ok(()) => exit::SUCCESS,
+        Err(err) => {
+            eprintln!("Error reading git history: {}", err);
+            exit::FAILURE
+        }
+    }
+}
 
-                if let Some(command_str) = inner_value.as_str() {
-                    // dbg!(&command_str);
-                    send_command(command_str); // Execute resolved command
-                } else {
-                    eprintln!("Invalid command format: {:?}", inner_value);
+fn show_diff_since(repo: Option<&Path>, cmd: &str, stat: bool) -> i32 {
+    match open_repository(repo).and_then(|repo| diff_since_last_run(&repo, cmd, stat)) {
+        Ok(None) => println!(
+            "No auto-commit found for '{}'. Run `atomic {}` to create one.",
+            cmd, cmd
+        ),
+        Ok(Some(diff)) if diff.is_empty() => {
+            println!("Nothing changed since the last run of '{}'", cmd)
+        }
+        Ok(Some(diff)) => pager::print_paged(&diff),
+        Err(err) => {
+            eprintln!("Error reading git history: {}", err);
+            return exit::FAILURE;
+        }
+    }
+    exit::SUCCESS
+}
+
+/// `--snapshot`: commits the work tree with the `[atomic]` commit settings and tags it.
+fn take_snapshot(
+    repo: Option<&Path>,
+    atomic: &Path,
+    profile: Option<&str>,
+    name: &str,
+    no_sign: bool,
+) -> i32 {
+    let config = match atomic.is_file() {
+        true => load_toml(atomic, profile).and_then(AtomicConfig::from_value),
+        // a snapshot doesn't need a project
+        false => Ok(AtomicConfig::default()),
+    };
+    let result = config.and_then(|config| {
+        let options = CommitOptions {
+            repo,
+            no_sign,
+            ..commit_settings(&config.atomic, None)?
+        };
+        create_snapshot(&open_repository(repo)?, name, &options)
+    });
+    match result {
+        Ok(snapshot) => {
+            println!(
+                "Snapshot {} at {}: {}",
+                snapshot.slug,
+                snapshot.short_id(),
+                snapshot.subject
+            );
+            0
+        }
+        Err(err) => failed(&err),
+    }
+}
+
+fn show_snapshots(repo: Option<&Path>) -> i32 {
+    match open_repository(repo).and_then(|repo| list_snapshots(&repo)) {
+        Ok(snapshots) if snapshots.is_empty() => {
+            println!("No snapshots yet. `atomic --snapshot <name>` takes one.")
+        }
+        Ok(snapshots) => pager::print_paged(&render_snapshots(&snapshots, now_secs())),
+        Err(err) => {
+            return failed(&err);
+        }
+    }
+    0
+}
+
+/// `--verify`: checks the auto-commits in `range`, or since the base branch without one. Fails
+/// when one of them didn't run with the config committed in its tree.
+fn verify_commits(
+    repo: Option<&Path>,
+    atomic: &Path,
+    range: Option<&str>,
+    profile: Option<&str>,
+) -> i32 {
+    let result = open_repository(repo).and_then(|repo| {
+        let base = match range {
+            Some(_) => None,
+            None => base_branch(&repo, atomic, profile),
+        };
+        verify::verify(&repo, range, base.as_deref())
+    });
+    match result {
+        Ok(checked) if checked.is_empty() => {
+            println!("No auto-commits to verify.");
+            exit::SUCCESS
+        }
+        Ok(checked) => {
+            pager::print_paged(&verify::render(&checked));
+            match checked.iter().any(|commit| commit.status.failed()) {
+                true => exit::FAILURE,
+                false => exit::SUCCESS,
+            }
+        }
+        Err(err) => failed(&err),
+    }
+}
+
+/// `--artifacts`: the runs that collected artifacts.
+fn show_artifacts(atomic: &Path) -> i32 {
+    let history = artifacts::load(atomic);
+    if history.runs.is_empty() {
+        println!("No artifacts collected yet. Set `artifacts` on a command to keep its files.");
+    } else {
+        pager::print_paged(&artifacts::render(&history));
+    }
+    exit::SUCCESS
+}
+
+/// `--restore`: shows the commits the branch would drop and resets it once confirmed.
+fn restore(repo: Option<&Path>, name: &str, yes: bool, interactivity: Interactivity) -> i32 {
+    let result = open_repository(repo).and_then(|repo| {
+        let plan = plan_restore(&repo, name)?;
+        if plan.head == plan.snapshot.id {
+            println!("Already at snapshot {}.", plan.snapshot.slug);
+            return Ok(());
+        }
+        if !plan.abandoned.is_empty() {
+            println!(
+                "Restoring {} moves the branch off these commits:",
+                plan.snapshot.slug
+            );
+            let now = now_secs();
+            for commit in &plan.abandoned {
+                println!("{}", format_commit_row(commit, now));
+            }
+        }
+        let question = format!("Reset the branch to snapshot {}?", plan.snapshot.slug);
+        if !yes && !confirm(&question, interactivity)? {
+            return Ok(());
+        }
+        if let Some(backup) = restore_snapshot(&repo, &plan)? {
+            println!("The dropped commits are kept at {}", backup);
+        }
+        println!(
+            "Restored snapshot {} ({})",
+            plan.snapshot.slug,
+            plan.snapshot.short_id()
+        );
+        Ok(())
+    });
+    match result {
+        Ok(()) => 0,
+        Err(err) => failed(&err),
+    }
+}
+
+fn run_doctor(atomic: &Path, repo: Option<&Path>, profile: Option<&str>) -> i32 {
+    println!("{}\n", doctor::header());
+    let results = doctor::run_checks(atomic, repo, profile);
+    print!("{}", doctor::render(&results));
+    i32::from(doctor::has_failures(&results))
+}
+
+/// `--check`: prints every error and warning in the config. Errors fail the check, and with
+/// `strict` warnings do too; `quiet` leaves warnings out.
+fn check_config(atomic: &Path, strict: bool, quiet: bool) -> i32 {
+    let diagnostics = match toml::check_config(atomic) {
+        Ok(diagnostics) => diagnostics,
+        Err(err) => return failed(&err),
+    };
+    for diagnostic in &diagnostics {
+        match diagnostic.severity {
+            Severity::Error => println!("error: {}", diagnostic),
+            Severity::Warning if !quiet => println!("warning: {}", diagnostic),
+            Severity::Warning => {}
+        }
+    }
+    match diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.severity)
+        .max()
+    {
+        None => {
+            println!("{} is valid.", atomic.display());
+            exit::SUCCESS
+        }
+        Some(Severity::Warning) if !strict => exit::SUCCESS,
+        Some(_) => exit::VALIDATION,
+    }
+}
+
+/// `--lint`: prints each warning, returning 1 when there were any.
+fn run_lint(atomic: &Path, profile: Option<&str>) -> i32 {
+    let config = load_and_validate_toml(atomic, profile).and_then(AtomicConfig::from_value);
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            return failed(&err);
+        }
+    };
+    let lints = lint::lint(&config, atomic.parent().unwrap_or(Path::new(".")));
+    for lint in &lints {
+        println!("{}", lint);
+    }
+    match lints.len() {
+        0 => {
+            println!("No problems found.");
+            0
+        }
+        1 => {
+            println!("\n1 warning");
+            1
+        }
+        count => {
+            println!("\n{} warnings", count);
+            1
+        }
+    }
+}
+
+/// `--export` and `--import` edit TOML in place, keeping its comments, so they need atomic.toml.
+fn require_toml(atomic: &Path, flag: &str) -> Result<()> {
+    if yaml::is_yaml(atomic) {
+        return Err(AtomicError::Generic(format!(
+            "{} only works with atomic.toml, not {}",
+            flag,
+            atomic.display()
+        )));
+    }
+    Ok(())
+}
+
+fn export_to(atomic: &Path, file: &Path, sections: &[&str]) -> Result<()> {
+    require_toml(atomic, "--export")?;
+    let export = export_commands(&read_toml_text(atomic)?, sections, &today())?;
+    fs::write(file, export).map_err(|err| {
+        AtomicError::Generic(format!("Unable to write {}: {}", file.display(), err))
+    })?;
+    println!("Exported [{}] to {}", sections.join("], ["), file.display());
+    Ok(())
+}
+
+fn import_from(
+    atomic: &Path,
+    file: &Path,
+    overwrite: bool,
+    interactivity: Interactivity,
+) -> Result<()> {
+    require_toml(atomic, "--import")?;
+    let incoming = fs::read_to_string(file).map_err(|err| {
+        AtomicError::Generic(format!("Unable to read {}: {}", file.display(), err))
+    })?;
+    let local = read_toml_text(atomic)?;
+    let plan = ImportPlan::new(&local, &incoming, overwrite)?;
+
+    let summary = plan.summary();
+    if !summary.is_empty() {
+        println!(
+            "Importing {} into atomic.toml:\n{}",
+            file.display(),
+            summary
+        );
+    }
+    if !plan.has_changes() {
+        println!("Nothing to import.");
+        return Ok(());
+    }
+    if !confirm("Apply these changes to atomic.toml?", interactivity)? {
+        println!("Import cancelled.");
+        return Ok(());
+    }
+
+    fs::write(atomic, plan.apply(&local)?).map_err(|err| {
+        AtomicError::Generic(format!("Unable to write {}: {}", atomic.display(), err))
+    })?;
+    println!("Updated atomic.toml.");
+    Ok(())
+}
+
+/// Applies every migration to atomic.toml, writing the result next to it, or over it with `write`.
+fn migrate_config(atomic: &Path, write: bool) -> Result<()> {
+    require_toml(atomic, "--migrate-config")?;
+    let text = read_toml_text(atomic)?;
+    let (migrated, changes) = migrate::migrate(&text)?;
+    for change in &changes {
+        println!("  {}", change);
+    }
+    if migrated == text {
+        println!("atomic.toml is already in the current format; nothing to migrate.");
+        return Ok(());
+    }
+
+    let target = match write {
+        true => {
+            print!("\n{}", migrate::diff(&text, &migrated, "atomic.toml")?);
+            atomic.to_path_buf()
+        }
+        false => atomic.with_extension("toml.new"),
+    };
+    fs::write(&target, migrated).map_err(|err| {
+        AtomicError::Generic(format!("Unable to write {}: {}", target.display(), err))
+    })?;
+    match write {
+        true => println!("Updated atomic.toml."),
+        false => println!(
+            "Wrote {}. Review it, or run `atomic --migrate-config --write` to update atomic.toml in place.",
+            target.display()
+        ),
+    }
+    Ok(())
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// The branch `--log` measures "my" commits against: `[atomic] base_branch`, or else the one
+/// [`detect_base_branch`] finds, which is reported so a surprising pick is visible.
+///
+/// `None` when neither works out; the log then isn't cut off.
+fn base_branch(repo: &Repository, atomic: &Path, profile: Option<&str>) -> Option<String> {
+    let configured = load_toml(atomic, profile)
+        .ok()
+        .map(|config| Settings::from_config(&config).map(|settings| settings.base_branch));
+    match configured {
+        Some(Ok(Some(branch))) if !branch.is_empty() => return Some(branch),
+        Some(Err(err)) => errors::report(&err),
+        _ => {}
+    }
+
+    match detect_base_branch(repo) {
+        Ok(branch) => {
+            eprintln!(
+                "base branch: {} (detected; set base_branch under [atomic] to change it)",
+                branch
+            );
+            Some(branch)
+        }
+        Err(err) => {
+            eprintln!("warning: {}", err);
+            None
+        }
+    }
+}
+
+/// `--edit-config`: opens atomic.toml, or with `global` the global config, in the user's editor.
+fn edit_config(atomic: &Path, global: bool) -> Result<()> {
+    let file = if global {
+        let file = global_config().ok_or_else(|| {
+            AtomicError::Generic(
+                "no config directory to keep the global config in; set HOME (or APPDATA on Windows)"
+                    .to_string(),
+            )
+        })?;
+        if init::write_global_template(&file)? {
+            println!("Created {}", file.display());
+        }
+        file
+    } else if atomic.is_file() {
+        atomic.to_path_buf()
+    } else {
+        return Err(AtomicError::Generic(format!(
+            "{} doesn't exist yet; run `atomic --init` to create it",
+            atomic.display()
+        )));
+    };
+
+    let mut argv = editor(|key| env::var(key).ok())?;
+    argv.push(file.display().to_string());
+    let status = process::Command::new(&argv[0])
+        .args(&argv[1..])
+        .status()
+        .map_err(|err| AtomicError::Generic(format!("Failed to start {}: {}", argv[0], err)))?;
+    if !status.success() {
+        return Err(AtomicError::Generic(format!(
+            "{} exited with {}",
+            argv[0], status
+        )));
+    }
+    Ok(())
+}
+
+/// The editor to open files in, split into words: `$VISUAL`, then `$EDITOR`, then the
+/// platform's own.
+///
+/// # Errors
+/// Returns an error if the variable can't be split, e.g. it has an unclosed quote.
+fn editor(var: impl Fn(&str) -> Option<String>) -> Result<Vec<String>> {
+    let editor = ["VISUAL", "EDITOR"]
+        .iter()
+        .find_map(|key| var(key).filter(|editor| !editor.trim().is_empty()))
+        .unwrap_or_else(|| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+    shell_words::split(&editor)
+        .map_err(|err| AtomicError::Generic(format!("Can't parse editor '{}': {}", editor, err)))
+}
+
+/// Creates atomic.toml from the template that fits the project, or the one named by `from`.
+fn start_init(
+    atomic: &Path,
+    format: Option<&str>,
+    from: Option<&str>,
+    interactivity: Interactivity,
+) -> i32 {
+    let atomic = match format {
+        Some("yaml") => atomic.with_file_name("atomic.yaml"),
+        Some(_) => atomic.with_file_name("atomic.toml"),
+        None => atomic.to_path_buf(),
+    };
+    if let Some(from) = from {
+        return match init::init_from(&atomic, from) {
+            Ok(init::FromRepo::Listed(templates)) if templates.is_empty() => {
+                eprintln!("{} has no templates", from);
+                exit::FAILURE
+            }
+            Ok(init::FromRepo::Listed(templates)) => {
+                println!("templates in {}:", from);
+                for template in templates {
+                    println!("  {}", template);
                 }
+                println!("pick one with `atomic --init --from {}#<name>`", from);
+                exit::SUCCESS
+            }
+            Ok(init::FromRepo::Written(_)) => {
+                println!("Created {} from {}.", atomic.display(), from);
+                offer_gitignore(&atomic, interactivity);
+                exit::SUCCESS
+            }
+            Err(err) => failed(&err),
+        };
+    }
+    match init::write_template(&atomic) {
+        Ok(template) => {
+            println!(
+                "Created {} from the {} template.",
+                atomic.display(),
+                template.name
+            );
+            offer_gitignore(&atomic, interactivity);
+            exit::SUCCESS
+        }
+        Err(err) => failed(&err),
+    }
+}
+
+/// After `--init`, offers to add atomic's block to the repository's .gitignore when it doesn't
+/// ignore atomic's state yet. Only asked at a terminal.
+fn offer_gitignore(atomic: &Path, interactivity: Interactivity) {
+    if !io::stdin().is_terminal() {
+        return;
+    }
+    let dir = atomic
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let Ok(repo) = Repository::discover(dir) else {
+        return;
+    };
+    let Some(file) = repo.workdir().map(|workdir| workdir.join(".gitignore")) else {
+        return;
+    };
+    let text = fs::read_to_string(&file).unwrap_or_default();
+    if gitignore::missing(&text, gitignore::PATTERNS).is_empty() {
+        return;
+    }
+    let question = format!(
+        "Add {} to {}?",
+        gitignore::PATTERNS.join(", "),
+        file.display()
+    );
+    let result = confirm(&question, interactivity).and_then(|yes| match yes {
+        true => gitignore::manage(&file),
+        false => Ok(false),
+    });
+    match result {
+        Ok(true) => println!("Added atomic's block to {}.", file.display()),
+        Ok(false) => {}
+        Err(err) => eprintln!("warning: {}", err),
+    }
+}
+
+/// `--init --merge`: adds the template's missing commands to atomic.toml, or with `dry_run`
+/// shows them as a diff without writing anything.
+fn merge_init(atomic: &Path, template: Option<&str>, dry_run: bool) -> Result<()> {
+    require_toml(atomic, "--merge")?;
+    let template = match template {
+        Some(name) => init::template_named(name)
+            .ok_or_else(|| AtomicError::Generic(format!("There's no template '{}'", name)))?,
+        None => init::detect_template(atomic.parent().unwrap_or(Path::new(""))),
+    };
+    let local = match fs::read_to_string(atomic) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        _ => read_toml_text(atomic)?,
+    };
+    let merge = init::merge_template(&local, template)?;
+
+    let summary = merge.summary();
+    if !summary.is_empty() {
+        println!(
+            "Merging the {} template into {}:\n{}",
+            template.name,
+            atomic.display(),
+            summary
+        );
+    }
+    if merge.added.is_empty() {
+        println!("Nothing to add.");
+    } else if dry_run {
+        print!("{}", migrate::diff(&local, &merge.text, "atomic.toml")?);
+    } else {
+        fs::write(atomic, &merge.text).map_err(|err| {
+            AtomicError::Generic(format!("Unable to write {}: {}", atomic.display(), err))
+        })?;
+        println!("Updated {}.", atomic.display());
+    }
+    Ok(())
+}
+
+/// `--template <ACTION> <NAME>`: prints template `name`, compares atomic.toml with it, or
+/// replaces the saved copy next to the global config at `global` with the bundled one.
+fn template_action(
+    atomic: &Path,
+    global: Option<&Path>,
+    action: &str,
+    name: &str,
+    yes: bool,
+    interactivity: Interactivity,
+) -> Result<()> {
+    let template = init::template_named(name).ok_or_else(|| {
+        AtomicError::Generic(format!(
+            "There's no template '{}'; pick rust or generic",
+            name
+        ))
+    })?;
+    let saved = global.map(|global| template::saved_path(global, template));
+    let saved_text = match &saved {
+        Some(saved) => template::read_saved(saved)?,
+        None => None,
+    };
+    match action {
+        "show" => {
+            if let (Some(saved), Some(_)) = (&saved, &saved_text) {
+                eprintln!("(your saved copy, {})", saved.display());
+            }
+            print!("{}", saved_text.as_deref().unwrap_or(template.text));
+        }
+        "diff" => {
+            require_toml(atomic, "--template diff")?;
+            let local = template::parse("atomic.toml", &read_toml_text(atomic)?)?;
+            let theirs = template::parse(
+                template.name,
+                saved_text.as_deref().unwrap_or(template.text),
+            )?;
+            let diffs = template::structural_diff(&local, &theirs);
+            if diffs.is_empty() {
+                println!("atomic.toml matches the {} template.", template.name);
+            } else {
+                println!(
+                    "atomic.toml against the {} template (+ missing from atomic.toml, - only in \
+                     atomic.toml, ~ different):\n{}",
+                    template.name,
+                    template::render(&diffs).trim_end()
+                );
             }
         }
         _ => {
-            // Handle other types of values if necessary
+            let saved = saved.ok_or_else(|| {
+                AtomicError::Generic(
+                    "no config directory to keep saved templates in; set HOME (or APPDATA on Windows)"
+                        .to_string(),
+                )
+            })?;
+            let old = match &saved_text {
+                Some(text) => template::parse(&saved.display().to_string(), text)?,
+                None => Default::default(),
+            };
+            let diffs =
+                template::structural_diff(&old, &template::parse(template.name, template.text)?);
+            if saved_text.is_some() && diffs.is_empty() {
+                println!(
+                    "{} already matches the bundled {} template.",
+                    saved.display(),
+                    template.name
+                );
+                return Ok(());
+            }
+            println!(
+                "{} against the bundled {} template (+ only in the bundled one, - only in your \
+                 copy, ~ different):\n{}",
+                saved.display(),
+                template.name,
+                template::render(&diffs).trim_end()
+            );
+            if !yes
+                && !confirm(
+                    &format!("Replace {} with the bundled template?", saved.display()),
+                    interactivity,
+                )?
+            {
+                println!("Update cancelled.");
+                return Ok(());
+            }
+            let written = match saved.parent() {
+                Some(dir) => fs::create_dir_all(dir),
+                None => Ok(()),
+            }
+            .and_then(|_| fs::write(&saved, template.text));
+            written.map_err(|err| {
+                AtomicError::Generic(format!("Unable to write {}: {}", saved.display(), err))
+            })?;
+            println!("Updated {}.", saved.display());
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pick(names: &[&str], answers: &str) -> (Option<String>, String) {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let mut output = Vec::new();
+        let picked = pick_command(&names, &mut answers.as_bytes(), &mut output);
+        (picked, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_editor() {
+        let env = |vars: &'static [(&str, &str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(
+            editor(env(&[("VISUAL", "code --wait"), ("EDITOR", "nano")])).unwrap(),
+            ["code", "--wait"]
+        );
+        assert_eq!(
+            editor(env(&[("VISUAL", " "), ("EDITOR", "nano")])).unwrap(),
+            ["nano"]
+        );
+        let fallback = if cfg!(windows) { "notepad" } else { "vi" };
+        assert_eq!(editor(env(&[])).unwrap(), [fallback]);
+        assert!(editor(env(&[("EDITOR", "'unclosed")])).is_err());
+    }
+
+    #[test]
+    fn test_pick_command() {
+        let names = ["build", "bench", "test"];
+
+        let (picked, output) = pick(&names, "2\n");
+        assert_eq!(picked.as_deref(), Some("bench"));
+        assert!(output.starts_with("  1) build\n  2) bench\n  3) test\n"));
+
+        assert_eq!(pick(&names, "test\n").0.as_deref(), Some("test"));
+        assert_eq!(pick(&names, "bu\n").0.as_deref(), Some("build"));
+
+        // an ambiguous prefix, then a number out of range, then a good answer
+        let (picked, output) = pick(&names, "b\n9\nt\n");
+        assert_eq!(picked.as_deref(), Some("test"));
+        assert!(output.contains("'b' could be build, bench"));
+        assert!(output.contains("pick a number from 1 to 3"));
+
+        // gives up after three misses
+        let (picked, output) = pick(&names, "x\n0\ny\n1\n");
+        assert_eq!(picked, None);
+        assert_eq!(output.matches("run which command?").count(), 3);
+
+        assert_eq!(pick(&names, "\n").0, None);
+        assert_eq!(pick(&names, "").0, None);
+        assert_eq!(pick(&[], "1\n").0, None);
+    }
+
+    #[test]
+    fn test_again_repeats_last_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let log = dir.path().join("log.txt");
+        fs::write(
+            &atomic,
+            format!(
+                "[custom]\ngen = \"echo $ATOMIC_PROFILE >> '{}'\"\n[profile.ci]\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        let run = |matches: &ArgMatches| {
+            let options = RunOptions {
+                profile: matches.get_one::<String>("profile").cloned(),
+                no_commit: true,
+                ..Default::default()
+            };
+            let cmd = matches.get_one::<String>("CMD").unwrap();
+            run_command(cmd, &atomic, &options)
+        };
+
+        // the profile came from ATOMIC_PROFILE, so it's added to what's kept
+        let args = vec!["gen".to_string()];
+        let first = replay(&args).unwrap();
+        let kept = recorded_args(&first, args, Some("ci"), None);
+        assert_eq!(kept, ["gen", "--profile", "ci"]);
+        let first = replay(&kept).unwrap();
+        assert_eq!(run(&first), Ok(0));
+        last_run::save(&atomic, &LastRun::new(kept, false)).unwrap();
+
+        let again = last_run::load(&atomic).unwrap();
+        let again = replay(again.repeatable_args().unwrap()).unwrap();
+        assert_eq!(run(&again), Ok(0));
+        assert_eq!(fs::read_to_string(&log).unwrap(), "ci\nci\n");
+
+        let picked = recorded_args(&replay(&[]).unwrap(), Vec::new(), None, Some("gen"));
+        assert_eq!(picked, ["gen"]);
+
+        last_run::save(&atomic, &LastRun::new(vec!["--stdin".to_string()], true)).unwrap();
+        assert!(last_run::load(&atomic).unwrap().repeatable_args().is_err());
+        assert!(replay(&["--again".to_string(), "gen".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_template_actions() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let global = dir.path().join("config/atomic/config.toml");
+        let saved = dir.path().join("config/atomic/templates/rust.toml");
+        let action = |action: &str, name: &str| {
+            template_action(
+                &atomic,
+                Some(&global),
+                action,
+                name,
+                true,
+                Interactivity::Interactive,
+            )
+        };
+
+        assert!(action("show", "rust").is_ok());
+        assert!(action("show", "python").is_err());
+        assert!(action("diff", "rust").is_err(), "no atomic.toml yet");
+        fs::write(&atomic, "[default]\nfmt = \"cargo fmt\"\n").unwrap();
+        assert!(action("diff", "generic").is_ok());
+
+        // update saves the bundled template when there's no copy yet, then refreshes it
+        assert!(action("update", "rust").is_ok());
+        let bundled = init::template_named("rust").unwrap().text;
+        assert_eq!(fs::read_to_string(&saved).unwrap(), bundled);
+        fs::write(&saved, "[default]\nfmt = \"old\"\n").unwrap();
+        assert!(action("update", "Rust").is_ok());
+        assert_eq!(fs::read_to_string(&saved).unwrap(), bundled);
+
+        // without a config directory there's nowhere to save one
+        assert!(template_action(
+            &atomic,
+            None,
+            "update",
+            "rust",
+            true,
+            Interactivity::Interactive
+        )
+        .is_err());
+
+        let matches = cli()
+            .try_get_matches_from(["atomic", "--template", "diff", "rust"])
+            .unwrap();
+        let values: Vec<&String> = matches.get_many("template").unwrap().collect();
+        assert_eq!(values, ["diff", "rust"]);
+        assert!(cli()
+            .try_get_matches_from(["atomic", "--template", "show"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_git_off_refuses_git_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let matches = |args: &[&str]| cli().try_get_matches_from(args).unwrap();
+
+        let plain = matches(&["atomic", "build"]);
+        assert_eq!(git_off(&plain, &atomic, None), None);
+        let flagged = matches(&["atomic", "--no-git", "--commit", "build"]);
+        assert_eq!(git_off(&flagged, &atomic, None), Some("--no-git"));
+        assert_eq!(git_flag_given(&flagged), Some("commit"));
+        assert_eq!(git_flag_given(&matches(&["atomic", "--log"])), Some("log"));
+        let verify = matches(&["atomic", "--no-git", "--verify"]);
+        assert_eq!(git_off(&verify, &atomic, None), Some("--no-git"));
+        assert_eq!(git_flag_given(&verify), Some("verify"));
+        assert_eq!(
+            git_flag_given(&matches(&["atomic", "--verify", "main..HEAD"])),
+            Some("verify")
+        );
+        assert_eq!(git_flag_given(&plain), None);
+
+        fs::write(&atomic, "[atomic]\ngit = false\n").unwrap();
+        assert_eq!(
+            git_off(&plain, &atomic, None),
+            Some("`git = false` in atomic.toml")
+        );
+    }
 }
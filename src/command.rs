@@ -0,0 +1,3418 @@
+//! Resolves commands from atomic.toml and executes them.
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use git2::Repository;
+use toml::{map::Map, Value};
+
+use crate::artifacts;
+use crate::cargo_json;
+use crate::ci::{Fold, Interactivity};
+use crate::clean_env;
+use crate::config::{
+    section_label, split_qualifier, AtomicConfig, ChainStep, CommandSpec, ConfirmSpec,
+    DetailedCommand, LargeFiles, OsSpec, Settings, Steps, TmpDirMode, MAX_MATRIX,
+};
+use crate::config_changes;
+use crate::container::Container;
+use crate::envfile;
+use crate::events::{self, Event};
+use crate::git::{
+    branch_issue, commit_local_changes, commit_signature, config_hash, current_branch,
+    open_repository, report_exit, spawn_argv, spawn_command, unhashed_paths, worktree_snapshot,
+    Capture, CommitOptions, RunChanges, AUTO_COMMIT_PREFIX, CAPTURE_LIMIT, CONFIG_HASH_TRAILER,
+    NO_CHANGES, STEPS_TRAILER,
+};
+use crate::gitignore;
+use crate::inputs::{self, Freshness};
+use crate::issues;
+use crate::junit::{self, CaseResult};
+use crate::lock::{self, CommandLock};
+use crate::nesting::Nesting;
+use crate::progress::Ticker;
+use crate::stats::{self, now};
+use crate::tmpdir::{self, TmpDir};
+use crate::toml::{load_and_validate_toml, merge_toml};
+use crate::trace;
+use crate::util::{confirm, suggest};
+use crate::{AtomicError, Result};
+
+/// Upper bound for `retries`, so a typo can't keep a failing command looping for ages.
+const MAX_RETRIES: i64 = 10;
+
+/// How many times a failing command is re-run, and how long to wait between attempts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Reads `retries` and `retry_delay` from a command table.
+    ///
+    /// # Errors
+    /// Returns an error if `retries` is not an integer between 0 and 10, or `retry_delay`
+    /// is not a valid duration.
+    pub fn from_command(detail: &DetailedCommand) -> Result<Self> {
+        let retries = match detail.retries {
+            None => 0,
+            Some(n) if (0..=MAX_RETRIES).contains(&n) => n as u32,
+            Some(other) => {
+                return Err(AtomicError::Generic(format!(
+                    "Invalid retries value {}: expected a whole number between 0 and {}",
+                    other, MAX_RETRIES
+                )))
+            }
+        };
+
+        let delay = match &detail.retry_delay {
+            None => Duration::ZERO,
+            Some(delay) => delay.to_duration()?,
+        };
+
+        Ok(Self { retries, delay })
+    }
+}
+
+/// The result of running a single command, possibly over several attempts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepOutcome {
+    /// The command's name, the chain step's label, or for a step on its own its shell command.
+    pub name: String,
+    pub code: i32,
+    pub attempts: u32,
+    /// Set for chain steps whose failure doesn't fail the run.
+    pub ignored: bool,
+    /// The last lines of a failed step's stdout, when it was captured, as under `--junit`.
+    pub output: Option<String>,
+}
+
+impl StepOutcome {
+    pub fn success(&self) -> bool {
+        self.code == 0
+    }
+
+    /// Whether this step fails the run: it failed, and isn't marked `ignore_failure`.
+    pub fn failed(&self) -> bool {
+        !self.success() && !self.ignored
+    }
+}
+
+/// How a command table with `confirm` asks before it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Confirm {
+    /// `confirm = true`: answer y/N.
+    YesNo,
+    /// `confirm = "<prompt>"`: show the prompt, then type the command's name.
+    Typed(String),
+}
+
+impl Confirm {
+    /// Reads `confirm` from a command table; other commands never ask.
+    pub fn from_spec(spec: &CommandSpec) -> Option<Self> {
+        match spec.detail()?.confirm.as_ref()? {
+            ConfirmSpec::Flag(false) => None,
+            ConfirmSpec::Flag(true) => Some(Confirm::YesNo),
+            ConfirmSpec::Prompt(prompt) => Some(Confirm::Typed(prompt.clone())),
+        }
+    }
+
+    /// Writes the prompt for running `cmd` to `output` and reads the answer from `input`.
+    ///
+    /// A typed confirmation matches the command name ignoring case and surrounding whitespace.
+    /// No answer at all counts as no.
+    pub fn ask(
+        &self,
+        cmd: &str,
+        input: &mut impl BufRead,
+        output: &mut impl Write,
+    ) -> io::Result<bool> {
+        match self {
+            Confirm::YesNo => write!(output, "Run '{}'? [y/N] ", cmd)?,
+            Confirm::Typed(prompt) => write!(output, "{}\nType '{}' to run it: ", prompt, cmd)?,
+        }
+        output.flush()?;
+
+        let mut answer = String::new();
+        input.read_line(&mut answer)?;
+        let answer = answer.trim();
+        Ok(match self {
+            Confirm::YesNo => matches!(answer, "y" | "Y" | "yes"),
+            Confirm::Typed(_) => answer.to_lowercase() == cmd.to_lowercase(),
+        })
+    }
+}
+
+/// Invocation-wide options for [`run_command`], gathered from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    pub profile: Option<String>,
+    pub show_diff: bool,
+    /// Skip the auto-commit regardless of the `[atomic] auto_commit` setting.
+    pub no_commit: bool,
+    /// Commit files that were already dirty before the command, whatever
+    /// `[atomic] autocommit_preexisting` says.
+    pub include_preexisting: bool,
+    /// `--repo`: work on the repository containing this path instead of the current one.
+    pub repo: Option<PathBuf>,
+    /// `--yes`: run commands with `confirm` set without asking.
+    pub yes: bool,
+    /// Whether prompts may be shown; in CI mode the auto-commit is also off unless `commit` is set.
+    pub interactivity: Interactivity,
+    /// `--commit`: auto-commit in CI mode too.
+    pub commit: bool,
+    /// How deep this run is inside commands run by other atomics.
+    pub nesting: Nesting,
+    /// `--allow-nested-commit`: auto-commit and run the hooks even when nested.
+    pub allow_nested_commit: bool,
+    /// `--no-git`: only run the command, as `[atomic] git = false` does.
+    pub no_git: bool,
+    /// `--no-wait`: fail at once when a command with `lock = true` is already running.
+    pub no_wait: bool,
+    /// `--keep-tmp`: keep every `ATOMIC_TMPDIR` instead of removing it, and say where it is.
+    pub keep_tmp: bool,
+    /// `--no-change-notice`: don't say when atomic.toml changed since the last run.
+    pub no_change_notice: bool,
+    /// `--no-sign`: auto-commit unsigned even when git's `commit.gpgsign` is set.
+    pub no_sign: bool,
+    /// `--force`: run commands with `inputs` even when those haven't changed.
+    pub force: bool,
+    /// The markers around each step's output; `--no-fold` turns them off.
+    pub fold: Fold,
+    /// `--artifacts-dir`: where `artifacts` go instead of `.atomic/artifacts/<run-id>`.
+    pub artifacts_dir: Option<PathBuf>,
+}
+
+/// State shared by every step of a single atomic invocation.
+#[derive(Debug, Clone)]
+pub struct RunContext {
+    pub config: AtomicConfig,
+    pub env: Vec<(String, String)>,
+    /// Set while running a command table with `container = "<image>"`.
+    pub container: Option<Container>,
+    pub interactivity: Interactivity,
+    pub fold: Fold,
+    /// Set while running a command with `capture_output = "commit_body"`.
+    pub capture: bool,
+    /// Stdout kept so far for the commit message, by step. Every copy of the context shares it.
+    pub captured: Rc<RefCell<Vec<(String, Capture)>>>,
+    /// Set while running a command with `cargo_json = true`.
+    pub cargo_json: bool,
+    /// Set while running a chain with `tmpdir = true`, which gives each step its own.
+    pub tmpdir_per_step: bool,
+    pub keep_tmp: bool,
+    /// Set while running a command with `clean_env = true`: `env` is all its steps get.
+    pub clean_env: bool,
+}
+
+impl RunContext {
+    /// Runs one shell step in the project root, inside this context's container if it has one.
+    fn run_step(&self, cmd: &str, policy: &RetryPolicy) -> StepOutcome {
+        let root = self.project_root();
+        let cwd = Some(root.as_path()).filter(|root| !root.as_os_str().is_empty());
+        let section = Fold::next_section();
+        if let Some(start) = self.fold.start(cmd, section, now()) {
+            println!("{}", start);
+        }
+        let cargo_cmd = self
+            .cargo_json
+            .then(|| cargo_json::with_message_format(cmd))
+            .flatten();
+        let cmd = cargo_cmd.as_deref().unwrap_or(cmd);
+        let mut capture = match cargo_cmd {
+            Some(_) => Some(Capture::cargo_json()),
+            None => (self.capture || junit::recording()).then(Capture::default),
+        };
+        let mut outcome = match &self.container {
+            None => send_with_retries(
+                cmd,
+                policy,
+                &self.env,
+                self.clean_env,
+                cwd,
+                capture.as_mut(),
+            ),
+            Some(container) => {
+                let argv = container.argv(cmd, &root, &self.env);
+                retry_step(cmd, policy, || {
+                    capture.as_mut().map(Capture::clear);
+                    spawn_argv(&argv, &[], cwd, capture.as_mut())
+                })
+            }
+        };
+        if let Some(end) = self.fold.end(section, now(), outcome.failed()) {
+            println!("{}", end);
+        }
+        let summary = capture
+            .as_ref()
+            .and_then(|capture| capture.cargo.as_ref())
+            .and_then(|cargo| cargo.diagnostics.summary());
+        if let Some(summary) = summary {
+            eprintln!("cargo: {}", summary);
+        }
+        if !outcome.success() {
+            outcome.output = capture
+                .as_ref()
+                .map(|capture| capture.tail(junit::OUTPUT_LINES))
+                .filter(|tail| !tail.is_empty());
+        }
+        if let Some(capture) = capture.filter(|_| self.capture) {
+            self.captured.borrow_mut().push((cmd.to_string(), capture));
+        }
+        outcome
+    }
+
+    /// The context for a command table: a copy running in `image` when it sets `container`.
+    ///
+    /// # Errors
+    /// Returns an error if no container runtime is available.
+    fn with_container(&self, image: Option<&str>) -> Result<Cow<'_, RunContext>> {
+        let Some(image) = image else {
+            return Ok(Cow::Borrowed(self));
+        };
+        let runtime = self.config.atomic.container_runtime.as_deref();
+        let container = Container::new(image, runtime.filter(|r| !r.is_empty()))?;
+        Ok(Cow::Owned(RunContext {
+            container: Some(container),
+            ..self.clone()
+        }))
+    }
+
+    /// The context for a command that sets `capture_output`: a copy that keeps its stdout.
+    fn with_capture(&self, capture: bool) -> Cow<'_, RunContext> {
+        if !capture || self.capture {
+            return Cow::Borrowed(self);
+        }
+        Cow::Owned(RunContext {
+            capture: true,
+            ..self.clone()
+        })
+    }
+
+    /// The context for a command that sets `cargo_json`: a copy that reads cargo's JSON output.
+    fn with_cargo_json(&self, cargo_json: bool) -> Cow<'_, RunContext> {
+        if !cargo_json || self.cargo_json {
+            return Cow::Borrowed(self);
+        }
+        Cow::Owned(RunContext {
+            cargo_json: true,
+            ..self.clone()
+        })
+    }
+
+    /// The context for a command that sets `env_file`: a copy with that file's variables too.
+    ///
+    /// # Errors
+    /// Returns an error if the file is missing or can't be parsed.
+    fn with_env_file(&self, file: Option<&str>) -> Result<Cow<'_, RunContext>> {
+        let Some(file) = file else {
+            return Ok(Cow::Borrowed(self));
+        };
+        let mut env = self.env.clone();
+        for var in envfile::load_file(&self.project_root(), file, true)? {
+            envfile::set(&mut env, var);
+        }
+        Ok(Cow::Owned(RunContext {
+            env,
+            ..self.clone()
+        }))
+    }
+
+    /// The context for a command that sets `clean_env`: a copy whose steps get only its
+    /// environment, with the variables of atomic's own that are allowed added to it.
+    fn with_clean_env(&self, clean: bool, allowed: &[String]) -> Cow<'_, RunContext> {
+        if !clean {
+            return Cow::Borrowed(self);
+        }
+        let mut env = clean_env::inherited(allowed);
+        env.extend(self.env.iter().cloned());
+        Cow::Owned(RunContext {
+            env,
+            clean_env: true,
+            ..self.clone()
+        })
+    }
+
+    /// The context for a chain with `tmpdir = true`: a copy that gives every step its own.
+    fn with_tmpdir_per_step(&self, per_step: bool) -> Cow<'_, RunContext> {
+        if !per_step || self.tmpdir_per_step {
+            return Cow::Borrowed(self);
+        }
+        Cow::Owned(RunContext {
+            tmpdir_per_step: true,
+            ..self.clone()
+        })
+    }
+
+    /// Runs `run` with a new `ATOMIC_TMPDIR` for `step`, then removes the directory unless
+    /// `--keep-tmp` was given, or the step `failed` and `keep_tmp_on_failure` is set.
+    ///
+    /// # Errors
+    /// Returns an error if the directory can't be created, or what `run` returns.
+    fn in_tmpdir<T>(
+        &self,
+        step: &str,
+        run: impl FnOnce(&RunContext) -> Result<T>,
+        failed: impl FnOnce(&T) -> bool,
+    ) -> Result<T> {
+        let dir = TmpDir::create(&self.config_path(), step)?;
+        let mut env = self.env.clone();
+        let var = (
+            tmpdir::ENV_VAR.to_string(),
+            dir.path().display().to_string(),
+        );
+        envfile::set(&mut env, var);
+        let result = run(&RunContext {
+            env,
+            ..self.clone()
+        });
+        let keep_failed = self.config.atomic.keep_tmp_on_failure == Some(true)
+            && result.as_ref().map_or(true, failed);
+        dir.finish(step, self.keep_tmp || keep_failed);
+        result
+    }
+
+    fn config_path(&self) -> PathBuf {
+        self.env
+            .iter()
+            .find(|(key, _)| key == "ATOMIC_CONFIG_PATH")
+            .map(|(_, path)| PathBuf::from(path))
+            .unwrap_or_default()
+    }
+
+    fn project_root(&self) -> PathBuf {
+        env_root(&self.env)
+    }
+}
+
+/// Key the command read by `atomic --stdin` is registered under.
+pub const ADHOC_COMMAND: &str = "adhoc";
+
+/// Loads atomic.toml and runs `cmd` from it, auto-committing the result.
+///
+/// Returns the exit code for the run: 0, or the code of the step that failed.
+///
+/// # Errors
+/// Returns an error, for the caller to report, when atomic.toml can't be loaded, `cmd` isn't in
+/// it, or can't start; also when it needs confirmation that isn't given, see [`Confirm`].
+pub fn run_command<P: AsRef<Path>>(cmd: &str, atomic: P, options: &RunOptions) -> Result<i32> {
+    let atomic = atomic.as_ref();
+    let _run = trace::span(format!("atomic {}", cmd));
+
+    // read in atomic file and parse it out, with the active profile applied
+    let loaded = {
+        let _load = trace::span("load atomic.toml");
+        load_and_validate_toml(atomic, options.profile.as_deref())
+            .and_then(AtomicConfig::from_value)
+    };
+    if let Some(socket) = loaded
+        .as_ref()
+        .ok()
+        .and_then(|c| c.atomic.event_socket.as_ref())
+    {
+        if !events::is_enabled() {
+            events::listen_at(Path::new(socket));
+        }
+    }
+    events::emit(Event::run_started(cmd));
+    let config = match loaded {
+        Ok(config) => config,
+        Err(err) => {
+            let result = Err(err);
+            events::emit(Event::run_finished(cmd, &result));
+            return result;
+        }
+    };
+
+    let record = config.atomic.stats != Some(false) && config.command(cmd).is_some();
+    // a nested atomic runs the same config, which its outer one already checked
+    let snapshot = match config.atomic.change_notice != Some(false)
+        && !options.no_change_notice
+        && !options.nesting.is_nested()
+    {
+        true => config_changes::check(atomic),
+        false => None,
+    };
+    let started = Instant::now();
+    let result = execute_command(cmd, config, atomic, options);
+    // best-effort: a project atomic can't write to still runs
+    if record {
+        let name = split_qualifier(cmd).map_or(cmd, |(_, name)| name);
+        let _ = stats::record(atomic, name, started.elapsed());
+    }
+    if let Some(snapshot) = snapshot {
+        let _ = config_changes::save(atomic, &snapshot);
+    }
+    events::emit(Event::run_finished(cmd, &result));
+    result
+}
+
+/// Runs a command table given as a TOML fragment, as if it were `[custom.adhoc]`.
+///
+/// Named steps resolve against `atomic` when it exists, but the file is not required.
+/// Auto-commit stays off unless the fragment sets `commit = true`. Returns the exit code like
+/// [`run_command`].
+///
+/// # Errors
+/// Returns an error if the fragment doesn't parse, fails [`validate_custom_entry`], the
+/// existing atomic.toml can't be loaded, or a `confirm` prompt is declined.
+pub fn run_adhoc(fragment: &str, atomic: &Path, options: &RunOptions) -> Result<i32> {
+    let _run = trace::span("atomic --stdin");
+    let entry: Value =
+        toml::from_str(fragment).map_err(|err| AtomicError::TomlParse("stdin".to_string(), err))?;
+    validate_custom_entry(&entry)?;
+
+    let loaded = {
+        let _load = trace::span("load atomic.toml");
+        load_and_validate_toml(atomic, options.profile.as_deref())
+    };
+    let mut parsed_toml = match loaded {
+        Ok(parsed_toml) => parsed_toml,
+        Err(AtomicError::ConfigNotFound(_)) => Value::Table(Map::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut adhoc = Map::new();
+    adhoc.insert(ADHOC_COMMAND.to_string(), entry.clone());
+    merge_toml(
+        &mut parsed_toml,
+        Value::Table(Map::from_iter([(
+            "custom".to_string(),
+            Value::Table(adhoc),
+        )])),
+    );
+
+    let options = RunOptions {
+        no_commit: options.no_commit
+            || !entry
+                .get("commit")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        ..options.clone()
+    };
+    execute_command(
+        ADHOC_COMMAND,
+        AtomicConfig::from_value(parsed_toml)?,
+        atomic,
+        &options,
+    )
+}
+
+/// Checks that a `[custom.<name>]` table has a usable `command` and valid retry settings.
+pub fn validate_custom_entry(entry: &Value) -> Result<()> {
+    let invalid = |problem: &str| Err(AtomicError::InvalidStdinCommand(problem.to_string()));
+    let Value::Table(table) = entry else {
+        return invalid("Command entries must be tables");
+    };
+
+    match table.get("command") {
+        Some(Value::Array(steps)) if steps.is_empty() => {
+            return invalid("`command` chain has no steps")
+        }
+        Some(command) if command.clone().try_into::<Steps>().is_err() => {
+            return invalid(
+                "`command` must be a string or an array of strings and `{ run = \"...\" }` tables",
+            )
+        }
+        Some(_) => {}
+        None => return invalid("Missing required key `command`"),
+    }
+
+    let detail: DetailedCommand = entry
+        .clone()
+        .try_into()
+        .map_err(|err| AtomicError::InvalidStdinCommand(err.to_string()))?;
+    RetryPolicy::from_command(&detail).map(|_| ())
+}
+
+/// Returns the run's exit code; see [`run_command`].
+///
+/// # Errors
+/// Returns an error if `cmd` isn't in `config`, or something stops it from starting, such as a
+/// `confirm` prompt that is declined; what goes wrong once it runs is reported as it happens.
+fn execute_command(
+    cmd: &str,
+    config: AtomicConfig,
+    atomic: &Path,
+    options: &RunOptions,
+) -> Result<i32> {
+    let Some(spec) = config.command(cmd).cloned() else {
+        return Err(command_not_found(cmd, &config));
+    };
+    warn_shadowed(&config, cmd);
+    if !spec.runs_here() {
+        skip_elsewhere(&config, cmd, &spec);
+        return Ok(0);
+    }
+    let name = split_qualifier(cmd).map_or(cmd, |(_, name)| name);
+    let freshness = match spec.inputs() {
+        Some(inputs) => Some(inputs::check(atomic, name, inputs, spec.outputs())?),
+        None => None,
+    };
+    if let (Some(Freshness::UpToDate(_)), false) = (&freshness, options.force) {
+        println!(
+            "'{}' is up to date: its inputs haven't changed since it last succeeded \
+             (--force runs it anyway)",
+            cmd
+        );
+        return Ok(0);
+    }
+
+    let git = git_on(&config.atomic, options);
+    if !git {
+        eprintln!(
+            "git is off ({}): not auto-committing, and ATOMIC_BRANCH / ATOMIC_ISSUE are empty",
+            if options.no_git {
+                "--no-git"
+            } else {
+                "git = false in atomic.toml"
+            }
+        );
+    }
+    let env = {
+        let _env = trace::span("resolve environment");
+        let mut env = atomic_env(atomic, options.profile.as_deref(), options.nesting, git);
+        if let Some(issues) = &config.atomic.issues {
+            env.push(issues::title_var(atomic, issues, &env));
+        }
+        let root = env_root(&env);
+        envfile::load(&root, config.atomic.env_files.as_deref()).map(|mut vars| {
+            vars.extend(env);
+            vars
+        })
+    };
+    let ctx = RunContext {
+        env: env?,
+        config,
+        container: None,
+        interactivity: options.interactivity,
+        fold: options.fold,
+        capture: false,
+        captured: Rc::default(),
+        cargo_json: false,
+        tmpdir_per_step: false,
+        keep_tmp: options.keep_tmp,
+        clean_env: false,
+    };
+
+    let nested = skips_hooks(options);
+    if nested {
+        eprintln!(
+            "nested atomic invocation detected: skipping the auto-commit and [atomic] hooks \
+             (pass --allow-nested-commit to keep them)"
+        );
+    }
+    if git && !nested {
+        let repo = open_repository(options.repo.as_deref()).ok();
+        if let Some(workdir) = repo.as_ref().and_then(|repo| repo.workdir()) {
+            let managed = ctx.config.atomic.manage_gitignore == Some(true);
+            gitignore::check(atomic, workdir, managed);
+        }
+    }
+
+    let auto_commit = no_commit_reason(&ctx.config.atomic, options).is_none();
+    // hashed before the run, which may well change atomic.toml
+    let provenance: Vec<_> = config_hash(atomic)
+        .into_iter()
+        .map(|hash| (CONFIG_HASH_TRAILER, hash))
+        .collect();
+    let commit_options = CommitOptions {
+        repo: options.repo.as_deref(),
+        no_sign: options.no_sign,
+        branch: spec.commit_branch(),
+        trailers: &provenance,
+        ..commit_settings(&ctx.config.atomic, Some(cmd))?
+    };
+    let needs = resolve_needs(&ctx.config, cmd)?;
+
+    // a missing git identity would only surface after the command already ran
+    if auto_commit {
+        let _check = trace::span("check git identity");
+        check_commit_identity(&commit_options)?;
+    }
+
+    if !options.yes {
+        let commands = needs.iter().map(|(name, spec)| (name.as_str(), spec));
+        for (name, spec) in commands.chain([(cmd, &spec)]) {
+            let Some(confirm) = Confirm::from_spec(spec) else {
+                continue;
+            };
+            if options.interactivity.is_ci() {
+                return Err(AtomicError::ConfirmInCi(name.to_string()));
+            }
+            confirm_on_terminal(name, &confirm)?;
+        }
+    }
+
+    // held until the auto-commit is done
+    let _lock = match spec.detail().filter(|detail| detail.lock == Some(true)) {
+        Some(detail) => Some(command_lock(atomic, name, detail, options.no_wait)?),
+        None => None,
+    };
+
+    let code = if nested {
+        0
+    } else {
+        run_global_hook(&ctx, "pre_run", &[])
+    };
+    if code != 0 {
+        eprintln!("pre_run failed; not running '{}'", cmd);
+        return Ok(code);
+    }
+    let max_size = match &ctx.config.atomic.artifact_max_size {
+        Some(size) => size.to_bytes()?,
+        None => artifacts::DEFAULT_MAX_SIZE,
+    };
+    artifacts::start(
+        atomic,
+        cmd,
+        options.artifacts_dir.as_deref(),
+        max_size,
+        now(),
+    );
+    let result = run_and_commit(
+        &ctx,
+        cmd,
+        &spec,
+        &needs,
+        auto_commit,
+        commit_options,
+        options,
+    );
+    artifacts::finish();
+    let succeeded = result == Ok(0);
+    if let (Some(freshness), true) = (&freshness, succeeded) {
+        if let Err(err) = inputs::record(atomic, name, freshness.hash()) {
+            eprintln!("warning: {}", err);
+        }
+    }
+    let outcome = if succeeded { "success" } else { "failure" };
+    let env = [("ATOMIC_RESULT".to_string(), outcome.to_string())];
+    if !nested && run_global_hook(&ctx, "post_run", &env) != 0 {
+        eprintln!("warning: post_run failed");
+    }
+    result
+}
+
+/// Takes the lock of a command with `lock = true`, waiting up to its `lock_wait`. `name` is
+/// without its section qualifier, so `custom:migrate` and `migrate` share one lock.
+///
+/// # Errors
+/// Returns an error if `lock_wait` is invalid, or the lock is still held when the wait is over.
+fn command_lock(
+    atomic: &Path,
+    name: &str,
+    detail: &DetailedCommand,
+    no_wait: bool,
+) -> Result<CommandLock> {
+    let wait = match &detail.lock_wait {
+        _ if no_wait => Some(Duration::ZERO),
+        Some(wait) => Some(wait.to_duration()?),
+        None => None,
+    };
+    lock::acquire(atomic, name, wait)
+}
+
+/// Runs `cmd` after what it needs, then auto-commits when that's on, returning the exit code.
+///
+/// # Errors
+/// Returns an error if a command can't be run, or the auto-commit fails.
+fn run_and_commit(
+    ctx: &RunContext,
+    cmd: &str,
+    spec: &CommandSpec,
+    needs: &[(String, CommandSpec)],
+    auto_commit: bool,
+    commit_options: CommitOptions,
+    options: &RunOptions,
+) -> Result<i32> {
+    // remember what was dirty beforehand, so unrelated edits aren't swept into the commit
+    let repo = open_repository(options.repo.as_deref())
+        .ok()
+        .filter(|_| auto_commit);
+    let before = repo
+        .as_ref()
+        .and_then(|repo| worktree_snapshot(repo).ok())
+        .unwrap_or_default();
+    if let Some(repo) = &repo {
+        for path in unhashed_paths(repo, &before) {
+            eprintln!(
+                "note: {} is too big to hash; only its size and modification time show whether \
+                 '{}' changed it",
+                path, cmd
+            );
+        }
+    }
+
+    let code = run_needs(ctx, cmd, needs)?;
+    if code != 0 {
+        return Ok(code);
+    }
+
+    let outcomes = execute_resolved_command(ctx, cmd, spec)?;
+    let failed = outcomes.iter().filter(|o| o.failed()).count();
+    let ignored = outcomes.iter().filter(|o| o.ignored).count();
+    if outcomes.len() > 1 && failed + ignored > 0 {
+        eprintln!("{}", failure_summary(cmd, outcomes.len(), failed, ignored));
+    }
+    if failed > 0 {
+        return Ok(exit_code(&outcomes));
+    }
+    if !auto_commit {
+        return Ok(0);
+    }
+
+    let _commit = trace::span("auto-commit");
+    // clean tree before the run: the command made every change
+    let changes = match &repo {
+        Some(repo) if !before.is_empty() => {
+            Some(RunChanges::between(&before, &worktree_snapshot(repo)?))
+        }
+        _ => None,
+    };
+    let paths = match &changes {
+        Some(changes) => paths_to_commit(cmd, changes, &ctx.config.atomic, options)?,
+        None => None,
+    };
+    let origins = changes
+        .as_ref()
+        .and_then(|changes| changes.commit_paragraph(paths.as_deref()));
+    let output = commit_body(&ctx.captured.borrow());
+    let body = match (output, origins) {
+        (Some(output), Some(origins)) => Some(format!("{}\n{}", output, origins)),
+        (output, origins) => output.or(origins),
+    };
+    let mut trailers = commit_options.trailers.to_vec();
+    trailers.push((STEPS_TRAILER, steps_trailer(&outcomes)));
+    let commit_options = CommitOptions {
+        paths: paths.as_deref(),
+        body: body.as_deref(),
+        trailers: &trailers,
+        ..commit_options
+    };
+    let message = auto_commit_message(cmd, spec);
+    match commit_local_changes(&message, &commit_options)? {
+        Some(commit) => {
+            let commit = commit.with_origins(changes.as_ref());
+            commit.print_summary(options.show_diff);
+            events::emit(Event::commit_created(&commit));
+        }
+        None => println!("{}", NO_CHANGES),
+    }
+    Ok(0)
+}
+
+/// The `--- output ---` paragraph of the auto-commit message: the stdout kept from steps run with
+/// `capture_output = "commit_body"`, in the order they ran, each under a `[step]` header when
+/// there are several. Output that isn't UTF-8 is kept lossily, with a warning.
+fn commit_body(captured: &[(String, Capture)]) -> Option<String> {
+    for (step, capture) in captured {
+        let invalid = capture.invalid_utf8();
+        if invalid > 0 {
+            eprintln!(
+                "warning: output of '{}' contained invalid UTF-8, kept in the commit message \
+                 lossily ({} replacement character{})",
+                step,
+                invalid,
+                if invalid == 1 { "" } else { "s" }
+            );
+        }
+    }
+    let captured: Vec<_> = captured
+        .iter()
+        .map(|(step, capture)| (step, capture.text(), capture.truncated))
+        .filter(|(_, text, _)| !text.trim().is_empty())
+        .collect();
+    if captured.is_empty() {
+        return None;
+    }
+    let mut body = String::from("--- output ---\n");
+    for (step, text, truncated) in &captured {
+        if captured.len() > 1 {
+            body.push_str(&format!("[{}]\n", step));
+        }
+        body.push_str(text.trim_end());
+        body.push('\n');
+        if *truncated {
+            body.push_str(&format!(
+                "[output truncated at {} KB]\n",
+                CAPTURE_LIMIT / 1024
+            ));
+        }
+    }
+    Some(body)
+}
+
+/// Set in the environment of the global hooks, so an atomic started from one skips them.
+const GLOBAL_HOOK_GUARD: &str = "ATOMIC_IN_GLOBAL_HOOK";
+
+/// Runs `[atomic] pre_run` or `post_run` with `extra_env`, returning its exit code.
+///
+/// Unset hooks, and hooks inside an atomic run by another hook, count as success.
+fn run_global_hook(ctx: &RunContext, key: &str, extra_env: &[(String, String)]) -> i32 {
+    if std::env::var_os(GLOBAL_HOOK_GUARD).is_some() {
+        return 0;
+    }
+    let hook = match key {
+        "pre_run" => &ctx.config.atomic.pre_run,
+        _ => &ctx.config.atomic.post_run,
+    };
+    let Some(hook) = hook else {
+        return 0;
+    };
+
+    let _hook = trace::span(key);
+    let mut env = ctx.env.clone();
+    env.extend_from_slice(extra_env);
+    env.push((GLOBAL_HOOK_GUARD.to_string(), "1".to_string()));
+    let ctx = RunContext { env, ..ctx.clone() };
+    match run_steps(&ctx, hook, &RetryPolicy::default()) {
+        Ok(outcomes) => exit_code(&outcomes),
+        Err(err) => {
+            eprintln!("Error running {}: {}", key, err);
+            1
+        }
+    }
+}
+
+/// The `Atomic-Steps` trailer of a run: `fmt=0, test=0`, each step with its exit code. The commas
+/// between a `matrix` step's values become semicolons, to keep the steps apart.
+fn steps_trailer(outcomes: &[StepOutcome]) -> String {
+    outcomes
+        .iter()
+        .map(|outcome| format!("{}={}", outcome.name.replace(',', ";"), outcome.code))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `2 of 5 steps in 'ci' failed, 1 failed (ignored)` for the end of a chain.
+fn failure_summary(cmd: &str, total: usize, failed: usize, ignored: usize) -> String {
+    match (failed, ignored) {
+        (0, _) => format!(
+            "{} of {} steps in '{}' failed (ignored)",
+            ignored, total, cmd
+        ),
+        (_, 0) => format!("{} of {} steps in '{}' failed", failed, total, cmd),
+        _ => format!(
+            "{} of {} steps in '{}' failed, {} failed (ignored)",
+            failed, total, cmd, ignored
+        ),
+    }
+}
+
+/// The exit code of the first failed step, with launch failures (-1) reported as 1.
+///
+/// Ignored failures don't count.
+fn exit_code(outcomes: &[StepOutcome]) -> i32 {
+    match outcomes.iter().find(|outcome| outcome.failed()) {
+        Some(outcome) if outcome.code > 0 => outcome.code,
+        Some(_) => 1,
+        None => 0,
+    }
+}
+
+/// The commands `cmd` needs, directly or through other commands, in the order to run them.
+///
+/// Each appears once, after everything it needs itself; `cmd` is not included.
+///
+/// # Errors
+/// Returns an error if `needs` names a command atomic.toml doesn't define, or the dependencies
+/// form a cycle.
+pub fn resolve_needs(config: &AtomicConfig, cmd: &str) -> Result<Vec<(String, CommandSpec)>> {
+    let mut order = Vec::new();
+    visit_needs(config, cmd, &mut Vec::new(), &mut order)?;
+    // cmd itself finishes last
+    order.pop();
+    Ok(order)
+}
+
+/// Depth-first walk behind [`resolve_needs`]; `stack` holds the commands being visited.
+fn visit_needs(
+    config: &AtomicConfig,
+    name: &str,
+    stack: &mut Vec<String>,
+    order: &mut Vec<(String, CommandSpec)>,
+) -> Result<()> {
+    if order.iter().any(|(done, _)| done == name) {
+        return Ok(());
+    }
+    if let Some(start) = stack.iter().position(|visiting| visiting == name) {
+        let mut cycle = stack[start..].to_vec();
+        cycle.push(name.to_string());
+        return Err(AtomicError::NeedsCycle(cycle));
+    }
+
+    let Some(spec) = config.command(name) else {
+        return Err(AtomicError::UnknownNeed(
+            stack.last().cloned().unwrap_or_default(),
+            name.to_string(),
+            command_suggestions(name, config),
+        ));
+    };
+
+    stack.push(name.to_string());
+    for dep in spec.needs() {
+        visit_needs(config, dep, stack, order)?;
+    }
+    stack.pop();
+    order.push((name.to_string(), spec.clone()));
+    Ok(())
+}
+
+/// Runs the commands `cmd` needs in order, stopping at the first that fails.
+///
+/// Returns 0 when all of them succeeded, so `cmd` can run, or the failing one's exit code.
+///
+/// # Errors
+/// Returns an error if one of them can't be run.
+fn run_needs(ctx: &RunContext, cmd: &str, needs: &[(String, CommandSpec)]) -> Result<i32> {
+    for (i, (name, spec)) in needs.iter().enumerate() {
+        warn_shadowed(&ctx.config, name);
+        if !spec.runs_here() {
+            skip_elsewhere(&ctx.config, name, spec);
+            continue;
+        }
+        let _need = trace::span(name.as_str());
+        let result = execute_resolved_command(ctx, name, spec).map(|outcomes| exit_code(&outcomes));
+        if result != Ok(0) {
+            eprintln!("'{}' failed, so '{}' was not run", name, cmd);
+            let skipped = needs[i + 1..].iter().map(|(name, _)| name.as_str());
+            for name in skipped.chain([cmd]) {
+                let section = ctx.config.section_of(name).unwrap_or("custom");
+                junit::record(name, section, Duration::ZERO, CaseResult::Skipped);
+            }
+            return result;
+        }
+    }
+    Ok(0)
+}
+
+/// Reports that `name` is skipped because its `os` doesn't include this platform.
+fn skip_elsewhere(config: &AtomicConfig, name: &str, spec: &CommandSpec) {
+    let platforms = spec.os().map(OsSpec::describe).unwrap_or_default();
+    eprintln!("skipping '{}': it only runs on {}", name, platforms);
+    let section = config.section_of(name).unwrap_or("custom");
+    junit::record(name, section, Duration::ZERO, CaseResult::Skipped);
+}
+
+/// Warns when the unqualified `name` is defined in several sections, naming the one that runs and
+/// how to run the others.
+fn warn_shadowed(config: &AtomicConfig, name: &str) {
+    let shadowed = config.shadowed(name);
+    let Some(section) = config.section_of(name).filter(|_| !shadowed.is_empty()) else {
+        return;
+    };
+    let others: Vec<String> = shadowed.iter().map(|other| section_label(other)).collect();
+    let qualified: Vec<String> = shadowed
+        .iter()
+        .map(|other| format!("{}:{}", other, name))
+        .collect();
+    eprintln!(
+        "warning: running '{}' from {}, which shadows {}; write {} to run another",
+        name,
+        section_label(section),
+        others.join(" and "),
+        qualified.join(" or ")
+    );
+}
+
+/// Why `cargo_json` does nothing for the command `name`: none of the commands it runs is a
+/// single cargo build, check, clippy, test, run or doc.
+pub fn cargo_json_warning(config: &AtomicConfig, name: &str, spec: &CommandSpec) -> Option<String> {
+    if !spec.cargo_json() {
+        return None;
+    }
+    let commands: Vec<String> = match spec.steps() {
+        Steps::Shell(cmd) => vec![cmd.clone()],
+        Steps::Chain(chain) => chain
+            .iter()
+            .filter_map(|step| plan_step(config, step, &RetryPolicy::default()).ok())
+            .map(|step| step.command)
+            .collect(),
+    };
+    if commands
+        .iter()
+        .any(|cmd| cargo_json::with_message_format(cmd).is_some())
+    {
+        return None;
+    }
+    Some(format!(
+        "'{}' sets cargo_json, but doesn't run a cargo command that can report JSON; it runs as written",
+        name
+    ))
+}
+
+/// Builds the error for an unknown command, suggesting close matches from atomic.toml.
+pub fn command_not_found(cmd: &str, config: &AtomicConfig) -> AtomicError {
+    AtomicError::CommandNotFound(cmd.to_string(), command_suggestions(cmd, config))
+}
+
+/// Commands in atomic.toml whose names are close to `cmd`.
+fn command_suggestions(cmd: &str, config: &AtomicConfig) -> Vec<String> {
+    let names = config.command_names();
+    suggest(cmd, names.iter().map(String::as_str))
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Builds the environment variables atomic exports to every command it runs.
+///
+/// These names are a stable contract for scripts:
+///
+/// | Variable              | Value                                                          |
+/// |-----------------------|----------------------------------------------------------------|
+/// | `ATOMIC_CONFIG_PATH`  | absolute path to atomic.toml                                   |
+/// | `ATOMIC_PROJECT_ROOT` | absolute path of the directory holding atomic.toml             |
+/// | `ATOMIC_BRANCH`       | current git branch, empty when detached, outside a repo, or `git` is false |
+/// | `ATOMIC_ISSUE`        | issue number parsed from the branch name, empty if there is none |
+/// | `ATOMIC_PROFILE`      | the active profile, only set when one is selected              |
+/// | `ATOMIC_ACTIVE`       | process id of the atomic running the command                   |
+/// | `ATOMIC_DEPTH`        | how many atomics the command runs under, 1 for a top-level run |
+///
+/// With `git` false the repository isn't looked at, so the branch and issue are empty.
+/// `run_command` adds `ATOMIC_ISSUE_TITLE` with `[atomic.issues]`; see [`crate::issues`].
+/// Steps of commands with `tmpdir` set also get `ATOMIC_TMPDIR`; see [`crate::tmpdir`].
+pub fn atomic_env(
+    atomic: &Path,
+    profile: Option<&str>,
+    nesting: Nesting,
+    git: bool,
+) -> Vec<(String, String)> {
+    let config_path = fs::canonicalize(atomic).unwrap_or_else(|_| atomic.to_path_buf());
+    let project_root = config_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    let branch = Some(&project_root)
+        .filter(|_| git)
+        .and_then(|root| Repository::discover(root).ok())
+        .and_then(|repo| current_branch(&repo))
+        .unwrap_or_default();
+    let issue = branch_issue(&branch)
+        .map(|issue| issue.to_string())
+        .unwrap_or_default();
+
+    let mut env = vec![
+        (
+            "ATOMIC_CONFIG_PATH".to_string(),
+            config_path.display().to_string(),
+        ),
+        (
+            "ATOMIC_PROJECT_ROOT".to_string(),
+            project_root.display().to_string(),
+        ),
+        ("ATOMIC_BRANCH".to_string(), branch),
+        ("ATOMIC_ISSUE".to_string(), issue),
+    ];
+    if let Some(profile) = profile {
+        env.push(("ATOMIC_PROFILE".to_string(), profile.to_string()));
+    }
+    env.extend(nesting.child_env());
+    env
+}
+
+/// The `ATOMIC_PROJECT_ROOT` of `env`, from [`atomic_env`].
+pub fn env_root(env: &[(String, String)]) -> PathBuf {
+    env.iter()
+        .find(|(key, _)| key == "ATOMIC_PROJECT_ROOT")
+        .map(|(_, root)| PathBuf::from(root))
+        .unwrap_or_default()
+}
+
+/// Whether a run with `options` uses git: not with `--no-git` or `[atomic] git = false`.
+pub fn git_on(settings: &Settings, options: &RunOptions) -> bool {
+    !options.no_git && settings.git != Some(false)
+}
+
+/// Whether a run with `options` leaves the auto-commit and `[atomic]` hooks to the atomic that
+/// started it, as nested runs do unless told otherwise.
+pub fn skips_hooks(options: &RunOptions) -> bool {
+    options.nesting.is_nested() && !options.allow_nested_commit
+}
+
+/// Why a run with `options` won't auto-commit, or `None` when it will.
+///
+/// Auto-commits are on unless `auto_commit = false`, git is off, the run is nested, it was asked
+/// not to, or it's in CI mode without `--commit`.
+pub fn no_commit_reason(settings: &Settings, options: &RunOptions) -> Option<&'static str> {
+    if options.no_git {
+        Some("--no-git")
+    } else if settings.git == Some(false) {
+        Some("git = false in atomic.toml")
+    } else if settings.auto_commit == Some(false) {
+        Some("auto_commit = false in atomic.toml")
+    } else if skips_hooks(options) {
+        Some("nested atomic invocation")
+    } else if options.no_commit {
+        Some("the command doesn't set commit = true")
+    } else if options.interactivity.is_ci() && !options.commit {
+        Some("CI mode without --commit")
+    } else {
+        None
+    }
+}
+
+/// `max_commit_file_size` when atomic.toml doesn't set it.
+const DEFAULT_MAX_COMMIT_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Reads the `[atomic]` settings that govern the auto-commit after `cmd`, or a `--snapshot`
+/// commit without it.
+///
+/// Git hooks only run with `run_git_hooks = true`; `commit_author` is the `"Name <email>"` used
+/// when git config has none; files over `max_commit_file_size` stop the commit unless
+/// `large_files = "skip"`.
+///
+/// # Errors
+/// Returns an error if `max_commit_file_size` is invalid.
+pub fn commit_settings<'a>(
+    settings: &'a Settings,
+    cmd: Option<&'a str>,
+) -> Result<CommitOptions<'a>> {
+    let max_file_size = match &settings.max_commit_file_size {
+        Some(size) => size.to_bytes()?,
+        None => DEFAULT_MAX_COMMIT_FILE_SIZE,
+    };
+    Ok(CommitOptions {
+        command: cmd,
+        run_git_hooks: settings.run_git_hooks.unwrap_or(false),
+        commit_author: settings
+            .commit_author
+            .as_deref()
+            .filter(|author| !author.is_empty()),
+        paths: None,
+        repo: None,
+        body: None,
+        max_file_size: Some(max_file_size),
+        skip_large_files: settings.large_files == Some(LargeFiles::Skip),
+        no_sign: false,
+        branch: None,
+        trailers: &[],
+    })
+}
+
+/// The subject of the auto-commit after `cmd`: its `commit_message`, or `atomic: <cmd>`.
+pub fn auto_commit_message(cmd: &str, spec: &CommandSpec) -> String {
+    match spec.commit_message() {
+        Some(message) => message.trim().to_string(),
+        None => format!("{}{}", AUTO_COMMIT_PREFIX, cmd),
+    }
+}
+
+/// Decides what the auto-commit after `cmd` takes when files were dirty before it ran.
+///
+/// Returns `None` to commit everything, or the paths the command changed. Which one follows
+/// `--include-preexisting`, then `[atomic] autocommit_preexisting`: `include`, `exclude`, or
+/// `ask` (the default), which prompts on a terminal and excludes otherwise.
+fn paths_to_commit(
+    cmd: &str,
+    changes: &RunChanges,
+    settings: &Settings,
+    options: &RunOptions,
+) -> Result<Option<Vec<String>>> {
+    if changes.preexisting.is_empty() {
+        return Ok(None);
+    }
+
+    let mode = match options.include_preexisting {
+        true => "include",
+        false => settings.autocommit_preexisting.as_deref().unwrap_or("ask"),
+    };
+
+    eprintln!(
+        "warning: these files already had uncommitted changes before '{}' ran:",
+        cmd
+    );
+    for path in &changes.preexisting {
+        eprintln!("  {}", path);
+    }
+
+    let include = match mode {
+        "include" => true,
+        "ask" if io::stdin().is_terminal() && !options.interactivity.is_ci() => {
+            confirm("Include them in the auto-commit?", options.interactivity)?
+        }
+        _ => false,
+    };
+
+    if include {
+        eprintln!("Including them in the auto-commit.");
+        Ok(None)
+    } else {
+        eprintln!(
+            "Leaving them out of the auto-commit. Pass --include-preexisting, or set \
+             autocommit_preexisting = \"include\" under [atomic], to commit them too."
+        );
+        Ok(Some(changes.changed.clone()))
+    }
+}
+
+/// Makes sure an auto-commit will have an author before any command runs.
+///
+/// Outside a git repository there is nothing to check; the commit itself reports that.
+fn check_commit_identity(options: &CommitOptions) -> Result<()> {
+    match open_repository(options.repo) {
+        Ok(repo) => commit_signature(&repo.config()?, options.commit_author).map(|_| ()),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Asks on the terminal before running `cmd`, with the prompt on stderr so redirected output
+/// doesn't swallow it.
+///
+/// # Errors
+/// Returns [`AtomicError::Aborted`] if the answer is no, or an error if there's no terminal to
+/// ask on, so unattended runs stop instead of hanging.
+fn confirm_on_terminal(cmd: &str, confirm: &Confirm) -> Result<()> {
+    let no_terminal = |err: io::Error| {
+        AtomicError::Generic(format!(
+            "'{}' needs confirmation, but there is no terminal to ask on ({}). \
+             Pass --yes to run it anyway.",
+            cmd, err
+        ))
+    };
+    let tty = open_terminal().map_err(no_terminal)?;
+    match confirm.ask(cmd, &mut io::BufReader::new(tty), &mut io::stderr()) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(AtomicError::Aborted(cmd.to_string())),
+        Err(err) => Err(no_terminal(err)),
+    }
+}
+
+/// The controlling terminal, read directly so it works even when stdin is redirected.
+fn open_terminal() -> io::Result<fs::File> {
+    if cfg!(windows) {
+        fs::File::open("CONIN$")
+    } else {
+        fs::File::open("/dev/tty")
+    }
+}
+
+/// Executes the command `name` from atomic.toml: a shell string, a chain of steps, or a
+/// `[custom.<name>]` table with a `command` key.
+fn execute_resolved_command(
+    ctx: &RunContext,
+    name: &str,
+    spec: &CommandSpec,
+) -> Result<Vec<StepOutcome>> {
+    // chains and matrices send events for each of their steps instead
+    let single = matches!(spec.steps(), Steps::Shell(_)) && spec.matrix().is_none();
+    if single {
+        events::emit(Event::StepStarted {
+            name: name.to_string(),
+        });
+    }
+    let started = Instant::now();
+    let mut outcomes = match spec {
+        CommandSpec::Plain(steps) => run_steps(ctx, steps, &RetryPolicy::default()),
+        CommandSpec::Detailed(detail) => {
+            let policy = RetryPolicy::from_command(detail)?;
+            let ctx = ctx.with_container(detail.container.as_deref())?;
+            let ctx = ctx.with_capture(spec.captures_output());
+            let ctx = ctx.with_cargo_json(spec.cargo_json());
+            let ctx = ctx.with_clean_env(spec.clean_env(), spec.env_allow());
+            let ctx = ctx.with_env_file(spec.env_file())?;
+            if let Some(warning) = cargo_json_warning(&ctx.config, name, spec) {
+                eprintln!("warning: {}", warning);
+            }
+            match spec.tmpdir() {
+                TmpDirMode::None => run_detailed(&ctx, name, spec, &policy),
+                TmpDirMode::PerStep if !single => {
+                    run_detailed(&ctx.with_tmpdir_per_step(true), name, spec, &policy)
+                }
+                _ => ctx.in_tmpdir(
+                    name,
+                    |ctx| run_detailed(ctx, name, spec, &policy),
+                    |outcomes| outcomes.iter().any(StepOutcome::failed),
+                ),
+            }
+        }
+    }?;
+    // a matrix over a shell command collected after each combination instead
+    if spec.matrix().is_none() || !matches!(spec.steps(), Steps::Shell(_)) {
+        artifacts::collect(name, &ctx.project_root(), spec.artifacts());
+    }
+    // chains and matrices record each of their steps instead
+    if let (true, [outcome]) = (single, outcomes.as_mut_slice()) {
+        outcome.name = name.to_string();
+        events::emit(Event::step_finished(name, outcome.code, started.elapsed()));
+        let section = ctx.config.section_of(name).unwrap_or("custom");
+        junit::record(
+            name,
+            section,
+            started.elapsed(),
+            CaseResult::from_outcome(outcome),
+        );
+    }
+    Ok(outcomes)
+}
+
+/// Runs a shell command, or each step of a chain, with `policy`.
+fn run_steps(ctx: &RunContext, steps: &Steps, policy: &RetryPolicy) -> Result<Vec<StepOutcome>> {
+    match steps {
+        Steps::Shell(cmd) => Ok(vec![ctx.run_step(cmd, policy)]),
+        Steps::Chain(chain) => run_chain(ctx, chain, policy),
+    }
+}
+
+/// Runs a command table: each combination of its `matrix`, each step of its chain, or its shell
+/// command, stopping at the first failure when it sets `fail_fast`.
+fn run_detailed(
+    ctx: &RunContext,
+    name: &str,
+    spec: &CommandSpec,
+    policy: &RetryPolicy,
+) -> Result<Vec<StepOutcome>> {
+    let fail_fast = spec.fail_fast();
+    match (spec.matrix(), spec.steps()) {
+        (Some(matrix), _) => {
+            let steps = plan_matrix(&ctx.config, name, spec, matrix, policy)?;
+            run_planned(ctx, &steps, fail_fast)
+        }
+        (None, Steps::Chain(chain)) => {
+            run_planned(ctx, &plan_chain(&ctx.config, chain, policy)?, fail_fast)
+        }
+        (None, steps) => run_steps(ctx, steps, policy),
+    }
+}
+
+/// One step of a chain, resolved before anything in the chain runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedStep {
+    /// The step as written in the chain: a command name or a shell command.
+    pub name: String,
+    /// The shell command that runs.
+    pub command: String,
+    /// The named command's `desc`, if it has one.
+    pub desc: Option<String>,
+    /// Whether `name` refers to another command in atomic.toml.
+    pub named: bool,
+    pub policy: RetryPolicy,
+    /// The named command's `container`, if it sets one.
+    pub container: Option<String>,
+    /// Written as `-name` or with `ignore_failure = true`: a failure doesn't fail the run.
+    pub ignore_failure: bool,
+    /// The named command's `os`, when it doesn't include this platform and the step is skipped.
+    pub only_on: Option<String>,
+    /// The named command sets `capture_output = "commit_body"`.
+    pub capture_output: bool,
+    /// The named command sets `cargo_json = true`.
+    pub cargo_json: bool,
+    /// The named command's `env_file`, if it sets one.
+    pub env_file: Option<String>,
+    /// The named command sets `tmpdir`, so the step gets its own `ATOMIC_TMPDIR`.
+    pub tmpdir: bool,
+    /// `flag=foo, os=linux`: the `matrix` combination this step runs with, if any.
+    pub matrix: Option<String>,
+    /// The `artifacts` collected once the step finishes.
+    pub artifacts: Vec<String>,
+}
+
+impl PlannedStep {
+    /// The `[2/6] clippy — lint with warnings denied` line printed before the step runs.
+    ///
+    /// Named steps are described by their `desc`, or their shell command without one.
+    pub fn progress(&self, position: usize, total: usize) -> String {
+        let prefix = format!("[{}/{}] {}", position, total, self.label());
+        match (&self.desc, self.named || self.name != self.command) {
+            (Some(desc), _) => format!("{} — {}", prefix, desc),
+            (None, true) => format!("{} — {}", prefix, self.command),
+            (None, false) => prefix,
+        }
+    }
+
+    /// The step's name, followed by its `matrix` combination: `test [flag=foo]`.
+    pub fn label(&self) -> String {
+        match &self.matrix {
+            Some(combination) => format!("{} [{}]", self.name, combination),
+            None => self.name.clone(),
+        }
+    }
+
+    /// The section the step's JUnit testcase is filed under: the named command's, or `shell`.
+    fn section(&self, config: &AtomicConfig) -> &'static str {
+        // a matrix over a shell command is named after its command table, not `shell`
+        let fallback = match self.named {
+            true => "custom",
+            false => "shell",
+        };
+        config.section_of(&self.name).unwrap_or(fallback)
+    }
+
+    /// This step for one `matrix` combination, with `{key}` in its command replaced by the value.
+    /// Steps that aren't named commands are named after their command, so their name changes too.
+    fn for_combination(&self, combination: &Combination) -> Self {
+        let name = match self.named {
+            true => self.name.clone(),
+            false => substitute(&self.name, combination),
+        };
+        Self {
+            name,
+            command: substitute(&self.command, combination),
+            matrix: Some(
+                combination
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            ..self.clone()
+        }
+    }
+}
+
+/// One combination of `matrix` values: each key with the value it takes.
+pub type Combination = Vec<(String, String)>;
+
+/// Every combination of the `matrix` values, the last key changing fastest.
+///
+/// # Errors
+/// Returns an error if a key has no values, or there are more than [`MAX_MATRIX`] combinations.
+pub fn matrix_combinations(matrix: &BTreeMap<String, Vec<String>>) -> Result<Vec<Combination>> {
+    if let Some((key, _)) = matrix.iter().find(|(_, values)| values.is_empty()) {
+        return Err(AtomicError::Generic(format!(
+            "matrix key '{}' has no values",
+            key
+        )));
+    }
+    let total = matrix
+        .values()
+        .fold(1usize, |total, values| total.saturating_mul(values.len()));
+    if total > MAX_MATRIX {
+        return Err(AtomicError::Generic(format!(
+            "matrix has {} combinations, more than the limit of {}",
+            total, MAX_MATRIX
+        )));
+    }
+    let mut combinations = vec![Combination::new()];
+    for (key, values) in matrix {
+        combinations = combinations
+            .iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push((key.clone(), value.clone()));
+                    combination
+                })
+            })
+            .collect();
+    }
+    Ok(combinations)
+}
+
+/// `text` with every `{key}` of `combination` replaced by its value.
+fn substitute(text: &str, combination: &Combination) -> String {
+    combination
+        .iter()
+        .fold(text.to_string(), |text, (key, value)| {
+            text.replace(&format!("{{{}}}", key), value)
+        })
+}
+
+/// Resolves the command `name`, which sets `matrix`, into its steps for every combination, one
+/// combination after the other. A chain repeats all of its steps for each combination.
+///
+/// # Errors
+/// Returns an error if the matrix has no values for a key or too many combinations, or a step
+/// can't be resolved; see [`plan_chain`].
+pub fn plan_matrix(
+    config: &AtomicConfig,
+    name: &str,
+    spec: &CommandSpec,
+    matrix: &BTreeMap<String, Vec<String>>,
+    policy: &RetryPolicy,
+) -> Result<Vec<PlannedStep>> {
+    let combinations = matrix_combinations(matrix)?;
+    let steps = match spec.steps() {
+        Steps::Shell(command) => vec![PlannedStep {
+            desc: spec.desc().map(str::to_string),
+            artifacts: spec.artifacts().to_vec(),
+            ..shell_step(name, command, policy, false)
+        }],
+        Steps::Chain(chain) => plan_chain(config, chain, policy)?,
+    };
+    Ok(combinations
+        .iter()
+        .flat_map(|combination| steps.iter().map(|step| step.for_combination(combination)))
+        .collect())
+}
+
+/// Resolves every step of a chain, looking up steps that name another command in atomic.toml.
+///
+/// Steps that aren't named commands keep `policy`; named command tables bring their own. See
+/// [`ChainStep::resolve`] for which steps ignore their failure.
+///
+/// # Errors
+/// Returns an error if a step names a chain, or a command with invalid retry settings.
+pub fn plan_chain(
+    config: &AtomicConfig,
+    chain: &[ChainStep],
+    policy: &RetryPolicy,
+) -> Result<Vec<PlannedStep>> {
+    assert!(!chain.is_empty(), "Array of sub-values is empty");
+
+    chain
+        .iter()
+        .map(|chain_step| plan_step(config, chain_step, policy))
+        .collect()
+}
+
+/// Resolves one step of a chain; see [`plan_chain`].
+///
+/// # Errors
+/// Returns an error if the step names a chain, or a command with invalid retry settings.
+pub fn plan_step(
+    config: &AtomicConfig,
+    chain_step: &ChainStep,
+    policy: &RetryPolicy,
+) -> Result<PlannedStep> {
+    let (name, ignore_failure) = chain_step.resolve(config);
+    let step = |command: &str| shell_step(name, command, policy, ignore_failure);
+    let Some(spec) = config.command(name) else {
+        return Ok(step(name));
+    };
+    let Steps::Shell(command) = spec.steps() else {
+        return Err(AtomicError::NestedChain(name.to_string()));
+    };
+    let detail = spec.detail();
+    Ok(PlannedStep {
+        desc: spec.desc().map(str::to_string),
+        named: true,
+        policy: match detail {
+            Some(detail) => RetryPolicy::from_command(detail)?,
+            None => policy.clone(),
+        },
+        container: detail.and_then(|detail| detail.container.clone()),
+        only_on: spec
+            .os()
+            .filter(|_| !spec.runs_here())
+            .map(OsSpec::describe),
+        capture_output: spec.captures_output(),
+        cargo_json: spec.cargo_json(),
+        env_file: spec.env_file().map(str::to_string),
+        tmpdir: spec.tmpdir() != TmpDirMode::None,
+        artifacts: spec.artifacts().to_vec(),
+        ..step(command)
+    })
+}
+
+/// A step running the shell `command` as it is, under `name`.
+fn shell_step(
+    name: &str,
+    command: &str,
+    policy: &RetryPolicy,
+    ignore_failure: bool,
+) -> PlannedStep {
+    PlannedStep {
+        name: name.to_string(),
+        command: command.to_string(),
+        desc: None,
+        named: false,
+        policy: policy.clone(),
+        container: None,
+        ignore_failure,
+        only_on: None,
+        capture_output: false,
+        cargo_json: false,
+        env_file: None,
+        tmpdir: false,
+        matrix: None,
+        artifacts: Vec::new(),
+    }
+}
+
+/// Runs each step of a chain, printing its position in the chain first, until one fails.
+fn run_chain(
+    ctx: &RunContext,
+    chain: &[ChainStep],
+    policy: &RetryPolicy,
+) -> Result<Vec<StepOutcome>> {
+    run_planned(ctx, &plan_chain(&ctx.config, chain, policy)?, false)
+}
+
+/// Runs `steps` in order, printing each one's position first, and stops at the first failure
+/// that isn't ignored; the steps after it are reported as skipped. A failed `matrix` combination
+/// only stops the rest when `fail_fast` is set, and the failed ones are listed at the end.
+fn run_planned(
+    ctx: &RunContext,
+    steps: &[PlannedStep],
+    fail_fast: bool,
+) -> Result<Vec<StepOutcome>> {
+    let mut outcomes = Vec::new();
+    let mut failed_combinations = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        let label = step.label();
+        if let Some(platforms) = &step.only_on {
+            eprintln!(
+                "[{}/{}] {} skipped: it only runs on {}",
+                i + 1,
+                steps.len(),
+                label,
+                platforms
+            );
+            junit::record(
+                &label,
+                step.section(&ctx.config),
+                Duration::ZERO,
+                CaseResult::Skipped,
+            );
+            continue;
+        }
+        if step.named {
+            warn_shadowed(&ctx.config, &step.name);
+        }
+        // named steps get their own span, with the shell command nested under it
+        let _step = step.named.then(|| trace::span(&step.name));
+        let step_ctx = ctx.with_container(step.container.as_deref())?;
+        let step_ctx = step_ctx.with_capture(step.capture_output);
+        let step_ctx = step_ctx.with_cargo_json(step.cargo_json);
+        let step_ctx = step_ctx.with_env_file(step.env_file.as_deref())?;
+        eprintln!("{}", step.progress(i + 1, steps.len()));
+        events::emit(Event::StepStarted {
+            name: label.clone(),
+        });
+        let ticker = Ticker::start(ctx.interactivity);
+        let started = Instant::now();
+        let mut outcome = match ctx.tmpdir_per_step || step.tmpdir {
+            true => step_ctx.in_tmpdir(
+                &label,
+                |ctx| Ok(ctx.run_step(&step.command, &step.policy)),
+                |outcome| !outcome.success(),
+            )?,
+            false => step_ctx.run_step(&step.command, &step.policy),
+        };
+        let elapsed = started.elapsed();
+        drop(ticker);
+        outcome.name = label.clone();
+        artifacts::collect(&label, &step_ctx.project_root(), &step.artifacts);
+        events::emit(Event::step_finished(&label, outcome.code, elapsed));
+        if step.ignore_failure && !outcome.success() {
+            eprintln!("[{}/{}] {} failed (ignored)", i + 1, steps.len(), label);
+            outcome.ignored = true;
+        }
+        junit::record(
+            &label,
+            step.section(&ctx.config),
+            elapsed,
+            CaseResult::from_outcome(&outcome),
+        );
+        let failed = outcome.failed();
+        outcomes.push(outcome);
+        if failed && step.matrix.is_some() {
+            failed_combinations.push(label.clone());
+        }
+        let rest = &steps[i + 1..];
+        if failed && (fail_fast || step.matrix.is_none()) && !rest.is_empty() {
+            eprintln!(
+                "{}'{}' failed, not running the other {} step{}",
+                if fail_fast { "fail_fast: " } else { "" },
+                label,
+                rest.len(),
+                if rest.len() == 1 { "" } else { "s" }
+            );
+            for skipped in rest {
+                let section = skipped.section(&ctx.config);
+                junit::record(
+                    &skipped.label(),
+                    section,
+                    Duration::ZERO,
+                    CaseResult::Skipped,
+                );
+            }
+            break;
+        }
+    }
+    if !failed_combinations.is_empty() {
+        let total = steps.iter().filter(|step| step.matrix.is_some()).count();
+        eprintln!(
+            "matrix: {} of {} steps failed: {}",
+            failed_combinations.len(),
+            total,
+            failed_combinations.join(", ")
+        );
+    }
+    Ok(outcomes)
+}
+
+/// Runs `cmd`, re-running it on a non-zero exit until it succeeds or the retries run out.
+///
+/// The returned outcome carries the exit code of the last attempt, and `capture` its stdout.
+/// With `clean`, `env` is all the command gets; see [`spawn_command`].
+pub fn send_with_retries(
+    cmd: &str,
+    policy: &RetryPolicy,
+    env: &[(String, String)],
+    clean: bool,
+    cwd: Option<&Path>,
+    mut capture: Option<&mut Capture>,
+) -> StepOutcome {
+    retry_step(cmd, policy, || {
+        // only the last attempt's output is kept
+        capture.as_deref_mut().map(Capture::clear);
+        spawn_command(cmd, env, clean, cwd, capture.as_deref_mut())
+    })
+}
+
+/// Retry loop behind [`send_with_retries`], for any way of launching `cmd`.
+fn retry_step(
+    cmd: &str,
+    policy: &RetryPolicy,
+    mut spawn: impl FnMut() -> io::Result<i32>,
+) -> StepOutcome {
+    let _span = trace::span(cmd);
+    if policy.retries == 0 {
+        return StepOutcome {
+            name: cmd.to_string(),
+            code: report_exit(cmd, spawn()),
+            attempts: 1,
+            ignored: false,
+            output: None,
+        };
+    }
+
+    let total = policy.retries + 1;
+    let mut attempts = 1;
+    loop {
+        let code = match spawn() {
+            Ok(code) => code,
+            Err(err) => {
+                eprintln!("Failed to execute command: {}\nError: {}", cmd, err);
+                -1
+            }
+        };
+
+        if code == 0 {
+            return StepOutcome {
+                name: cmd.to_string(),
+                code,
+                attempts,
+                ignored: false,
+                output: None,
+            };
+        }
+        if attempts == total {
+            eprintln!(
+                "Command failed with status code: {} after {} attempts",
+                code, attempts
+            );
+            return StepOutcome {
+                name: cmd.to_string(),
+                code,
+                attempts,
+                ignored: false,
+                output: None,
+            };
+        }
+
+        attempts += 1;
+        thread::sleep(policy.delay);
+        println!("attempt {}/{}: {}", attempts, total, cmd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::init_repo;
+
+    fn config(s: &str) -> AtomicConfig {
+        AtomicConfig::from_value(toml::from_str(s).unwrap()).unwrap()
+    }
+
+    fn spec(s: &str) -> CommandSpec {
+        toml::from_str(s).unwrap()
+    }
+
+    /// The trailers an auto-commit of `cmd` ends with, after running `steps`.
+    fn trailers(cmd: &str, atomic: &Path, steps: &str) -> String {
+        format!(
+            "Atomic-Version: {}\nAtomic-Command: {}\nAtomic-Config-Hash: {}\nAtomic-Steps: {}\n",
+            env!("CARGO_PKG_VERSION"),
+            cmd,
+            config_hash(atomic).unwrap(),
+            steps
+        )
+    }
+
+    #[test]
+    fn test_steps_trailer() {
+        let outcome = |name: &str, code| StepOutcome {
+            name: name.to_string(),
+            code,
+            attempts: 1,
+            ignored: false,
+            output: None,
+        };
+        assert_eq!(
+            steps_trailer(&[outcome("fmt", 0), outcome("test [os=linux, flag=foo]", 101)]),
+            "fmt=0, test [os=linux; flag=foo]=101"
+        );
+        assert_eq!(steps_trailer(&[]), "");
+    }
+
+    #[test]
+    fn test_auto_commit_message() {
+        let fmt = spec("command = \"cargo fmt\"\ncommit_message = \" style: auto-format \"\n");
+        assert_eq!(auto_commit_message("fmt", &fmt), "style: auto-format");
+        assert_eq!(fmt.commit_branch(), None);
+        let blank = spec("command = \"cargo fmt\"\ncommit_message = \"\"\n");
+        assert_eq!(auto_commit_message("fmt", &blank), "atomic: fmt");
+        assert_eq!(
+            auto_commit_message("fmt", &CommandSpec::Plain(Steps::Shell("cargo fmt".into()))),
+            "atomic: fmt"
+        );
+    }
+
+    #[test]
+    fn test_retry_policy_from_command() {
+        let policy = |s: &str| {
+            toml::from_str::<DetailedCommand>(&format!("command = \"cargo fetch\"\n{}", s))
+                .map_err(|err| AtomicError::Generic(err.to_string()))
+                .and_then(|detail| RetryPolicy::from_command(&detail))
+        };
+        assert_eq!(policy(""), Ok(RetryPolicy::default()));
+        assert_eq!(
+            policy("retries = 3\nretry_delay = \"500ms\""),
+            Ok(RetryPolicy {
+                retries: 3,
+                delay: Duration::from_millis(500)
+            })
+        );
+        assert!(policy("retries = 11").is_err());
+        assert!(policy("retries = -1").is_err());
+        assert!(policy("retries = \"3\"").is_err());
+        assert!(policy("retry_delay = \"soon\"").is_err());
+    }
+
+    #[cfg(unix)]
+    fn flaky_command(dir: &Path, succeed_on: u32) -> String {
+        let counter = dir.join("count");
+        format!(
+            "n=$(cat '{0}' 2>/dev/null || echo 0); n=$((n+1)); echo $n > '{0}'; [ $n -ge {1} ]",
+            counter.display(),
+            succeed_on
+        )
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_with_retries_recovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = RetryPolicy {
+            retries: 3,
+            delay: Duration::ZERO,
+        };
+
+        let command = flaky_command(dir.path(), 3);
+        assert_eq!(
+            send_with_retries(&command, &policy, &[], false, None, None),
+            StepOutcome {
+                name: command.clone(),
+                code: 0,
+                attempts: 3,
+                ignored: false,
+                output: None,
+            }
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_with_retries_gives_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = RetryPolicy {
+            retries: 1,
+            delay: Duration::from_millis(10),
+        };
+
+        let outcome = send_with_retries(
+            &flaky_command(dir.path(), 5),
+            &policy,
+            &[],
+            false,
+            None,
+            None,
+        );
+        assert!(!outcome.success());
+        assert_eq!(outcome.code, 1);
+        assert_eq!(outcome.attempts, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_atomic_env_reaches_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        repo.set_head("refs/heads/feature-144-dark-mode").unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(&atomic, "").unwrap();
+
+        let env = atomic_env(&atomic, Some("ci"), Nesting::default(), true);
+        let out = dir.path().join("env.txt");
+        let cmd = format!(
+            "echo \"$ATOMIC_CONFIG_PATH|$ATOMIC_PROJECT_ROOT|$ATOMIC_BRANCH|$ATOMIC_ISSUE|$ATOMIC_PROFILE|$ATOMIC_DEPTH\" > '{}'",
+            out.display()
+        );
+        assert!(
+            send_with_retries(&cmd, &RetryPolicy::default(), &env, false, None, None).success()
+        );
+
+        let root = fs::canonicalize(dir.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(out).unwrap().trim(),
+            format!(
+                "{}|{}|feature-144-dark-mode|144|ci|1",
+                root.join("atomic.toml").display(),
+                root.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_env_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(dir.path().join(".env"), "TOKEN=from-env\nREGION=eu\n").unwrap();
+        fs::write(dir.path().join(".env.local"), "TOKEN=\"local secret\"\n").unwrap();
+        fs::write(
+            dir.path().join(".env.test"),
+            "REGION=test\nATOMIC_DEPTH=7\n",
+        )
+        .unwrap();
+        fs::write(
+            &atomic,
+            r#"
+            [atomic]
+            git = false
+
+            [custom]
+            show = "echo \"$TOKEN|$REGION|$ATOMIC_DEPTH\" > out.txt"
+            both = ["show", "echo \"$TOKEN|$REGION\" >> out.txt"]
+
+            [custom.test]
+            command = "echo \"$TOKEN|$REGION|$ATOMIC_DEPTH\" > out.txt"
+            env_file = ".env.test"
+            "#,
+        )
+        .unwrap();
+        let options = RunOptions::default();
+        let out = || fs::read_to_string(dir.path().join("out.txt")).unwrap();
+
+        assert_eq!(run_command("show", &atomic, &options), Ok(0));
+        assert_eq!(out(), "local secret|eu|1\n");
+        assert_eq!(run_command("test", &atomic, &options), Ok(0));
+        assert_eq!(out(), "local secret|test|1\n");
+
+        // listed files must exist, and only they are read
+        let text = fs::read_to_string(&atomic).unwrap();
+        let listed = text.replace("git = false", "git = false\nenv_files = [\".env\"]");
+        fs::write(&atomic, &listed).unwrap();
+        assert_eq!(run_command("both", &atomic, &options), Ok(0));
+        assert_eq!(out(), "from-env|eu|1\nfrom-env|eu\n");
+        fs::write(&atomic, listed.replace("\".env\"]", "\".env.missing\"]")).unwrap();
+        assert!(run_command("show", &atomic, &options).is_err());
+    }
+
+    #[test]
+    fn test_tmpdirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let record = r#"[ -d \"$ATOMIC_TMPDIR\" ] && echo \"$ATOMIC_TMPDIR\" >> dirs.txt"#;
+        fs::write(
+            &atomic,
+            format!(
+                r#"
+                [atomic]
+                git = false
+
+                [custom]
+                plain = "echo \"[$ATOMIC_TMPDIR]\" >> dirs.txt"
+                mixed = ["plain", "own"]
+
+                [custom.own]
+                command = "{0}"
+                tmpdir = true
+
+                [custom.chain]
+                command = ["{0}", "{0}"]
+                tmpdir = true
+
+                [custom.shared]
+                command = ["{0}", "{0}", "exit 3"]
+                tmpdir = "shared"
+                "#,
+                record
+            ),
+        )
+        .unwrap();
+        let options = RunOptions::default();
+        let dirs = || -> Vec<String> {
+            let path = dir.path().join("dirs.txt");
+            let text = fs::read_to_string(&path).unwrap_or_default();
+            let _ = fs::remove_file(&path);
+            text.lines().map(str::to_string).collect()
+        };
+        let gone = |dirs: &[String]| dirs.iter().all(|dir| !Path::new(dir).exists());
+
+        assert_eq!(run_command("plain", &atomic, &options), Ok(0));
+        assert_eq!(dirs(), ["[]"]);
+        assert_eq!(run_command("mixed", &atomic, &options), Ok(0));
+        let mixed = dirs();
+        assert_eq!(mixed[0], "[]");
+        assert!(mixed[1].contains(".atomic/tmp/own-"), "{:?}", mixed);
+        assert!(gone(&mixed[1..]));
+
+        assert_eq!(run_command("chain", &atomic, &options), Ok(0));
+        let chain = dirs();
+        assert_eq!(chain.len(), 2);
+        assert_ne!(chain[0], chain[1]);
+        assert!(gone(&chain));
+
+        assert_eq!(run_command("shared", &atomic, &options), Ok(3));
+        let shared = dirs();
+        assert_eq!(shared[0], shared[1]);
+        assert!(gone(&shared));
+
+        // kept when asked, or on failure with keep_tmp_on_failure
+        let keep = RunOptions {
+            keep_tmp: true,
+            ..Default::default()
+        };
+        assert_eq!(run_command("own", &atomic, &keep), Ok(0));
+        assert!(Path::new(&dirs()[0]).is_dir());
+        let text = fs::read_to_string(&atomic).unwrap();
+        fs::write(
+            &atomic,
+            text.replace("git = false", "git = false\nkeep_tmp_on_failure = true"),
+        )
+        .unwrap();
+        assert_eq!(run_command("own", &atomic, &options), Ok(0));
+        assert!(gone(&dirs()));
+        assert_eq!(run_command("shared", &atomic, &options), Ok(3));
+        assert!(Path::new(&dirs()[0]).is_dir());
+    }
+
+    #[test]
+    fn test_validate_custom_entry() {
+        let entry = |s: &str| -> Value { toml::from_str(s).unwrap() };
+
+        assert_eq!(
+            validate_custom_entry(&entry(r#"command = "cargo fmt""#)),
+            Ok(())
+        );
+        assert_eq!(
+            validate_custom_entry(&entry(r#"command = ["cargo fmt", "cargo clippy"]"#)),
+            Ok(())
+        );
+        assert_eq!(
+            validate_custom_entry(&entry("retries = 2")),
+            Err(AtomicError::InvalidStdinCommand(
+                "Missing required key `command`".to_string()
+            ))
+        );
+        assert_eq!(
+            validate_custom_entry(&entry("command = []")),
+            Err(AtomicError::InvalidStdinCommand(
+                "`command` chain has no steps".to_string()
+            ))
+        );
+        assert_eq!(
+            validate_custom_entry(&entry(
+                r#"command = ["echo a", { run = "false", ignore_failure = true }]"#
+            )),
+            Ok(())
+        );
+        assert!(validate_custom_entry(&entry("command = 5")).is_err());
+        assert!(validate_custom_entry(&entry("command = [\"echo a\", 5]")).is_err());
+        assert!(validate_custom_entry(&entry("command = [{ ignore_failure = true }]")).is_err());
+        assert!(validate_custom_entry(&entry("command = \"x\"\nretries = 99")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_adhoc() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let out = dir.path().join("out.txt");
+        fs::write(
+            &atomic,
+            format!("[custom]\nhello = \"echo hello >> '{}'\"\n", out.display()),
+        )
+        .unwrap();
+
+        // named steps resolve against the real config, raw strings run as-is
+        let fragment = format!(
+            "command = [\"hello\", \"echo world >> '{}'\"]",
+            out.display()
+        );
+        assert_eq!(run_adhoc(&fragment, &atomic, &RunOptions::default()), Ok(0));
+        assert_eq!(fs::read_to_string(&out).unwrap(), "hello\nworld\n");
+
+        // atomic.toml is optional
+        fs::remove_file(&atomic).unwrap();
+        let fragment = format!("command = \"echo again >> '{}'\"", out.display());
+        assert_eq!(run_adhoc(&fragment, &atomic, &RunOptions::default()), Ok(0));
+        assert_eq!(fs::read_to_string(&out).unwrap(), "hello\nworld\nagain\n");
+
+        assert_eq!(
+            run_adhoc("retries = 1", &atomic, &RunOptions::default()),
+            Err(AtomicError::InvalidStdinCommand(
+                "Missing required key `command`".to_string()
+            ))
+        );
+        assert!(matches!(
+            run_adhoc("command = = 1", &atomic, &RunOptions::default()),
+            Err(AtomicError::TomlParse(..))
+        ));
+    }
+
+    #[test]
+    fn test_trace_spans_follow_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            "[default]\ngreet = \"echo hi\"\n\n[custom]\nall = [\"greet\", \"echo bye\"]\n",
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_commit: true,
+            ..Default::default()
+        };
+
+        trace::enable();
+        run_command("all", &atomic, &options).unwrap();
+        let spans: Vec<_> = trace::finish()
+            .into_iter()
+            .map(|span| (span.depth, span.name))
+            .collect();
+
+        let expected = [
+            (0, "atomic all"),
+            (1, "load atomic.toml"),
+            (1, "resolve environment"),
+            (1, "greet"),
+            (2, "echo hi"),
+            (1, "echo bye"),
+        ];
+        assert_eq!(
+            spans,
+            expected.map(|(depth, name)| (depth, name.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_plan_chain() {
+        let config = config(
+            r#"
+            [default]
+            fmt = "cargo fmt"
+            [custom.clippy]
+            command = "cargo clippy -- -D warnings"
+            desc = "lint with warnings denied"
+            retries = 2
+            container = "rust:1.78"
+            [custom.ci]
+            command = ["fmt", "clippy"]
+            "#,
+        );
+        let chain = ["fmt", "clippy", "cargo test"].map(ChainStep::from);
+
+        let steps = plan_chain(&config, &chain, &RetryPolicy::default()).unwrap();
+        let commands: Vec<_> = steps.iter().map(|step| step.command.as_str()).collect();
+        assert_eq!(
+            commands,
+            ["cargo fmt", "cargo clippy -- -D warnings", "cargo test"]
+        );
+        assert_eq!(steps[1].policy.retries, 2);
+        assert_eq!(steps[1].container.as_deref(), Some("rust:1.78"));
+        assert!(!steps[2].named);
+
+        let lines: Vec<_> = steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| step.progress(i + 1, steps.len()))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "[1/3] fmt — cargo fmt",
+                "[2/3] clippy — lint with warnings denied",
+                "[3/3] cargo test",
+            ]
+        );
+
+        // nothing runs when a step can't be resolved
+        assert_eq!(
+            plan_chain(&config, &["ci".into()], &RetryPolicy::default()),
+            Err(AtomicError::NestedChain("ci".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_plan_matrix() {
+        let config = config(
+            r#"
+            [custom]
+            fmt = "cargo fmt"
+            [custom.features]
+            command = "cargo test --features {flag}"
+            desc = "test a feature set"
+            matrix = { flag = ["foo", "bar", "foo,bar"] }
+            [custom.cross]
+            command = ["fmt", "cargo build --target {target} --{profile}"]
+            matrix = { target = ["x86_64", "aarch64"], profile = ["dev", "release"] }
+            [custom.huge]
+            command = "true"
+            matrix = { a = ["1", "2", "3", "4"], b = ["1", "2", "3"], c = ["1", "2", "3"] }
+            "#,
+        );
+        let plan = |name: &str| {
+            let spec = config.command(name).unwrap();
+            let matrix = spec.matrix().unwrap();
+            plan_matrix(&config, name, spec, matrix, &RetryPolicy::default())
+        };
+
+        let steps = plan("features").unwrap();
+        let lines: Vec<_> = steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| step.progress(i + 1, steps.len()))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "[1/3] features [flag=foo] — test a feature set",
+                "[2/3] features [flag=bar] — test a feature set",
+                "[3/3] features [flag=foo,bar] — test a feature set",
+            ]
+        );
+        assert_eq!(steps[2].command, "cargo test --features foo,bar");
+
+        // keys are taken in order, so `profile` comes before `target`, and the last changes fastest
+        let steps = plan("cross").unwrap();
+        let labels: Vec<_> = steps.iter().map(PlannedStep::label).collect();
+        assert_eq!(
+            labels,
+            [
+                "fmt [profile=dev, target=x86_64]",
+                "cargo build --target x86_64 --dev [profile=dev, target=x86_64]",
+                "fmt [profile=dev, target=aarch64]",
+                "cargo build --target aarch64 --dev [profile=dev, target=aarch64]",
+                "fmt [profile=release, target=x86_64]",
+                "cargo build --target x86_64 --release [profile=release, target=x86_64]",
+                "fmt [profile=release, target=aarch64]",
+                "cargo build --target aarch64 --release [profile=release, target=aarch64]",
+            ]
+        );
+        assert!(steps[0].named);
+        assert_eq!(steps[0].command, "cargo fmt");
+
+        assert_eq!(
+            plan("huge"),
+            Err(AtomicError::Generic(
+                "matrix has 36 combinations, more than the limit of 32".to_string()
+            ))
+        );
+        let empty = BTreeMap::from([("flag".to_string(), Vec::new())]);
+        assert!(matrix_combinations(&empty).is_err());
+    }
+
+    #[test]
+    fn test_matrix_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            r#"
+            [atomic]
+            git = false
+
+            [custom.all]
+            command = "echo {n} >> ran.txt && [ {n} != 2 ]"
+            matrix = { n = ["1", "2", "3"] }
+
+            [custom.fast]
+            command = "echo {n} >> ran.txt && [ {n} != 2 ]"
+            matrix = { n = ["1", "2", "3"] }
+            fail_fast = true
+
+            [custom.chain]
+            command = ["echo a >> ran.txt && false", "echo b >> ran.txt"]
+            fail_fast = true
+            "#,
+        )
+        .unwrap();
+        let ran = || {
+            let path = dir.path().join("ran.txt");
+            let text = fs::read_to_string(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            text
+        };
+        let options = RunOptions::default();
+
+        // every combination runs, and the run fails when one of them did
+        assert_eq!(run_command("all", &atomic, &options), Ok(1));
+        assert_eq!(ran(), "1\n2\n3\n");
+
+        assert_eq!(run_command("fast", &atomic, &options), Ok(1));
+        assert_eq!(ran(), "1\n2\n");
+        assert_eq!(run_command("chain", &atomic, &options), Ok(1));
+        assert_eq!(ran(), "a\n");
+    }
+
+    #[test]
+    fn test_ignored_chain_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let log = dir.path().join("log.txt");
+        let log = log.display();
+        fs::write(
+            &atomic,
+            format!(
+                r#"
+                [custom]
+                flaky = "exit 3"
+                docs = "echo docs >> '{log}'"
+                best-effort = ["-flaky", {{ run = "exit 4", ignore_failure = true }}, "docs"]
+                strict = ["flaky", "docs"]
+                "#
+            ),
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_commit: true,
+            interactivity: Interactivity::Ci {
+                github_actions: false,
+            },
+            ..Default::default()
+        };
+        let read_log = || fs::read_to_string(dir.path().join("log.txt")).unwrap_or_default();
+
+        let config = config(&fs::read_to_string(&atomic).unwrap());
+        let Some(Steps::Chain(chain)) = config.command("best-effort").map(CommandSpec::steps)
+        else {
+            panic!("best-effort should be a chain");
+        };
+        let steps = plan_chain(&config, chain, &RetryPolicy::default()).unwrap();
+        let planned: Vec<_> = steps
+            .iter()
+            .map(|step| (step.name.as_str(), step.ignore_failure))
+            .collect();
+        assert_eq!(
+            planned,
+            [("flaky", true), ("exit 4", true), ("docs", false)]
+        );
+
+        assert_eq!(run_command("best-effort", &atomic, &options), Ok(0));
+        assert_eq!(read_log(), "docs\n");
+        // a failure that isn't ignored stops the chain
+        assert_eq!(run_command("strict", &atomic, &options), Ok(3));
+        assert_eq!(read_log(), "docs\n");
+
+        assert_eq!(
+            failure_summary("ci", 5, 0, 2),
+            "2 of 5 steps in 'ci' failed (ignored)"
+        );
+        assert_eq!(
+            failure_summary("ci", 5, 2, 1),
+            "2 of 5 steps in 'ci' failed, 1 failed (ignored)"
+        );
+    }
+
+    #[test]
+    fn test_os_gated_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let log = dir.path().join("log.txt");
+        let elsewhere = if cfg!(windows) { "unix" } else { "windows" };
+        let here = if cfg!(windows) { "windows" } else { "unix" };
+        fs::write(
+            &atomic,
+            format!(
+                r#"
+                [custom]
+                docs = "echo docs >> '{log}'"
+                ci = ["elsewhere", "local", "docs"]
+
+                [custom.elsewhere]
+                command = "exit 7"
+                os = "{elsewhere}"
+
+                [custom.local]
+                command = "echo local >> '{log}'"
+                os = ["{here}", "{elsewhere}"]
+                "#,
+                log = log.display(),
+            ),
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_commit: true,
+            ..Default::default()
+        };
+        let read_log = || fs::read_to_string(&log).unwrap_or_default();
+
+        let config = config(&fs::read_to_string(&atomic).unwrap());
+        assert!(!config.command("elsewhere").unwrap().runs_here());
+        assert!(config.command("local").unwrap().runs_here());
+
+        assert_eq!(run_command("elsewhere", &atomic, &options), Ok(0));
+        assert_eq!(read_log(), "");
+        assert_eq!(run_command("ci", &atomic, &options), Ok(0));
+        assert_eq!(read_log(), "local\ndocs\n");
+    }
+
+    #[test]
+    fn test_junit_cases() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            r#"
+            [default]
+            build = "echo build"
+            [custom]
+            flaky = "exit 3"
+            ci = ["build", "-flaky", "echo done"]
+            [custom.broken]
+            command = "echo 'first line' && echo 'broke: 1 < 2' && exit 2"
+            [custom.release]
+            command = "echo release"
+            needs = ["ci", "broken", "build"]
+            "#,
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_commit: true,
+            ..Default::default()
+        };
+
+        junit::enable();
+        assert_eq!(run_command("release", &atomic, &options), Ok(2));
+        let cases: Vec<_> = junit::finish()
+            .into_iter()
+            .map(|case| (case.name, case.classname, case.result))
+            .collect();
+        let case =
+            |name: &str, classname: &str, result| (name.to_string(), classname.to_string(), result);
+        let failed = |code, output: &str| CaseResult::Failed {
+            code,
+            attempts: 1,
+            ignored: false,
+            output: Some(output.to_string()),
+        };
+        assert_eq!(
+            cases,
+            [
+                case("build", "default", CaseResult::Passed),
+                case(
+                    "flaky",
+                    "custom",
+                    CaseResult::Failed {
+                        code: 3,
+                        attempts: 1,
+                        ignored: true,
+                        output: None,
+                    }
+                ),
+                case("echo done", "shell", CaseResult::Passed),
+                case("broken", "custom", failed(2, "first line\nbroke: 1 < 2")),
+                case("build", "default", CaseResult::Skipped),
+                case("release", "custom", CaseResult::Skipped),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_run_skips_commit_and_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            r#"
+            gen = "echo $ATOMIC_DEPTH > depth.txt"
+            [atomic]
+            pre_run = "echo pre > hook.txt"
+            "#,
+        )
+        .unwrap();
+        let repo = init_repo(dir.path(), &[]);
+        let initial = repo.head().unwrap().target().unwrap();
+        let head = || repo.head().unwrap().target().unwrap();
+
+        let nested = RunOptions {
+            repo: Some(dir.path().to_path_buf()),
+            nesting: Nesting { depth: 1 },
+            ..Default::default()
+        };
+
+        assert_eq!(run_command("gen", &atomic, &nested), Ok(0));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("depth.txt")).unwrap(),
+            "2\n"
+        );
+        assert!(!dir.path().join("hook.txt").exists());
+        assert_eq!(head(), initial, "a nested run must not commit");
+
+        // the first run's files are already there, so have them committed too
+        let allowed = RunOptions {
+            allow_nested_commit: true,
+            include_preexisting: true,
+            ..nested
+        };
+        assert_eq!(run_command("gen", &atomic, &allowed), Ok(0));
+        assert!(dir.path().join("hook.txt").exists());
+        assert_ne!(head(), initial);
+    }
+
+    #[test]
+    fn test_runs_are_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            "[atomic]\ngit = false\n[default]\nbuild = \"echo build\"\n",
+        )
+        .unwrap();
+        let options = RunOptions::default();
+        let runs = |name: &str| stats::load(&atomic).commands.get(name).map(|s| s.runs);
+
+        assert_eq!(run_command("build", &atomic, &options), Ok(0));
+        assert_eq!(run_command("default:build", &atomic, &options), Ok(0));
+        assert!(matches!(
+            run_command("nope", &atomic, &options),
+            Err(AtomicError::CommandNotFound(..))
+        ));
+        assert_eq!(runs("build"), Some(2));
+        assert_eq!(runs("nope"), None);
+
+        fs::write(
+            &atomic,
+            "[atomic]\ngit = false\nstats = false\n[default]\nbuild = \"echo build\"\n",
+        )
+        .unwrap();
+        assert_eq!(run_command("build", &atomic, &options), Ok(0));
+        assert_eq!(runs("build"), Some(2));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_events_for_a_chain() {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::net::UnixListener;
+
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            "[default]\ngen = \"echo hi > out.txt\"\n\n[custom]\nall = [\"gen\", \"exit 0\"]\n",
+        )
+        .unwrap();
+        let repo = init_repo(dir.path(), &[]);
+        // outside the repository, so the socket isn't part of the commit
+        let sockets = tempfile::tempdir().unwrap();
+        let socket = sockets.path().join("events.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let reader = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            BufReader::new(stream)
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(&line.unwrap()).unwrap())
+                .collect::<Vec<_>>()
+        });
+        let options = RunOptions {
+            repo: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        events::listen_at(&socket);
+        assert_eq!(run_command("all", &atomic, &options), Ok(0));
+        events::finish();
+        let received = reader.join().unwrap();
+
+        let kinds: Vec<&str> = received
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "run_started",
+                "step_started",
+                "step_finished",
+                "step_started",
+                "step_finished",
+                "commit_created",
+                "run_finished"
+            ]
+        );
+        assert_eq!(received[0]["command"], "all");
+        assert_eq!(received[0]["pid"], std::process::id());
+        assert_eq!(received[1]["name"], "gen");
+        assert_eq!(received[3]["name"], "exit 0");
+        assert_eq!(received[4]["code"], 0);
+        let head = repo.head().unwrap().target().unwrap();
+        assert_eq!(received[5]["hash"], head.to_string());
+        assert_eq!(received[5]["files"], 1);
+        assert_eq!(
+            received[5]["origins"],
+            serde_json::json!({ "out.txt": "command" })
+        );
+        assert_eq!(received[6]["status"], "passed");
+    }
+
+    #[test]
+    fn test_auto_commit_records_who_changed_what() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            "gen = \"echo generated > gen.txt && echo formatted >> notes.md\"\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("notes.md"), "notes\n").unwrap();
+        fs::write(dir.path().join("todo.txt"), "todo\n").unwrap();
+        let repo = init_repo(dir.path(), &[]);
+
+        // edited by hand before the run
+        fs::write(dir.path().join("notes.md"), "notes\nmine\n").unwrap();
+        fs::write(dir.path().join("todo.txt"), "todo\nmine\n").unwrap();
+
+        let options = RunOptions {
+            repo: Some(dir.path().to_path_buf()),
+            include_preexisting: true,
+            ..Default::default()
+        };
+        assert_eq!(run_command("gen", &atomic, &options), Ok(0));
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(
+            head.message().unwrap(),
+            format!(
+                "atomic: gen\n\n--- changed before the run ---\ntodo.txt (pre-existing change)\n\
+                 notes.md (both)\n\n{}",
+                trailers("gen", &atomic, "gen=0")
+            )
+        );
+
+        // made by the command alone, so not listed
+        assert!(head.tree().unwrap().get_name("gen.txt").is_some());
+    }
+
+    #[test]
+    fn test_no_git_runs_without_committing() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            "gen = \"echo \\\"[$ATOMIC_BRANCH]\\\" > branch.txt\"\n",
+        )
+        .unwrap();
+        let repo = init_repo(dir.path(), &[]);
+        let initial = repo.head().unwrap().target().unwrap();
+        let head = || repo.head().unwrap().target().unwrap();
+        let branch = || fs::read_to_string(dir.path().join("branch.txt")).unwrap();
+
+        let options = RunOptions {
+            repo: Some(dir.path().to_path_buf()),
+            include_preexisting: true,
+            ..Default::default()
+        };
+        let no_git = RunOptions {
+            no_git: true,
+            ..options.clone()
+        };
+        assert_eq!(run_command("gen", &atomic, &no_git), Ok(0));
+        assert_eq!(branch(), "[]\n");
+        assert_eq!(head(), initial, "--no-git must not commit");
+
+        let text = fs::read_to_string(&atomic).unwrap();
+        fs::write(&atomic, format!("{}[atomic]\ngit = false\n", text)).unwrap();
+        assert_eq!(run_command("gen", &atomic, &options), Ok(0));
+        assert_eq!(head(), initial, "git = false must not commit");
+
+        fs::write(&atomic, text).unwrap();
+        assert_eq!(run_command("gen", &atomic, &options), Ok(0));
+        assert_ne!(branch(), "[]\n");
+        assert_ne!(head(), initial);
+    }
+
+    #[test]
+    fn test_qualified_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            r#"
+            build = "echo root >> ran.txt"
+            all = ["build", "default:build", "root:build"]
+
+            [atomic]
+            pre_run = ["default:build"]
+
+            [default]
+            build = "echo default >> ran.txt"
+
+            [custom]
+            build = "echo custom >> ran.txt"
+            "#,
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_git: true,
+            ..Default::default()
+        };
+        let ran = || {
+            let path = dir.path().join("ran.txt");
+            let ran = fs::read_to_string(&path).unwrap_or_default();
+            let _ = fs::remove_file(&path);
+            ran
+        };
+
+        assert_eq!(run_command("build", &atomic, &options), Ok(0));
+        assert_eq!(ran(), "default\ncustom\n");
+        assert_eq!(run_command("root:build", &atomic, &options), Ok(0));
+        assert_eq!(ran(), "default\nroot\n");
+        assert_eq!(run_command("all", &atomic, &options), Ok(0));
+        assert_eq!(ran(), "default\ncustom\ndefault\nroot\n");
+        assert_eq!(run_command("root:all", &atomic, &options), Ok(0));
+        ran();
+        assert!(matches!(
+            run_command("custom:all", &atomic, &options),
+            Err(AtomicError::CommandNotFound(..))
+        ));
+        assert_eq!(ran(), "");
+    }
+
+    #[test]
+    fn test_locked_command_waits_or_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            r#"
+            [custom.migrate]
+            command = "echo ran >> ran.txt"
+            lock = true
+            lock_wait = "50ms"
+            "#,
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_git: true,
+            ..Default::default()
+        };
+        let no_wait = RunOptions {
+            no_wait: true,
+            ..options.clone()
+        };
+        let ran = || fs::read_to_string(dir.path().join("ran.txt")).unwrap_or_default();
+
+        let held = lock::acquire(&atomic, "migrate", None).unwrap();
+        assert!(run_command("migrate", &atomic, &no_wait).is_err());
+        assert!(run_command("migrate", &atomic, &options).is_err());
+        // qualified, it's still the same command and the same lock
+        assert!(run_command("custom:migrate", &atomic, &no_wait).is_err());
+        assert_eq!(ran(), "", "a locked command must not run");
+
+        drop(held);
+        assert_eq!(run_command("migrate", &atomic, &no_wait), Ok(0));
+        assert_eq!(ran(), "ran\n");
+    }
+
+    #[test]
+    fn test_captured_output_in_commit_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            r#"
+            [custom]
+            release = ["notes", "echo not kept", "version"]
+
+            [custom.notes]
+            command = "echo '- fixed the widget' > notes.md && cat notes.md"
+            capture_output = "commit_body"
+
+            [custom.version]
+            command = "echo v1.2.0 | tee VERSION"
+            capture_output = "commit_body"
+
+            [custom.quiet]
+            command = "echo hidden > quiet.md"
+            capture_output = "none"
+
+            [custom.latin1]
+            command = "printf 'caf\\351\\n' | tee latin1.txt"
+            capture_output = "commit_body"
+            "#,
+        )
+        .unwrap();
+        let repo = init_repo(dir.path(), &[]);
+        let options = RunOptions {
+            repo: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let message = || {
+            let head = repo.head().unwrap().peel_to_commit().unwrap();
+            head.message().unwrap().to_string()
+        };
+
+        assert_eq!(run_command("notes", &atomic, &options), Ok(0));
+        assert_eq!(
+            message(),
+            format!(
+                "atomic: notes\n\n--- output ---\n- fixed the widget\n\n{}",
+                trailers("notes", &atomic, "notes=0")
+            )
+        );
+
+        assert_eq!(run_command("release", &atomic, &options), Ok(0));
+        let release = message();
+        assert!(
+            release.contains(
+                "--- output ---\n[echo '- fixed the widget' > notes.md && cat notes.md]\n\
+                 - fixed the widget\n[echo v1.2.0 | tee VERSION]\nv1.2.0\n"
+            ),
+            "{}",
+            release
+        );
+        // its step is still listed in the trailers
+        assert!(!release.contains("\nnot kept"));
+        assert!(release.ends_with(&trailers(
+            "release",
+            &atomic,
+            "notes=0, echo not kept=0, version=0"
+        )));
+
+        assert_eq!(run_command("quiet", &atomic, &options), Ok(0));
+        assert_eq!(
+            message(),
+            format!("atomic: quiet\n\n{}", trailers("quiet", &atomic, "quiet=0"))
+        );
+
+        // output that isn't UTF-8 is kept lossily, while files get the bytes as written
+        if cfg!(unix) {
+            assert_eq!(run_command("latin1", &atomic, &options), Ok(0));
+            assert!(
+                message().contains("--- output ---\ncaf\u{fffd}\n"),
+                "{}",
+                message()
+            );
+            assert_eq!(
+                fs::read(dir.path().join("latin1.txt")).unwrap(),
+                b"caf\xe9\n"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cargo_json() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        // a stand-in for cargo that replays real `cargo test --message-format=json` output
+        let cargo = dir.path().join("cargo");
+        fs::write(
+            &cargo,
+            format!(
+                "#!/bin/sh\necho \"$@\" > args.txt\ncat '{}/fixtures/cargo_test.jsonl'\n",
+                env!("CARGO_MANIFEST_DIR")
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&cargo, fs::Permissions::from_mode(0o755)).unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            format!(
+                r#"
+                [custom.test]
+                command = "{0} test -- --nocapture"
+                cargo_json = true
+                capture_output = "commit_body"
+
+                [custom.fmt]
+                command = "{0} fmt"
+                cargo_json = true
+
+                [custom.both]
+                command = ["fmt", "test"]
+                cargo_json = true
+                "#,
+                cargo.display()
+            ),
+        )
+        .unwrap();
+        let repo = init_repo(dir.path(), &[]);
+        let options = RunOptions {
+            repo: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        assert_eq!(run_command("test", &atomic, &options), Ok(0));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("args.txt")).unwrap(),
+            "test --message-format=json -- --nocapture\n"
+        );
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let message = head.message().unwrap();
+        assert!(
+            message.contains("warning: unused variable: `unused`"),
+            "{}",
+            message
+        );
+        assert!(message.contains("running 2 tests"), "{}", message);
+        assert!(!message.contains("\"reason\""), "{}", message);
+
+        let config =
+            AtomicConfig::from_value(load_and_validate_toml(&atomic, None).unwrap()).unwrap();
+        let warning = |name: &str| cargo_json_warning(&config, name, config.command(name).unwrap());
+        assert_eq!(warning("test"), None);
+        assert_eq!(
+            warning("fmt").as_deref(),
+            Some("'fmt' sets cargo_json, but doesn't run a cargo command that can report JSON; it runs as written")
+        );
+        // one cargo step in a chain is enough
+        assert_eq!(warning("both"), None);
+    }
+
+    #[test]
+    fn test_global_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let out = dir.path().join("out.txt");
+        let options = RunOptions {
+            no_commit: true,
+            ..Default::default()
+        };
+        let write_config = |pre_run: &str| {
+            fs::write(
+                &atomic,
+                format!(
+                    "[atomic]\npre_run = \"{pre_run}\"\n\
+                     post_run = [\"echo post $ATOMIC_RESULT $ATOMIC_IN_GLOBAL_HOOK >> '{out}'\"]\n\
+                     [custom]\nok = \"echo ok >> '{out}'\"\nfail = \"exit 4\"\n",
+                    out = out.display()
+                ),
+            )
+            .unwrap()
+        };
+
+        write_config(&format!("echo pre >> '{}'", out.display()));
+        assert_eq!(run_command("ok", &atomic, &options), Ok(0));
+        assert_eq!(run_command("fail", &atomic, &options), Ok(4));
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "pre\nok\npost success 1\npre\npost failure 1\n"
+        );
+
+        // a failing pre_run stops everything, post_run included
+        fs::remove_file(&out).unwrap();
+        write_config("exit 3");
+        assert_eq!(run_command("ok", &atomic, &options), Ok(3));
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn test_command_not_found_suggestions() {
+        let config = config(
+            r#"
+            lint = "cargo clippy"
+            [default]
+            check = "cargo check"
+            [custom]
+            clippy = "cargo clippy"
+            clippy_max = "cargo clippy -- -D warnings"
+            [atomic]
+            base_branch = "main"
+            "#,
+        );
+
+        assert_eq!(
+            config.command_names(),
+            vec!["clippy", "clippy_max", "check", "lint"]
+        );
+        assert_eq!(
+            command_not_found("clipy", &config).to_string(),
+            "Command 'clipy' not found in atomic.toml. Did you mean 'clippy'?"
+        );
+        assert_eq!(
+            command_not_found("base_branch", &config).to_string(),
+            "Command 'base_branch' not found in atomic.toml."
+        );
+    }
+
+    fn need_names(needs: Result<Vec<(String, CommandSpec)>>) -> Result<Vec<String>> {
+        needs.map(|needs| needs.into_iter().map(|(name, _)| name).collect())
+    }
+
+    #[test]
+    fn test_resolve_needs() {
+        let config = config(
+            r#"
+            [default]
+            build = "cargo build"
+            [custom.test]
+            command = "cargo test"
+            needs = ["build"]
+            [custom.lint]
+            command = "cargo clippy"
+            needs = ["build"]
+            [custom.deploy]
+            command = "./deploy.sh"
+            needs = ["test", "lint"]
+            "#,
+        );
+
+        // build is shared by test and lint but only listed once
+        assert_eq!(
+            need_names(resolve_needs(&config, "deploy")),
+            Ok(vec![
+                "build".to_string(),
+                "test".to_string(),
+                "lint".to_string()
+            ])
+        );
+        assert_eq!(
+            need_names(resolve_needs(&config, "test")),
+            Ok(vec!["build".to_string()])
+        );
+        assert_eq!(need_names(resolve_needs(&config, "build")), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_resolve_needs_errors() {
+        let config = config(
+            r#"
+            [custom.a]
+            command = "echo a"
+            needs = ["b"]
+            [custom.b]
+            command = "echo b"
+            needs = ["c"]
+            [custom.c]
+            command = "echo c"
+            needs = ["a"]
+            [custom.deploy]
+            command = "./deploy.sh"
+            needs = ["buld"]
+            [custom.build]
+            command = "cargo build"
+            "#,
+        );
+
+        assert_eq!(
+            resolve_needs(&config, "a").map(|_| ()),
+            Err(AtomicError::NeedsCycle(
+                ["a", "b", "c", "a"].map(str::to_string).to_vec()
+            ))
+        );
+        let unknown = resolve_needs(&config, "deploy").unwrap_err();
+        assert_eq!(
+            unknown,
+            AtomicError::UnknownNeed(
+                "deploy".to_string(),
+                "buld".to_string(),
+                vec!["build".to_string()]
+            )
+        );
+        assert_eq!(
+            unknown.to_string(),
+            "'deploy' needs 'buld', which is not defined in atomic.toml. Did you mean 'build'?"
+        );
+    }
+
+    #[test]
+    fn test_needs_run_once_and_stop_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let log = dir.path().join("log.txt");
+        let log = log.display();
+        fs::write(
+            &atomic,
+            format!(
+                r#"
+                [custom.build]
+                command = "echo build >> '{log}'"
+                [custom.test]
+                command = "echo test >> '{log}'"
+                needs = ["build"]
+                [custom.lint]
+                command = "echo lint >> '{log}'"
+                needs = ["build"]
+                [custom.deploy]
+                command = "echo deploy >> '{log}'"
+                needs = ["test", "lint"]
+                [custom.broken]
+                command = "exit 1"
+                [custom.release]
+                command = "echo release >> '{log}'"
+                needs = ["broken", "build"]
+                "#
+            ),
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_commit: true,
+            ..Default::default()
+        };
+        let read_log = || fs::read_to_string(dir.path().join("log.txt")).unwrap_or_default();
+
+        assert_eq!(run_command("deploy", &atomic, &options), Ok(0));
+        assert_eq!(read_log(), "build\ntest\nlint\ndeploy\n");
+
+        fs::remove_file(dir.path().join("log.txt")).unwrap();
+        assert_eq!(run_command("release", &atomic, &options), Ok(1));
+        assert_eq!(read_log(), "");
+    }
+
+    #[test]
+    fn test_inputs_skip_unchanged_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let log = dir.path().join("log.txt");
+        fs::create_dir(dir.path().join("proto")).unwrap();
+        fs::write(dir.path().join("proto/api.proto"), "v1").unwrap();
+        fs::write(
+            &atomic,
+            format!(
+                r#"
+                [custom.codegen]
+                command = "echo codegen >> '{}' && test ! -e fail"
+                inputs = ["proto/**/*.proto"]
+                "#,
+                log.display()
+            ),
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_commit: true,
+            ..Default::default()
+        };
+        let runs = || fs::read_to_string(&log).unwrap_or_default().lines().count();
+
+        assert_eq!(run_command("codegen", &atomic, &options), Ok(0));
+        assert_eq!(run_command("codegen", &atomic, &options), Ok(0));
+        assert_eq!(runs(), 1);
+
+        fs::write(dir.path().join("proto/api.proto"), "v2").unwrap();
+        assert_eq!(run_command("codegen", &atomic, &options), Ok(0));
+        assert_eq!(runs(), 2);
+
+        let forced = RunOptions {
+            force: true,
+            ..options.clone()
+        };
+        assert_eq!(run_command("codegen", &atomic, &forced), Ok(0));
+        assert_eq!(runs(), 3);
+
+        // a failed run doesn't count as being up to date
+        fs::write(dir.path().join("proto/api.proto"), "v3").unwrap();
+        fs::write(dir.path().join("fail"), "").unwrap();
+        assert_eq!(run_command("codegen", &atomic, &options), Ok(1));
+        fs::remove_file(dir.path().join("fail")).unwrap();
+        assert_eq!(run_command("codegen", &atomic, &options), Ok(0));
+        assert_eq!(run_command("codegen", &atomic, &options), Ok(0));
+        assert_eq!(runs(), 5);
+    }
+
+    #[test]
+    fn test_run_against_other_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(&atomic, "[custom]\ngen = \"echo generated > gen.txt\"\n").unwrap();
+        let repo = init_repo(dir.path(), &[]);
+
+        // the test runs from the crate root, not the temp repository
+        let options = RunOptions {
+            repo: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        run_command("gen", &atomic, &options).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("gen.txt")).unwrap(),
+            "generated\n"
+        );
+        assert!(!Path::new("gen.txt").exists());
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("atomic: gen"));
+        assert!(head.tree().unwrap().get_name("gen.txt").is_some());
+    }
+
+    #[test]
+    fn test_confirm_from_spec() {
+        assert_eq!(Confirm::from_spec(&spec("command = \"x\"")), None);
+        assert_eq!(
+            Confirm::from_spec(&CommandSpec::Plain(Steps::Shell(
+                "rm -rf target".to_string()
+            ))),
+            None
+        );
+        assert_eq!(
+            Confirm::from_spec(&spec("command = \"x\"\nconfirm = false")),
+            None
+        );
+        assert_eq!(
+            Confirm::from_spec(&spec("command = \"x\"\nconfirm = true")),
+            Some(Confirm::YesNo)
+        );
+        assert_eq!(
+            Confirm::from_spec(&spec(
+                "command = \"x\"\nconfirm = \"This drops the database\""
+            )),
+            Some(Confirm::Typed("This drops the database".to_string()))
+        );
+        assert!(toml::from_str::<CommandSpec>("command = \"x\"\nconfirm = 1").is_err());
+    }
+
+    #[test]
+    fn test_confirm_ask() {
+        let ask = |confirm: &Confirm, answer: &str| {
+            let mut output = Vec::new();
+            let ran = confirm
+                .ask("reset-db", &mut answer.as_bytes(), &mut output)
+                .unwrap();
+            (ran, String::from_utf8(output).unwrap())
+        };
+
+        assert_eq!(
+            ask(&Confirm::YesNo, "y\n"),
+            (true, "Run 'reset-db'? [y/N] ".to_string())
+        );
+        assert!(!ask(&Confirm::YesNo, "\n").0);
+        // no answer at all, e.g. stdin closed
+        assert!(!ask(&Confirm::YesNo, "").0);
+
+        let typed = Confirm::Typed("This drops the staging database.".to_string());
+        assert_eq!(
+            ask(&typed, "  Reset-DB \n"),
+            (
+                true,
+                "This drops the staging database.\nType 'reset-db' to run it: ".to_string()
+            )
+        );
+        assert!(!ask(&typed, "yes\n").0);
+        assert!(!ask(&typed, "reset\n").0);
+    }
+
+    #[test]
+    fn test_yes_skips_confirm() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            "[custom.clean]\ncommand = \"echo cleaned > cleaned.txt\"\nconfirm = true\n",
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_commit: true,
+            yes: true,
+            ..Default::default()
+        };
+
+        run_command("clean", &atomic, &options).unwrap();
+        assert!(dir.path().join("cleaned.txt").exists());
+    }
+
+    #[test]
+    fn test_ci_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            "[custom]\ngen = \"echo generated >> gen.txt\"\nfail = \"exit 3\"\n\n\
+             [custom.wipe]\ncommand = \"echo wiped > wiped.txt\"\nconfirm = true\n",
+        )
+        .unwrap();
+        let repo = init_repo(dir.path(), &[]);
+        let initial = repo.head().unwrap().target().unwrap();
+        let head = || repo.head().unwrap().peel_to_commit().unwrap().id();
+
+        let ci = RunOptions {
+            repo: Some(dir.path().to_path_buf()),
+            interactivity: Interactivity::Ci {
+                github_actions: false,
+            },
+            ..Default::default()
+        };
+
+        // no auto-commit in CI unless asked for
+        assert_eq!(run_command("gen", &atomic, &ci), Ok(0));
+        assert!(dir.path().join("gen.txt").exists());
+        assert_eq!(head(), initial);
+        let commit = RunOptions {
+            commit: true,
+            ..ci.clone()
+        };
+        assert_eq!(run_command("gen", &atomic, &commit), Ok(0));
+        assert_ne!(head(), initial);
+
+        assert_eq!(run_command("fail", &atomic, &ci), Ok(3));
+
+        // destructive prompts abort rather than wait
+        assert_eq!(
+            run_command("wipe", &atomic, &ci),
+            Err(AtomicError::ConfirmInCi("wipe".to_string()))
+        );
+        assert!(!dir.path().join("wiped.txt").exists());
+    }
+
+    #[test]
+    fn test_paths_to_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("wip.txt"), "a\n").unwrap();
+        let repo = init_repo(dir.path(), &[]);
+
+        let settings = |mode: &str| Settings {
+            autocommit_preexisting: Some(mode.to_string()),
+            ..Default::default()
+        };
+        let options = RunOptions::default();
+
+        // the command touched everything that was dirty: commit everything
+        fs::write(dir.path().join("wip.txt"), "half done\n").unwrap();
+        let before = worktree_snapshot(&repo).unwrap();
+        fs::write(dir.path().join("wip.txt"), "formatted\n").unwrap();
+        let touched = RunChanges::between(&before, &worktree_snapshot(&repo).unwrap());
+        assert_eq!(
+            paths_to_commit("codegen", &touched, &settings("exclude"), &options),
+            Ok(None)
+        );
+
+        fs::write(dir.path().join("wip.txt"), "half done\n").unwrap();
+        fs::write(dir.path().join("gen.rs"), "// generated\n").unwrap();
+        let changes = RunChanges::between(&before, &worktree_snapshot(&repo).unwrap());
+
+        assert_eq!(
+            paths_to_commit("codegen", &changes, &settings("exclude"), &options),
+            Ok(Some(vec!["gen.rs".to_string()]))
+        );
+        assert_eq!(
+            paths_to_commit("codegen", &changes, &settings("include"), &options),
+            Ok(None)
+        );
+        let include = RunOptions {
+            include_preexisting: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            paths_to_commit("codegen", &changes, &settings("exclude"), &include),
+            Ok(None)
+        );
+    }
+}
@@ -0,0 +1,756 @@
+//! Typed view of atomic.toml, for running commands.
+//!
+//! Files are checked against the rules in [`crate::schema`] before they get here, since those
+//! name the offending key; [`AtomicConfig::from_value`] then only maps shapes onto types.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::Deserialize;
+use toml::Value;
+
+use crate::toml::SETTINGS_TABLE;
+use crate::util::{parse_duration, parse_size, platform_matches};
+use crate::{AtomicError, Result};
+
+/// Sections a command name can be qualified with, as in `default:build`, in the order an
+/// unqualified name is looked up.
+pub const QUALIFIERS: &[&str] = &["custom", "default", "root"];
+/// Most combinations a `matrix` may expand to.
+pub const MAX_MATRIX: usize = 32;
+
+/// Splits a qualified name such as `default:build` into its section and name.
+pub fn split_qualifier(name: &str) -> Option<(&'static str, &str)> {
+    let (section, name) = name.split_once(':')?;
+    let section = QUALIFIERS.iter().find(|qualifier| **qualifier == section)?;
+    Some((section, name))
+}
+
+/// `[custom]`, `[default]`, or `the top level`, for messages about where a command is defined.
+pub fn section_label(section: &str) -> String {
+    match section {
+        "root" => "the top level".to_string(),
+        section => format!("[{}]", section),
+    }
+}
+
+/// A parsed atomic.toml, with any profile already applied.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct AtomicConfig {
+    #[serde(default)]
+    pub atomic: Settings,
+    #[serde(default)]
+    pub default: BTreeMap<String, CommandSpec>,
+    #[serde(default)]
+    pub custom: BTreeMap<String, CommandSpec>,
+    /// Commands written at the top level, outside any section.
+    #[serde(flatten)]
+    pub root: BTreeMap<String, CommandSpec>,
+}
+
+/// The `[atomic]` table.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Settings {
+    pub auto_commit: Option<bool>,
+    pub base_branch: Option<String>,
+    pub commit_author: Option<String>,
+    pub container_runtime: Option<String>,
+    pub autocommit_preexisting: Option<String>,
+    pub run_git_hooks: Option<bool>,
+    pub pre_run: Option<Steps>,
+    pub post_run: Option<Steps>,
+    pub no_picker: Option<bool>,
+    pub root_only: Option<bool>,
+    pub git: Option<bool>,
+    pub max_commit_file_size: Option<SizeSpec>,
+    pub large_files: Option<LargeFiles>,
+    pub stats: Option<bool>,
+    pub event_socket: Option<String>,
+    pub env_files: Option<Vec<String>>,
+    pub keep_tmp_on_failure: Option<bool>,
+    pub change_notice: Option<bool>,
+    pub workspace_repos: Option<Vec<String>>,
+    pub artifact_max_size: Option<SizeSpec>,
+    pub manage_gitignore: Option<bool>,
+    /// `[atomic.lints]`: `false` turns a rule of `atomic --lint` off.
+    pub lints: Option<BTreeMap<String, bool>>,
+    /// `[atomic.issues]`: where `ATOMIC_ISSUE_TITLE` is looked up; see [`crate::issues`].
+    pub issues: Option<IssueSettings>,
+}
+
+impl Settings {
+    /// The `[atomic]` table of a whole config, for settings needed without its commands.
+    ///
+    /// # Errors
+    /// Returns an error if a setting holds the wrong type.
+    pub fn from_config(config: &Value) -> Result<Self> {
+        match config.get(SETTINGS_TABLE) {
+            Some(table) => table
+                .clone()
+                .try_into()
+                .map_err(AtomicError::InvalidSetting),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+/// The `[atomic.issues]` table.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct IssueSettings {
+    pub provider: IssueProvider,
+    /// `owner/name` of a GitHub repository, instead of the `origin` remote's.
+    pub repo: Option<String>,
+    pub base_url: Option<String>,
+    /// The Jira project key issue numbers belong to, such as `ABC` for `ABC-144`.
+    pub project: Option<String>,
+    pub token_env: Option<String>,
+    pub cache_ttl: Option<DurationSpec>,
+}
+
+/// The tracker of `[atomic.issues]`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueProvider {
+    Github,
+    Jira,
+}
+
+/// What an auto-commit does with files over `max_commit_file_size`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LargeFiles {
+    /// Stop the auto-commit, listing them.
+    #[default]
+    Abort,
+    /// Commit everything else, leaving them uncommitted.
+    Skip,
+}
+
+/// `"10MB"`, or a whole number of bytes.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum SizeSpec {
+    Text(String),
+    Bytes(i64),
+}
+
+/// A command as written in atomic.toml.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum CommandSpec {
+    /// `name = "cargo build"` or `name = ["check", "cargo fmt"]`.
+    Plain(Steps),
+    /// A `[custom.<name>]` table.
+    Detailed(Box<DetailedCommand>),
+}
+
+/// What a command runs.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Steps {
+    /// A single shell command.
+    Shell(String),
+    /// Shell commands and names of other commands, run in order.
+    Chain(Vec<ChainStep>),
+}
+
+/// One element of a chain.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ChainStep {
+    /// A command name or shell command. `-name` runs command `name` without failing the chain.
+    Plain(String),
+    /// `{ run = "docs-check", ignore_failure = true }`, with `run` taken as written.
+    Table {
+        run: String,
+        #[serde(default)]
+        ignore_failure: bool,
+    },
+}
+
+/// A command table; see `COMMAND_TABLE_RULES` for what each key does.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DetailedCommand {
+    pub command: Steps,
+    pub desc: Option<String>,
+    pub retries: Option<i64>,
+    pub retry_delay: Option<DurationSpec>,
+    pub commit: Option<bool>,
+    pub container: Option<String>,
+    #[serde(default)]
+    pub needs: Vec<String>,
+    pub confirm: Option<ConfirmSpec>,
+    pub os: Option<OsSpec>,
+    pub capture_output: Option<CaptureOutput>,
+    pub lock: Option<bool>,
+    pub lock_wait: Option<DurationSpec>,
+    pub cargo_json: Option<bool>,
+    pub env_file: Option<String>,
+    pub tmpdir: Option<TmpDirSpec>,
+    /// `{ flag = ["foo", "bar"] }`: run the command once for each combination of values.
+    pub matrix: Option<BTreeMap<String, Vec<String>>>,
+    pub fail_fast: Option<bool>,
+    pub lint: Option<bool>,
+    pub inputs: Option<Vec<String>>,
+    pub outputs: Option<Vec<String>>,
+    pub clean_env: Option<bool>,
+    pub env_allow: Option<Vec<String>>,
+    pub commit_message: Option<String>,
+    pub commit_branch: Option<String>,
+    pub artifacts: Option<Vec<String>>,
+}
+
+/// Where `capture_output` sends a command's stdout, besides the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureOutput {
+    /// Into the body of the auto-commit that follows the run.
+    CommitBody,
+    /// Nowhere; the same as leaving it out.
+    None,
+}
+
+/// `"5s"`, or a whole number of seconds.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum DurationSpec {
+    Text(String),
+    Seconds(i64),
+}
+
+/// `os = "macos"`, or several platforms; see [`crate::util::PLATFORMS`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum OsSpec {
+    One(String),
+    Any(Vec<String>),
+}
+
+/// `tmpdir = true`, or `"shared"` for one directory across a chain.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum TmpDirSpec {
+    Flag(bool),
+    Mode(String),
+}
+
+/// How a command's steps get an `ATOMIC_TMPDIR`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TmpDirMode {
+    None,
+    /// A new directory for every step.
+    PerStep,
+    /// One directory for the whole command.
+    Shared,
+}
+
+/// `confirm = true`, or a prompt to show before the command's name must be typed.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum ConfirmSpec {
+    Flag(bool),
+    Prompt(String),
+}
+
+impl AtomicConfig {
+    /// Maps a parsed atomic.toml onto the typed config.
+    ///
+    /// # Errors
+    /// Returns an error if a value has the wrong shape; running the schema first gives better
+    /// messages for those.
+    pub fn from_value(value: Value) -> Result<Self> {
+        value
+            .try_into()
+            .map_err(|err| AtomicError::Validation(vec![err.to_string()]))
+    }
+
+    /// Looks up a command by name: `[custom]` first, then `[default]`, then top-level commands.
+    ///
+    /// A name qualified with its section, such as `default:build`, is only looked up there.
+    pub fn command(&self, name: &str) -> Option<&CommandSpec> {
+        let section = self.section_of(name)?;
+        let name = split_qualifier(name).map_or(name, |(_, name)| name);
+        self.section(section).get(name)
+    }
+
+    /// Which section [`command`](Self::command) finds `name` in: `custom`, `default`, or `root`
+    /// for a top-level command.
+    pub fn section_of(&self, name: &str) -> Option<&'static str> {
+        match split_qualifier(name) {
+            Some((section, name)) => {
+                Some(section).filter(|_| self.section(section).contains_key(name))
+            }
+            None => QUALIFIERS
+                .iter()
+                .copied()
+                .find(|section| self.section(section).contains_key(name)),
+        }
+    }
+
+    /// The sections that define `name` too, but lose to the one [`command`](Self::command) finds
+    /// it in. Qualified names never shadow anything.
+    pub fn shadowed(&self, name: &str) -> Vec<&'static str> {
+        if split_qualifier(name).is_some() {
+            return Vec::new();
+        }
+        QUALIFIERS
+            .iter()
+            .copied()
+            .filter(|section| self.section(section).contains_key(name))
+            .skip(1)
+            .collect()
+    }
+
+    fn section(&self, section: &str) -> &BTreeMap<String, CommandSpec> {
+        match section {
+            "custom" => &self.custom,
+            "default" => &self.default,
+            _ => &self.root,
+        }
+    }
+
+    /// Every command name, in [`command`](Self::command)'s lookup order, without repeats.
+    pub fn command_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for name in self
+            .custom
+            .keys()
+            .chain(self.default.keys())
+            .chain(self.root.keys())
+        {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+}
+
+impl DurationSpec {
+    /// # Errors
+    /// Returns an error for a malformed string or a negative number of seconds.
+    pub fn to_duration(&self) -> Result<Duration> {
+        match self {
+            DurationSpec::Text(text) => parse_duration(text),
+            DurationSpec::Seconds(secs) if *secs >= 0 => Ok(Duration::from_secs(*secs as u64)),
+            DurationSpec::Seconds(secs) => Err(AtomicError::Generic(format!(
+                "Invalid duration {}: expected a string such as \"5s\" or a whole number of seconds",
+                secs
+            ))),
+        }
+    }
+}
+
+impl SizeSpec {
+    /// # Errors
+    /// Returns an error for a malformed string or a negative number of bytes.
+    pub fn to_bytes(&self) -> Result<u64> {
+        match self {
+            SizeSpec::Text(text) => parse_size(text),
+            SizeSpec::Bytes(bytes) => u64::try_from(*bytes).map_err(|_| {
+                AtomicError::Generic(format!(
+                    "Invalid size {}: expected a string such as \"10MB\" or a whole number of bytes",
+                    bytes
+                ))
+            }),
+        }
+    }
+}
+
+impl OsSpec {
+    pub fn platforms(&self) -> Vec<&str> {
+        match self {
+            OsSpec::One(platform) => vec![platform.as_str()],
+            OsSpec::Any(platforms) => platforms.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// `macos`, or `windows or macos`.
+    pub fn describe(&self) -> String {
+        self.platforms().join(" or ")
+    }
+}
+
+impl CommandSpec {
+    pub fn steps(&self) -> &Steps {
+        match self {
+            CommandSpec::Plain(steps) => steps,
+            CommandSpec::Detailed(detail) => &detail.command,
+        }
+    }
+
+    /// The command table, when it was written as one.
+    pub fn detail(&self) -> Option<&DetailedCommand> {
+        match self {
+            CommandSpec::Plain(_) => None,
+            CommandSpec::Detailed(detail) => Some(detail),
+        }
+    }
+
+    pub fn needs(&self) -> &[String] {
+        self.detail().map_or(&[], |detail| &detail.needs)
+    }
+
+    /// The command's `os`, if it only runs on some platforms.
+    pub fn os(&self) -> Option<&OsSpec> {
+        self.detail().and_then(|detail| detail.os.as_ref())
+    }
+
+    /// Whether the command's stdout goes into the auto-commit message.
+    pub fn captures_output(&self) -> bool {
+        self.detail().and_then(|detail| detail.capture_output) == Some(CaptureOutput::CommitBody)
+    }
+
+    /// Whether the command sets `cargo_json = true`.
+    pub fn cargo_json(&self) -> bool {
+        self.detail().and_then(|detail| detail.cargo_json) == Some(true)
+    }
+
+    /// The command's `desc`, unless it's missing or empty.
+    pub fn desc(&self) -> Option<&str> {
+        self.detail()
+            .and_then(|detail| detail.desc.as_deref())
+            .filter(|desc| !desc.is_empty())
+    }
+
+    /// How the command's steps get an `ATOMIC_TMPDIR`, from `tmpdir`.
+    pub fn tmpdir(&self) -> TmpDirMode {
+        match self.detail().and_then(|detail| detail.tmpdir.as_ref()) {
+            None | Some(TmpDirSpec::Flag(false)) => TmpDirMode::None,
+            Some(TmpDirSpec::Mode(mode)) if mode == "shared" => TmpDirMode::Shared,
+            Some(_) => TmpDirMode::PerStep,
+        }
+    }
+
+    /// The command's `env_file`, if it sets one.
+    pub fn env_file(&self) -> Option<&str> {
+        self.detail().and_then(|detail| detail.env_file.as_deref())
+    }
+
+    /// The subject its auto-commits get instead of `atomic: <name>`, unless it's empty.
+    pub fn commit_message(&self) -> Option<&str> {
+        self.detail()
+            .and_then(|detail| detail.commit_message.as_deref())
+            .filter(|message| !message.trim().is_empty())
+    }
+
+    /// The branch its auto-commits go onto instead of the checked-out one.
+    pub fn commit_branch(&self) -> Option<&str> {
+        self.detail()
+            .and_then(|detail| detail.commit_branch.as_deref())
+    }
+
+    /// Whether the command sets `clean_env = true`.
+    pub fn clean_env(&self) -> bool {
+        self.detail().and_then(|detail| detail.clean_env) == Some(true)
+    }
+
+    /// The variables `env_allow` keeps under `clean_env`, besides the usual ones.
+    pub fn env_allow(&self) -> &[String] {
+        self.detail()
+            .and_then(|detail| detail.env_allow.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// The command's `matrix`, if it sets one.
+    pub fn matrix(&self) -> Option<&BTreeMap<String, Vec<String>>> {
+        self.detail().and_then(|detail| detail.matrix.as_ref())
+    }
+
+    /// Whether the command sets `fail_fast = true`.
+    pub fn fail_fast(&self) -> bool {
+        self.detail().and_then(|detail| detail.fail_fast) == Some(true)
+    }
+
+    /// The command's `inputs`, if it only runs when they changed.
+    pub fn inputs(&self) -> Option<&[String]> {
+        self.detail().and_then(|detail| detail.inputs.as_deref())
+    }
+
+    /// The command's `outputs`, which have to exist for it to be skipped.
+    pub fn outputs(&self) -> &[String] {
+        self.detail()
+            .and_then(|detail| detail.outputs.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// The command's `artifacts`, copied out once it finishes; see [`crate::artifacts`].
+    pub fn artifacts(&self) -> &[String] {
+        self.detail()
+            .and_then(|detail| detail.artifacts.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Whether the command runs on this platform: it has no `os`, or `os` names this one.
+    pub fn runs_here(&self) -> bool {
+        self.os().is_none_or(|os| platform_matches(os.platforms()))
+    }
+}
+
+impl ChainStep {
+    /// What the step runs, and whether its failure is ignored.
+    ///
+    /// A leading `-` only marks an ignored failure when the rest names a command in `config`, so
+    /// shell commands starting with a dash run as written.
+    pub fn resolve(&self, config: &AtomicConfig) -> (&str, bool) {
+        match self {
+            ChainStep::Plain(step) => match step.strip_prefix('-') {
+                Some(name) if config.command(step).is_none() && config.command(name).is_some() => {
+                    (name, true)
+                }
+                _ => (step, false),
+            },
+            ChainStep::Table {
+                run,
+                ignore_failure,
+            } => (run, *ignore_failure),
+        }
+    }
+}
+
+impl From<&str> for ChainStep {
+    fn from(step: &str) -> Self {
+        ChainStep::Plain(step.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::validate_toml_schema;
+
+    fn config(s: &str) -> AtomicConfig {
+        let value: Value = ::toml::from_str(s).unwrap();
+        assert!(validate_toml_schema(&value).is_empty());
+        AtomicConfig::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_settings_from_config() {
+        let value: Value = ::toml::from_str(
+            "[atomic]\nauto_commit = false\nbase_branch = \"develop\"\n\n[custom]\nx = \"true\"\n",
+        )
+        .unwrap();
+        let settings = Settings::from_config(&value).unwrap();
+        assert_eq!(settings.auto_commit, Some(false));
+        assert_eq!(settings.base_branch.as_deref(), Some("develop"));
+        assert_eq!(settings.git, None);
+
+        // configs without [atomic] get the defaults
+        let empty = Value::Table(::toml::map::Map::new());
+        assert_eq!(Settings::from_config(&empty), Ok(Settings::default()));
+
+        let wrong: Value = ::toml::from_str("[atomic]\ngit = \"no\"\n").unwrap();
+        let err = Settings::from_config(&wrong).unwrap_err();
+        assert!(matches!(err, AtomicError::InvalidSetting(_)));
+        assert!(err.to_string().starts_with("Invalid [atomic] setting"));
+    }
+
+    #[test]
+    fn test_shipped_configs() {
+        for text in [
+            include_str!("../atomic.toml"),
+            include_str!("../template/rust.toml"),
+            include_str!("../template/example.toml"),
+        ] {
+            assert!(!config(text).command_names().is_empty());
+        }
+        assert_eq!(
+            config(include_str!("../template/rust.toml")).command("chain"),
+            Some(&CommandSpec::Plain(Steps::Chain(vec![
+                "check".into(),
+                "clippy".into(),
+                "cargo fmt".into()
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_command_shapes() {
+        let config = config(
+            r#"
+            root = "echo root"
+            [default]
+            build = "cargo build"
+            test = ["build", "cargo test"]
+            [custom.deploy]
+            command = ["test", "./deploy.sh"]
+            desc = "ship it"
+            retries = 2
+            retry_delay = "5s"
+            commit = false
+            container = "rust:1.78"
+            needs = ["build"]
+            confirm = "This goes to production."
+            os = ["unix", "windows"]
+            [custom.fetch]
+            command = "cargo fetch"
+            retry_delay = 3
+            confirm = true
+            os = "windows"
+            [atomic]
+            auto_commit = false
+            pre_run = "./preflight.sh"
+            post_run = ["echo done"]
+            "#,
+        );
+
+        assert_eq!(
+            config.command("root"),
+            Some(&CommandSpec::Plain(Steps::Shell("echo root".to_string())))
+        );
+        assert_eq!(
+            config.command("build").map(CommandSpec::steps),
+            Some(&Steps::Shell("cargo build".to_string()))
+        );
+        assert!(matches!(
+            config.command("test"),
+            Some(CommandSpec::Plain(Steps::Chain(steps))) if steps.len() == 2
+        ));
+
+        let deploy = config
+            .command("deploy")
+            .and_then(CommandSpec::detail)
+            .unwrap();
+        assert_eq!(deploy.desc.as_deref(), Some("ship it"));
+        assert_eq!(deploy.retries, Some(2));
+        assert_eq!(
+            deploy.retry_delay,
+            Some(DurationSpec::Text("5s".to_string()))
+        );
+        assert_eq!(deploy.commit, Some(false));
+        assert_eq!(deploy.container.as_deref(), Some("rust:1.78"));
+        assert_eq!(deploy.needs, ["build"]);
+        assert_eq!(
+            deploy.confirm,
+            Some(ConfirmSpec::Prompt("This goes to production.".to_string()))
+        );
+        assert_eq!(
+            deploy.os.as_ref().map(OsSpec::describe).as_deref(),
+            Some("unix or windows")
+        );
+        assert!(config.command("deploy").unwrap().runs_here());
+
+        let fetch = config
+            .command("fetch")
+            .and_then(CommandSpec::detail)
+            .unwrap();
+        assert_eq!(fetch.retry_delay, Some(DurationSpec::Seconds(3)));
+        assert_eq!(fetch.confirm, Some(ConfirmSpec::Flag(true)));
+        assert!(fetch.needs.is_empty());
+        assert_eq!(config.command("fetch").unwrap().runs_here(), cfg!(windows));
+
+        assert_eq!(config.atomic.auto_commit, Some(false));
+        assert_eq!(
+            config.atomic.pre_run,
+            Some(Steps::Shell("./preflight.sh".to_string()))
+        );
+        assert_eq!(
+            config.atomic.post_run,
+            Some(Steps::Chain(vec!["echo done".into()]))
+        );
+        // settings are not commands
+        assert_eq!(config.command("auto_commit"), None);
+        assert_eq!(
+            config.command_names(),
+            ["deploy", "fetch", "build", "test", "root"]
+        );
+    }
+
+    #[test]
+    fn test_lookup_order() {
+        let config = config(
+            r#"
+            build = "echo root"
+            check = "echo root"
+            [default]
+            build = "echo default"
+            check = "echo default"
+            lint = "echo default"
+            [custom]
+            build = "echo custom"
+            lint = "echo custom"
+            "#,
+        );
+        let runs = |name: &str| match config.command(name).map(CommandSpec::steps) {
+            Some(Steps::Shell(cmd)) => Some(cmd.as_str()),
+            _ => None,
+        };
+        assert_eq!(runs("build"), Some("echo custom"));
+        assert_eq!(runs("check"), Some("echo default"));
+        assert_eq!(runs("lint"), Some("echo custom"));
+        assert_eq!(runs("custom:build"), Some("echo custom"));
+        assert_eq!(runs("default:build"), Some("echo default"));
+        assert_eq!(runs("root:build"), Some("echo root"));
+        assert_eq!(runs("root:lint"), None);
+        assert_eq!(runs("shell:build"), None);
+
+        assert_eq!(config.section_of("build"), Some("custom"));
+        assert_eq!(config.section_of("check"), Some("default"));
+        assert_eq!(config.section_of("root:check"), Some("root"));
+        assert_eq!(config.section_of("default:lint"), Some("default"));
+        assert_eq!(config.section_of("cargo test"), None);
+
+        assert_eq!(config.shadowed("build"), ["default", "root"]);
+        assert_eq!(config.shadowed("check"), ["root"]);
+        assert!(config.shadowed("default:build").is_empty());
+        assert!(config.shadowed("missing").is_empty());
+        assert_eq!(config.command_names(), ["build", "lint", "check"]);
+    }
+
+    #[test]
+    fn test_chain_steps() {
+        let config = config(
+            r#"
+            docs = "cargo doc"
+            "-v" = "echo dash"
+            [custom.ci]
+            command = [
+                "-docs",
+                "-missing",
+                "-v",
+                { run = "-rf", ignore_failure = true },
+                { run = "docs" },
+            ]
+            "#,
+        );
+        let Some(Steps::Chain(chain)) = config.command("ci").map(CommandSpec::steps) else {
+            panic!("ci should be a chain");
+        };
+        let resolved: Vec<_> = chain.iter().map(|step| step.resolve(&config)).collect();
+        assert_eq!(
+            resolved,
+            [
+                ("docs", true),
+                // not a command, so the dash is part of a shell command
+                ("-missing", false),
+                // a command is named "-v" itself
+                ("-v", false),
+                ("-rf", true),
+                ("docs", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duration_spec() {
+        assert_eq!(
+            DurationSpec::Text("250ms".to_string()).to_duration(),
+            Ok(Duration::from_millis(250))
+        );
+        assert_eq!(
+            DurationSpec::Seconds(4).to_duration(),
+            Ok(Duration::from_secs(4))
+        );
+        assert!(DurationSpec::Seconds(-1).to_duration().is_err());
+        assert!(DurationSpec::Text("soon".to_string())
+            .to_duration()
+            .is_err());
+    }
+
+    #[test]
+    fn test_wrong_shapes() {
+        let value: Value = ::toml::from_str("[custom.x]\ncommand = 5\n").unwrap();
+        assert!(AtomicConfig::from_value(value).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+//! The notice printed when atomic.toml changed since the last run, so an edit made by someone
+//! else doesn't go unnoticed: `atomic.toml changed since last run: 2 commands added, 1 modified`.
+//!
+//! After every run the file's hash and a hash of each command and setting are kept under
+//! `.atomic/state/`, in a file named after the config's path so several configs in one project
+//! don't overwrite each other. The next run compares the entries, not the text, so comments and
+//! formatting don't count as changes.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use toml::Value;
+
+use crate::last_run::{state_dir, STATE_DIR};
+use crate::schema::SECTIONS;
+use crate::toml::{get_toml_content, read_toml_text};
+use crate::{AtomicError, Result};
+
+const SUBDIR: &str = "state";
+/// Version written to new files; bump it when older atomics would misread them.
+const VERSION: u32 = 1;
+
+/// What atomic saw of a config at the end of the last run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub version: u32,
+    /// The config this is a snapshot of.
+    pub path: String,
+    /// Hash of the file's text, to skip comparing entries when nothing changed.
+    pub hash: String,
+    /// Hash of each entry's value, by [`entries`] name.
+    #[serde(default)]
+    pub entries: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    pub fn new(path: &Path, text: &str, config: &Value) -> Self {
+        Self {
+            version: VERSION,
+            path: path.display().to_string(),
+            hash: hex(fnv1a(text.as_bytes())),
+            entries: entries(config),
+        }
+    }
+}
+
+/// The entries that differ between two snapshots, by name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// `2 commands added, 1 modified; 1 setting changed`: commands by what happened to them, and
+/// `[atomic]` settings together.
+impl fmt::Display for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let all = [
+            (&self.added, "added"),
+            (&self.modified, "modified"),
+            (&self.removed, "removed"),
+        ];
+        let mut parts = Vec::new();
+        for (names, what) in all {
+            let count = names.iter().filter(|name| !is_setting(name)).count();
+            match (count, parts.is_empty()) {
+                (0, _) => {}
+                // the noun goes with the first count only
+                (1, true) => parts.push(format!("1 command {}", what)),
+                (count, true) => parts.push(format!("{} commands {}", count, what)),
+                (count, false) => parts.push(format!("{} {}", count, what)),
+            }
+        }
+        let mut text = parts.join(", ");
+        let settings = all
+            .iter()
+            .flat_map(|(names, _)| names.iter())
+            .filter(|name| is_setting(name))
+            .count();
+        if settings > 0 {
+            if !text.is_empty() {
+                text.push_str("; ");
+            }
+            let noun = if settings == 1 { "setting" } else { "settings" };
+            text.push_str(&format!("{} {} changed", settings, noun));
+        }
+        f.write_str(&text)
+    }
+}
+
+fn is_setting(name: &str) -> bool {
+    let name = match name.strip_prefix("profile.") {
+        Some(rest) => rest.split_once('.').map_or(rest, |(_, name)| name),
+        None => name,
+    };
+    name.starts_with("atomic.")
+}
+
+/// Every command and setting in `config`, named like `custom.build`, `atomic.git`, a bare name
+/// for a top-level command and `profile.ci.custom.build` inside a profile, with a hash of its
+/// value.
+pub fn entries(config: &Value) -> BTreeMap<String, String> {
+    let mut entries = BTreeMap::new();
+    add_entries(&mut entries, "", config);
+    entries
+}
+
+fn add_entries(entries: &mut BTreeMap<String, String>, prefix: &str, config: &Value) {
+    let Some(table) = config.as_table() else {
+        return;
+    };
+    for (key, value) in table {
+        let section = SECTIONS.iter().any(|section| section.name == key);
+        match value.as_table() {
+            Some(items) if section => {
+                for (name, item) in items {
+                    let name = format!("{}{}.{}", prefix, key, name);
+                    entries.insert(name, hash_value(item));
+                }
+            }
+            Some(profiles) if key == "profile" && prefix.is_empty() => {
+                for (profile, overlay) in profiles {
+                    add_entries(entries, &format!("profile.{}.", profile), overlay);
+                }
+            }
+            _ => {
+                entries.insert(format!("{}{}", prefix, key), hash_value(value));
+            }
+        }
+    }
+}
+
+fn hash_value(value: &Value) -> String {
+    hex(fnv1a(value.to_string().as_bytes()))
+}
+
+/// What changed from the `old` entries to the `new` ones; see [`entries`].
+pub fn compare(old: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Changes {
+    let mut changes = Changes::default();
+    for (name, hash) in new {
+        match old.get(name) {
+            None => changes.added.push(name.clone()),
+            Some(before) if before != hash => changes.modified.push(name.clone()),
+            Some(_) => {}
+        }
+    }
+    changes.removed = old
+        .keys()
+        .filter(|name| !new.contains_key(*name))
+        .cloned()
+        .collect();
+    changes
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher stays the same across releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// Where the snapshot of the config at `atomic` is kept.
+pub fn path(atomic: &Path) -> PathBuf {
+    let absolute = fs::canonicalize(atomic).unwrap_or_else(|_| atomic.to_path_buf());
+    let name = atomic
+        .file_name()
+        .map_or("config".into(), |name| name.to_string_lossy());
+    atomic
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(STATE_DIR)
+        .join(SUBDIR)
+        .join(format!(
+            "{}-{}.toml",
+            name,
+            hex(fnv1a(absolute.display().to_string().as_bytes()))
+        ))
+}
+
+/// The snapshot saved by the last run of the config at `atomic`, if it can be read.
+pub fn load(atomic: &Path) -> Option<Snapshot> {
+    let text = fs::read_to_string(path(atomic)).ok()?;
+    ::toml::from_str(&text).ok()
+}
+
+/// Keeps `snapshot` for the next run of the config at `atomic`.
+///
+/// # Errors
+/// Returns an error if the state directory or file can't be written.
+pub fn save(atomic: &Path, snapshot: &Snapshot) -> Result<()> {
+    let file = path(atomic);
+    let text = ::toml::to_string(snapshot)
+        .map_err(|err| AtomicError::Generic(format!("Failed to serialize snapshot: {}", err)))?;
+    state_dir(atomic, Some(SUBDIR))
+        .and_then(|_| fs::write(&file, text))
+        .map_err(|err| AtomicError::Generic(format!("Failed to write {}: {}", file.display(), err)))
+}
+
+/// The notice for the config called `name`, when its entries differ between the `last` snapshot
+/// and the `current` one.
+pub fn notice(name: &str, last: &Snapshot, current: &Snapshot) -> Option<String> {
+    if last.hash == current.hash {
+        return None;
+    }
+    let changes = compare(&last.entries, &current.entries);
+    if changes.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{} changed since last run: {} (`atomic --doctor` checks it)",
+        name, changes
+    ))
+}
+
+/// Prints the notice when the config at `atomic` changed since the last run, and returns the
+/// snapshot to [`save`] once this run is done. Nothing is printed on the first run, and `None`
+/// is returned when the file can't be read.
+pub fn check(atomic: &Path) -> Option<Snapshot> {
+    let text = read_toml_text(atomic).ok()?;
+    let config = get_toml_content(atomic).ok()?;
+    let current = Snapshot::new(atomic, &text, &config);
+    let name = atomic
+        .file_name()
+        .map_or("atomic.toml".into(), |name| name.to_string_lossy());
+    if let Some(notice) = load(atomic).and_then(|last| notice(&name, &last, &current)) {
+        eprintln!("{}", notice);
+    }
+    Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What changed from the `old` config to the `new` one.
+    fn summarize(old: &str, new: &str) -> Changes {
+        let parse = |text: &str| -> Value { ::toml::from_str(text).unwrap() };
+        compare(&entries(&parse(old)), &entries(&parse(new)))
+    }
+
+    #[test]
+    fn test_summarize_across_sections() {
+        let old = r#"
+            lint = "cargo clippy"
+            [atomic]
+            git = true
+            [default]
+            fmt = "cargo fmt"
+            [custom]
+            build = "cargo build"
+            test = "cargo test"
+            [profile.ci.custom]
+            test = "cargo test --locked"
+        "#;
+        let new = r#"
+            # comments and formatting don't count
+            lint   =   "cargo clippy"
+            [atomic]
+            git = false
+            [default]
+            fmt = "cargo fmt --all"
+            [custom]
+            build = "cargo build"
+            docs = "cargo doc"
+            [custom.bench]
+            command = ["cargo bench"]
+            [profile.ci.custom]
+            test = "cargo test --locked"
+            [profile.ci.atomic]
+            stats = false
+        "#;
+        let changes = summarize(old, new);
+        assert_eq!(
+            changes,
+            Changes {
+                added: vec![
+                    "custom.bench".to_string(),
+                    "custom.docs".to_string(),
+                    "profile.ci.atomic.stats".to_string(),
+                ],
+                removed: vec!["custom.test".to_string()],
+                modified: vec!["atomic.git".to_string(), "default.fmt".to_string()],
+            }
+        );
+        assert_eq!(
+            changes.to_string(),
+            "2 commands added, 1 modified, 1 removed; 2 settings changed"
+        );
+
+        assert!(summarize(old, old).is_empty());
+        assert_eq!(
+            summarize("", "[custom]\nbuild = \"cargo build\"\n").to_string(),
+            "1 command added"
+        );
+        assert_eq!(
+            summarize("[atomic]\ngit = true\n", "").to_string(),
+            "1 setting changed"
+        );
+    }
+
+    #[test]
+    fn test_notice_per_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let nested = dir.path().join("sub/atomic.toml");
+        fs::create_dir_all(nested.parent().unwrap()).unwrap();
+        fs::write(&atomic, "[custom]\nbuild = \"make\"\n").unwrap();
+        fs::write(&nested, "[custom]\nbuild = \"make\"\n").unwrap();
+        assert_ne!(path(&atomic), path(&nested));
+
+        // the first run has nothing to compare with
+        let first = check(&atomic).unwrap();
+        assert!(load(&atomic).is_none());
+        save(&atomic, &first).unwrap();
+        assert_eq!(load(&atomic), Some(first.clone()));
+
+        fs::write(&atomic, "# same\n[custom]\nbuild = \"make\"\n").unwrap();
+        let reformatted = check(&atomic).unwrap();
+        assert_ne!(reformatted.hash, first.hash);
+        assert_eq!(notice("atomic.toml", &first, &reformatted), None);
+
+        fs::write(
+            &atomic,
+            "[custom]\nbuild = \"make all\"\ntest = \"make test\"\n",
+        )
+        .unwrap();
+        let edited = check(&atomic).unwrap();
+        assert_eq!(
+            notice("atomic.toml", &first, &edited).unwrap(),
+            "atomic.toml changed since last run: 1 command added, 1 modified \
+             (`atomic --doctor` checks it)"
+        );
+        // the other config keeps its own snapshot
+        assert!(load(&nested).is_none());
+    }
+}
@@ -0,0 +1,220 @@
+//! Runs command steps inside a docker or podman container when a command table sets `container`.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::{AtomicError, Result};
+
+/// Where the project root is mounted inside the container, and the working directory there.
+pub const CONTAINER_WORKDIR: &str = "/work";
+
+/// Runtimes tried, in order, when `[atomic] container_runtime` isn't set.
+const RUNTIMES: [&str; 2] = ["docker", "podman"];
+
+/// A container image to run steps in, and the runtime binary that launches it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Container {
+    pub runtime: String,
+    pub image: String,
+}
+
+impl Container {
+    /// Resolves the runtime for `image`: `configured` if given, otherwise docker, then podman.
+    ///
+    /// # Errors
+    /// Returns an error if the runtime can't be started, so nothing runs without it.
+    pub fn new(image: &str, configured: Option<&str>) -> Result<Self> {
+        let runtime = match configured {
+            Some(runtime) if runtime_available(runtime) => runtime.to_string(),
+            Some(runtime) => {
+                return Err(AtomicError::Generic(format!(
+                    "Container runtime '{}' from [atomic] container_runtime was not found on PATH",
+                    runtime
+                )))
+            }
+            None => RUNTIMES
+                .into_iter()
+                .find(|runtime| runtime_available(runtime))
+                .ok_or(AtomicError::Static(
+                    "`container` needs docker or podman on PATH, or [atomic] container_runtime",
+                ))?
+                .to_string(),
+        };
+
+        Ok(Self {
+            runtime,
+            image: image.to_string(),
+        })
+    }
+
+    /// The argv that runs `cmd` with `sh -c` in this container, with the project mounted at
+    /// [`CONTAINER_WORKDIR`] and `env` forwarded.
+    ///
+    /// Env values that are paths inside `project_root` are rewritten to their container paths.
+    pub fn argv(&self, cmd: &str, project_root: &Path, env: &[(String, String)]) -> Vec<String> {
+        let mut argv = vec![
+            self.runtime.clone(),
+            "run".to_string(),
+            "--rm".to_string(),
+            "-v".to_string(),
+            format!("{}:{}", project_root.display(), CONTAINER_WORKDIR),
+            "-w".to_string(),
+            CONTAINER_WORKDIR.to_string(),
+        ];
+        for (key, value) in env {
+            argv.push("-e".to_string());
+            argv.push(format!("{}={}", key, container_path(value, project_root)));
+        }
+        argv.extend([
+            self.image.clone(),
+            "sh".to_string(),
+            "-c".to_string(),
+            cmd.to_string(),
+        ]);
+        argv
+    }
+}
+
+/// Maps a host path under `project_root` to where it is mounted; anything else is unchanged.
+fn container_path(value: &str, project_root: &Path) -> String {
+    if project_root.as_os_str().is_empty() {
+        return value.to_string();
+    }
+    match Path::new(value).strip_prefix(project_root) {
+        Ok(rest) if rest.as_os_str().is_empty() => CONTAINER_WORKDIR.to_string(),
+        Ok(rest) => format!(
+            "{}/{}",
+            CONTAINER_WORKDIR,
+            rest.to_string_lossy().replace('\\', "/")
+        ),
+        Err(_) => value.to_string(),
+    }
+}
+
+fn runtime_available(runtime: &str) -> bool {
+    Command::new(runtime)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn container() -> Container {
+        Container {
+            runtime: "docker".to_string(),
+            image: "rust:1.78".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_argv() {
+        let root = PathBuf::from("/home/me/project");
+        assert_eq!(
+            container().argv("cargo build && cargo test", &root, &[]),
+            vec![
+                "docker",
+                "run",
+                "--rm",
+                "-v",
+                "/home/me/project:/work",
+                "-w",
+                "/work",
+                "rust:1.78",
+                "sh",
+                "-c",
+                "cargo build && cargo test",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_argv_forwards_env() {
+        let root = PathBuf::from("/home/me/project");
+        let env = [
+            (
+                "ATOMIC_CONFIG_PATH".to_string(),
+                "/home/me/project/atomic.toml".to_string(),
+            ),
+            (
+                "ATOMIC_PROJECT_ROOT".to_string(),
+                "/home/me/project".to_string(),
+            ),
+            ("ATOMIC_BRANCH".to_string(), "feature-7-x".to_string()),
+            ("ATOMIC_ISSUE".to_string(), String::new()),
+        ];
+
+        let podman = Container {
+            runtime: "podman".to_string(),
+            ..container()
+        };
+        let argv = podman.argv("make", &root, &env);
+        assert_eq!(argv[0], "podman");
+        assert_eq!(
+            argv[7..15],
+            [
+                "-e",
+                "ATOMIC_CONFIG_PATH=/work/atomic.toml",
+                "-e",
+                "ATOMIC_PROJECT_ROOT=/work",
+                "-e",
+                "ATOMIC_BRANCH=feature-7-x",
+                "-e",
+                "ATOMIC_ISSUE=",
+            ]
+        );
+        assert_eq!(argv[15..], ["rust:1.78", "sh", "-c", "make"]);
+    }
+
+    #[test]
+    fn test_container_path() {
+        let root = Path::new("/home/me/project");
+        assert_eq!(container_path("/home/me/project", root), "/work");
+        assert_eq!(
+            container_path("/home/me/project/sub/dir", root),
+            "/work/sub/dir"
+        );
+        assert_eq!(
+            container_path("/home/me/projects", root),
+            "/home/me/projects"
+        );
+        assert_eq!(container_path("main", root), "main");
+        assert_eq!(container_path("/anything", Path::new("")), "/anything");
+    }
+
+    #[test]
+    fn test_missing_runtime() {
+        assert_eq!(
+            Container::new("rust:1.78", Some("definitely-not-a-runtime")),
+            Err(AtomicError::Generic(
+                "Container runtime 'definitely-not-a-runtime' from [atomic] container_runtime \
+                 was not found on PATH"
+                    .to_string()
+            ))
+        );
+    }
+
+    /// Needs a working docker or podman; set ATOMIC_CONTAINER_TESTS=1 to run it.
+    #[test]
+    fn test_container_round_trip() {
+        if std::env::var_os("ATOMIC_CONTAINER_TESTS").is_none() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let container = Container::new("alpine:3", None).unwrap();
+        let argv = container.argv("echo inside > out.txt && exit 3", dir.path(), &[]);
+
+        let status = Command::new(&argv[0]).args(&argv[1..]).status().unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("out.txt")).unwrap(),
+            "inside\n"
+        );
+    }
+}
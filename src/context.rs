@@ -0,0 +1,160 @@
+//! `atomic --context`: where atomic is and what it would work with, for shell prompts and
+//! scripts. Nothing runs and nothing is printed along the way, and the config is only parsed,
+//! not checked against the schema, so it stays fast enough for a prompt hook.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::config::AtomicConfig;
+use crate::git::{branch_issue, current_branch, has_changes, open_repository};
+use crate::toml::load_toml;
+use crate::Result;
+
+/// The project atomic would run in.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Context {
+    /// The directory holding the config.
+    pub root: PathBuf,
+    pub config: PathBuf,
+    /// `None` when detached, outside a repository, or with git turned off.
+    pub branch: Option<String>,
+    pub issue: Option<u64>,
+    pub profile: Option<String>,
+    /// Commands the config defines, after the profile and the layers above it are applied.
+    pub commands: usize,
+    /// Whether the work tree has uncommitted changes; `None` without a repository.
+    pub dirty: Option<bool>,
+}
+
+impl Context {
+    /// The context of the config at `atomic`, or `None` when there's no config there. `repo` is
+    /// `--repo`, and `git` false leaves the repository alone.
+    ///
+    /// # Errors
+    /// Returns an error if the config can't be read or parsed.
+    pub fn resolve(
+        atomic: &Path,
+        profile: Option<&str>,
+        repo: Option<&Path>,
+        git: bool,
+    ) -> Result<Option<Self>> {
+        if !atomic.is_file() {
+            return Ok(None);
+        }
+        let parsed = AtomicConfig::from_value(load_toml(atomic, profile)?)?;
+        let git = git && parsed.atomic.git != Some(false);
+        let commands = parsed.command_names().len();
+        let config = fs::canonicalize(atomic).unwrap_or_else(|_| atomic.to_path_buf());
+        let root = config.parent().map(Path::to_path_buf).unwrap_or_default();
+
+        let repository = match git {
+            true => open_repository(repo).ok(),
+            false => None,
+        };
+        let branch = repository.as_ref().and_then(current_branch);
+        Ok(Some(Self {
+            root,
+            config,
+            issue: branch.as_deref().and_then(branch_issue),
+            branch,
+            profile: profile.map(str::to_string),
+            commands,
+            dirty: repository.as_ref().and_then(|repo| has_changes(repo).ok()),
+        }))
+    }
+
+    /// `key=value` lines, in a fixed order, with an empty value for what isn't known.
+    pub fn render(&self) -> String {
+        let or_empty = |value: Option<String>| value.unwrap_or_default();
+        [
+            ("root", self.root.display().to_string()),
+            ("config", self.config.display().to_string()),
+            ("branch", or_empty(self.branch.clone())),
+            ("issue", or_empty(self.issue.map(|issue| issue.to_string()))),
+            ("profile", or_empty(self.profile.clone())),
+            ("commands", self.commands.to_string()),
+            ("dirty", or_empty(self.dirty.map(|dirty| dirty.to_string()))),
+        ]
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::init_repo;
+
+    #[test]
+    fn test_outside_a_project() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        assert_eq!(Context::resolve(&atomic, None, None, false), Ok(None));
+
+        fs::write(&atomic, "[custom\n").unwrap();
+        assert!(Context::resolve(&atomic, None, None, false).is_err());
+    }
+
+    #[test]
+    fn test_inside_a_project() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let atomic = root.join("atomic.toml");
+        fs::write(
+            &atomic,
+            "[default]\nfmt = \"cargo fmt\"\n\n\
+             [custom]\nfmt = \"cargo fmt --all\"\ntest = \"cargo test\"\n\n\
+             [profile.ci.custom]\nlint = \"cargo clippy\"\n",
+        )
+        .unwrap();
+
+        let repo = init_repo(&root, &[]);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        repo.branch("feature-42-prompt", &head, false).unwrap();
+        repo.set_head("refs/heads/feature-42-prompt").unwrap();
+
+        let context = Context::resolve(&atomic, Some("ci"), Some(&root), true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            context.render(),
+            format!(
+                "root={0}\nconfig={0}/atomic.toml\nbranch=feature-42-prompt\nissue=42\n\
+                 profile=ci\ncommands=3\ndirty=false\n",
+                root.display()
+            )
+        );
+        assert_eq!(
+            serde_json::to_value(&context).unwrap(),
+            serde_json::json!({
+                "root": root,
+                "config": atomic,
+                "branch": "feature-42-prompt",
+                "issue": 42,
+                "profile": "ci",
+                "commands": 3,
+                "dirty": false,
+            })
+        );
+
+        fs::write(root.join("notes.md"), "wip\n").unwrap();
+        let dirty = Context::resolve(&atomic, None, Some(&root), true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(dirty.dirty, Some(true));
+        assert_eq!(dirty.commands, 2);
+
+        // without git nothing about the repository is known
+        let no_git = Context::resolve(&atomic, None, Some(&root), false)
+            .unwrap()
+            .unwrap();
+        assert!(no_git
+            .render()
+            .ends_with("branch=\nissue=\nprofile=\ncommands=2\ndirty=\n"));
+    }
+}
@@ -0,0 +1,450 @@
+//! `atomic --doctor`: independent checks for the setup problems that usually break atomic.
+
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use git2::{BranchType, Repository};
+use toml::Value;
+
+use crate::command::cargo_json_warning;
+use crate::config::{section_label, AtomicConfig, Settings};
+use crate::git::{branch_issue, commit_signature, current_branch, open_repository};
+use crate::toml::load_and_validate_toml;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        })
+    }
+}
+
+/// The outcome of one doctor check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// How to fix a warning or failure.
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Header identifying the build, for pasting into bug reports.
+pub fn header() -> String {
+    format!(
+        "atomic {} on {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )
+}
+
+/// Runs every check against the project at `atomic` and the repository from `--repo`, or the
+/// current one.
+pub fn run_checks(atomic: &Path, repo: Option<&Path>, profile: Option<&str>) -> Vec<CheckResult> {
+    let mut results = vec![check_git_installed()];
+
+    let (config_check, config) = check_config(atomic, profile);
+    let shadowing_check = config.clone().map(check_shadowing);
+    let cargo_json_check = config.clone().and_then(check_cargo_json);
+    let commit_author = config
+        .as_ref()
+        .and_then(|config| Settings::from_config(config).ok()?.commit_author)
+        .filter(|author| !author.is_empty());
+
+    match open_repository(repo) {
+        Ok(repo) => {
+            results.push(CheckResult::pass(
+                "git repository",
+                repo.workdir()
+                    .unwrap_or_else(|| repo.path())
+                    .display()
+                    .to_string(),
+            ));
+            results.push(match repo.config() {
+                Ok(git_config) => check_identity(&git_config, commit_author.as_deref()),
+                Err(err) => CheckResult::fail(
+                    "git identity",
+                    format!("can't read git config: {}", err),
+                    "check that .git/config and ~/.gitconfig are readable",
+                ),
+            });
+            results.push(config_check);
+            results.push(check_remote(&repo));
+            results.push(check_branch(&repo));
+        }
+        Err(_) => {
+            results.push(CheckResult::fail(
+                "git repository",
+                "the current directory is not inside a git repository",
+                "run atomic from your project, or `git init` to start one",
+            ));
+            results.push(config_check);
+        }
+    }
+    results.extend(shadowing_check);
+    results.extend(cargo_json_check);
+    results
+}
+
+/// Renders results one per line, with hints indented underneath.
+pub fn render(results: &[CheckResult]) -> String {
+    let mut out = String::new();
+    for result in results {
+        out.push_str(&format!(
+            "[{}] {}: {}\n",
+            result.status, result.name, result.detail
+        ));
+        if let Some(hint) = &result.hint {
+            out.push_str(&format!("       {}\n", hint));
+        }
+    }
+    out
+}
+
+/// Only hard failures should make `--doctor` exit non-zero.
+pub fn has_failures(results: &[CheckResult]) -> bool {
+    results.iter().any(|result| result.status == Status::Fail)
+}
+
+/// atomic commits through libgit2, but hooks and most commands still shell out to `git`.
+pub fn check_git_installed() -> CheckResult {
+    match Command::new("git").arg("--version").output() {
+        Ok(output) if output.status.success() => CheckResult::pass(
+            "git",
+            String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        ),
+        _ => CheckResult::warn(
+            "git",
+            "`git` was not found on PATH",
+            "install git; hooks and commands that call git will fail without it",
+        ),
+    }
+}
+
+/// atomic.toml must exist and pass validation. Returns the loaded config when it does.
+pub fn check_config(atomic: &Path, profile: Option<&str>) -> (CheckResult, Option<Value>) {
+    match load_and_validate_toml(atomic, profile) {
+        Ok(config) => {
+            let detail = match profile {
+                Some(profile) => format!("{} is valid (profile: {})", atomic.display(), profile),
+                None => format!("{} is valid", atomic.display()),
+            };
+            (CheckResult::pass("atomic.toml", detail), Some(config))
+        }
+        Err(err) => (
+            CheckResult::fail(
+                "atomic.toml",
+                err.to_string(),
+                "fix the problems above; `atomic --schema` can feed your editor's TOML validation",
+            ),
+            None,
+        ),
+    }
+}
+
+/// A command name defined in several sections only runs from the first of `[custom]`,
+/// `[default]` and the top level, unless it's qualified as in `default:build`.
+pub fn check_shadowing(config: Value) -> CheckResult {
+    let config = match AtomicConfig::from_value(config) {
+        Ok(config) => config,
+        Err(err) => {
+            return CheckResult::fail("command names", err.to_string(), "fix the problems above")
+        }
+    };
+    let shadowed: Vec<String> = config
+        .command_names()
+        .into_iter()
+        .filter_map(|name| {
+            let shadowed = config.shadowed(&name);
+            let winner = config.section_of(&name).filter(|_| !shadowed.is_empty())?;
+            let losers: Vec<String> = shadowed.iter().map(|s| section_label(s)).collect();
+            Some(format!(
+                "'{}' from {} shadows {}",
+                name,
+                section_label(winner),
+                losers.join(" and ")
+            ))
+        })
+        .collect();
+    if shadowed.is_empty() {
+        return CheckResult::pass("command names", "each command is defined once");
+    }
+    CheckResult::warn(
+        "command names",
+        shadowed.join("; "),
+        "rename one, or qualify the name where you mean another, e.g. `default:build`",
+    )
+}
+
+/// Auto-commits need an author from git config or `[atomic] commit_author`.
+pub fn check_identity(config: &git2::Config, commit_author: Option<&str>) -> CheckResult {
+    match commit_signature(config, commit_author) {
+        Ok(user) => CheckResult::pass(
+            "git identity",
+            format!(
+                "{} <{}>",
+                user.name().unwrap_or_default(),
+                user.email().unwrap_or_default()
+            ),
+        ),
+        Err(err) => {
+            let detail = err.to_string();
+            let (detail, hint) = detail.split_once('\n').unwrap_or((&detail, ""));
+            CheckResult::fail("git identity", detail, hint)
+        }
+    }
+}
+
+/// A missing `origin` or upstream only matters once you push, so both are warnings.
+pub fn check_remote(repo: &Repository) -> CheckResult {
+    if repo.find_remote("origin").is_err() {
+        return CheckResult::warn(
+            "remote",
+            "no `origin` remote",
+            "add one with `git remote add origin <url>`",
+        );
+    }
+
+    let Some(branch) = current_branch(repo) else {
+        return CheckResult::warn(
+            "remote",
+            "HEAD is detached, so there is no upstream to check",
+            "check out a branch",
+        );
+    };
+    let upstream = repo
+        .find_branch(&branch, BranchType::Local)
+        .and_then(|local| local.upstream());
+    match upstream {
+        Ok(upstream) => CheckResult::pass(
+            "remote",
+            format!(
+                "{} tracks {}",
+                branch,
+                upstream.name().ok().flatten().unwrap_or("its upstream")
+            ),
+        ),
+        Err(_) => CheckResult::warn(
+            "remote",
+            format!("{} has no upstream branch", branch),
+            format!("push it with `git push -u origin {}`", branch),
+        ),
+    }
+}
+
+/// Branches named like `feature-144-description` give commands `ATOMIC_ISSUE`.
+pub fn check_branch(repo: &Repository) -> CheckResult {
+    match current_branch(repo) {
+        Some(branch) => match branch_issue(&branch) {
+            Some(issue) => CheckResult::pass("branch", format!("{} (issue #{})", branch, issue)),
+            None => CheckResult::warn(
+                "branch",
+                format!(
+                    "{} has no issue number, so ATOMIC_ISSUE will be empty",
+                    branch
+                ),
+                "name branches like `feature-144-description` to set it",
+            ),
+        },
+        None => CheckResult::warn(
+            "branch",
+            "HEAD is detached",
+            "check out a branch before running commands that auto-commit",
+        ),
+    }
+}
+
+/// `cargo_json` only does something for a single cargo build, check, clippy, test, run or doc.
+/// `None` when no command sets it.
+pub fn check_cargo_json(config: Value) -> Option<CheckResult> {
+    let config = AtomicConfig::from_value(config).ok()?;
+    let names: Vec<String> = config
+        .command_names()
+        .into_iter()
+        .filter(|name| config.command(name).is_some_and(|spec| spec.cargo_json()))
+        .collect();
+    if names.is_empty() {
+        return None;
+    }
+    let warnings: Vec<String> = names
+        .iter()
+        .filter_map(|name| cargo_json_warning(&config, name, config.command(name)?))
+        .collect();
+    Some(match warnings.is_empty() {
+        true => CheckResult::pass("cargo_json", format!("set for {}", names.join(", "))),
+        false => CheckResult::warn(
+            "cargo_json",
+            warnings.join("; "),
+            "remove cargo_json there, or make the command a plain `cargo <subcommand>`",
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_check_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+
+        let (result, config) = check_config(&atomic, None);
+        assert_eq!(result.status, Status::Fail);
+        assert!(result.detail.contains("not found"), "{}", result.detail);
+        assert!(config.is_none());
+
+        fs::write(&atomic, "[atomic]\nauto_commit = \"yes\"\n").unwrap();
+        let (result, _) = check_config(&atomic, None);
+        assert_eq!(result.status, Status::Fail);
+        assert!(result.detail.contains("expected a boolean"));
+
+        fs::write(&atomic, "[default]\ncheck = \"cargo check\"\n").unwrap();
+        let (result, config) = check_config(&atomic, None);
+        assert_eq!(result.status, Status::Pass);
+        assert!(config.is_some());
+    }
+
+    #[test]
+    fn test_check_shadowing() {
+        let config = |text: &str| ::toml::from_str::<Value>(text).unwrap();
+        let result = check_shadowing(config("build = \"make\"\n[custom]\nlint = \"clippy\"\n"));
+        assert_eq!(result.status, Status::Pass);
+
+        let result = check_shadowing(config(
+            "build = \"make\"\n[default]\nbuild = \"cargo build\"\n[custom]\nbuild = \"x\"\n",
+        ));
+        assert_eq!(result.status, Status::Warn);
+        assert_eq!(
+            result.detail,
+            "'build' from [custom] shadows [default] and the top level"
+        );
+    }
+
+    #[test]
+    fn test_check_cargo_json() {
+        let config = |text: &str| ::toml::from_str::<Value>(text).unwrap();
+        assert_eq!(check_cargo_json(config("build = \"cargo build\"\n")), None);
+
+        let result = check_cargo_json(config(
+            "[custom.build]\ncommand = \"cargo build\"\ncargo_json = true\n",
+        ))
+        .unwrap();
+        assert_eq!(result.status, Status::Pass);
+        assert_eq!(result.detail, "set for build");
+
+        let result = check_cargo_json(config(
+            "[custom.lint]\ncommand = \"npm run lint\"\ncargo_json = true\n",
+        ))
+        .unwrap();
+        assert_eq!(result.status, Status::Warn);
+        assert!(result.detail.starts_with("'lint' sets cargo_json"));
+    }
+
+    #[test]
+    fn test_check_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = git2::Config::open(&dir.path().join("config")).unwrap();
+
+        let result = check_identity(&config, None);
+        assert_eq!(result.status, Status::Fail);
+        assert_eq!(
+            result.detail,
+            "git user.name is not set, so atomic can't auto-commit."
+        );
+        assert!(result
+            .hint
+            .unwrap()
+            .contains("git config --global user.name"));
+
+        let result = check_identity(&config, Some("CI Bot <ci@example.com>"));
+        assert_eq!(
+            result,
+            CheckResult::pass("git identity", "CI Bot <ci@example.com>")
+        );
+    }
+
+    #[test]
+    fn test_check_remote_and_branch() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        repo.set_head("refs/heads/feature-144-dark-mode").unwrap();
+
+        assert_eq!(check_remote(&repo).status, Status::Warn);
+        assert_eq!(
+            check_branch(&repo),
+            CheckResult::pass("branch", "feature-144-dark-mode (issue #144)")
+        );
+
+        repo.remote("origin", "https://example.com/atomic.git")
+            .unwrap();
+        let result = check_remote(&repo);
+        assert_eq!(result.status, Status::Warn);
+        assert_eq!(
+            result.detail,
+            "feature-144-dark-mode has no upstream branch"
+        );
+
+        repo.set_head("refs/heads/main").unwrap();
+        assert_eq!(check_branch(&repo).status, Status::Warn);
+    }
+
+    #[test]
+    fn test_render_and_failures() {
+        let results = vec![
+            CheckResult::pass("git", "git version 2.45.0"),
+            CheckResult::warn("remote", "no `origin` remote", "add one"),
+        ];
+        assert_eq!(
+            render(&results),
+            "[pass] git: git version 2.45.0\n[warn] remote: no `origin` remote\n       add one\n"
+        );
+        assert!(!has_failures(&results));
+        assert!(has_failures(&[CheckResult::fail(
+            "git repository",
+            "none",
+            "git init"
+        )]));
+    }
+}
@@ -0,0 +1,246 @@
+//! `.env` files: variables loaded from `[atomic] env_files` and a command's `env_file`, and
+//! passed to every step along with atomic's own.
+//!
+//! The format is the common subset of dotenv: `KEY=VALUE` lines, `#` comments, an optional
+//! `export ` prefix, and single- or double-quoted values. Double quotes take `\n`, `\t`, `\r`,
+//! `\"`, `\\` and `\$` escapes; single quotes keep everything as written.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::{AtomicError, Result};
+
+/// Read when `[atomic] env_files` isn't set, if they exist.
+pub const DEFAULT_FILES: &[&str] = &[".env", ".env.local"];
+
+/// What's shown instead of a value from an env file, so secrets stay out of the output.
+pub const HIDDEN: &str = "<set>";
+
+/// The variables in `text`, in order, read from the file called `name`.
+///
+/// # Errors
+/// Returns [`AtomicError::EnvFile`] with the line number of the first line that can't be read.
+pub fn parse(text: &str, name: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line).trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let var = parse_line(line)
+            .map_err(|message| AtomicError::EnvFile(name.to_string(), i + 1, message))?;
+        set(&mut vars, var);
+    }
+    Ok(vars)
+}
+
+fn parse_line(line: &str) -> std::result::Result<(String, String), String> {
+    let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+    let Some((key, value)) = line.split_once('=') else {
+        return Err(format!("expected KEY=VALUE, found '{}'", line));
+    };
+    let key = key.trim_end();
+    let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    if !valid {
+        return Err(format!("'{}' is not a variable name", key));
+    }
+
+    let value = value.trim_start();
+    let (value, rest) = match value.chars().next() {
+        Some('"') => double_quoted(&value[1..])?,
+        Some('\'') => {
+            let end = value[1..]
+                .find('\'')
+                .ok_or_else(|| "missing closing '".to_string())?;
+            (value[1..=end].to_string(), &value[end + 2..])
+        }
+        // a comment needs a space before it, so `a#b` keeps its `#`
+        _ => match value.find(" #").or_else(|| value.find("\t#")) {
+            Some(at) => (value[..at].trim_end().to_string(), ""),
+            None => (value.trim_end().to_string(), ""),
+        },
+    };
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected '{}' after the closing quote", rest));
+    }
+    Ok((key.to_string(), value))
+}
+
+/// The value of a double-quoted string whose opening quote is already gone, and what follows it.
+fn double_quoted(text: &str) -> std::result::Result<(String, &str), String> {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &text[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                Some(c) => return Err(format!("unknown escape '\\{}'", c)),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    Err("missing closing \"".to_string())
+}
+
+/// Sets `key` to `value` in `vars`, replacing an earlier value.
+pub fn set(vars: &mut Vec<(String, String)>, (key, value): (String, String)) {
+    match vars.iter_mut().find(|(existing, _)| *existing == key) {
+        Some(var) => var.1 = value,
+        None => vars.push((key, value)),
+    }
+}
+
+/// The variables in `files`, relative to `root`, later files overriding earlier ones. Without
+/// `files`, [`DEFAULT_FILES`] are read, skipping those that don't exist.
+///
+/// # Errors
+/// Returns an error if a file given in `files` is missing, or any file can't be read or parsed.
+pub fn load(root: &Path, files: Option<&[String]>) -> Result<Vec<(String, String)>> {
+    let defaults: Vec<String> = DEFAULT_FILES.iter().map(|f| f.to_string()).collect();
+    let (files, required) = match files {
+        Some(files) => (files, true),
+        None => (defaults.as_slice(), false),
+    };
+    let mut vars = Vec::new();
+    for file in files {
+        for var in load_file(root, file, required)? {
+            set(&mut vars, var);
+        }
+    }
+    Ok(vars)
+}
+
+/// The variables in `file`, relative to `root`; none when it's missing and not `required`.
+/// Names starting with `ATOMIC_` are left out, since atomic sets those itself.
+///
+/// # Errors
+/// See [`load`].
+pub fn load_file(root: &Path, file: &str, required: bool) -> Result<Vec<(String, String)>> {
+    let path = root.join(file);
+    match fs::read_to_string(&path) {
+        Ok(text) => Ok(parse(&text, file)?
+            .into_iter()
+            .filter(|(key, _)| !key.starts_with("ATOMIC_"))
+            .collect()),
+        Err(err) if err.kind() == ErrorKind::NotFound && !required => Ok(Vec::new()),
+        Err(err) => Err(AtomicError::Generic(format!(
+            "Failed to read env file {}: {}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(text: &str) -> Vec<(String, String)> {
+        parse(text, ".env").unwrap()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = "# database\n\
+                    DATABASE_URL=postgres://localhost/app\n\
+                    \n\
+                    export API_KEY = abc123  # from the dashboard\n\
+                    EMPTY=\n\
+                    HASH=a#b\n\
+                    app.mode=dev\n";
+        assert_eq!(
+            vars(text),
+            pairs(&[
+                ("DATABASE_URL", "postgres://localhost/app"),
+                ("API_KEY", "abc123"),
+                ("EMPTY", ""),
+                ("HASH", "a#b"),
+                ("app.mode", "dev"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_quotes() {
+        let text = r#"GREETING="hello \"world\"\n\tbye"
+SINGLE='no $escapes\n here' # comment
+PRICE="\$5 and \\ back"
+SPACES="  kept  "
+"#;
+        assert_eq!(
+            vars(text),
+            pairs(&[
+                ("GREETING", "hello \"world\"\n\tbye"),
+                ("SINGLE", "no $escapes\\n here"),
+                ("PRICE", "$5 and \\ back"),
+                ("SPACES", "  kept  "),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_crlf_and_overrides() {
+        assert_eq!(
+            vars("A=1\r\nB='two'\r\n\r\nA=3\r\n"),
+            pairs(&[("A", "3"), ("B", "two")])
+        );
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        let error = |text: &str| parse(text, ".env.local").unwrap_err().to_string();
+        assert_eq!(
+            error("A=1\n\njust words\n"),
+            ".env.local:3: expected KEY=VALUE, found 'just words'"
+        );
+        assert_eq!(
+            error("1ABC=x"),
+            ".env.local:1: '1ABC' is not a variable name"
+        );
+        assert_eq!(error("A=\"open"), ".env.local:1: missing closing \"");
+        assert_eq!(error("A='open"), ".env.local:1: missing closing '");
+        assert_eq!(
+            error("A=\"x\" y"),
+            ".env.local:1: unexpected 'y' after the closing quote"
+        );
+        assert_eq!(error("A=\"\\q\""), ".env.local:1: unknown escape '\\q'");
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(load(dir.path(), None).unwrap(), Vec::new());
+
+        fs::write(dir.path().join(".env"), "A=1\nB=2\n").unwrap();
+        fs::write(dir.path().join(".env.local"), "B=local\nATOMIC_DEPTH=9\n").unwrap();
+        assert_eq!(
+            load(dir.path(), None).unwrap(),
+            pairs(&[("A", "1"), ("B", "local")])
+        );
+
+        let files = [".env".to_string(), ".env.test".to_string()];
+        let err = load(dir.path(), Some(&files)).unwrap_err();
+        assert!(err.to_string().contains(".env.test"), "{}", err);
+        assert_eq!(
+            load(dir.path(), Some(&files[..1])).unwrap(),
+            pairs(&[("A", "1"), ("B", "2")])
+        );
+    }
+}
@@ -0,0 +1,628 @@
+//! Stable codes for atomic's errors, the one place they're printed, and `--explain-error`.
+//!
+//! Every [`AtomicError`] variant has a code like `ATM010`, printed as `error[ATM010]: ...` with a
+//! hint pointing at `atomic --explain-error ATM010`, which prints the longer text kept in
+//! [`EXPLANATIONS`]. Codes never change meaning once released: retire a code rather than reuse it.
+//!
+//! Codes are grouped by what went wrong: `ATM00x` the config, `ATM01x` running commands, `ATM03x`
+//! git, and `ATM09x` everything else.
+
+use std::cell::Cell;
+use std::io::IsTerminal;
+
+use crate::ci::Interactivity;
+use crate::AtomicError;
+
+impl AtomicError {
+    /// The error's stable code; see [`EXPLANATIONS`].
+    pub fn code(&self) -> &'static str {
+        match self {
+            AtomicError::ConfigNotFound(_) => "ATM001",
+            AtomicError::TomlParse(..) => "ATM002",
+            AtomicError::YamlParse(..) => "ATM003",
+            AtomicError::Validation(_) => "ATM004",
+            AtomicError::InvalidSetting(..) => "ATM005",
+            AtomicError::EnvFile(..) => "ATM006",
+            AtomicError::InvalidStdinCommand(_) => "ATM007",
+            AtomicError::ProfileNotFound(_) => "ATM008",
+            AtomicError::AmbiguousConfig(_) => "ATM009",
+            AtomicError::CommandNotFound(..) => "ATM010",
+            AtomicError::Aborted(_) => "ATM011",
+            AtomicError::NeedsCycle(_) => "ATM012",
+            AtomicError::UnknownNeed(..) => "ATM013",
+            AtomicError::ConfirmInCi(_) => "ATM014",
+            AtomicError::Locked(..) => "ATM015",
+            AtomicError::TooDeep(_) => "ATM016",
+            AtomicError::NoLastRun => "ATM017",
+            AtomicError::NestedChain(_) => "ATM018",
+            AtomicError::MissingGitIdentity(..) => "ATM031",
+            AtomicError::InvalidCommitAuthor(_) => "ATM032",
+            AtomicError::LargeFiles(..) => "ATM033",
+            AtomicError::Signing(_) => "ATM034",
+            AtomicError::GitError(_) => "ATM035",
+            AtomicError::Generic(_) => "ATM090",
+            AtomicError::Static(_) => "ATM091",
+            AtomicError::ParseInt(_) => "ATM092",
+            AtomicError::UnclosedGitignoreBlock => "ATM093",
+        }
+    }
+}
+
+/// What `atomic --explain-error <code>` prints for one code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Explanation {
+    pub code: &'static str,
+    pub title: &'static str,
+    /// What causes the error and how it's usually fixed.
+    pub text: &'static str,
+}
+
+/// Every code, in order.
+pub const EXPLANATIONS: &[Explanation] = &[
+    Explanation {
+        code: "ATM001",
+        title: "config not found",
+        text: "\
+atomic looked for atomic.toml (or atomic.yaml) in the current directory and the directories
+above it, up to the root of the repository, and found none.
+
+Common fixes:
+  - run `atomic --init` to create one, optionally from a template with `--from`
+  - run atomic from inside the project, or point it there with `--repo <path>`",
+    },
+    Explanation {
+        code: "ATM002",
+        title: "config is not valid TOML",
+        text: "\
+atomic.toml couldn't be parsed. The message gives the line and column where parsing stopped.
+
+Common fixes:
+  - quote strings: `build = \"cargo build\"`, not `build = cargo build`
+  - close every `[section]` bracket and every array
+  - define each key once per table",
+    },
+    Explanation {
+        code: "ATM003",
+        title: "config is not valid YAML",
+        text: "\
+atomic.yaml couldn't be parsed, or doesn't have the shape atomic.toml has: top-level sections
+holding commands that are strings, lists of steps, or tables.
+
+Common fixes:
+  - indent with spaces, consistently
+  - quote commands that contain `:` or start with a special character
+  - run `atomic --migrate-config` to see how the file reads as TOML",
+    },
+    Explanation {
+        code: "ATM004",
+        title: "config doesn't match the schema",
+        text: "\
+atomic.toml parsed, but some keys have the wrong type, are missing, or have a value atomic can't
+use. Every problem is listed with the key it's under. Unknown keys and sections are only
+warnings, and don't cause this.
+
+Common fixes:
+  - check the spelling of the key against `atomic --schema`
+  - run `atomic --check` to list every error and warning
+  - run `atomic --doctor` for the same checks with more context
+  - run `atomic --migrate-config` when the file was written for an older atomic",
+    },
+    Explanation {
+        code: "ATM005",
+        title: "invalid [atomic] setting",
+        text: "\
+A setting under [atomic] has a value of the wrong type, such as a string where a boolean is
+expected.
+
+Common fixes:
+  - write booleans without quotes: `git = false`
+  - check the setting in `atomic --schema`",
+    },
+    Explanation {
+        code: "ATM006",
+        title: "env file can't be read",
+        text: "\
+A file named by `env_file` is missing, or one of its lines isn't `KEY=value`. The message gives
+the file and line.
+
+Common fixes:
+  - create the file, or drop `env_file` from the command
+  - comment lines out with `#` rather than leaving stray text
+  - quote values that contain spaces",
+    },
+    Explanation {
+        code: "ATM007",
+        title: "invalid --stdin command",
+        text: "\
+The command table piped into `atomic --stdin` doesn't have the shape of a `[custom.<name>]`
+table: `command` is missing or empty, has the wrong type, or another key has a wrong value.
+
+Common fixes:
+  - give it a `command`: `command = \"cargo build\"`, or an array of steps
+  - write chain steps as strings or `{ run = \"...\", ignore_failure = true }` tables
+  - check the other keys against `atomic --schema`",
+    },
+    Explanation {
+        code: "ATM008",
+        title: "profile not found",
+        text: "\
+`--profile` or ATOMIC_PROFILE names a profile that atomic.toml doesn't define under
+[profile.<name>].
+
+Common fixes:
+  - check the spelling of the profile
+  - unset ATOMIC_PROFILE when it was left over from another project
+  - add a [profile.<name>] table with the commands it changes",
+    },
+    Explanation {
+        code: "ATM009",
+        title: "more than one config",
+        text: "\
+The directory has both atomic.toml and atomic.yaml, and atomic won't guess which one is meant.
+
+Common fixes:
+  - keep one of them; `atomic --migrate-config` shows how the YAML reads as TOML",
+    },
+    Explanation {
+        code: "ATM010",
+        title: "command not found",
+        text: "\
+The command given on the command line isn't defined in atomic.toml, or in the profile that's
+active. Similar names are suggested when there are any.
+
+Common fixes:
+  - run `atomic --list` to see the commands there are
+  - check `--profile` and ATOMIC_PROFILE, which add and replace commands
+  - define it under [custom]",
+    },
+    Explanation {
+        code: "ATM011",
+        title: "aborted by user",
+        text: "\
+A command with `confirm` set was answered with no, so it didn't run.
+
+Common fixes:
+  - answer yes, or type the command's name when it asks for that
+  - pass `--yes` to run it without asking, for example in scripts",
+    },
+    Explanation {
+        code: "ATM012",
+        title: "`needs` forms a cycle",
+        text: "\
+Commands list each other in `needs`, directly or through other commands, so there's no order
+to run them in. The message shows the loop.
+
+Common fixes:
+  - remove one of the `needs` entries in the loop
+  - move the shared steps into a command both can need",
+    },
+    Explanation {
+        code: "ATM013",
+        title: "`needs` names an unknown command",
+        text: "\
+A command's `needs` lists a command that atomic.toml doesn't define, with the profile applied.
+
+Common fixes:
+  - check the spelling; the message suggests close matches
+  - run `atomic --list` to see the commands there are
+  - define the command, or remove it from `needs`",
+    },
+    Explanation {
+        code: "ATM014",
+        title: "confirmation in CI mode",
+        text: "\
+The command, or one it needs, has `confirm` set, and CI mode never stops to ask, so it didn't
+run.
+
+Common fixes:
+  - pass `--yes` when the CI job really should run it
+  - run it outside CI, where atomic asks on the terminal",
+    },
+    Explanation {
+        code: "ATM015",
+        title: "command already running",
+        text: "\
+The command has `lock = true` and another atomic is running it in this project. The message
+names the process holding the lock.
+
+Common fixes:
+  - wait for the other run to finish, or leave out `--no-wait` to wait for it
+  - raise `lock_wait` on the command",
+    },
+    Explanation {
+        code: "ATM016",
+        title: "nested too deep",
+        text: "\
+atomic was started by a command atomic ran, five levels deep, which usually means commands
+call each other in a loop.
+
+Common fixes:
+  - look for a command whose steps run atomic with itself, or with a command that runs it back",
+    },
+    Explanation {
+        code: "ATM017",
+        title: "no run to repeat",
+        text: "\
+`--again` repeats the last command run in this project, and none has run here yet.
+
+Common fixes:
+  - run the command by name once; `--again` repeats it from then on",
+    },
+    Explanation {
+        code: "ATM018",
+        title: "chain step names a chain",
+        text: "\
+A step of a chain names another command that is itself a chain. Chains don't nest, so nothing
+in the chain ran.
+
+Common fixes:
+  - list the other chain's steps in this one instead
+  - move the other chain into `needs`, so it runs first",
+    },
+    Explanation {
+        code: "ATM031",
+        title: "git identity not set",
+        text: "\
+atomic auto-commits as the git user, and git has no user.name or user.email configured.
+
+Common fixes:
+  - `git config --global user.name \"Your Name\"` and the same for user.email
+  - set `commit_author = \"Name <email>\"` under [atomic]
+  - pass `--no-commit`, or set `auto_commit = false`, to skip the commit",
+    },
+    Explanation {
+        code: "ATM032",
+        title: "invalid commit_author",
+        text: "\
+`commit_author` under [atomic] has to look like `Name <email>`.
+
+Common fixes:
+  - write both parts: `commit_author = \"CI Bot <ci@example.com>\"`
+  - remove the setting to commit as the git user",
+    },
+    Explanation {
+        code: "ATM033",
+        title: "files over max_commit_file_size",
+        text: "\
+The auto-commit stopped because some changed files are larger than `max_commit_file_size`
+(10 MB unless set), which usually means build output or data that doesn't belong in git.
+
+Common fixes:
+  - add them to .gitignore
+  - track them with Git LFS
+  - set `large_files = \"skip\"` under [atomic] to commit everything else, or raise the limit",
+    },
+    Explanation {
+        code: "ATM034",
+        title: "signing failed",
+        text: "\
+git's `commit.gpgsign` is set, so atomic signs its auto-commits, and the signing program failed
+or couldn't be found.
+
+Common fixes:
+  - check `git commit -S` works on its own; atomic uses the same settings
+  - set user.signingkey, and gpg.format for SSH or X.509 keys
+  - pass `--no-sign` to commit unsigned this time",
+    },
+    Explanation {
+        code: "ATM035",
+        title: "git error",
+        text: "\
+libgit2 reported a problem with the repository, such as a missing HEAD, a locked index or a
+corrupt object.
+
+Common fixes:
+  - run `git status` to see whether git itself is happy with the repository
+  - remove a stale .git/index.lock left by a crashed git
+  - pass `--no-git`, or set `git = false`, to only run commands",
+    },
+    Explanation {
+        code: "ATM090",
+        title: "error",
+        text: "\
+Something went wrong that doesn't have its own code yet. The message says what.
+
+If it looks like a bug, please open an issue with the message and `atomic --version --verbose`.",
+    },
+    Explanation {
+        code: "ATM091",
+        title: "error",
+        text: "\
+An internal check failed, such as a repository without a current branch. The message says what.
+
+If it looks like a bug, please open an issue with the message and `atomic --version --verbose`.",
+    },
+    Explanation {
+        code: "ATM092",
+        title: "not a number",
+        text: "\
+A value that has to be a whole number, such as an issue number taken from the branch name,
+isn't one.
+
+Common fixes:
+  - check the value the message names",
+    },
+    Explanation {
+        code: "ATM093",
+        title: "unclosed .gitignore block",
+        text: "\
+.gitignore has the line atomic starts its managed block with, but not the line that ends it,
+so atomic can't tell which lines are its own.
+
+Common fixes:
+  - add `# --- end atomic ---` after atomic's lines, or remove the block's first line",
+    },
+];
+
+/// The explanation for `code`, in any case.
+pub fn explanation(code: &str) -> Option<&'static Explanation> {
+    EXPLANATIONS
+        .iter()
+        .find(|explanation| explanation.code.eq_ignore_ascii_case(code.trim()))
+}
+
+/// How errors are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Plain,
+    /// ANSI colors, for a terminal.
+    Color,
+    /// A `::error` workflow command first, so the code shows up as an annotation.
+    GithubActions,
+}
+
+impl Style {
+    /// Color on a terminal unless `NO_COLOR` is set, workflow commands under GitHub Actions, and
+    /// plain text otherwise.
+    pub fn detect(interactivity: Interactivity) -> Self {
+        match interactivity {
+            Interactivity::Ci {
+                github_actions: true,
+            } => Style::GithubActions,
+            Interactivity::Ci { .. } => Style::Plain,
+            Interactivity::Interactive
+                if std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none() =>
+            {
+                Style::Color
+            }
+            Interactivity::Interactive => Style::Plain,
+        }
+    }
+}
+
+/// `err` as printed: `error[ATM010]: <message>`, any further lines of the message as context,
+/// then a hint pointing at `--explain-error`.
+pub fn render(err: &AtomicError, style: Style) -> String {
+    let code = err.code();
+    let message = err.to_string();
+    let (first, context) = message.split_once('\n').unwrap_or((&message, ""));
+    let hint = format!("run `atomic --explain-error {}` for common fixes", code);
+    let mut out = String::new();
+    match style {
+        Style::Color => out.push_str(&format!(
+            "\x1b[1;31merror[{}]\x1b[0m\x1b[1m: {}\x1b[0m\n",
+            code, first
+        )),
+        Style::GithubActions => {
+            // the annotation holds the whole message; `%0A` is how workflow commands break lines
+            let escaped = message
+                .replace('%', "%25")
+                .replace('\r', "%0D")
+                .replace('\n', "%0A");
+            out.push_str(&format!("::error title=atomic {}::{}\n", code, escaped));
+            out.push_str(&format!("error[{}]: {}\n", code, first));
+        }
+        Style::Plain => out.push_str(&format!("error[{}]: {}\n", code, first)),
+    }
+    for line in context.lines() {
+        out.push_str(&format!("  {}\n", line));
+    }
+    match style {
+        Style::Color => out.push_str(&format!("  \x1b[1;36m= help\x1b[0m: {}\n", hint)),
+        _ => out.push_str(&format!("  = help: {}\n", hint)),
+    }
+    out
+}
+
+thread_local! {
+    static STYLE: Cell<Style> = const { Cell::new(Style::Plain) };
+}
+
+/// Prints errors in `style` from now on; they're plain until this is called.
+pub fn set_style(style: Style) {
+    STYLE.with(|current| current.set(style));
+}
+
+/// Prints `err` to stderr; see [`set_style`].
+pub fn report(err: &AtomicError) {
+    eprint!("{}", render(err, STYLE.with(Cell::get)));
+}
+
+/// `atomic --explain-error <code>`: the explanation, or the known codes when `code` isn't one.
+/// Returns the exit code.
+pub fn explain_error(code: &str) -> i32 {
+    match explanation(code) {
+        Some(explanation) => {
+            println!(
+                "{}: {}\n\n{}",
+                explanation.code, explanation.title, explanation.text
+            );
+            0
+        }
+        None => {
+            eprintln!("unknown error code '{}'; known codes:", code);
+            for explanation in EXPLANATIONS {
+                eprintln!("  {}  {}", explanation.code, explanation.title);
+            }
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One error of every variant. The match has no wildcard, so a new variant doesn't compile
+    /// until it's added here, and then [`test_codes_are_stable`] until it has a code.
+    fn every_variant() -> Vec<AtomicError> {
+        let errors = vec![
+            AtomicError::Generic("boom".to_string()),
+            AtomicError::Static("boom"),
+            AtomicError::ConfigNotFound("atomic.toml".to_string()),
+            AtomicError::CommandNotFound("biuld".to_string(), vec!["build".to_string()]),
+            AtomicError::TomlParse(
+                "atomic.toml".to_string(),
+                ::toml::from_str::<::toml::Value>("[custom\n").unwrap_err(),
+            ),
+            AtomicError::YamlParse("atomic.yaml".to_string(), "bad indent".to_string()),
+            AtomicError::MissingGitIdentity("user.name", "Your Name"),
+            AtomicError::InvalidCommitAuthor("bot".to_string()),
+            AtomicError::InvalidSetting(::toml::Value::from("no").try_into::<bool>().unwrap_err()),
+            AtomicError::LargeFiles("10 MB".to_string(), vec!["data.bin".to_string()]),
+            AtomicError::EnvFile(".env".to_string(), 3, "expected KEY=value".to_string()),
+            AtomicError::Signing("gpg failed".to_string()),
+            AtomicError::Aborted("reset-db".to_string()),
+            AtomicError::NeedsCycle(vec!["a".to_string(), "a".to_string()]),
+            AtomicError::UnknownNeed("deploy".to_string(), "buld".to_string(), Vec::new()),
+            AtomicError::ConfirmInCi("reset-db".to_string()),
+            AtomicError::Locked("migrate".to_string(), None),
+            AtomicError::TooDeep(5),
+            AtomicError::NoLastRun,
+            AtomicError::UnclosedGitignoreBlock,
+            AtomicError::AmbiguousConfig(vec![
+                "atomic.toml".to_string(),
+                "atomic.yaml".to_string(),
+            ]),
+            AtomicError::ProfileNotFound("release".to_string()),
+            AtomicError::InvalidStdinCommand("`command` chain has no steps".to_string()),
+            AtomicError::NestedChain("ci".to_string()),
+            AtomicError::Validation(vec!["custom.x: unknown key".to_string()]),
+            AtomicError::ParseInt("x".parse::<i32>().unwrap_err()),
+            AtomicError::GitError(git2::Error::from_str("no HEAD")),
+        ];
+        for err in &errors {
+            match err {
+                AtomicError::Generic(_)
+                | AtomicError::Static(_)
+                | AtomicError::ConfigNotFound(_)
+                | AtomicError::CommandNotFound(..)
+                | AtomicError::TomlParse(..)
+                | AtomicError::YamlParse(..)
+                | AtomicError::MissingGitIdentity(..)
+                | AtomicError::InvalidCommitAuthor(_)
+                | AtomicError::InvalidSetting(..)
+                | AtomicError::LargeFiles(..)
+                | AtomicError::EnvFile(..)
+                | AtomicError::Signing(_)
+                | AtomicError::Aborted(_)
+                | AtomicError::NeedsCycle(_)
+                | AtomicError::UnknownNeed(..)
+                | AtomicError::ConfirmInCi(_)
+                | AtomicError::Locked(..)
+                | AtomicError::TooDeep(_)
+                | AtomicError::NoLastRun
+                | AtomicError::UnclosedGitignoreBlock
+                | AtomicError::AmbiguousConfig(_)
+                | AtomicError::ProfileNotFound(_)
+                | AtomicError::InvalidStdinCommand(_)
+                | AtomicError::NestedChain(_)
+                | AtomicError::Validation(_)
+                | AtomicError::ParseInt(_)
+                | AtomicError::GitError(_) => {}
+            }
+        }
+        errors
+    }
+
+    #[test]
+    fn test_codes_are_stable() {
+        let codes: Vec<(String, &str)> = every_variant()
+            .iter()
+            .map(|err| {
+                let variant = format!("{:?}", err);
+                let variant = variant.split(['(', ' ']).next().unwrap().to_string();
+                (variant, err.code())
+            })
+            .collect();
+        let codes: Vec<(&str, &str)> = codes.iter().map(|(v, c)| (v.as_str(), *c)).collect();
+        // released codes never change; only add to this list
+        assert_eq!(
+            codes,
+            [
+                ("Generic", "ATM090"),
+                ("Static", "ATM091"),
+                ("ConfigNotFound", "ATM001"),
+                ("CommandNotFound", "ATM010"),
+                ("TomlParse", "ATM002"),
+                ("YamlParse", "ATM003"),
+                ("MissingGitIdentity", "ATM031"),
+                ("InvalidCommitAuthor", "ATM032"),
+                ("InvalidSetting", "ATM005"),
+                ("LargeFiles", "ATM033"),
+                ("EnvFile", "ATM006"),
+                ("Signing", "ATM034"),
+                ("Aborted", "ATM011"),
+                ("NeedsCycle", "ATM012"),
+                ("UnknownNeed", "ATM013"),
+                ("ConfirmInCi", "ATM014"),
+                ("Locked", "ATM015"),
+                ("TooDeep", "ATM016"),
+                ("NoLastRun", "ATM017"),
+                ("UnclosedGitignoreBlock", "ATM093"),
+                ("AmbiguousConfig", "ATM009"),
+                ("ProfileNotFound", "ATM008"),
+                ("InvalidStdinCommand", "ATM007"),
+                ("NestedChain", "ATM018"),
+                ("Validation", "ATM004"),
+                ("ParseInt", "ATM092"),
+                ("GitError", "ATM035"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_every_code_is_explained() {
+        let mut codes: Vec<&str> = every_variant().iter().map(AtomicError::code).collect();
+        codes.sort_unstable();
+        let explained: Vec<&str> = EXPLANATIONS.iter().map(|e| e.code).collect();
+        assert_eq!(codes, explained, "one explanation per code, in order");
+        assert_eq!(
+            explanation("atm010").map(|e| e.title),
+            Some("command not found")
+        );
+        assert_eq!(explanation("ATM999"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let err = AtomicError::CommandNotFound("biuld".to_string(), vec!["build".to_string()]);
+        assert_eq!(
+            render(&err, Style::Plain),
+            "error[ATM010]: Command 'biuld' not found in atomic.toml. Did you mean 'build'?\n  \
+             = help: run `atomic --explain-error ATM010` for common fixes\n"
+        );
+
+        let err = AtomicError::Validation(vec!["a: bad".to_string(), "b: bad".to_string()]);
+        assert_eq!(
+            render(&err, Style::Plain),
+            "error[ATM004]: atomic.toml is invalid:\n    a: bad\n    b: bad\n  \
+             = help: run `atomic --explain-error ATM004` for common fixes\n"
+        );
+        let github = render(&err, Style::GithubActions);
+        assert!(
+            github.starts_with(
+                "::error title=atomic ATM004::atomic.toml is invalid:%0A  a: bad%0A  b: bad\n\
+                 error[ATM004]: atomic.toml is invalid:\n"
+            ),
+            "{}",
+            github
+        );
+        let color = render(&err, Style::Color);
+        assert!(
+            color.starts_with("\x1b[1;31merror[ATM004]\x1b[0m"),
+            "{:?}",
+            color
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! `--events <path>`: what a run is doing, as newline-delimited JSON sent to a unix socket (a
+//! named pipe on Windows), for status bars and other tools watching atomic.
+//!
+//! Sending is strictly best-effort. With nothing listening atomic runs as usual, and the first
+//! write that fails or would block stops the events for the rest of the run. Like
+//! [`crate::junit`], nothing is sent until [`enable`] is called on the current thread.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::git::{AutoCommit, Origin};
+use crate::stats;
+use crate::Result;
+
+/// One thing that happened during a run, sent as a line of JSON tagged with its `event`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    RunStarted {
+        command: String,
+        pid: u32,
+        /// Seconds since the Unix epoch.
+        timestamp: u64,
+    },
+    StepStarted {
+        name: String,
+    },
+    StepFinished {
+        name: String,
+        code: i32,
+        duration_ms: u64,
+    },
+    CommitCreated {
+        hash: String,
+        files: usize,
+        insertions: usize,
+        deletions: usize,
+        /// Who changed each committed path.
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        origins: BTreeMap<String, Origin>,
+        /// The `commit_branch` it went onto, when that isn't the checked-out branch.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        branch: Option<String>,
+    },
+    RunFinished {
+        command: String,
+        /// `passed`, `failed`, or `error` when atomic itself reported a problem.
+        status: &'static str,
+        code: i32,
+        /// The error's code, such as `ATM010`, when `status` is `error`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<&'static str>,
+    },
+}
+
+impl Event {
+    pub fn run_started(command: &str) -> Self {
+        Event::RunStarted {
+            command: command.to_string(),
+            pid: std::process::id(),
+            timestamp: stats::now(),
+        }
+    }
+
+    pub fn step_finished(name: &str, code: i32, duration: Duration) -> Self {
+        Event::StepFinished {
+            name: name.to_string(),
+            code,
+            duration_ms: u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+        }
+    }
+
+    pub fn commit_created(commit: &AutoCommit) -> Self {
+        Event::CommitCreated {
+            hash: commit.id.to_string(),
+            files: commit.stats.files_changed(),
+            insertions: commit.stats.insertions,
+            deletions: commit.stats.deletions,
+            origins: commit.origins.clone(),
+            branch: commit.branch.clone(),
+        }
+    }
+
+    /// The end of a run of `command` that returned `result`.
+    pub fn run_finished(command: &str, result: &Result<i32>) -> Self {
+        let (status, code, error) = match result {
+            Ok(0) => ("passed", 0, None),
+            Ok(code) => ("failed", *code, None),
+            Err(err) => ("error", 1, Some(err.code())),
+        };
+        Event::RunFinished {
+            command: command.to_string(),
+            status,
+            code,
+            error,
+        }
+    }
+}
+
+/// Where events go. Writes must not block; one that would should fail instead.
+pub trait Sink {
+    fn send(&mut self, line: &[u8]) -> io::Result<()>;
+}
+
+thread_local! {
+    static SINK: RefCell<Option<Box<dyn Sink>>> = const { RefCell::new(None) };
+}
+
+/// Sends this thread's events to `sink` from now on.
+pub fn enable(sink: Box<dyn Sink>) {
+    SINK.with(|current| *current.borrow_mut() = Some(sink));
+}
+
+pub fn is_enabled() -> bool {
+    SINK.with(|sink| sink.borrow().is_some())
+}
+
+/// Sends events to the listener at `path`; without one, nothing is sent.
+pub fn listen_at(path: &Path) {
+    if let Ok(sink) = connect(path) {
+        enable(sink);
+    }
+}
+
+/// Sends `event` if events are on, turning them off when it can't be sent.
+pub fn emit(event: Event) {
+    SINK.with(|sink| {
+        let mut sink = sink.borrow_mut();
+        let Some(current) = sink.as_mut() else {
+            return;
+        };
+        let Ok(mut line) = serde_json::to_vec(&event) else {
+            return;
+        };
+        line.push(b'\n');
+        if current.send(&line).is_err() {
+            *sink = None;
+        }
+    });
+}
+
+/// Stops sending events, closing the connection.
+pub fn finish() {
+    SINK.with(|sink| sink.borrow_mut().take());
+}
+
+#[cfg(unix)]
+fn connect(path: &Path) -> io::Result<Box<dyn Sink>> {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    struct Socket(UnixStream);
+
+    impl Sink for Socket {
+        fn send(&mut self, line: &[u8]) -> io::Result<()> {
+            self.0.write_all(line)
+        }
+    }
+
+    let stream = UnixStream::connect(path)?;
+    // a listener that stops reading fills the buffer, and then writes fail instead of waiting
+    stream.set_nonblocking(true)?;
+    Ok(Box::new(Socket(stream)))
+}
+
+/// `path` is the pipe's name, such as `\\.\pipe\atomic`.
+#[cfg(windows)]
+fn connect(path: &Path) -> io::Result<Box<dyn Sink>> {
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+
+    struct Pipe(File);
+
+    impl Sink for Pipe {
+        fn send(&mut self, line: &[u8]) -> io::Result<()> {
+            self.0.write_all(line)
+        }
+    }
+
+    let pipe = OpenOptions::new().write(true).open(path)?;
+    Ok(Box::new(Pipe(pipe)))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn connect(_path: &Path) -> io::Result<Box<dyn Sink>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AtomicError;
+    use std::rc::Rc;
+
+    /// Keeps what was sent, failing once `limit` lines are in.
+    struct Memory {
+        lines: Rc<RefCell<Vec<String>>>,
+        limit: usize,
+    }
+
+    impl Sink for Memory {
+        fn send(&mut self, line: &[u8]) -> io::Result<()> {
+            let mut lines = self.lines.borrow_mut();
+            if lines.len() == self.limit {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            lines.push(String::from_utf8(line.to_vec()).unwrap());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events_as_json_lines() {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        emit(Event::StepStarted {
+            name: "off".to_string(),
+        });
+        enable(Box::new(Memory {
+            lines: lines.clone(),
+            limit: 2,
+        }));
+        emit(Event::StepStarted {
+            name: "build".to_string(),
+        });
+        emit(Event::step_finished(
+            "build",
+            0,
+            Duration::from_millis(1500),
+        ));
+        assert!(is_enabled());
+        // the sink is full, so events stop without the run noticing
+        emit(Event::run_finished("build", &Ok(0)));
+        assert!(!is_enabled());
+        finish();
+
+        assert_eq!(
+            *lines.borrow(),
+            [
+                "{\"event\":\"step_started\",\"name\":\"build\"}\n",
+                "{\"event\":\"step_finished\",\"name\":\"build\",\"code\":0,\"duration_ms\":1500}\n",
+            ]
+        );
+        let err = AtomicError::CommandNotFound("lint".to_string(), Vec::new());
+        let finished = serde_json::to_value(Event::run_finished("lint", &Err(err))).unwrap();
+        assert_eq!(finished["status"], "error");
+        assert_eq!(finished["code"], 1);
+        assert_eq!(finished["error"], "ATM010");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_no_listener() {
+        let dir = tempfile::tempdir().unwrap();
+        listen_at(&dir.path().join("events.sock"));
+        assert!(!is_enabled());
+        emit(Event::run_started("build"));
+    }
+}
@@ -0,0 +1,186 @@
+//! The exit codes atomic ends with, so scripts wrapping it can tell what went wrong.
+//! `atomic --print-exit-codes` prints [`TABLE`].
+//!
+//! Like the error codes in [`crate::errors`], a code never changes meaning once released.
+
+use crate::{AtomicError, Result};
+
+pub const SUCCESS: i32 = 0;
+/// Anything without a code of its own.
+pub const FAILURE: i32 = 1;
+/// Bad arguments, or no atomic.toml.
+pub const USAGE: i32 = 2;
+pub const VALIDATION: i32 = 3;
+pub const COMMAND_NOT_FOUND: i32 = 4;
+pub const STEP_FAILED: i32 = 5;
+/// Stopped at a prompt or by Ctrl-C, as shells report SIGINT.
+pub const INTERRUPTED: i32 = 130;
+
+/// Every code with what it means, in order.
+pub const TABLE: &[(i32, &str)] = &[
+    (SUCCESS, "success"),
+    (
+        FAILURE,
+        "failure without a code of its own, such as a git error",
+    ),
+    (USAGE, "bad arguments, or no atomic.toml"),
+    (
+        VALIDATION,
+        "atomic.toml doesn't parse or doesn't match the schema (or has warnings, with --check --strict)",
+    ),
+    (COMMAND_NOT_FOUND, "the command isn't in atomic.toml"),
+    (
+        STEP_FAILED,
+        "a step failed (--passthrough-exit-code exits with its own code instead)",
+    ),
+    (INTERRUPTED, "interrupted, or a prompt was declined"),
+];
+
+impl AtomicError {
+    /// The code atomic exits with after reporting this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AtomicError::ConfigNotFound(_) | AtomicError::ProfileNotFound(_) => USAGE,
+            AtomicError::TomlParse(..)
+            | AtomicError::YamlParse(..)
+            | AtomicError::Validation(_)
+            | AtomicError::InvalidSetting(..) => VALIDATION,
+            AtomicError::CommandNotFound(..) => COMMAND_NOT_FOUND,
+            AtomicError::Aborted(_) => INTERRUPTED,
+            AtomicError::EnvFile(..)
+            | AtomicError::MissingGitIdentity(..)
+            | AtomicError::InvalidCommitAuthor(_)
+            | AtomicError::LargeFiles(..)
+            | AtomicError::Signing(_)
+            | AtomicError::GitError(_)
+            | AtomicError::NeedsCycle(_)
+            | AtomicError::UnknownNeed(..)
+            | AtomicError::ConfirmInCi(_)
+            | AtomicError::Locked(..)
+            | AtomicError::TooDeep(_)
+            | AtomicError::NoLastRun
+            | AtomicError::UnclosedGitignoreBlock
+            | AtomicError::AmbiguousConfig(_)
+            | AtomicError::InvalidStdinCommand(_)
+            | AtomicError::NestedChain(_)
+            | AtomicError::Generic(_)
+            | AtomicError::Static(_)
+            | AtomicError::ParseInt(_) => FAILURE,
+        }
+    }
+}
+
+/// The code for a run of a command that ended with `result`, as `run_command` returns it: the
+/// failed step's code, or with `passthrough` that code itself when it fits in an exit code.
+pub fn for_run(result: &Result<i32>, passthrough: bool) -> i32 {
+    match result {
+        Ok(SUCCESS) => SUCCESS,
+        Ok(code) if passthrough && (1..=255).contains(code) => *code,
+        Ok(INTERRUPTED) => INTERRUPTED,
+        Ok(_) => STEP_FAILED,
+        Err(err) => err.exit_code(),
+    }
+}
+
+/// [`TABLE`] as `atomic --print-exit-codes` prints it.
+pub fn render_table() -> String {
+    TABLE
+        .iter()
+        .map(|(code, meaning)| format!("{:>3}  {}\n", code, meaning))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_exit_codes() {
+        let parse_error = ::toml::from_str::<::toml::Value>("[x").unwrap_err();
+        let parse_int = "x".parse::<i32>().unwrap_err();
+        assert_eq!(AtomicError::Generic(String::new()).exit_code(), 1);
+        assert_eq!(AtomicError::Static("").exit_code(), 1);
+        assert_eq!(AtomicError::ConfigNotFound(String::new()).exit_code(), 2);
+        assert_eq!(
+            AtomicError::CommandNotFound(String::new(), Vec::new()).exit_code(),
+            4
+        );
+        assert_eq!(
+            AtomicError::TomlParse(String::new(), parse_error).exit_code(),
+            3
+        );
+        assert_eq!(
+            AtomicError::YamlParse(String::new(), String::new()).exit_code(),
+            3
+        );
+        assert_eq!(
+            AtomicError::MissingGitIdentity("user.name", "Your Name").exit_code(),
+            1
+        );
+        assert_eq!(
+            AtomicError::InvalidCommitAuthor(String::new()).exit_code(),
+            1
+        );
+        assert_eq!(
+            AtomicError::InvalidSetting(::toml::Value::from("no").try_into::<bool>().unwrap_err())
+                .exit_code(),
+            3
+        );
+        assert_eq!(
+            AtomicError::LargeFiles(String::new(), Vec::new()).exit_code(),
+            1
+        );
+        assert_eq!(
+            AtomicError::EnvFile(String::new(), 1, String::new()).exit_code(),
+            1
+        );
+        assert_eq!(AtomicError::Signing(String::new()).exit_code(), 1);
+        assert_eq!(AtomicError::Aborted(String::new()).exit_code(), 130);
+        assert_eq!(AtomicError::NeedsCycle(Vec::new()).exit_code(), 1);
+        assert_eq!(
+            AtomicError::UnknownNeed(String::new(), String::new(), Vec::new()).exit_code(),
+            1
+        );
+        assert_eq!(AtomicError::ConfirmInCi(String::new()).exit_code(), 1);
+        assert_eq!(AtomicError::Locked(String::new(), None).exit_code(), 1);
+        assert_eq!(AtomicError::TooDeep(5).exit_code(), 1);
+        assert_eq!(AtomicError::NoLastRun.exit_code(), 1);
+        assert_eq!(AtomicError::UnclosedGitignoreBlock.exit_code(), 1);
+        assert_eq!(AtomicError::AmbiguousConfig(Vec::new()).exit_code(), 1);
+        assert_eq!(AtomicError::ProfileNotFound(String::new()).exit_code(), 2);
+        assert_eq!(
+            AtomicError::InvalidStdinCommand(String::new()).exit_code(),
+            1
+        );
+        assert_eq!(AtomicError::NestedChain(String::new()).exit_code(), 1);
+        assert_eq!(AtomicError::Validation(Vec::new()).exit_code(), 3);
+        assert_eq!(AtomicError::ParseInt(parse_int).exit_code(), 1);
+        assert_eq!(
+            AtomicError::GitError(git2::Error::from_str("")).exit_code(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_run_exit_codes() {
+        assert_eq!(for_run(&Ok(0), false), 0);
+        assert_eq!(for_run(&Ok(0), true), 0);
+        assert_eq!(for_run(&Ok(101), false), 5);
+        assert_eq!(for_run(&Ok(101), true), 101);
+        assert_eq!(for_run(&Ok(1), false), 5);
+        assert_eq!(for_run(&Ok(130), false), 130);
+        // a step that never started has no code to pass through
+        assert_eq!(for_run(&Ok(-1), true), 5);
+        assert_eq!(for_run(&Ok(300), true), 5);
+        let not_found = AtomicError::CommandNotFound("x".to_string(), Vec::new());
+        assert_eq!(for_run(&Err(not_found), true), 4);
+    }
+
+    #[test]
+    fn test_render_table() {
+        let table = render_table();
+        assert!(table.starts_with("  0  success\n  1  "), "{}", table);
+        assert!(table.ends_with("130  interrupted, or a prompt was declined\n"));
+        assert_eq!(table.lines().count(), TABLE.len());
+    }
+}
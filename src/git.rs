@@ -1,142 +1,2971 @@
+use crate::cargo_json::CargoJson;
+use crate::progress::StageProgress;
+use crate::sign::Signer;
+use crate::util::format_size;
 use crate::{AtomicError, Result};
-use git2::{Repository, Signature};
+use git2::{
+    AttrCheckFlags, Branch, BranchType, Commit, Delta, Diff, DiffFormat, DiffOptions, ErrorCode,
+    Index, IndexEntry, IndexTime, ObjectType, Oid, Patch, Repository, Signature, Status,
+    StatusOptions, Tree,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::env;
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
 const _SEPERATORS: [char; 4] = ['-', ' ', ':', '_'];
 
-pub fn send_command(cmd: &str) {
+/// Reports a failed run of `cmd`, given the result of spawning it.
+///
+/// Returns the command's exit code, or `-1` if it could not be executed.
+pub fn report_exit(cmd: &str, result: std::io::Result<i32>) -> i32 {
+    match result {
+        Ok(0) => 0,
+        Ok(code) => {
+            eprintln!("Command failed with status code: {}", code);
+            code
+        }
+        Err(err) => {
+            // Handle execution errors
+            eprintln!("Failed to execute command: {}\nError: {}", cmd, err);
+            -1
+        }
+    }
+}
+
+/// How much of a step's stdout [`Capture`] keeps.
+pub const CAPTURE_LIMIT: usize = 8 * 1024;
+
+/// The start of a step's stdout, kept while it still goes to the terminal.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Capture {
+    bytes: Vec<u8>,
+    /// More than [`CAPTURE_LIMIT`] bytes were written, and the rest was dropped.
+    pub truncated: bool,
+    /// Set for `cargo_json`: stdout is cargo's JSON messages, shown and kept as cargo would have
+    /// printed them.
+    pub cargo: Option<CargoJson>,
+    /// The last [`CAPTURE_LIMIT`] bytes, for [`Capture::tail`].
+    end: Vec<u8>,
+}
+
+impl Capture {
+    /// A capture that reads cargo's `--message-format=json` output.
+    pub fn cargo_json() -> Self {
+        Self {
+            cargo: Some(CargoJson::default()),
+            ..Self::default()
+        }
+    }
+
+    /// Shows a chunk of stdout on the terminal and keeps it.
+    fn write(&mut self, bytes: &[u8]) {
+        let shown = match &mut self.cargo {
+            Some(cargo) => cargo.feed(bytes),
+            None => bytes.to_vec(),
+        };
+        self.show(&shown);
+    }
+
+    /// Shows whatever was held back waiting for the rest of a line.
+    fn finish(&mut self) {
+        if let Some(shown) = self.cargo.as_mut().map(CargoJson::finish) {
+            self.show(&shown);
+        }
+    }
+
+    fn show(&mut self, shown: &[u8]) {
+        // under `cargo test`, through the harness's capture, so what a test's commands print
+        // (such as the replayed output of a failing test run) doesn't land in its report
+        #[cfg(test)]
+        print!("{}", String::from_utf8_lossy(shown));
+        #[cfg(not(test))]
+        {
+            use std::io::Write;
+            let mut terminal = std::io::stdout().lock();
+            let _ = terminal.write_all(shown).and_then(|()| terminal.flush());
+        }
+        self.push(shown);
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let room = CAPTURE_LIMIT - self.bytes.len();
+        self.bytes
+            .extend_from_slice(&bytes[..bytes.len().min(room)]);
+        self.truncated |= bytes.len() > room;
+        self.end.extend_from_slice(bytes);
+        let over = self.end.len().saturating_sub(CAPTURE_LIMIT);
+        self.end.drain(..over);
+    }
+
+    /// Forgets what was kept, for another attempt of the step.
+    pub fn clear(&mut self) {
+        *self = Self {
+            cargo: self.cargo.as_ref().map(|_| CargoJson::default()),
+            ..Self::default()
+        };
+    }
+
+    /// What was kept, with anything that isn't UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(self.kept()).into_owned()
+    }
+
+    /// The last `lines` lines of the output, with anything that isn't UTF-8 replaced. Only the
+    /// last [`CAPTURE_LIMIT`] bytes are kept for it, so a longer line comes out cut.
+    pub fn tail(&self, lines: usize) -> String {
+        let text = String::from_utf8_lossy(&self.end);
+        let kept: Vec<&str> = text.lines().rev().take(lines).collect();
+        kept.into_iter().rev().collect::<Vec<_>>().join("\n")
+    }
+
+    /// How many replacement characters [`Capture::text`] has for output that wasn't UTF-8, such
+    /// as binary data or Latin-1 text.
+    pub fn invalid_utf8(&self) -> usize {
+        self.kept()
+            .utf8_chunks()
+            .filter(|chunk| !chunk.invalid().is_empty())
+            .count()
+    }
+
+    /// The kept bytes, less a character the limit cut in half, which isn't the output's fault.
+    fn kept(&self) -> &[u8] {
+        let bytes = &self.bytes;
+        if self.truncated {
+            for start in bytes.len().saturating_sub(3)..bytes.len() {
+                let incomplete = matches!(
+                    std::str::from_utf8(&bytes[start..]),
+                    Err(err) if err.valid_up_to() == 0 && err.error_len().is_none()
+                );
+                if incomplete {
+                    return &bytes[..start];
+                }
+            }
+        }
+        bytes
+    }
+}
+
+/// Runs `cmd` through the platform shell and returns its exit code without reporting it.
+///
+/// `env` is added to the child's environment, or with `clean` is all of it, and it runs in `cwd`
+/// when given. With `capture`,
+/// stdout is copied into it as well as to the terminal. An empty command is treated as a
+/// successful no-op.
+pub fn spawn_command(
+    cmd: &str,
+    env: &[(String, String)],
+    clean: bool,
+    cwd: Option<&Path>,
+    mut capture: Option<&mut Capture>,
+) -> std::io::Result<i32> {
     #[cfg(debug_assertions)]
     dbg!(cmd);
 
-    // Handle empty or invalid commands
-    if cmd.trim().is_empty() {
-        println!("No command provided or unknown command.");
-        return;
+    // Handle empty or invalid commands
+    if cmd.trim().is_empty() {
+        println!("No command provided or unknown command.");
+        return Ok(0);
+    }
+
+    // println!("Running command: {}", cmd);
+
+    let invocation = plan_invocation(cmd, cfg!(target_os = "windows"))?;
+    let result = run_invocation(&invocation, env, clean, cwd, capture.as_deref_mut());
+
+    // cmd builtins such as `echo` or `dir` have no executable, so retry those through the shell
+    match (result, &invocation) {
+        (Err(err), Invocation::Direct(_)) if err.kind() == ErrorKind::NotFound => run_invocation(
+            &Invocation::Shell(cmd.to_string()),
+            env,
+            clean,
+            cwd,
+            capture,
+        ),
+        (result, _) => result,
+    }
+}
+
+/// Runs a program directly with `argv`, bypassing the shell, and returns its exit code.
+pub fn spawn_argv(
+    argv: &[String],
+    env: &[(String, String)],
+    cwd: Option<&Path>,
+    capture: Option<&mut Capture>,
+) -> std::io::Result<i32> {
+    run_invocation(&Invocation::Direct(argv.to_vec()), env, false, cwd, capture)
+}
+
+/// cmd.exe silently truncates command lines longer than this.
+const WINDOWS_MAX_COMMAND_LEN: usize = 8191;
+
+/// Characters that need cmd.exe to interpret them. Backslashes are included because
+/// shell-words would treat them as escapes and mangle Windows paths.
+const WINDOWS_SHELL_METACHARACTERS: [char; 10] =
+    ['&', '|', '<', '>', '^', '%', '(', ')', '!', '\\'];
+
+/// How a command string from atomic.toml gets launched.
+#[derive(Debug, PartialEq)]
+pub enum Invocation {
+    /// Run the program directly with these arguments, bypassing the shell.
+    Direct(Vec<String>),
+    /// Hand the string, unchanged, to `sh -c` or `cmd /C`.
+    Shell(String),
+}
+
+/// Decides how to launch `cmd`.
+///
+/// Unix commands always go through `sh -c`. On Windows, commands without shell metacharacters
+/// are split with shell-words and run directly, which keeps quoting like
+/// `git commit -m "it's done"` intact; everything else is passed to `cmd /C` unchanged, so
+/// arguments there should use double quotes.
+///
+/// # Errors
+/// Returns an `InvalidInput` error on Windows when the command is over cmd.exe's length limit.
+pub fn plan_invocation(cmd: &str, windows: bool) -> std::io::Result<Invocation> {
+    if !windows {
+        return Ok(Invocation::Shell(cmd.to_string()));
+    }
+
+    let len = cmd.chars().count();
+    if len > WINDOWS_MAX_COMMAND_LEN {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "command is {} characters long, over the {} character limit of cmd.exe; split it into a chain of shorter commands",
+                len, WINDOWS_MAX_COMMAND_LEN
+            ),
+        ));
+    }
+
+    if cmd.contains(WINDOWS_SHELL_METACHARACTERS) {
+        return Ok(Invocation::Shell(cmd.to_string()));
+    }
+
+    match shell_words::split(cmd) {
+        Ok(argv) if !argv.is_empty() => Ok(Invocation::Direct(argv)),
+        _ => Ok(Invocation::Shell(cmd.to_string())),
+    }
+}
+
+fn run_invocation(
+    invocation: &Invocation,
+    env: &[(String, String)],
+    clean: bool,
+    cwd: Option<&Path>,
+    capture: Option<&mut Capture>,
+) -> std::io::Result<i32> {
+    // Build the command based on the OS
+    let mut process = match invocation {
+        Invocation::Direct(argv) => {
+            let mut c = Command::new(&argv[0]);
+            c.args(&argv[1..]);
+            c
+        }
+        Invocation::Shell(cmd) if cfg!(target_os = "windows") => {
+            let mut c = Command::new("cmd");
+            c.args(["/C", cmd]); // Use /C for Windows
+            c
+        }
+        Invocation::Shell(cmd) => {
+            let mut c = Command::new("sh");
+            c.args(["-c", cmd]); // Use -c for Unix-like systems
+            c
+        }
+    };
+    if let Some(cwd) = cwd {
+        process.current_dir(cwd);
+    }
+    if clean {
+        process.env_clear();
+    }
+
+    // Execute the command and hand back its status
+    let mut child = process
+        .stdout(match capture {
+            Some(_) => Stdio::piped(),
+            None => Stdio::inherit(),
+        })
+        .stderr(Stdio::inherit())
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .spawn()?;
+    if let (Some(capture), Some(mut stdout)) = (capture, child.stdout.take()) {
+        // copy as it arrives, so long-running steps still show progress
+        let mut buf = [0; 4096];
+        loop {
+            let read = stdout.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            capture.write(&buf[..read]);
+        }
+        capture.finish();
+    }
+    let status = child.wait()?;
+    // killed by a signal: the code a shell would report, 130 for Ctrl-C
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return Ok(128 + signal);
+    }
+    Ok(status.code().unwrap_or(-1))
+}
+
+/// Name of the branch HEAD points at, or `None` when HEAD is detached or can't be read.
+///
+/// An unborn branch (no commits yet) still reports its name.
+pub fn current_branch(repo: &Repository) -> Option<String> {
+    match repo.head() {
+        Ok(head) if head.is_branch() => head.shorthand().map(str::to_string),
+        Ok(_) => None,
+        Err(_) => repo
+            .find_reference("HEAD")
+            .ok()?
+            .symbolic_target()?
+            .strip_prefix("refs/heads/")
+            .map(str::to_string),
+    }
+}
+
+/// Issue number from a `feature-144-description` style branch name, if it has one.
+pub fn branch_issue(branch_name: &str) -> Option<u64> {
+    parse_branch_name(branch_name).ok()?.get(1)?.parse().ok()
+}
+
+pub fn _get_git_info() -> Result<(String, String, u64)> {
+    // Get the current directory
+    let current_dir = env::current_dir().expect("Failed to get current directory");
+
+    // Open the repository
+    let repo = Repository::discover(&current_dir)?;
+
+    // Get the current branch name
+    let head = repo.head().expect("Failed to get HEAD reference");
+    let branch_name = match head.shorthand() {
+        Some(name) => name,
+        None => return Err(AtomicError::Static("Failed to get current branch name")),
+    };
+
+    // Parse branch name into parts
+    let parts = parse_branch_name(branch_name)?; // Updated to handle Vec<String>
+
+    // Extract parts safely
+    let feature = parts.first().cloned().unwrap_or_default(); // First part as feature
+    let issue = parts.get(1).cloned().unwrap_or_default(); // Second part as issue number
+    let desc = parts
+        .get(2..)
+        .map(|rest| rest.join("-"))
+        .unwrap_or_default(); // Remaining parts as description
+
+    // Parse issue number safely
+    let issue_num = issue.parse::<u64>().unwrap_or(0);
+
+    // Return feature, description, and issue number
+    Ok((feature, desc, issue_num))
+}
+
+/// Line and file counts for the changes captured by a commit.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DiffStats {
+    pub added: usize,
+    pub modified: usize,
+    pub deleted: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub files: Vec<FileStat>,
+}
+
+/// Per-file entry of [`DiffStats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStat {
+    pub path: String,
+    pub status: char,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+impl DiffStats {
+    pub fn files_changed(&self) -> usize {
+        self.files.len()
+    }
+
+    /// One line summary, e.g. `4 files changed, 2 added, 1 modified, 1 deleted (+120 / -8)`.
+    pub fn summary(&self) -> String {
+        let files = self.files_changed();
+        let mut kinds = Vec::new();
+        for (count, kind) in [
+            (self.added, "added"),
+            (self.modified, "modified"),
+            (self.deleted, "deleted"),
+        ] {
+            if count > 0 {
+                kinds.push(format!("{} {}", count, kind));
+            }
+        }
+
+        format!(
+            "{} file{} changed{}{} (+{} / -{})",
+            files,
+            if files == 1 { "" } else { "s" },
+            if kinds.is_empty() { "" } else { ", " },
+            kinds.join(", "),
+            self.insertions,
+            self.deletions
+        )
+    }
+}
+
+/// A commit created by atomic, along with what it captured.
+#[derive(Debug, Clone)]
+pub struct AutoCommit {
+    pub id: Oid,
+    pub stats: DiffStats,
+    /// Who changed each committed path, when the work tree was snapshotted before the command;
+    /// see [`AutoCommit::with_origins`].
+    pub origins: BTreeMap<String, Origin>,
+    /// The `commit_branch` it went onto, when that isn't the checked-out branch.
+    pub branch: Option<String>,
+}
+
+impl AutoCommit {
+    pub fn short_id(&self) -> String {
+        self.id.to_string().chars().take(7).collect()
+    }
+
+    /// Prints the `Auto-commit <hash>: ...` summary, and the per-file list when `show_files` is set.
+    pub fn print_summary(&self, show_files: bool) {
+        match &self.branch {
+            Some(branch) => println!(
+                "Auto-commit {} on {}: {}",
+                self.short_id(),
+                branch,
+                self.stats.summary()
+            ),
+            None => println!("Auto-commit {}: {}", self.short_id(), self.stats.summary()),
+        }
+        if show_files {
+            for file in &self.stats.files {
+                let origin = match self.origins.get(&file.path) {
+                    Some(origin) => format!(" [{}]", origin.label()),
+                    None => String::new(),
+                };
+                println!(
+                    "  {} {} (+{} / -{}){}",
+                    file.status, file.path, file.insertions, file.deletions, origin
+                );
+            }
+        }
+    }
+
+    /// Records, for every committed path, whether the command, the user beforehand, or both
+    /// changed it. `changes` is `None` when the tree was clean before the run, so the command
+    /// made every change.
+    pub fn with_origins(mut self, changes: Option<&RunChanges>) -> Self {
+        self.origins = self
+            .stats
+            .files
+            .iter()
+            .map(|file| {
+                let origin = changes.map_or(Origin::Command, |changes| changes.origin(&file.path));
+                (file.path.clone(), origin)
+            })
+            .collect();
+        self
+    }
+}
+
+/// Collects file and line statistics for the changes between `old_tree` and `new_tree`.
+///
+/// A missing `old_tree` compares against the empty tree, as for a root commit.
+pub fn diff_stats(
+    repo: &Repository,
+    old_tree: Option<&Tree>,
+    new_tree: &Tree,
+) -> Result<DiffStats> {
+    let diff = repo.diff_tree_to_tree(old_tree, Some(new_tree), None)?;
+    stats_for(&diff)
+}
+
+/// File and line statistics for every delta in `diff`.
+fn stats_for(diff: &Diff) -> Result<DiffStats> {
+    let mut stats = DiffStats::default();
+
+    for idx in 0..diff.deltas().len() {
+        let Some(patch) = Patch::from_diff(diff, idx)? else {
+            continue;
+        };
+        let delta = patch.delta();
+        let status = match delta.status() {
+            Delta::Added | Delta::Untracked => {
+                stats.added += 1;
+                'A'
+            }
+            Delta::Deleted => {
+                stats.deleted += 1;
+                'D'
+            }
+            _ => {
+                stats.modified += 1;
+                'M'
+            }
+        };
+        let path = delta
+            .new_file()
+            .path()
+            .or_else(|| delta.old_file().path())
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+        let (_, insertions, deletions) = patch.line_stats()?;
+
+        stats.insertions += insertions;
+        stats.deletions += deletions;
+        stats.files.push(FileStat {
+            path,
+            status,
+            insertions,
+            deletions,
+        });
+    }
+
+    Ok(stats)
+}
+
+/// Knobs for [`commit_changes`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CommitOptions<'a> {
+    /// Recorded in an `Atomic-Command` trailer so later runs can find the commit again.
+    pub command: Option<&'a str>,
+    /// Run the repository's pre-commit and commit-msg hooks, as `git commit` would.
+    pub run_git_hooks: bool,
+    /// `[atomic] commit_author`, used when git has no `user.name` / `user.email`.
+    pub commit_author: Option<&'a str>,
+    /// Commit only these work tree paths instead of everything. Anything else already staged
+    /// stays staged but out of the commit.
+    pub paths: Option<&'a [String]>,
+    /// `--repo`: commit in the repository containing this path; see [`open_repository`].
+    pub repo: Option<&'a Path>,
+    /// A paragraph to put between the message and the trailer, such as captured output.
+    pub body: Option<&'a str>,
+    /// `[atomic] max_commit_file_size`: files bigger than this stop the commit, unless Git LFS
+    /// tracks them.
+    pub max_file_size: Option<u64>,
+    /// `large_files = "skip"`: leave files over `max_file_size` out instead of stopping.
+    pub skip_large_files: bool,
+    /// `--no-sign`: commit unsigned even when `commit.gpgsign` is set.
+    pub no_sign: bool,
+    /// `commit_branch`: commit onto this branch, created from HEAD if it's missing, instead of the
+    /// checked-out one. HEAD, the index and the work tree stay as they were.
+    pub branch: Option<&'a str>,
+    /// More trailers after `Atomic-Command`, such as the `Atomic-Config-Hash` and `Atomic-Steps`
+    /// of a run.
+    pub trailers: &'a [(&'a str, String)],
+}
+
+/// Printed instead of a commit summary when a command left the work tree as it was.
+pub const NO_CHANGES: &str = "No changes detected, skipping auto-commit";
+
+/// Opens the repository atomic works on: the one containing `path` (from `--repo`) when given,
+/// otherwise the one `GIT_DIR` / `GIT_WORK_TREE` point at, otherwise the one containing the
+/// current directory.
+pub fn open_repository(path: Option<&Path>) -> Result<Repository> {
+    Ok(match path {
+        Some(path) => Repository::discover(path)?,
+        None => Repository::open_from_env()?,
+    })
+}
+
+/// Commits onto the HEAD of the repository, or linked worktree, from [`open_repository`].
+pub fn commit_local_changes(message: &str, options: &CommitOptions) -> Result<Option<AutoCommit>> {
+    let repo = open_repository(options.repo)?;
+    commit_changes(&repo, message, options)
+}
+
+/// Stages every change in the work tree of `repo` and commits it onto the current branch, or the
+/// `branch` of `options`.
+///
+/// Returns `None`, without running hooks or committing, when the staged tree matches HEAD.
+pub fn commit_changes(
+    repo: &Repository,
+    message: &str,
+    options: &CommitOptions,
+) -> Result<Option<AutoCommit>> {
+    let side = side_branch(repo, options.branch)?;
+    let result = commit_onto(repo, message, options, side.as_deref());
+    if side.is_some() {
+        // what was staged only built the side branch's tree; the index goes back as it was
+        repo.index()?.read(true)?;
+    }
+    result
+}
+
+/// `refs/heads/<branch>` for a `commit_branch` other than the checked-out one, which
+/// auto-commits go onto anyway.
+fn side_branch(repo: &Repository, branch: Option<&str>) -> Result<Option<String>> {
+    let Some(branch) = branch else {
+        return Ok(None);
+    };
+    if !Branch::name_is_valid(branch)? {
+        return Err(AtomicError::Generic(format!(
+            "commit_branch '{}' isn't a valid branch name",
+            branch
+        )));
+    }
+    if current_branch(repo).as_deref() == Some(branch) {
+        return Ok(None);
+    }
+    Ok(Some(format!("refs/heads/{}", branch)))
+}
+
+/// [`commit_changes`] onto `side`, a full reference name, when given: the commit's parent is that
+/// branch (or HEAD, while it doesn't exist yet), the index isn't written and hooks don't run.
+fn commit_onto(
+    repo: &Repository,
+    message: &str,
+    options: &CommitOptions,
+    side: Option<&str>,
+) -> Result<Option<AutoCommit>> {
+    let user = commit_signature(&repo.config()?, options.commit_author)?;
+    let signer = match options.no_sign {
+        true => None,
+        false => Signer::from_config(&repo.config()?)?,
+    };
+
+    let message = match options.body {
+        Some(body) => format!("{}\n\n{}\n", message.trim_end(), body.trim_end()),
+        None => message.to_string(),
+    };
+    let mut trailers = vec![(VERSION_TRAILER, env!("CARGO_PKG_VERSION"))];
+    trailers.extend(options.command.map(|command| (COMMAND_TRAILER, command)));
+    trailers.extend(
+        options
+            .trailers
+            .iter()
+            .map(|(key, value)| (*key, value.as_str())),
+    );
+    let mut message = append_trailers(&message, &trailers);
+
+    let mut index = repo.index()?;
+
+    let mut progress = StageProgress::start();
+    match options.paths {
+        // only what differs from the index, rather than matching "*" against the whole tree
+        None => stage_paths(repo, &mut index, &unstaged_paths(repo)?, &mut progress)?,
+        // an empty pathspec would match everything
+        Some([]) => {}
+        Some(paths) => {
+            let mut staged = 0;
+            let mut count = |_: &Path, _: &[u8]| {
+                staged += 1;
+                progress.staged(staged);
+                0
+            };
+            index.add_all(
+                paths,
+                git2::IndexAddOption::DISABLE_PATHSPEC_MATCH,
+                Some(&mut count),
+            )?;
+            index.update_all(paths, None)?;
+        }
+    }
+    drop(progress);
+
+    let parent_commit = match side.map(|side| repo.find_reference(side)) {
+        Some(Ok(tip)) => tip.peel_to_commit()?,
+        Some(Err(err)) if err.code() != ErrorCode::NotFound => return Err(err.into()),
+        _ => repo.head()?.peel_to_commit()?,
+    };
+    if let Some(limit) = options.max_file_size {
+        let large = large_files(repo, &index, &parent_commit.tree()?, options.paths, limit)?;
+        if !large.is_empty() {
+            let listed: Vec<String> = large
+                .iter()
+                .map(|(path, size)| format!("{} ({})", path, format_size(*size)))
+                .collect();
+            if !options.skip_large_files {
+                // drop what was just staged; the repository shares this index
+                index.read(true)?;
+                return Err(AtomicError::LargeFiles(format_size(limit), listed));
+            }
+            eprintln!(
+                "leaving files over max_commit_file_size ({}) out of the auto-commit:",
+                format_size(limit)
+            );
+            for file in &listed {
+                eprintln!("  {}", file);
+            }
+            unstage(
+                &mut index,
+                &parent_commit.tree()?,
+                large.iter().map(|(path, _)| path),
+            )?;
+        }
+    }
+    if side.is_none() {
+        index.write()?;
+    }
+
+    // comparing tree ids is enough; identical content always hashes to the same tree
+    if commit_tree(repo, &mut index, &parent_commit, options.paths)? == parent_commit.tree_id() {
+        return Ok(None);
+    }
+
+    // hooks check and re-stage the checked-out branch, which a side branch commit doesn't touch
+    if options.run_git_hooks && side.is_none() {
+        run_git_hook(repo, "pre-commit", &[])?;
+        // formatters in pre-commit hooks often re-stage the files they touched
+        index.read(true)?;
+
+        let message_file = repo.path().join("COMMIT_EDITMSG");
+        fs::write(&message_file, &message).map_err(|err| {
+            AtomicError::Generic(format!("Failed to write commit message: {}", err))
+        })?;
+        if run_git_hook(repo, "commit-msg", &[&message_file])? {
+            message = fs::read_to_string(&message_file).map_err(|err| {
+                AtomicError::Generic(format!("Failed to read commit message: {}", err))
+            })?;
+        }
+    }
+
+    let repo_reference = repo.head()?.resolve()?;
+    let branch = side.unwrap_or(repo_reference.name().expect("No HEAD exists"));
+
+    // Commit the changes, unless a pre-commit hook undid them all
+    let tree_id = commit_tree(repo, &mut index, &parent_commit, options.paths)?;
+    if tree_id == parent_commit.tree_id() {
+        return Ok(None);
+    }
+    let tree = repo.find_tree(tree_id)?;
+    let id = write_commit(repo, branch, &user, signer, &message, &tree, &parent_commit)?;
+
+    let stats = diff_stats(repo, Some(&parent_commit.tree()?), &tree)?;
+    Ok(Some(AutoCommit {
+        id,
+        stats,
+        origins: BTreeMap::new(),
+        branch: side.map(|side| side.trim_start_matches("refs/heads/").to_string()),
+    }))
+}
+
+/// Commits `tree` onto `parent` and moves `branch` to it, signed when `signer` is given.
+fn write_commit(
+    repo: &Repository,
+    branch: &str,
+    user: &Signature,
+    signer: Option<Signer>,
+    message: &str,
+    tree: &Tree,
+    parent: &Commit,
+) -> Result<Oid> {
+    let Some(signer) = signer else {
+        return Ok(repo.commit(Some(branch), user, user, message, tree, &[parent])?);
+    };
+    let buffer = repo.commit_create_buffer(user, user, message, tree, &[parent])?;
+    let signature = signer.sign(&buffer)?;
+    let content = std::str::from_utf8(&buffer)
+        .map_err(|_| AtomicError::Static("commit message is not valid UTF-8"))?;
+    let id = repo.commit_signed(content, &signature, None)?;
+    // unlike `commit`, `commit_signed` leaves the branch where it was
+    let summary = message.lines().next().unwrap_or_default();
+    repo.reference(branch, id, true, &format!("commit: {}", summary))?;
+    Ok(id)
+}
+
+/// Staged files that differ from `parent` and are bigger than `limit`, with their sizes, leaving
+/// out files Git LFS tracks, since those are committed as small pointers.
+///
+/// Sizes come from the index, so nothing is read again. Only `paths` are looked at when given.
+fn large_files(
+    repo: &Repository,
+    index: &Index,
+    parent: &Tree,
+    paths: Option<&[String]>,
+    limit: u64,
+) -> Result<Vec<(String, u64)>> {
+    let mut large = Vec::new();
+    for entry in index.iter() {
+        let size = u64::from(entry.file_size);
+        if size <= limit {
+            continue;
+        }
+        let path = String::from_utf8_lossy(&entry.path).into_owned();
+        if paths.is_some_and(|paths| !paths.contains(&path)) {
+            continue;
+        }
+        let unchanged = parent
+            .get_path(Path::new(&path))
+            .is_ok_and(|committed| committed.id() == entry.id);
+        if unchanged || tracked_by_lfs(repo, &path)? {
+            continue;
+        }
+        large.push((path, size));
+    }
+    Ok(large)
+}
+
+/// Whether `.gitattributes` gives `path` the Git LFS filter.
+fn tracked_by_lfs(repo: &Repository, path: &str) -> Result<bool> {
+    let filter = repo.get_attr(Path::new(path), "filter", AttrCheckFlags::FILE_THEN_INDEX)?;
+    Ok(filter == Some("lfs"))
+}
+
+/// Puts `paths` in `index` back the way they are in `parent`, removing the ones it doesn't have.
+fn unstage<'p>(
+    index: &mut Index,
+    parent: &Tree,
+    paths: impl IntoIterator<Item = &'p String>,
+) -> Result<()> {
+    for path in paths {
+        match parent.get_path(Path::new(path)) {
+            Ok(committed) => index.add(&IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: committed.filemode() as u32,
+                uid: 0,
+                gid: 0,
+                // zeroed stat data makes git compare the content, so it shows as modified
+                file_size: 0,
+                id: committed.id(),
+                flags: path.len().min(0xfff) as u16,
+                flags_extended: 0,
+                path: path.as_bytes().to_vec(),
+            })?,
+            Err(_) => index.remove_path(Path::new(path))?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes the tree to commit: the whole index, or `parent` with only `paths` taken from it.
+fn commit_tree(
+    repo: &Repository,
+    index: &mut Index,
+    parent: &Commit,
+    paths: Option<&[String]>,
+) -> Result<Oid> {
+    let Some(paths) = paths else {
+        return Ok(index.write_tree()?);
+    };
+
+    let mut partial = Index::new()?;
+    partial.read_tree(&parent.tree()?)?;
+    for path in paths {
+        match index.get_path(Path::new(path), 0) {
+            Some(entry) => partial.add(&entry)?,
+            None => match partial.remove_path(Path::new(path)) {
+                Err(err) if err.code() != ErrorCode::NotFound => return Err(err.into()),
+                _ => {}
+            },
+        }
+    }
+    Ok(partial.write_tree_to(repo)?)
+}
+
+/// Files bigger than this aren't hashed for a [`worktree_snapshot`]; their size and modification
+/// time stand in for their contents.
+pub const SNAPSHOT_HASH_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// Fingerprints of every path that differs from HEAD, keyed by path relative to the work tree.
+///
+/// The fingerprint is the blob id of the file in the work tree, or `None` when it was deleted,
+/// so comparing two snapshots shows which paths changed in between. Files over
+/// [`SNAPSHOT_HASH_LIMIT`] get a fingerprint of their size and modification time instead.
+pub fn worktree_snapshot(repo: &Repository) -> Result<BTreeMap<String, Option<Oid>>> {
+    let workdir = repo
+        .workdir()
+        .ok_or(AtomicError::Static("Can't snapshot a bare repository"))?;
+
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .include_ignored(false);
+
+    let mut snapshot = BTreeMap::new();
+    for entry in repo.statuses(Some(&mut options))?.iter() {
+        let Some(path) = entry.path() else {
+            continue;
+        };
+        snapshot.insert(path.to_string(), fingerprint(&workdir.join(path))?);
+    }
+    Ok(snapshot)
+}
+
+fn fingerprint(path: &Path) -> Result<Option<Oid>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(None),
+    };
+    if metadata.len() <= SNAPSHOT_HASH_LIMIT {
+        return Ok(Some(Oid::hash_file(ObjectType::Blob, path)?));
+    }
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let stand_in = format!("{} bytes, modified {:?}", metadata.len(), modified);
+    Ok(Some(Oid::hash_object(
+        ObjectType::Blob,
+        stand_in.as_bytes(),
+    )?))
+}
+
+/// The paths of `snapshot` over [`SNAPSHOT_HASH_LIMIT`], which it didn't hash.
+pub fn unhashed_paths(repo: &Repository, snapshot: &BTreeMap<String, Option<Oid>>) -> Vec<String> {
+    let Some(workdir) = repo.workdir() else {
+        return Vec::new();
+    };
+    snapshot
+        .keys()
+        .filter(|path| {
+            fs::metadata(workdir.join(path))
+                .is_ok_and(|metadata| metadata.is_file() && metadata.len() > SNAPSHOT_HASH_LIMIT)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Who changed a path an auto-commit took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    /// Clean before the run; the command changed it.
+    Command,
+    /// Already dirty before the run, and the command left it alone.
+    Preexisting,
+    /// Already dirty before the run, and the command changed it again.
+    Both,
+}
+
+impl Origin {
+    pub fn label(self) -> &'static str {
+        match self {
+            Origin::Command => "changed by command",
+            Origin::Preexisting => "pre-existing change",
+            Origin::Both => "both",
+        }
+    }
+}
+
+/// Paths with uncommitted changes, split by whether a command made them.
+#[derive(Debug, Default, PartialEq)]
+pub struct RunChanges {
+    /// Paths the command created, modified or deleted, including ones that were already dirty.
+    pub changed: Vec<String>,
+    /// Paths that were already dirty before the command and that it didn't touch.
+    pub preexisting: Vec<String>,
+    /// Paths of `changed` that were already dirty before the command.
+    pub both: Vec<String>,
+}
+
+impl RunChanges {
+    /// Compares snapshots taken with [`worktree_snapshot`] before and after a command.
+    pub fn between(
+        before: &BTreeMap<String, Option<Oid>>,
+        after: &BTreeMap<String, Option<Oid>>,
+    ) -> Self {
+        let mut changes = Self::default();
+        for (path, fingerprint) in after {
+            match before.get(path) {
+                Some(previous) if previous == fingerprint => changes.preexisting.push(path.clone()),
+                Some(_) => {
+                    changes.changed.push(path.clone());
+                    changes.both.push(path.clone());
+                }
+                None => changes.changed.push(path.clone()),
+            }
+        }
+        // dirty before and clean now means the command reverted it
+        let reverted = before.keys().filter(|path| !after.contains_key(*path));
+        changes.changed.extend(reverted.clone().cloned());
+        changes.both.extend(reverted.cloned());
+        changes.changed.sort();
+        changes.both.sort();
+        changes
+    }
+
+    pub fn origin(&self, path: &str) -> Origin {
+        let has = |paths: &[String]| paths.iter().any(|p| p == path);
+        if has(&self.preexisting) {
+            Origin::Preexisting
+        } else if has(&self.both) {
+            Origin::Both
+        } else {
+            Origin::Command
+        }
+    }
+
+    /// The `--- changed before the run ---` paragraph of an auto-commit message, naming the
+    /// committed paths of `paths` (all of them when `None`) that the command didn't make alone.
+    pub fn commit_paragraph(&self, paths: Option<&[String]>) -> Option<String> {
+        let committed = |path: &&String| paths.is_none_or(|paths| paths.contains(path));
+        let lines: Vec<String> = self
+            .preexisting
+            .iter()
+            .chain(&self.both)
+            .filter(committed)
+            .map(|path| format!("{} ({})\n", path, self.origin(path).label()))
+            .collect();
+        if lines.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "--- changed before the run ---\n{}",
+            lines.concat()
+        ))
+    }
+}
+
+/// Picks the identity for auto-commits: git's `user.name` and `user.email`, or `fallback`
+/// (a `"Name <email>"` string) when either is missing.
+///
+/// # Errors
+/// Returns [`AtomicError::MissingGitIdentity`] naming the missing key when there is no fallback,
+/// and [`AtomicError::InvalidCommitAuthor`] if `fallback` is malformed.
+pub fn commit_signature(
+    config: &git2::Config,
+    fallback: Option<&str>,
+) -> Result<Signature<'static>> {
+    // parse up front so a typo in atomic.toml is reported even while git config covers for it
+    let fallback = fallback.map(parse_author).transpose()?;
+
+    let name = config.get_string("user.name").ok();
+    let email = config.get_string("user.email").ok();
+
+    let (name, email) = match (name, email, fallback) {
+        (Some(name), Some(email), _) => (name, email),
+        (_, _, Some(author)) => author,
+        (None, _, None) => return Err(AtomicError::MissingGitIdentity("user.name", "Your Name")),
+        (_, None, None) => {
+            return Err(AtomicError::MissingGitIdentity(
+                "user.email",
+                "you@example.com",
+            ))
+        }
+    };
+    Ok(Signature::now(&name, &email)?)
+}
+
+/// Splits `"CI Bot <ci@example.com>"` into its name and email.
+fn parse_author(author: &str) -> Result<(String, String)> {
+    let invalid = || AtomicError::InvalidCommitAuthor(author.to_string());
+
+    let (name, rest) = author.trim().split_once('<').ok_or_else(invalid)?;
+    let email = rest.strip_suffix('>').ok_or_else(invalid)?;
+    let name = name.trim();
+
+    if name.is_empty()
+        || email.is_empty()
+        || email.contains(|c: char| c == '<' || c == '>' || c.is_whitespace())
+    {
+        return Err(invalid());
+    }
+    Ok((name.to_string(), email.to_string()))
+}
+
+/// Directory git looks for hooks in: `core.hooksPath` when set, otherwise `.git/hooks`.
+///
+/// Linked worktrees share the main repository's hooks, so the default comes from the common dir.
+pub fn hooks_dir(repo: &Repository) -> PathBuf {
+    let configured = repo
+        .config()
+        .and_then(|config| config.get_path("core.hooksPath"))
+        .ok();
+
+    match configured {
+        Some(path) if path.is_absolute() => path,
+        // relative hook paths are resolved against the work tree, like git does
+        Some(path) => repo.workdir().unwrap_or_else(|| repo.path()).join(path),
+        None => common_dir(repo).join("hooks"),
+    }
+}
+
+/// The git dir shared by every worktree: `.git` itself, or the main `.git` for a linked worktree,
+/// whose own git dir (`.git/worktrees/<name>`) names it in a `commondir` file.
+pub fn common_dir(repo: &Repository) -> PathBuf {
+    if !repo.is_worktree() {
+        return repo.path().to_path_buf();
+    }
+    match fs::read_to_string(repo.path().join("commondir")) {
+        Ok(common) => {
+            let common = repo.path().join(common.trim());
+            fs::canonicalize(&common).unwrap_or(common)
+        }
+        Err(_) => repo.path().to_path_buf(),
+    }
+}
+
+/// Runs the hook `name` if the repository has an executable one.
+///
+/// Returns whether a hook ran. Hook output goes straight to the terminal.
+///
+/// # Errors
+/// Returns an error if the hook can't be started or exits non-zero, which aborts the commit.
+pub fn run_git_hook(repo: &Repository, name: &str, args: &[&Path]) -> Result<bool> {
+    let hook = hooks_dir(repo).join(name);
+    if !is_executable(&hook) {
+        return Ok(false);
+    }
+
+    // git for windows runs hooks through its bundled sh
+    let mut process = if cfg!(target_os = "windows") {
+        let mut c = Command::new("sh");
+        c.arg(&hook);
+        c
+    } else {
+        Command::new(&hook)
+    };
+    let status = process
+        .args(args)
+        .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+        .status()
+        .map_err(|err| AtomicError::Generic(format!("Failed to run {} hook: {}", name, err)))?;
+
+    if !status.success() {
+        return Err(AtomicError::Generic(format!(
+            "{} hook failed with status code {}, auto-commit aborted",
+            name,
+            status.code().unwrap_or(-1)
+        )));
+    }
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Trailer key naming the atomic command that produced an auto-commit.
+pub const COMMAND_TRAILER: &str = "Atomic-Command";
+/// Trailer key with the version of atomic that made a commit.
+pub const VERSION_TRAILER: &str = "Atomic-Version";
+/// Trailer key with the blob id of the config file an auto-commit's command ran with.
+pub const CONFIG_HASH_TRAILER: &str = "Atomic-Config-Hash";
+/// Trailer key listing the steps of an auto-commit's run with their exit codes: `fmt=0, test=0`.
+pub const STEPS_TRAILER: &str = "Atomic-Steps";
+
+/// The trailers in the last paragraph of `message`, in order. Empty unless every line of that
+/// paragraph is a `Key: value` trailer; the subject never is one, even when it looks like it.
+pub fn parse_trailers(message: &str) -> Vec<(&str, &str)> {
+    let Some((_, last_paragraph)) = message.trim_end().rsplit_once("\n\n") else {
+        return Vec::new();
+    };
+    last_paragraph
+        .lines()
+        .map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let valid =
+                !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+            valid.then(|| (key, value.trim()))
+        })
+        .collect::<Option<Vec<_>>>()
+        .unwrap_or_default()
+}
+
+/// `message` with `trailers` added to the paragraph of trailers it ends with, or as a new one.
+pub fn append_trailers(message: &str, trailers: &[(&str, &str)]) -> String {
+    if trailers.is_empty() {
+        return message.to_string();
+    }
+    let message = message.trim_end();
+    let separator = match parse_trailers(message).is_empty() {
+        true => "\n\n",
+        false => "\n",
+    };
+    let lines: String = trailers
+        .iter()
+        .map(|(key, value)| format!("{}: {}\n", key, value))
+        .collect();
+    format!("{}{}{}", message, separator, lines)
+}
+
+/// The `Atomic-Config-Hash` of the config file at `path`: the id git gives its contents as a
+/// blob, so `atomic --verify` can look for it in a commit's tree.
+pub fn config_hash(path: &Path) -> Result<String> {
+    Ok(Oid::hash_file(ObjectType::Blob, path)?.to_string())
+}
+
+/// Returns the value of the trailer `key` in the last paragraph of a commit `message`.
+pub fn commit_trailer<'a>(message: &'a str, key: &str) -> Option<&'a str> {
+    let last_paragraph = message.trim_end().rsplit("\n\n").next()?;
+    last_paragraph.lines().rev().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        (k.trim() == key).then(|| v.trim())
+    })
+}
+
+/// Finds the most recent commit reachable from HEAD whose `Atomic-Command` trailer names `command`.
+pub fn find_last_run<'r>(repo: &'r Repository, command: &str) -> Result<Option<Commit<'r>>> {
+    let mut revwalk = repo.revwalk()?;
+    match revwalk.push_head() {
+        Ok(()) => {}
+        Err(err) if err.code() == ErrorCode::UnbornBranch => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        if commit_trailer(commit.message().unwrap_or_default(), COMMAND_TRAILER) == Some(command) {
+            return Ok(Some(commit));
+        }
+    }
+    Ok(None)
+}
+
+/// Renders what changed in the work tree since `command`'s last auto-commit, for `atomic --diff`.
+///
+/// The tree of that commit is compared with the work tree as the index sees it, untracked files
+/// included, and shown as a patch, or with `stat` as one line per file and a summary. Returns
+/// `None` when no auto-commit records `command`, and an empty string when nothing changed.
+pub fn diff_since_last_run(repo: &Repository, command: &str, stat: bool) -> Result<Option<String>> {
+    let Some(commit) = find_last_run(repo, command)? else {
+        return Ok(None);
+    };
+    let mut options = DiffOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true);
+    let diff = repo.diff_tree_to_workdir_with_index(Some(&commit.tree()?), Some(&mut options))?;
+
+    let mut out = String::new();
+    if stat {
+        let stats = stats_for(&diff)?;
+        if stats.files.is_empty() {
+            return Ok(Some(out));
+        }
+        for file in &stats.files {
+            out.push_str(&format!(
+                "  {} {} (+{} / -{})\n",
+                file.status, file.path, file.insertions, file.deletions
+            ));
+        }
+        out.push_str(&stats.summary());
+        out.push('\n');
+    } else {
+        diff.print(DiffFormat::Patch, |_, _, line| {
+            if matches!(line.origin(), '+' | '-' | ' ') {
+                out.push(line.origin());
+            }
+            out.push_str(&String::from_utf8_lossy(line.content()));
+            true
+        })?;
+    }
+    Ok(Some(out))
+}
+
+/// Collects what `commit` changed relative to its first parent.
+pub fn commit_stats(repo: &Repository, commit: &Commit) -> Result<AutoCommit> {
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) => None,
+    };
+    let stats = diff_stats(repo, parent_tree.as_ref(), &commit.tree()?)?;
+    Ok(AutoCommit {
+        id: commit.id(),
+        stats,
+        origins: BTreeMap::new(),
+        branch: None,
+    })
+}
+
+/// Prefix of every commit message atomic writes, used to tell auto-commits apart from manual ones.
+pub const AUTO_COMMIT_PREFIX: &str = "atomic: ";
+
+/// What `atomic --log` shows for a single commit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitSummary {
+    pub short_id: String,
+    pub subject: String,
+    /// Commit time in seconds since the unix epoch.
+    pub time: i64,
+    pub auto: bool,
+}
+
+impl CommitSummary {
+    fn from_commit(commit: &Commit) -> Self {
+        let subject = commit.summary().unwrap_or_default().to_string();
+        Self {
+            short_id: commit.id().to_string().chars().take(7).collect(),
+            // commands with a `commit_message` are still marked by their trailer
+            auto: subject.starts_with(AUTO_COMMIT_PREFIX)
+                || commit_trailer(commit.message().unwrap_or_default(), COMMAND_TRAILER).is_some(),
+            subject,
+            time: commit.time().seconds(),
+        }
+    }
+}
+
+/// Local branches tried, in order, when the remote doesn't say which branch is the default.
+const BASE_BRANCH_CANDIDATES: [&str; 4] = ["main", "master", "trunk", "develop"];
+
+/// Works out the branch work gets merged into, for when `base_branch` isn't configured.
+///
+/// Uses what `origin/HEAD` points at (e.g. `origin/main`), then the first of main, master, trunk
+/// and develop that exists locally.
+///
+/// # Errors
+/// Returns an error if neither turns anything up.
+pub fn detect_base_branch(repo: &Repository) -> Result<String> {
+    let remote_head = repo
+        .find_reference("refs/remotes/origin/HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().map(str::to_string));
+    if let Some(base) =
+        remote_head.and_then(|target| target.strip_prefix("refs/remotes/").map(str::to_string))
+    {
+        return Ok(base);
+    }
+
+    BASE_BRANCH_CANDIDATES
+        .into_iter()
+        .find(|name| repo.find_branch(name, BranchType::Local).is_ok())
+        .map(str::to_string)
+        .ok_or(AtomicError::Static(
+            "Couldn't detect the base branch: origin/HEAD isn't set and there is no main, \
+             master, trunk or develop branch. Set base_branch under [atomic] in atomic.toml.",
+        ))
+}
+
+/// Lists up to `limit` commits reachable from HEAD, newest first.
+///
+/// When `base` names an existing branch (local, or remote such as `origin/main`), the walk stops
+/// at the merge-base with it so only the commits made on the current branch are returned. An
+/// unborn branch yields an empty list.
+pub fn branch_log(
+    repo: &Repository,
+    limit: usize,
+    base: Option<&str>,
+) -> Result<Vec<CommitSummary>> {
+    let head = match repo.head() {
+        Ok(head) => head.peel_to_commit()?,
+        Err(err) if err.code() == ErrorCode::UnbornBranch => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(head.id())?;
+
+    let base_commit = base.and_then(|base| repo.revparse_single(base).ok()?.peel_to_commit().ok());
+    if let Some(base_commit) = base_commit {
+        let merge_base = repo.merge_base(head.id(), base_commit.id())?;
+        // on the base branch itself there is nothing to cut off
+        if merge_base != head.id() {
+            revwalk.hide(merge_base)?;
+        }
+    }
+
+    revwalk
+        .take(limit)
+        .map(|oid| Ok(CommitSummary::from_commit(&repo.find_commit(oid?)?)))
+        .collect()
+}
+
+/// Renders one `atomic --log` row; auto-commits are marked with a `*`.
+pub fn format_commit_row(commit: &CommitSummary, now: i64) -> String {
+    format!(
+        "{} {} {:<14} {}",
+        if commit.auto { "*" } else { " " },
+        commit.short_id,
+        relative_time(now - commit.time),
+        commit.subject
+    )
+}
+
+/// Turns an age in seconds into `just now`, `5 minutes ago`, `3 days ago` and so on.
+pub fn relative_time(seconds: i64) -> String {
+    const UNITS: [(i64, &str); 6] = [
+        (60 * 60 * 24 * 365, "year"),
+        (60 * 60 * 24 * 30, "month"),
+        (60 * 60 * 24 * 7, "week"),
+        (60 * 60 * 24, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+    ];
+
+    for (size, name) in UNITS {
+        let count = seconds / size;
+        if count > 0 {
+            return format!(
+                "{} {}{} ago",
+                count,
+                name,
+                if count == 1 { "" } else { "s" }
+            );
+        }
+    }
+    "just now".to_string()
+}
+
+/// Namespace of the lightweight tags `atomic --snapshot` creates.
+pub const SNAPSHOT_TAGS: &str = "refs/tags/atomic/snapshot/";
+
+/// Where `atomic --restore` keeps the commits a restore would otherwise drop.
+pub const BACKUP_REFS: &str = "refs/atomic/backup/";
+
+/// Turns a snapshot name like `Before refactor!` into the slug its tag is named after,
+/// `before-refactor`: lowercase letters, digits and `_`, with anything else as single dashes.
+///
+/// # Errors
+/// Returns an error when nothing usable is left of `name`.
+pub fn snapshot_slug(name: &str) -> Result<String> {
+    let mut slug = String::new();
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        return Err(AtomicError::Generic(format!(
+            "Can't name a snapshot '{}': use letters or digits",
+            name
+        )));
+    }
+    Ok(slug.to_string())
+}
+
+/// A commit tagged by `atomic --snapshot`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotTag {
+    pub slug: String,
+    pub id: Oid,
+    pub subject: String,
+    /// Commit time in seconds since the unix epoch.
+    pub time: i64,
+}
+
+impl SnapshotTag {
+    fn new(slug: &str, commit: &Commit) -> Self {
+        Self {
+            slug: slug.to_string(),
+            id: commit.id(),
+            subject: commit.summary().unwrap_or_default().to_string(),
+            time: commit.time().seconds(),
+        }
+    }
+
+    pub fn short_id(&self) -> String {
+        self.id.to_string().chars().take(7).collect()
+    }
+}
+
+/// Commits the work tree as [`commit_changes`] does, or HEAD's tree unchanged when nothing
+/// changed, and tags the commit `atomic/snapshot/<slug>` after `name`.
+///
+/// # Errors
+/// Returns an error if `name` has no usable slug, a snapshot by that slug exists, or the commit
+/// fails.
+pub fn create_snapshot(
+    repo: &Repository,
+    name: &str,
+    options: &CommitOptions,
+) -> Result<SnapshotTag> {
+    let slug = snapshot_slug(name)?;
+    let tag = format!("{}{}", SNAPSHOT_TAGS, slug);
+    if repo.find_reference(&tag).is_ok() {
+        return Err(AtomicError::Generic(format!(
+            "A snapshot named '{}' already exists",
+            slug
+        )));
+    }
+
+    let message = format!("{}snapshot {}", AUTO_COMMIT_PREFIX, name.trim());
+    let id = match commit_changes(repo, &message, options)? {
+        Some(commit) => commit.id,
+        // a snapshot of a clean tree is an empty commit, like `git commit --allow-empty`
+        None => {
+            let user = commit_signature(&repo.config()?, options.commit_author)?;
+            let signer = match options.no_sign {
+                true => None,
+                false => Signer::from_config(&repo.config()?)?,
+            };
+            let head = repo.head()?;
+            let branch = head.resolve()?.name().unwrap_or("HEAD").to_string();
+            let parent = head.peel_to_commit()?;
+            let tree = parent.tree()?;
+            write_commit(repo, &branch, &user, signer, &message, &tree, &parent)?
+        }
+    };
+    repo.reference(&tag, id, false, &format!("atomic: snapshot {}", slug))?;
+    Ok(SnapshotTag::new(&slug, &repo.find_commit(id)?))
+}
+
+/// Every snapshot in the repository, newest first.
+pub fn list_snapshots(repo: &Repository) -> Result<Vec<SnapshotTag>> {
+    let mut snapshots = Vec::new();
+    for reference in repo.references_glob(&format!("{}*", SNAPSHOT_TAGS))? {
+        let reference = reference?;
+        let Some(slug) = reference
+            .name()
+            .and_then(|name| name.strip_prefix(SNAPSHOT_TAGS))
+        else {
+            continue;
+        };
+        snapshots.push(SnapshotTag::new(slug, &reference.peel_to_commit()?));
+    }
+    snapshots.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.slug.cmp(&b.slug)));
+    Ok(snapshots)
+}
+
+/// Renders `atomic --snapshots`, one snapshot per line with its slug, commit, age and subject.
+pub fn render_snapshots(snapshots: &[SnapshotTag], now: i64) -> String {
+    let width = snapshots
+        .iter()
+        .map(|snapshot| snapshot.slug.len())
+        .max()
+        .unwrap_or(0);
+    snapshots
+        .iter()
+        .map(|snapshot| {
+            format!(
+                "{:<width$}  {} {:<14} {}
+",
+                snapshot.slug,
+                snapshot.short_id(),
+                relative_time(now - snapshot.time),
+                snapshot.subject,
+                width = width
+            )
+        })
+        .collect()
+}
+
+/// What `atomic --restore` is about to do: move the branch from `head` to the snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestorePlan {
+    pub snapshot: SnapshotTag,
+    pub head: Oid,
+    /// Commits on the branch that aren't in the snapshot, newest first.
+    pub abandoned: Vec<CommitSummary>,
+}
+
+/// Paths whose work tree copy differs from the index, untracked ones included: what `git add -A`
+/// would stage.
+///
+/// Rename detection stays off, so a renamed file is its old path, deleted, and its new one.
+/// Ignored files are left out.
+pub fn unstaged_paths(repo: &Repository) -> Result<Vec<String>> {
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .include_ignored(false);
+    let unstaged = Status::WT_NEW
+        | Status::WT_MODIFIED
+        | Status::WT_DELETED
+        | Status::WT_TYPECHANGE
+        | Status::WT_RENAMED
+        | Status::CONFLICTED;
+    Ok(repo
+        .statuses(Some(&mut options))?
+        .iter()
+        .filter(|entry| entry.status().intersects(unstaged))
+        .filter_map(|entry| entry.path().map(str::to_string))
+        .collect())
+}
+
+/// Stages `paths` from the work tree of `repo`: those that exist are added, the rest removed from
+/// the index.
+fn stage_paths(
+    repo: &Repository,
+    index: &mut Index,
+    paths: &[String],
+    progress: &mut StageProgress,
+) -> Result<()> {
+    let workdir = repo
+        .workdir()
+        .ok_or(AtomicError::Static("Can't commit in a bare repository"))?;
+    for (staged, path) in paths.iter().enumerate() {
+        if fs::symlink_metadata(workdir.join(path)).is_ok() {
+            // a repository nested in the work tree is listed as `vendor/`, and added as a gitlink
+            index.add_path(Path::new(path.trim_end_matches('/')))?;
+        } else {
+            index.remove_path(Path::new(path))?;
+        }
+        progress.staged(staged + 1);
+    }
+    Ok(())
+}
+
+/// Whether the work tree of `repo` has changes to commit, untracked files included.
+pub fn has_changes(repo: &Repository) -> Result<bool> {
+    let mut options = StatusOptions::new();
+    options
+        .include_untracked(true)
+        .recurse_untracked_dirs(false)
+        .exclude_submodules(true);
+    Ok(!repo.statuses(Some(&mut options))?.is_empty())
+}
+
+/// Works out what restoring the snapshot called `name`, or its slug, would drop, without touching anything.
+///
+/// # Errors
+/// Returns an error if there's no such snapshot, or the work tree has uncommitted changes, which
+/// a restore would throw away.
+pub fn plan_restore(repo: &Repository, name: &str) -> Result<RestorePlan> {
+    let slug = snapshot_slug(name)?;
+    let reference = repo
+        .find_reference(&format!("{}{}", SNAPSHOT_TAGS, slug))
+        .map_err(|_| {
+            AtomicError::Generic(format!(
+                "No snapshot named '{}'; `atomic --snapshots` lists them",
+                slug
+            ))
+        })?;
+    let snapshot = SnapshotTag::new(&slug, &reference.peel_to_commit()?);
+    if has_changes(repo)? {
+        return Err(AtomicError::Static(
+            "The work tree has uncommitted changes, which restoring a snapshot would throw away. \
+             Commit them, or set them aside with `git stash`, and try again.",
+        ));
+    }
+
+    let head = repo.head()?.peel_to_commit()?.id();
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(head)?;
+    revwalk.hide(snapshot.id)?;
+    let abandoned = revwalk
+        .map(|oid| Ok(CommitSummary::from_commit(&repo.find_commit(oid?)?)))
+        .collect::<Result<_>>()?;
+    Ok(RestorePlan {
+        snapshot,
+        head,
+        abandoned,
+    })
+}
+
+/// Resets the current branch, index and work tree to the snapshot in `plan`.
+///
+/// When that drops commits, the old HEAD is kept under [`BACKUP_REFS`] first, so they stay
+/// reachable, and the name of that ref is returned.
+///
+/// # Errors
+/// Returns an error if the work tree changed since [`plan_restore`] or the reset fails.
+pub fn restore_snapshot(repo: &Repository, plan: &RestorePlan) -> Result<Option<String>> {
+    if has_changes(repo)? || repo.head()?.peel_to_commit()?.id() != plan.head {
+        return Err(AtomicError::Static(
+            "The repository changed while restoring; nothing was reset",
+        ));
+    }
+    let backup = match plan.abandoned.is_empty() {
+        true => None,
+        false => {
+            let name = format!("{}{}", BACKUP_REFS, plan.head);
+            let message = format!("atomic: before restoring {}", plan.snapshot.slug);
+            repo.reference(&name, plan.head, true, &message)?;
+            Some(name)
+        }
+    };
+    let target = repo.find_object(plan.snapshot.id, None)?;
+    repo.reset(&target, git2::ResetType::Hard, None)?;
+    Ok(backup)
+}
+
+pub fn parse_branch_name(branch_name: &str) -> Result<Vec<String>> {
+    // Check if the branch name is empty or contains only delimiters
+    if branch_name.trim().is_empty() || branch_name.chars().all(|c| c == '-')
+    // Check for only delimiters
+    {
+        return Err(AtomicError::Static(
+            "Branch name cannot be empty or contain only delimiters.",
+        ));
+    }
+
+    // Parse parts by splitting on '-'
+    let parts: Vec<String> = branch_name
+        .split('-') // Split by '-'
+        .filter(|s| !s.is_empty()) // Filter out empty parts
+        .map(|s| s.to_string()) // Convert to String
+        .collect();
+
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::init_repo;
+
+    fn command_options(command: &str) -> CommitOptions<'_> {
+        CommitOptions {
+            command: Some(command),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(unix)]
+    fn write_hook(dir: &Path, name: &str, script: &str) {
+        use std::os::unix::fs::PermissionsExt;
+        let hook = dir.join(".git/hooks").join(name);
+        fs::create_dir_all(hook.parent().unwrap()).unwrap();
+        fs::write(&hook, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pre_commit_hook_rejects() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        let before = repo.head().unwrap().target();
+        write_hook(dir.path(), "pre-commit", "echo 'not formatted' >&2; exit 1");
+
+        fs::write(dir.path().join("a.txt"), "b\n").unwrap();
+        let options = CommitOptions {
+            run_git_hooks: true,
+            ..Default::default()
+        };
+        let err = commit_changes(&repo, "atomic: fmt", &options).unwrap_err();
+        assert!(
+            err.to_string().contains("pre-commit hook failed"),
+            "{}",
+            err
+        );
+        assert_eq!(repo.head().unwrap().target(), before);
+
+        // hooks are opt-in
+        assert!(
+            commit_changes(&repo, "atomic: fmt", &CommitOptions::default())
+                .unwrap()
+                .is_some()
+        );
+        assert_ne!(repo.head().unwrap().target(), before);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pre_commit_hook_restages() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        write_hook(
+            dir.path(),
+            "pre-commit",
+            "echo formatted > a.txt && git add a.txt",
+        );
+        write_hook(
+            dir.path(),
+            "commit-msg",
+            "echo 'Reviewed-by: hook' >> \"$1\"",
+        );
+
+        fs::write(dir.path().join("a.txt"), "messy\n").unwrap();
+        let options = CommitOptions {
+            run_git_hooks: true,
+            ..command_options("fmt")
+        };
+        commit_changes(&repo, "atomic: fmt", &options).unwrap();
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let blob = head
+            .tree()
+            .unwrap()
+            .get_name("a.txt")
+            .unwrap()
+            .to_object(&repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        assert_eq!(blob.content(), b"formatted\n");
+        assert!(head.message().unwrap().ends_with("Reviewed-by: hook\n"));
+    }
+
+    fn identity_config(dir: &Path, name: Option<&str>, email: Option<&str>) -> git2::Config {
+        // a fresh file per call, kept until `dir` is removed
+        let file = tempfile::NamedTempFile::new_in(dir).unwrap();
+        let mut config = git2::Config::open(&file.into_temp_path().keep().unwrap()).unwrap();
+        if let Some(name) = name {
+            config.set_str("user.name", name).unwrap();
+        }
+        if let Some(email) = email {
+            config.set_str("user.email", email).unwrap();
+        }
+        config
+    }
+
+    #[test]
+    fn test_commit_signature_missing_identity() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = identity_config(dir.path(), None, Some("tester@example.com"));
+        let err = commit_signature(&config, None).err().unwrap();
+        assert_eq!(
+            err,
+            AtomicError::MissingGitIdentity("user.name", "Your Name")
+        );
+        assert!(err
+            .to_string()
+            .contains("git config --global user.name \"Your Name\""));
+
+        let config = identity_config(dir.path(), Some("Atomic Tester"), None);
+        let err = commit_signature(&config, None).err().unwrap();
+        assert_eq!(
+            err,
+            AtomicError::MissingGitIdentity("user.email", "you@example.com")
+        );
+    }
+
+    #[test]
+    fn test_commit_signature_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = identity_config(dir.path(), None, None);
+
+        let user = commit_signature(&config, Some("CI Bot <ci@example.com>")).unwrap();
+        assert_eq!(user.name(), Some("CI Bot"));
+        assert_eq!(user.email(), Some("ci@example.com"));
+
+        // git config wins when it is complete
+        let config = identity_config(
+            dir.path(),
+            Some("Atomic Tester"),
+            Some("tester@example.com"),
+        );
+        let user = commit_signature(&config, Some("CI Bot <ci@example.com>")).unwrap();
+        assert_eq!(user.name(), Some("Atomic Tester"));
+    }
+
+    #[test]
+    fn test_commit_signature_malformed_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = identity_config(
+            dir.path(),
+            Some("Atomic Tester"),
+            Some("tester@example.com"),
+        );
+
+        for author in [
+            "CI Bot",
+            "<ci@example.com>",
+            "CI Bot <>",
+            "CI Bot <ci@example.com",
+            "CI Bot <c i@x>",
+        ] {
+            assert_eq!(
+                commit_signature(&config, Some(author)).err().unwrap(),
+                AtomicError::InvalidCommitAuthor(author.to_string()),
+                "{}",
+                author
+            );
+        }
+    }
+
+    #[test]
+    fn test_commit_in_linked_worktree() {
+        let dir = tempfile::tempdir().unwrap();
+        let main_dir = dir.path().join("main");
+        let repo = init_repo(&main_dir, &[("a.txt", "a\n")]);
+        let main_head = repo.head().unwrap().target();
+
+        let wt_dir = dir.path().join("feature");
+        repo.worktree("feature", &wt_dir, None).unwrap();
+        let wt = Repository::discover(wt_dir.join(".")).unwrap();
+        assert_eq!(hooks_dir(&wt), repo.path().join("hooks"));
+
+        fs::write(wt_dir.join("b.txt"), "b\n").unwrap();
+        let commit = commit_changes(&wt, "atomic: build", &command_options("build"))
+            .unwrap()
+            .unwrap();
+
+        // the commit lands on the worktree's branch, leaving the main checkout alone
+        assert_eq!(current_branch(&wt).as_deref(), Some("feature"));
+        assert_eq!(wt.head().unwrap().target(), Some(commit.id));
+        assert_eq!(repo.head().unwrap().target(), main_head);
+        assert_eq!(
+            repo.find_branch("feature", BranchType::Local)
+                .unwrap()
+                .get()
+                .target(),
+            Some(commit.id)
+        );
+        assert!(!main_dir.join("b.txt").exists());
+        assert_eq!(
+            find_last_run(&repo, "build").unwrap().map(|c| c.id()),
+            None,
+            "main's history doesn't include the worktree's commit"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_worktree_uses_shared_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let main_dir = dir.path().join("main");
+        let repo = init_repo(&main_dir, &[("a.txt", "a\n")]);
+        write_hook(&main_dir, "pre-commit", "exit 1");
+
+        let wt_dir = dir.path().join("feature");
+        repo.worktree("feature", &wt_dir, None).unwrap();
+        let wt = Repository::open(&wt_dir).unwrap();
+
+        fs::write(wt_dir.join("b.txt"), "b\n").unwrap();
+        let options = CommitOptions {
+            run_git_hooks: true,
+            ..Default::default()
+        };
+        assert!(commit_changes(&wt, "atomic: build", &options).is_err());
+    }
+
+    #[test]
+    fn test_unchanged_tree_skips_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        let before = repo.head().unwrap().target();
+
+        // rewriting a file with identical content is still no change
+        fs::write(dir.path().join("a.txt"), "a\n").unwrap();
+        let commit = commit_changes(&repo, "atomic: fmt", &command_options("fmt")).unwrap();
+        assert!(commit.is_none());
+        assert_eq!(repo.head().unwrap().target(), before);
+
+        fs::write(dir.path().join("a.txt"), "b\n").unwrap();
+        let commit = commit_changes(&repo, "atomic: fmt", &command_options("fmt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(commit.stats.files_changed(), 1);
+        assert_eq!(repo.head().unwrap().target(), Some(commit.id));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unchanged_tree_skips_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        write_hook(dir.path(), "pre-commit", "exit 1");
+
+        let options = CommitOptions {
+            run_git_hooks: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            commit_changes(&repo, "atomic: fmt", &options).map(|c| c.is_none()),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_run_changes_between_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(
+            dir.path(),
+            &[("wip.txt", "a\n"), ("old.txt", "old\n"), ("b.txt", "b\n")],
+        );
+        assert!(worktree_snapshot(&repo).unwrap().is_empty());
+
+        fs::write(dir.path().join("wip.txt"), "half done\n").unwrap();
+        fs::write(dir.path().join("b.txt"), "dirty\n").unwrap();
+        let before = worktree_snapshot(&repo).unwrap();
+
+        // the command renames old.txt, edits b.txt again and reverts nothing
+        fs::rename(dir.path().join("old.txt"), dir.path().join("new.txt")).unwrap();
+        fs::write(dir.path().join("b.txt"), "formatted\n").unwrap();
+        let after = worktree_snapshot(&repo).unwrap();
+
+        assert_eq!(
+            RunChanges::between(&before, &after),
+            RunChanges {
+                changed: vec![
+                    "b.txt".to_string(),
+                    "new.txt".to_string(),
+                    "old.txt".to_string()
+                ],
+                preexisting: vec!["wip.txt".to_string()],
+                both: vec!["b.txt".to_string()],
+            }
+        );
+        let changes = RunChanges::between(&before, &after);
+        assert_eq!(changes.origin("new.txt"), Origin::Command);
+        assert_eq!(changes.origin("wip.txt"), Origin::Preexisting);
+        assert_eq!(changes.origin("b.txt"), Origin::Both);
+        assert_eq!(
+            changes.commit_paragraph(None).as_deref(),
+            Some("--- changed before the run ---\nwip.txt (pre-existing change)\nb.txt (both)\n")
+        );
+        let stat = |path: &str| FileStat {
+            path: path.to_string(),
+            status: 'M',
+            insertions: 1,
+            deletions: 0,
+        };
+        let commit = AutoCommit {
+            id: Oid::zero(),
+            stats: DiffStats {
+                files: vec![stat("b.txt"), stat("new.txt"), stat("wip.txt")],
+                ..Default::default()
+            },
+            origins: BTreeMap::new(),
+            branch: None,
+        };
+        let origins = |changes| {
+            commit
+                .clone()
+                .with_origins(changes)
+                .origins
+                .into_values()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            origins(Some(&changes)),
+            [Origin::Both, Origin::Command, Origin::Preexisting]
+        );
+        assert_eq!(origins(None), [Origin::Command; 3]);
+        let command_only = ["b.txt".to_string(), "new.txt".to_string()];
+        assert_eq!(
+            changes.commit_paragraph(Some(&command_only)).as_deref(),
+            Some("--- changed before the run ---\nb.txt (both)\n")
+        );
+
+        // reverting a dirty file counts as a change
+        fs::write(dir.path().join("wip.txt"), "a\n").unwrap();
+        let reverted = RunChanges::between(&before, &worktree_snapshot(&repo).unwrap());
+        assert!(reverted.changed.contains(&"wip.txt".to_string()));
+        assert!(reverted.preexisting.is_empty());
+    }
+
+    #[test]
+    fn test_commit_only_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("wip.txt", "a\n"), ("gone.txt", "g\n")]);
+
+        // preexisting work, staged and unstaged
+        fs::write(dir.path().join("wip.txt"), "half done\n").unwrap();
+        fs::write(dir.path().join("staged.txt"), "staged\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("staged.txt")).unwrap();
+        index.write().unwrap();
+
+        // what the command did
+        fs::write(dir.path().join("gen.rs"), "// generated\n").unwrap();
+        fs::remove_file(dir.path().join("gone.txt")).unwrap();
+
+        let paths = vec!["gen.rs".to_string(), "gone.txt".to_string()];
+        let options = CommitOptions {
+            paths: Some(&paths),
+            ..command_options("codegen")
+        };
+        let commit = commit_changes(&repo, "atomic: codegen", &options)
+            .unwrap()
+            .unwrap();
+        let stats = &commit.stats;
+        assert_eq!((stats.added, stats.modified, stats.deleted), (1, 0, 1));
+
+        // the preexisting changes are still there, and staged.txt is still staged
+        let statuses: Vec<_> = repo
+            .statuses(None)
+            .unwrap()
+            .iter()
+            .map(|e| (e.path().unwrap().to_string(), e.status()))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("staged.txt".to_string(), git2::Status::INDEX_NEW),
+                ("wip.txt".to_string(), git2::Status::WT_MODIFIED),
+            ]
+        );
+
+        // nothing left from the run: an empty path list commits nothing
+        let options = CommitOptions {
+            paths: Some(&[]),
+            ..command_options("codegen")
+        };
+        assert_eq!(
+            commit_changes(&repo, "atomic: codegen", &options).map(|c| c.is_none()),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_large_files_guard() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(
+            dir.path(),
+            &[("small.txt", "a\n"), ("kept.bin", &"k".repeat(4096))],
+        );
+        let head = || repo.head().unwrap().target().unwrap();
+        let before = head();
+        fs::write(dir.path().join("small.txt"), "b\n").unwrap();
+        fs::write(dir.path().join("build.bin"), "x".repeat(4096)).unwrap();
+        let options = CommitOptions {
+            max_file_size: Some(1024),
+            ..command_options("build")
+        };
+
+        // already committed files over the limit don't count, only new ones
+        assert_eq!(
+            commit_changes(&repo, "atomic: build", &options).map(|_| ()),
+            Err(AtomicError::LargeFiles(
+                "1.0 KB".to_string(),
+                vec!["build.bin (4.0 KB)".to_string()]
+            ))
+        );
+        assert_eq!(head(), before);
+        assert!(
+            repo.index()
+                .unwrap()
+                .get_path(Path::new("build.bin"), 0)
+                .is_none(),
+            "an aborted commit stages nothing"
+        );
+
+        let skip = CommitOptions {
+            skip_large_files: true,
+            ..options
+        };
+        let commit = commit_changes(&repo, "atomic: build", &skip)
+            .unwrap()
+            .unwrap();
+        let tree = repo.find_commit(commit.id).unwrap().tree().unwrap();
+        assert!(tree.get_path(Path::new("small.txt")).is_ok());
+        assert!(tree.get_path(Path::new("build.bin")).is_err());
+        let status = repo.status_file(Path::new("build.bin")).unwrap();
+        assert_eq!(status, git2::Status::WT_NEW);
+
+        // a committed file that grew past the limit is put back as it was
+        fs::write(dir.path().join("small.txt"), "c".repeat(4096)).unwrap();
+        fs::write(dir.path().join("other.txt"), "o\n").unwrap();
+        let commit = commit_changes(&repo, "atomic: build", &skip)
+            .unwrap()
+            .unwrap();
+        assert_eq!((commit.stats.added, commit.stats.modified), (1, 0));
+        let status = repo.status_file(Path::new("small.txt")).unwrap();
+        assert_eq!(status, git2::Status::WT_MODIFIED);
+
+        // files Git LFS tracks are stored as pointers, so they're exempt
+        fs::write(dir.path().join("small.txt"), "d\n").unwrap();
+        fs::write(
+            dir.path().join(".gitattributes"),
+            "*.bin filter=lfs diff=lfs merge=lfs -text\n",
+        )
+        .unwrap();
+        let commit = commit_changes(&repo, "atomic: build", &options)
+            .unwrap()
+            .unwrap();
+        let tree = repo.find_commit(commit.id).unwrap().tree().unwrap();
+        assert!(tree.get_path(Path::new("build.bin")).is_ok());
+    }
+
+    #[test]
+    fn test_hooks_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        assert_eq!(hooks_dir(&repo), repo.path().join("hooks"));
+
+        repo.config()
+            .unwrap()
+            .set_str("core.hooksPath", ".githooks")
+            .unwrap();
+        assert_eq!(hooks_dir(&repo), repo.workdir().unwrap().join(".githooks"));
+    }
+
+    #[test]
+    fn test_commit_branch() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let repo = init_repo(root, &[("a.txt", "a\n")]);
+        let head = repo.head().unwrap().target().unwrap();
+        // something the user staged, and something they didn't
+        fs::write(root.join("staged.txt"), "staged\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("staged.txt")).unwrap();
+        index.write().unwrap();
+        let staged = index.write_tree().unwrap();
+        fs::write(root.join("a.txt"), "formatted\n").unwrap();
+
+        let options = CommitOptions {
+            branch: Some("atomic/fmt"),
+            ..command_options("fmt")
+        };
+        let commit = commit_changes(&repo, "style: auto-format", &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(commit.branch.as_deref(), Some("atomic/fmt"));
+        assert_eq!(commit.stats.files_changed(), 2);
+
+        // the side branch starts at HEAD and has the work tree in it
+        let side = repo
+            .revparse_single("atomic/fmt")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(side.id(), commit.id);
+        assert_eq!(side.parent_id(0).unwrap(), head);
+        assert_eq!(side.summary(), Some("style: auto-format"));
+        let blob = side
+            .tree()
+            .unwrap()
+            .get_path(Path::new("a.txt"))
+            .unwrap()
+            .id();
+        assert_eq!(repo.find_blob(blob).unwrap().content(), b"formatted\n");
+
+        // while the checkout is left alone
+        assert_eq!(repo.head().unwrap().target(), Some(head));
+        assert_eq!(current_branch(&repo).as_deref(), Some("master"));
+        assert_eq!(
+            fs::read_to_string(root.join("a.txt")).unwrap(),
+            "formatted\n"
+        );
+        assert_eq!(repo.index().unwrap().write_tree().unwrap(), staged);
+        let reopened = Repository::open(root).unwrap();
+        assert_eq!(reopened.index().unwrap().write_tree().unwrap(), staged);
+
+        // later commits build on the side branch
+        fs::write(root.join("a.txt"), "formatted again\n").unwrap();
+        let second = commit_changes(&repo, "style: auto-format", &options)
+            .unwrap()
+            .unwrap();
+        let side = repo.find_commit(second.id).unwrap();
+        assert_eq!(side.parent_id(0).unwrap(), commit.id);
+        assert_eq!(second.stats.files_changed(), 1);
+        assert!(commit_changes(&repo, "style: auto-format", &options)
+            .unwrap()
+            .is_none());
+        assert_eq!(repo.head().unwrap().target(), Some(head));
     }
 
-    // Normalize quotes for Windows compatibility
-    #[cfg(target_os = "windows")]
-    let cmd = cmd.replace('\'', "\""); // Replace single quotes with double quotes
+    #[test]
+    fn test_commit_branch_checked_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        let head = repo.head().unwrap().target().unwrap();
+        fs::write(dir.path().join("a.txt"), "b\n").unwrap();
 
-    // println!("Running command: {}", cmd);
+        // the checked-out branch is an ordinary auto-commit
+        let options = CommitOptions {
+            branch: Some("master"),
+            ..Default::default()
+        };
+        let commit = commit_changes(&repo, "atomic: fmt", &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(commit.branch, None);
+        assert_eq!(repo.head().unwrap().target(), Some(commit.id));
+        assert_ne!(commit.id, head);
 
-    // Build the command based on the OS
-    let mut process = if cfg!(target_os = "windows") {
-        let mut c = Command::new("cmd");
-        c.args(["/C", &cmd]) // Use /C for Windows
-            .stdout(Stdio::inherit()) // Inherit stdout
-            .stderr(Stdio::inherit()); // Inherit stderr
-        c
-    } else {
-        let mut c = Command::new("sh");
-        c.args(["-c", &cmd]) // Use -c for Unix-like systems
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit());
-        c
-    };
+        let invalid = CommitOptions {
+            branch: Some("bad..name"),
+            ..Default::default()
+        };
+        let err = commit_changes(&repo, "atomic: fmt", &invalid).unwrap_err();
+        assert!(
+            err.to_string().contains("isn't a valid branch name"),
+            "{}",
+            err
+        );
+    }
 
-    // Execute the command and handle results
-    match process.output() {
-        Ok(output) => {
-            // Check for success or failure status
-            if !output.status.success() {
-                eprintln!(
-                    "Command failed with status code: {}",
-                    output.status.code().unwrap_or(-1)
-                );
-            }
+    #[test]
+    fn test_stage_only_changed_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for i in 0..3000 {
+            let file = root.join(format!("src/m{}/f{}.txt", i % 30, i));
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, i.to_string()).unwrap();
         }
-        Err(err) => {
-            // Handle execution errors
-            eprintln!("Failed to execute command: {}\nError: {}", cmd, err);
+        fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        let repo = init_repo(root, &[]);
+        let before = repo.head().unwrap().peel_to_tree().unwrap();
+
+        fs::write(root.join("src/m1/f1.txt"), "edited").unwrap();
+        fs::write(root.join("src/m2/f2.txt"), "edited").unwrap();
+        fs::remove_file(root.join("src/m3/f3.txt")).unwrap();
+        fs::rename(root.join("src/m4/f4.txt"), root.join("src/m4/renamed.txt")).unwrap();
+        fs::create_dir(root.join("docs")).unwrap();
+        fs::write(root.join("docs/new.md"), "new").unwrap();
+        fs::write(root.join("build.log"), "ignored").unwrap();
+
+        let mut unstaged = unstaged_paths(&repo).unwrap();
+        unstaged.sort();
+        assert_eq!(
+            unstaged,
+            [
+                "docs/new.md",
+                "src/m1/f1.txt",
+                "src/m2/f2.txt",
+                "src/m3/f3.txt",
+                "src/m4/f4.txt",
+                "src/m4/renamed.txt",
+            ]
+        );
+
+        commit_changes(&repo, "atomic: edit", &CommitOptions::default())
+            .unwrap()
+            .unwrap();
+        let after = repo.head().unwrap().peel_to_tree().unwrap();
+        let diff = repo
+            .diff_tree_to_tree(Some(&before), Some(&after), None)
+            .unwrap();
+        let mut changes: Vec<(Delta, String)> = diff
+            .deltas()
+            .map(|delta| {
+                let file = delta.new_file().path().or(delta.old_file().path());
+                (delta.status(), file.unwrap().to_string_lossy().into_owned())
+            })
+            .collect();
+        changes.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            changes,
+            [
+                (Delta::Added, "docs/new.md".to_string()),
+                (Delta::Modified, "src/m1/f1.txt".to_string()),
+                (Delta::Modified, "src/m2/f2.txt".to_string()),
+                (Delta::Deleted, "src/m3/f3.txt".to_string()),
+                (Delta::Deleted, "src/m4/f4.txt".to_string()),
+                (Delta::Added, "src/m4/renamed.txt".to_string()),
+            ]
+        );
+        // nothing left behind but the ignored file
+        assert!(unstaged_paths(&repo).unwrap().is_empty());
+        assert!(!has_changes(&repo).unwrap());
+    }
+
+    #[test]
+    fn test_stage_nested_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        init_repo(&dir.path().join("vendor"), &[("lib.txt", "lib\n")]);
+        assert_eq!(unstaged_paths(&repo).unwrap(), ["vendor/"]);
+        // matching "*" against it fails; it's committed as a gitlink instead
+        commit_changes(&repo, "atomic: vendor", &CommitOptions::default()).unwrap();
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert_eq!(
+            tree.get_path(Path::new("vendor")).unwrap().filemode(),
+            0o160000
+        );
+        assert!(unstaged_paths(&repo).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_open_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        init_repo(dir.path(), &[("readme.md", "hi\n")]);
+        fs::create_dir(dir.path().join("src")).unwrap();
+
+        let repo = open_repository(Some(&dir.path().join("src"))).unwrap();
+        assert_eq!(
+            repo.workdir().unwrap().canonicalize().unwrap(),
+            dir.path().canonicalize().unwrap()
+        );
+        assert!(open_repository(Some(&env::temp_dir().join("atomic-no-such-repo"))).is_err());
+    }
+
+    #[test]
+    fn test_commit_changes_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(
+            dir.path(),
+            &[("keep.txt", "one\ntwo\n"), ("gone.txt", "bye\n")],
+        );
+
+        fs::write(dir.path().join("new.txt"), "a\nb\nc\n").unwrap();
+        fs::write(dir.path().join("keep.txt"), "one\n2\n").unwrap();
+        fs::remove_file(dir.path().join("gone.txt")).unwrap();
+
+        let commit = commit_changes(&repo, "atomic: test", &CommitOptions::default())
+            .unwrap()
+            .unwrap();
+        let stats = &commit.stats;
+        assert_eq!((stats.added, stats.modified, stats.deleted), (1, 1, 1));
+        assert_eq!((stats.insertions, stats.deletions), (4, 2));
+        assert_eq!(
+            stats.summary(),
+            "3 files changed, 1 added, 1 modified, 1 deleted (+4 / -2)"
+        );
+        assert_eq!(commit.short_id().len(), 7);
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.id(), commit.id);
+        assert!(head.tree().unwrap().get_name("gone.txt").is_none());
+    }
+
+    /// Whether `program` can be run, for tests that need it.
+    fn available(program: &str) -> bool {
+        Command::new(program)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok()
+    }
+
+    /// Commits a change to `repo` with `options`, returning the commit's signature if it has one.
+    fn commit_signature_text(repo: &Repository, options: &CommitOptions) -> Result<Option<String>> {
+        let dir = repo.workdir().unwrap();
+        let count = fs::read_dir(dir).unwrap().count();
+        fs::write(dir.join(format!("change-{}.txt", count)), "x\n").unwrap();
+        let commit = commit_changes(repo, "atomic: sign", options)?.unwrap();
+        assert_eq!(repo.head()?.peel_to_commit()?.id(), commit.id);
+        Ok(repo
+            .extract_signature(&commit.id, None)
+            .ok()
+            .map(|(signature, _)| signature.as_str().unwrap().to_string()))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_gpg_signed_commit() {
+        use std::os::unix::fs::PermissionsExt;
+
+        if !available("gpg") {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let gpg = |args: &[&str]| {
+            Command::new("gpg")
+                .env("GNUPGHOME", home.path())
+                .args(["--batch", "--pinentry-mode", "loopback", "--passphrase", ""])
+                .args(args)
+                .output()
+                .unwrap()
+        };
+        let generated = gpg(&[
+            "--quick-gen-key",
+            "Atomic Tester <tester@example.com>",
+            "ed25519",
+            "sign",
+            "never",
+        ]);
+        if !generated.status.success() {
+            // a gpg too old for ed25519 or without an agent; nothing to test against
+            return;
+        }
+        // the agent and keyring live in GNUPGHOME, which only this test's gpg sees
+        let wrapper = home.path().join("gpg.sh");
+        fs::write(
+            &wrapper,
+            format!(
+                "#!/bin/sh\nGNUPGHOME='{}' exec gpg --batch --pinentry-mode loopback --passphrase '' \"$@\"\n",
+                home.path().display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        {
+            let mut config = repo.config().unwrap();
+            config.set_bool("commit.gpgsign", true).unwrap();
+            config
+                .set_str("user.signingkey", "tester@example.com")
+                .unwrap();
+            config
+                .set_str("gpg.program", wrapper.to_str().unwrap())
+                .unwrap();
         }
+        let signature = commit_signature_text(&repo, &CommitOptions::default())
+            .unwrap()
+            .unwrap();
+        assert!(
+            signature.starts_with("-----BEGIN PGP SIGNATURE-----"),
+            "{}",
+            signature
+        );
+
+        // the signature covers the commit as stored
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+        let (signature, data) = repo.extract_signature(&head, None).unwrap();
+        fs::write(home.path().join("commit.sig"), &*signature).unwrap();
+        fs::write(home.path().join("commit.txt"), &*data).unwrap();
+        let verify = home.path().join("commit.sig");
+        let data = home.path().join("commit.txt");
+        let verified = gpg(&["--verify", verify.to_str().unwrap(), data.to_str().unwrap()]);
+        assert!(verified.status.success(), "{:?}", verified);
+
+        let unsigned = CommitOptions {
+            no_sign: true,
+            ..Default::default()
+        };
+        assert_eq!(commit_signature_text(&repo, &unsigned).unwrap(), None);
+        let _ = Command::new("gpgconf")
+            .env("GNUPGHOME", home.path())
+            .args(["--kill", "gpg-agent"])
+            .status();
     }
-}
 
+    #[test]
+    fn test_ssh_signed_commit() {
+        if !available("ssh-keygen") {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let keys = tempfile::tempdir().unwrap();
+        let key = keys.path().join("id_ed25519");
+        let generated = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", "tester", "-f"])
+            .arg(&key)
+            .status();
+        if !generated.is_ok_and(|status| status.success()) {
+            return;
+        }
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        {
+            let mut config = repo.config().unwrap();
+            config.set_bool("commit.gpgsign", true).unwrap();
+            config.set_str("gpg.format", "ssh").unwrap();
+            config
+                .set_str("user.signingkey", key.to_str().unwrap())
+                .unwrap();
+        }
+        let signature = commit_signature_text(&repo, &CommitOptions::default())
+            .unwrap()
+            .unwrap();
+        assert!(
+            signature.starts_with("-----BEGIN SSH SIGNATURE-----"),
+            "{}",
+            signature
+        );
+    }
 
-pub fn _get_git_info() -> Result<(String, String, u64)> {
-    // Get the current directory
-    let current_dir = env::current_dir().expect("Failed to get current directory");
+    #[test]
+    fn test_signing_failure_stops_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        let before = repo.head().unwrap().peel_to_commit().unwrap().id();
+        {
+            let mut config = repo.config().unwrap();
+            config.set_bool("commit.gpgsign", true).unwrap();
+            config.set_str("gpg.program", "atomic-no-such-gpg").unwrap();
+        }
+        let err = commit_signature_text(&repo, &CommitOptions::default()).unwrap_err();
+        assert!(matches!(err, AtomicError::Signing(_)), "{}", err);
+        assert_eq!(repo.head().unwrap().peel_to_commit().unwrap().id(), before);
 
-    // Open the repository
-    let repo = Repository::open(&current_dir)?;
+        let unsigned = CommitOptions {
+            no_sign: true,
+            ..Default::default()
+        };
+        assert_eq!(commit_signature_text(&repo, &unsigned).unwrap(), None);
+    }
 
-    // Get the current branch name
-    let head = repo.head().expect("Failed to get HEAD reference");
-    let branch_name = match head.shorthand() {
-        Some(name) => name,
-        None => return Err(AtomicError::Static("Failed to get current branch name")),
-    };
+    #[test]
+    fn test_branch_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        let base = current_branch(&repo).unwrap();
 
-    // Parse branch name into parts
-    let parts = parse_branch_name(branch_name)?; // Updated to handle Vec<String>
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        repo.branch("feature-1-log", &head, false).unwrap();
+        repo.set_head("refs/heads/feature-1-log").unwrap();
 
-    // Extract parts safely
-    let feature = parts.get(0).cloned().unwrap_or_default(); // First part as feature
-    let issue = parts.get(1).cloned().unwrap_or_default();   // Second part as issue number
-    let desc = parts.get(2..).map(|rest| rest.join("-")).unwrap_or_default(); // Remaining parts as description
+        fs::write(dir.path().join("a.txt"), "b\n").unwrap();
+        commit_changes(&repo, "manual change", &CommitOptions::default()).unwrap();
+        fs::write(dir.path().join("a.txt"), "c\n").unwrap();
+        commit_changes(&repo, "atomic: build", &command_options("build")).unwrap();
 
-    // Parse issue number safely
-    let issue_num = issue.parse::<u64>().unwrap_or(0);
+        let mine = branch_log(&repo, 15, Some(&base)).unwrap();
+        assert_eq!(
+            mine.iter()
+                .map(|c| (c.subject.as_str(), c.auto))
+                .collect::<Vec<_>>(),
+            vec![("atomic: build", true), ("manual change", false)]
+        );
 
-    // Return feature, description, and issue number
-    Ok((feature, desc, issue_num))
-}
+        // a remote-tracking base works the same way
+        repo.reference("refs/remotes/origin/trunk", head.id(), false, "test")
+            .unwrap();
+        assert_eq!(
+            branch_log(&repo, 15, Some("origin/trunk")).unwrap().len(),
+            2
+        );
 
+        let all = branch_log(&repo, 15, None).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2].subject, "initial");
 
-pub fn commit_local_changes() -> Result<()> {
-    let repo = Repository::open(".")?;
+        assert_eq!(branch_log(&repo, 1, None).unwrap().len(), 1);
 
-    let mut index = repo.index()?;
+        // on the base branch itself the full history is shown
+        repo.set_head(&format!("refs/heads/{}", base)).unwrap();
+        assert_eq!(branch_log(&repo, 15, Some(&base)).unwrap().len(), 1);
+    }
 
-    // Add all changes to the index (staging area)
-    index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
+    #[cfg(unix)]
+    #[test]
+    fn test_capture_invalid_utf8() {
+        let mut capture = Capture::default();
+        // Latin-1 "café", then bytes that are never UTF-8
+        let cmd = r"printf 'caf\351 \377\376 done\n'";
+        assert_eq!(
+            spawn_command(cmd, &[], false, None, Some(&mut capture)).unwrap(),
+            0
+        );
+        assert_eq!(capture.bytes, b"caf\xe9 \xff\xfe done\n");
+        assert_eq!(capture.text(), "caf\u{fffd} \u{fffd}\u{fffd} done\n");
+        assert_eq!(capture.invalid_utf8(), 3);
+    }
 
-    let repo_reference = repo.head()?.resolve()?;
-    let branch = repo_reference.name().expect("No HEAD exists");
+    #[test]
+    fn test_capture() {
+        let mut capture = Capture::default();
+        assert_eq!(
+            spawn_command("echo kept", &[], false, None, Some(&mut capture)).unwrap(),
+            0
+        );
+        assert_eq!(capture.text().trim_end(), "kept");
+        assert!(!capture.truncated);
 
-    // Get the current user information from the Git configuration
-    let config = repo.config()?;
-    let user_name = config.get_string("user.name")?;
-    let user_email = config.get_string("user.email")?;
+        capture.push(&vec![b'a'; CAPTURE_LIMIT]);
+        assert_eq!(capture.text().len(), CAPTURE_LIMIT);
+        assert!(capture.truncated);
+        capture.clear();
+        assert_eq!(capture, Capture::default());
 
-    // Create a Signature with the retrieved user information
-    let user = Signature::now(&user_name, &user_email)?;
+        // a character cut in half by the limit is dropped rather than replaced
+        capture.push(&vec![b'a'; CAPTURE_LIMIT - 1]);
+        capture.push("é".as_bytes());
+        assert!(capture.truncated);
+        assert_eq!(capture.text().len(), CAPTURE_LIMIT - 1);
+        assert_eq!(capture.invalid_utf8(), 0);
+        capture.clear();
 
-    // Commit the changes
-    let tree_id = index.write_tree()?;
-    let tree = repo.find_tree(tree_id)?;
-    let parent_commit = repo.find_commit(repo.head()?.peel_to_commit()?.id())?;
-    repo.commit(Some(branch), &user, &user, "test", &tree, &[&parent_commit])?;
+        // the tail keeps the end, past the limit the start stops at
+        capture.push(b"first\n");
+        capture.push(&vec![b'a'; CAPTURE_LIMIT]);
+        capture.push(b"\nsecond\nthird\n");
+        assert_eq!(capture.tail(2), "second\nthird");
+        assert!(!capture.tail(3).contains("first"));
+        capture.clear();
 
-    Ok(())
-}
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        fs::write(dir.path().join("a.txt"), "b\n").unwrap();
+        let options = CommitOptions {
+            body: Some("--- output ---\nbuilt\n"),
+            ..command_options("build")
+        };
+        commit_changes(&repo, "atomic: build", &options).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let message = head.message().unwrap();
+        assert_eq!(
+            message,
+            concat!(
+                "atomic: build\n\n--- output ---\nbuilt\n\nAtomic-Version: ",
+                env!("CARGO_PKG_VERSION"),
+                "\nAtomic-Command: build\n"
+            )
+        );
+        assert_eq!(commit_trailer(message, COMMAND_TRAILER), Some("build"));
+    }
 
-pub fn parse_branch_name(branch_name: &str) -> Result<Vec<String>> {
-    // Check if the branch name is empty or contains only delimiters
-    if branch_name.trim().is_empty()
-        || branch_name.chars().all(|c| c == '-') // Check for only delimiters
-    {
-        return Err(AtomicError::Static(
-            "Branch name cannot be empty or contain only delimiters.",
-        ));
+    #[test]
+    fn test_parse_trailers() {
+        assert_eq!(
+            parse_trailers("atomic: build\n\nbody\n\nAtomic-Command: build\nSigned-off-by: me\n"),
+            [("Atomic-Command", "build"), ("Signed-off-by", "me")]
+        );
+        // a subject, or a paragraph with prose in it, has none
+        assert!(parse_trailers("fix: the widget").is_empty());
+        assert!(parse_trailers("fix\n\nAtomic-Command: fmt\nand then some").is_empty());
+        assert!(parse_trailers("fix\n\nsee the docs: for more").is_empty());
+        assert!(parse_trailers("").is_empty());
     }
 
-    // Parse parts by splitting on '-'
-    let parts: Vec<String> = branch_name
-        .split('-') // Split by '-'
-        .filter(|s| !s.is_empty()) // Filter out empty parts
-        .map(|s| s.to_string()) // Convert to String
-        .collect();
+    #[test]
+    fn test_append_trailers() {
+        let trailers = [(COMMAND_TRAILER, "fmt"), (STEPS_TRAILER, "fmt=0")];
+        assert_eq!(
+            append_trailers("atomic: fmt\n", &trailers),
+            "atomic: fmt\n\nAtomic-Command: fmt\nAtomic-Steps: fmt=0\n"
+        );
+        assert_eq!(
+            append_trailers("atomic: fmt\n\n--- output ---\nok", &trailers[..1]),
+            "atomic: fmt\n\n--- output ---\nok\n\nAtomic-Command: fmt\n"
+        );
+        // added to the trailers the message already has
+        let signed = append_trailers("fix\n\nSigned-off-by: me\n", &trailers);
+        assert_eq!(
+            signed,
+            "fix\n\nSigned-off-by: me\nAtomic-Command: fmt\nAtomic-Steps: fmt=0\n"
+        );
+        assert_eq!(parse_trailers(&signed).len(), 3);
+        assert_eq!(commit_trailer(&signed, STEPS_TRAILER), Some("fmt=0"));
+        assert_eq!(append_trailers("fix\n", &[]), "fix\n");
+    }
 
-    Ok(parts)
-}
+    #[test]
+    fn test_commit_trailer() {
+        let message = "atomic: build\n\nAtomic-Command: build\n";
+        assert_eq!(commit_trailer(message, COMMAND_TRAILER), Some("build"));
+        assert_eq!(commit_trailer("atomic: build", COMMAND_TRAILER), None);
+        assert_eq!(
+            commit_trailer(
+                "fix\n\nAtomic-Command: fmt\n\nSigned-off-by: me",
+                COMMAND_TRAILER
+            ),
+            None
+        );
+        assert_eq!(
+            commit_trailer("x\n\nFoo: bar\nAtomic-Command: gen", COMMAND_TRAILER),
+            Some("gen")
+        );
+    }
+
+    #[test]
+    fn test_find_last_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
 
+        fs::write(dir.path().join("gen.rs"), "// generated\n").unwrap();
+        let codegen = commit_changes(&repo, "atomic: codegen", &command_options("codegen"))
+            .unwrap()
+            .unwrap();
+        fs::write(dir.path().join("a.txt"), "formatted\n").unwrap();
+        let fmt = commit_changes(&repo, "atomic: fmt", &command_options("fmt"))
+            .unwrap()
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let found = find_last_run(&repo, "codegen").unwrap().unwrap();
+        assert_eq!(found.id(), codegen.id);
+        let stats = commit_stats(&repo, &found).unwrap().stats;
+        assert_eq!(stats.files[0].path, "gen.rs");
+        assert_eq!(stats.added, 1);
+
+        assert_eq!(find_last_run(&repo, "fmt").unwrap().unwrap().id(), fmt.id);
+        assert!(find_last_run(&repo, "deploy").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_diff_since_last_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        assert_eq!(diff_since_last_run(&repo, "codegen", false), Ok(None));
+
+        fs::write(dir.path().join("gen.rs"), "// generated\nfn one() {}\n").unwrap();
+        commit_changes(&repo, "atomic: codegen", &command_options("codegen"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            diff_since_last_run(&repo, "codegen", false),
+            Ok(Some(String::new()))
+        );
+
+        fs::write(dir.path().join("gen.rs"), "// generated\nfn two() {}\n").unwrap();
+        fs::write(dir.path().join("new.rs"), "fn three() {}\n").unwrap();
+        let patch = diff_since_last_run(&repo, "codegen", false)
+            .unwrap()
+            .unwrap();
+        assert!(
+            patch.contains("diff --git a/gen.rs b/gen.rs\n"),
+            "{}",
+            patch
+        );
+        assert!(patch.contains("\n // generated\n-fn one() {}\n+fn two() {}\n"));
+        assert!(patch.contains("+fn three() {}\n"));
+
+        assert_eq!(
+            diff_since_last_run(&repo, "codegen", true),
+            Ok(Some(
+                "  M gen.rs (+1 / -1)\n  A new.rs (+1 / -0)\n\
+                 2 files changed, 1 added, 1 modified (+2 / -1)\n"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_branch_log_unborn() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        assert_eq!(branch_log(&repo, 15, Some("main")), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_detect_base_branch() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        // start from a branch name none of the tiers would pick, without the one init made
+        let initial = current_branch(&repo).unwrap();
+        repo.branch("feature-7-x", &head, false).unwrap();
+        repo.set_head("refs/heads/feature-7-x").unwrap();
+        repo.find_branch(&initial, BranchType::Local)
+            .unwrap()
+            .delete()
+            .unwrap();
+        for name in BASE_BRANCH_CANDIDATES {
+            if let Ok(mut branch) = repo.find_branch(name, BranchType::Local) {
+                branch.delete().unwrap();
+            }
+        }
+
+        assert!(detect_base_branch(&repo).is_err());
+
+        // local branches, in order of preference
+        repo.branch("develop", &head, false).unwrap();
+        assert_eq!(detect_base_branch(&repo), Ok("develop".to_string()));
+        repo.branch("master", &head, false).unwrap();
+        assert_eq!(detect_base_branch(&repo), Ok("master".to_string()));
+
+        // the remote's default branch wins over all of them
+        repo.reference("refs/remotes/origin/trunk", head.id(), false, "test")
+            .unwrap();
+        repo.reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/trunk",
+            false,
+            "test",
+        )
+        .unwrap();
+        assert_eq!(detect_base_branch(&repo), Ok("origin/trunk".to_string()));
+    }
+
+    #[test]
+    fn test_format_commit_row() {
+        let commit = CommitSummary {
+            short_id: "3f2a91c".to_string(),
+            subject: "atomic: build".to_string(),
+            time: 1_000,
+            auto: true,
+        };
+        assert_eq!(
+            format_commit_row(&commit, 1_000 + 2 * 60 * 60),
+            "* 3f2a91c 2 hours ago    atomic: build"
+        );
+
+        let manual = CommitSummary {
+            auto: false,
+            subject: "fix typo".to_string(),
+            ..commit
+        };
+        assert_eq!(
+            format_commit_row(&manual, 1_010),
+            "  3f2a91c just now       fix typo"
+        );
+    }
+
+    #[test]
+    fn test_relative_time() {
+        assert_eq!(relative_time(0), "just now");
+        assert_eq!(relative_time(59), "just now");
+        assert_eq!(relative_time(60), "1 minute ago");
+        assert_eq!(relative_time(60 * 60 * 24 * 3), "3 days ago");
+        assert_eq!(relative_time(60 * 60 * 24 * 400), "1 year ago");
+    }
+
+    #[test]
+    fn test_plan_invocation_unix_uses_shell() {
+        assert_eq!(
+            plan_invocation("cargo build && cargo test", false).unwrap(),
+            Invocation::Shell("cargo build && cargo test".to_string())
+        );
+        let long = "a".repeat(9000);
+        assert_eq!(
+            plan_invocation(&long, false).unwrap(),
+            Invocation::Shell(long)
+        );
+    }
+
+    #[test]
+    fn test_plan_invocation_windows_keeps_apostrophes() {
+        assert_eq!(
+            plan_invocation(r#"git commit -m "it's done""#, true).unwrap(),
+            Invocation::Direct(vec![
+                "git".to_string(),
+                "commit".to_string(),
+                "-m".to_string(),
+                "it's done".to_string()
+            ])
+        );
+        // an unbalanced apostrophe is left for cmd to deal with
+        assert_eq!(
+            plan_invocation("echo it's done", true).unwrap(),
+            Invocation::Shell("echo it's done".to_string())
+        );
+    }
+
+    #[test]
+    fn test_plan_invocation_windows_metacharacters_pass_through() {
+        for cmd in [
+            "cargo build | findstr warning",
+            "cargo build && cargo test",
+            r#"type C:\temp\out.txt"#,
+            "echo %PATH%",
+        ] {
+            assert_eq!(
+                plan_invocation(cmd, true).unwrap(),
+                Invocation::Shell(cmd.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_plan_invocation_windows_length_limit() {
+        let cmd = format!("echo {}", "a".repeat(9000));
+        let err = plan_invocation(&cmd, true).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("split it into a chain"));
+
+        let cmd = format!("echo {}", "a".repeat(WINDOWS_MAX_COMMAND_LEN - 5));
+        assert!(plan_invocation(&cmd, true).is_ok());
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn test_spawn_command_windows() {
+        assert_eq!(
+            spawn_command(r#"git --version"#, &[], false, None, None).unwrap(),
+            0
+        );
+        assert_eq!(
+            spawn_command("echo it's done", &[], false, None, None).unwrap(),
+            0
+        );
+        assert_eq!(
+            spawn_command("echo a | findstr a", &[], false, None, None).unwrap(),
+            0
+        );
+        assert!(spawn_command(
+            &format!("echo {}", "a".repeat(9000)),
+            &[],
+            false,
+            None,
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_branch_issue() {
+        assert_eq!(branch_issue("feature-144-adding_dark_mode"), Some(144));
+        assert_eq!(branch_issue("bug-7"), Some(7));
+        assert_eq!(branch_issue("main"), None);
+        assert_eq!(branch_issue("feature-dark-mode"), None);
+        assert_eq!(branch_issue(""), None);
+    }
+
+    #[test]
+    fn test_diff_stats_summary_singular() {
+        let stats = DiffStats {
+            modified: 1,
+            insertions: 2,
+            files: vec![FileStat {
+                path: "a.rs".to_string(),
+                status: 'M',
+                insertions: 2,
+                deletions: 0,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(stats.summary(), "1 file changed, 1 modified (+2 / -0)");
+        assert_eq!(DiffStats::default().summary(), "0 files changed (+0 / -0)");
+    }
 
     #[test]
     fn test_parse_branch_name() {
@@ -191,4 +3020,106 @@ mod tests {
             ])
         );
     }
+
+    #[test]
+    fn test_snapshot_slug() {
+        assert_eq!(snapshot_slug("Before refactor").unwrap(), "before-refactor");
+        assert_eq!(
+            snapshot_slug("  v1.2: API / done!  ").unwrap(),
+            "v1-2-api-done"
+        );
+        assert_eq!(snapshot_slug("wip_2").unwrap(), "wip_2");
+        assert!(snapshot_slug(" -- ").is_err());
+    }
+
+    #[test]
+    fn test_create_and_list_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        let initial = repo.head().unwrap().target().unwrap();
+
+        // a clean tree still gets a commit of its own
+        let clean = create_snapshot(&repo, "Before refactor", &CommitOptions::default()).unwrap();
+        assert_eq!(clean.slug, "before-refactor");
+        assert_eq!(clean.subject, "atomic: snapshot Before refactor");
+        let commit = repo.find_commit(clean.id).unwrap();
+        assert_eq!(commit.parent_id(0).unwrap(), initial);
+        assert_eq!(
+            commit.tree_id(),
+            repo.find_commit(initial).unwrap().tree_id()
+        );
+
+        fs::write(dir.path().join("a.txt"), "b\n").unwrap();
+        let dirty = create_snapshot(&repo, "halfway", &CommitOptions::default()).unwrap();
+        assert_eq!(repo.head().unwrap().target(), Some(dirty.id));
+        assert!(!has_changes(&repo).unwrap());
+        assert_eq!(
+            repo.find_reference("refs/tags/atomic/snapshot/halfway")
+                .unwrap()
+                .target(),
+            Some(dirty.id)
+        );
+
+        let err = create_snapshot(&repo, "before  refactor", &CommitOptions::default());
+        assert!(err.unwrap_err().to_string().contains("already exists"));
+
+        let mut slugs: Vec<String> = list_snapshots(&repo)
+            .unwrap()
+            .into_iter()
+            .map(|snapshot| snapshot.slug)
+            .collect();
+        slugs.sort();
+        assert_eq!(slugs, ["before-refactor", "halfway"]);
+
+        let rendered = render_snapshots(std::slice::from_ref(&clean), clean.time + 120);
+        assert_eq!(
+            rendered,
+            format!(
+                "before-refactor  {} 2 minutes ago  atomic: snapshot Before refactor\n",
+                clean.short_id()
+            )
+        );
+    }
+
+    #[test]
+    fn test_restore_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+        let snapshot = create_snapshot(&repo, "start", &CommitOptions::default()).unwrap();
+        fs::write(dir.path().join("a.txt"), "b\n").unwrap();
+        let dropped = commit_changes(&repo, "atomic: fmt", &CommitOptions::default())
+            .unwrap()
+            .unwrap();
+
+        // uncommitted work would be lost, so it's refused
+        fs::write(dir.path().join("notes.md"), "wip\n").unwrap();
+        let err = plan_restore(&repo, "start").unwrap_err().to_string();
+        assert!(err.contains("uncommitted changes"), "{}", err);
+        fs::remove_file(dir.path().join("notes.md")).unwrap();
+
+        assert!(plan_restore(&repo, "nope")
+            .unwrap_err()
+            .to_string()
+            .contains("No snapshot named 'nope'"));
+
+        let plan = plan_restore(&repo, "Start").unwrap();
+        assert_eq!(plan.snapshot.id, snapshot.id);
+        assert_eq!(plan.abandoned.len(), 1);
+        assert_eq!(plan.abandoned[0].subject, "atomic: fmt");
+
+        let backup = restore_snapshot(&repo, &plan).unwrap().unwrap();
+        assert_eq!(backup, format!("refs/atomic/backup/{}", dropped.id));
+        assert_eq!(repo.head().unwrap().target(), Some(snapshot.id));
+        assert!(repo.head().unwrap().is_branch());
+        assert_eq!(fs::read_to_string(dir.path().join("a.txt")).unwrap(), "a\n");
+        assert_eq!(
+            repo.find_reference(&backup).unwrap().target(),
+            Some(dropped.id)
+        );
+
+        // already there: nothing is dropped, so there's no backup
+        let plan = plan_restore(&repo, "start").unwrap();
+        assert!(plan.abandoned.is_empty());
+        assert_eq!(restore_snapshot(&repo, &plan).unwrap(), None);
+    }
 }
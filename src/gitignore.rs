@@ -0,0 +1,329 @@
+//! Keeping atomic's state out of git: a note, once per project, when the repository's .gitignore
+//! doesn't ignore `.atomic/`, and with `[atomic] manage_gitignore = true` a block of atomic's own
+//! in it.
+//!
+//! The block sits between [`BEGIN`] and [`END`]. atomic only ever rewrites the lines between them,
+//! so everything else in the file stays as it was, byte for byte.
+
+use std::{fs, io::ErrorKind, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::last_run::{state_dir, STATE_DIR};
+use crate::{AtomicError, Result};
+
+/// The line atomic's block in .gitignore starts with.
+pub const BEGIN: &str = "# --- atomic (managed) ---";
+/// The line atomic's block in .gitignore ends with.
+pub const END: &str = "# --- end atomic ---";
+
+/// What atomic keeps in the work tree and git should never see.
+pub const PATTERNS: &[&str] = &[".atomic/"];
+
+const FILE: &str = "gitignore.toml";
+/// Version written to new files; bump it when older atomics would misread them.
+const VERSION: u32 = 1;
+
+/// Whether the .gitignore `line` ignores the directory `pattern`, such as `.atomic/`: written the
+/// same, anchored with `/` or `**/`, without its trailing `/`, or as `/*` or `/**` inside it.
+fn ignores(line: &str, pattern: &str) -> bool {
+    let line = line.trim();
+    let line = line.strip_prefix("**/").unwrap_or(line);
+    let line = line.strip_prefix('/').unwrap_or(line);
+    let name = pattern.trim_end_matches('/');
+    line == name
+        || line == pattern
+        || line
+            .strip_prefix(name)
+            .is_some_and(|rest| rest == "/*" || rest == "/**")
+}
+
+/// The `patterns` no line of the .gitignore `text` ignores. A later `!` line that un-ignores one
+/// isn't looked for.
+pub fn missing<'a>(text: &str, patterns: &[&'a str]) -> Vec<&'a str> {
+    patterns
+        .iter()
+        .copied()
+        .filter(|pattern| !text.lines().any(|line| ignores(line, pattern)))
+        .collect()
+}
+
+/// The byte range of the lines inside atomic's block in `text`, if it has one.
+///
+/// # Errors
+/// Returns an error if the block is never closed.
+fn find_block(text: &str) -> Result<Option<(usize, usize)>> {
+    let mut offset = 0;
+    let mut inside = None;
+    for line in text.split_inclusive('\n') {
+        let end = offset + line.len();
+        match (inside, line.trim()) {
+            (None, BEGIN) => inside = Some(end),
+            (Some(start), END) => return Ok(Some((start, offset))),
+            _ => {}
+        }
+        offset = end;
+    }
+    match inside {
+        Some(_) => Err(AtomicError::UnclosedGitignoreBlock),
+        None => Ok(None),
+    }
+}
+
+/// `text` with atomic's block holding exactly `patterns`: rewritten in place when it has one,
+/// appended after a blank line otherwise. Lines end in `\r\n` when the file's already do.
+/// Returns `None` when the block is already up to date.
+///
+/// # Errors
+/// Returns an error if the block is never closed.
+pub fn update_block(text: &str, patterns: &[&str]) -> Result<Option<String>> {
+    let newline = match text.contains("\r\n") {
+        true => "\r\n",
+        false => "\n",
+    };
+    let lines: String = patterns
+        .iter()
+        .map(|pattern| format!("{}{}", pattern, newline))
+        .collect();
+    let updated = match find_block(text)? {
+        Some((start, end)) => format!("{}{}{}", &text[..start], lines, &text[end..]),
+        None => {
+            let mut updated = text.to_string();
+            if !updated.is_empty() && !updated.ends_with('\n') {
+                updated.push_str(newline);
+            }
+            if !updated.is_empty() && !updated.ends_with(&format!("{}{}", newline, newline)) {
+                updated.push_str(newline);
+            }
+            format!("{}{}{}{}{}{}", updated, BEGIN, newline, lines, END, newline)
+        }
+    };
+    Ok((updated != text).then_some(updated))
+}
+
+/// The patterns atomic already said the project's .gitignore is missing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Noted {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    patterns: Vec<String>,
+}
+
+fn state_path(atomic: &Path) -> std::path::PathBuf {
+    atomic
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(STATE_DIR)
+        .join(FILE)
+}
+
+/// Brings atomic's block in `file` up to date. Returns whether it changed.
+///
+/// # Errors
+/// Returns an error if the file can't be read or written, or its block is never closed.
+pub fn manage(file: &Path) -> Result<bool> {
+    let text = match fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(file_error("read", file, err)),
+    };
+    let Some(updated) = update_block(&text, PATTERNS)? else {
+        return Ok(false);
+    };
+    fs::write(file, updated).map_err(|err| file_error("write", file, err))?;
+    Ok(true)
+}
+
+fn file_error(action: &str, file: &Path, err: std::io::Error) -> AtomicError {
+    AtomicError::Generic(format!("Failed to {} {}: {}", action, file.display(), err))
+}
+
+/// Before a run of the project around `atomic`, in the repository whose work tree is `workdir`:
+/// with `managed`, brings atomic's block in its .gitignore up to date; otherwise says, once for
+/// each set of [`PATTERNS`], which of them the .gitignore doesn't ignore.
+pub fn check(atomic: &Path, workdir: &Path, managed: bool) {
+    let file = workdir.join(".gitignore");
+    if managed {
+        match manage(&file) {
+            Ok(true) => eprintln!("updated atomic's block in {}", file.display()),
+            Ok(false) => {}
+            Err(err) => eprintln!("warning: {}", err),
+        }
+        return;
+    }
+    let path = state_path(atomic);
+    let noted: Noted = fs::read_to_string(&path)
+        .ok()
+        .and_then(|text| ::toml::from_str(&text).ok())
+        .unwrap_or_default();
+    if noted.patterns == PATTERNS {
+        return;
+    }
+    let text = fs::read_to_string(&file).unwrap_or_default();
+    let missing = missing(&text, PATTERNS);
+    if !missing.is_empty() {
+        eprintln!(
+            "note: {} doesn't ignore {}, where atomic keeps its state. Add it, or set \
+             manage_gitignore = true under [atomic] to have atomic keep it there.",
+            file.display(),
+            missing.join(", ")
+        );
+    }
+    let noted = Noted {
+        version: VERSION,
+        patterns: PATTERNS.iter().map(|pattern| pattern.to_string()).collect(),
+    };
+    let written = ::toml::to_string(&noted)
+        .map_err(|err| err.to_string())
+        .and_then(|text| {
+            state_dir(atomic, None)
+                .and_then(|_| fs::write(&path, text))
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = written {
+        eprintln!("warning: Failed to write {}: {}", path.display(), err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::{commit_changes, CommitOptions};
+    use crate::test_support::init_repo;
+
+    const FIXTURES: &[(&str, &str, &str)] = &[
+        (
+            "plain",
+            include_str!("../fixtures/gitignore/plain.before.gitignore"),
+            include_str!("../fixtures/gitignore/plain.after.gitignore"),
+        ),
+        (
+            "no_newline",
+            include_str!("../fixtures/gitignore/no_newline.before.gitignore"),
+            include_str!("../fixtures/gitignore/no_newline.after.gitignore"),
+        ),
+        (
+            "managed",
+            include_str!("../fixtures/gitignore/managed.before.gitignore"),
+            include_str!("../fixtures/gitignore/managed.after.gitignore"),
+        ),
+        (
+            "crlf",
+            include_str!("../fixtures/gitignore/crlf.before.gitignore"),
+            include_str!("../fixtures/gitignore/crlf.after.gitignore"),
+        ),
+        (
+            "current",
+            include_str!("../fixtures/gitignore/current.before.gitignore"),
+            include_str!("../fixtures/gitignore/current.after.gitignore"),
+        ),
+    ];
+
+    #[test]
+    fn test_update_block() {
+        for (name, before, after) in FIXTURES {
+            let updated = update_block(before, PATTERNS).unwrap();
+            assert_eq!(
+                updated.as_deref().unwrap_or(before),
+                *after,
+                "fixture {}",
+                name
+            );
+            // a second run has nothing left to do
+            assert_eq!(
+                update_block(after, PATTERNS).unwrap(),
+                None,
+                "fixture {}",
+                name
+            );
+            assert!(missing(after, PATTERNS).is_empty(), "fixture {}", name);
+        }
+        assert_eq!(
+            update_block("", PATTERNS).unwrap().unwrap(),
+            format!("{}\n.atomic/\n{}\n", BEGIN, END)
+        );
+        // only the inside of the block changes
+        let managed = FIXTURES[2].1;
+        let updated = update_block(managed, &["a/", "b/"]).unwrap().unwrap();
+        assert!(updated.starts_with(&managed[..managed.find(BEGIN).unwrap()]));
+        assert!(updated.ends_with(&managed[managed.find(END).unwrap()..]));
+        assert!(updated.contains(&format!("{}\na/\nb/\n{}", BEGIN, END)));
+    }
+
+    #[test]
+    fn test_unclosed_block() {
+        let text = format!("target/\n{}\n.atomic/\n", BEGIN);
+        assert!(update_block(&text, PATTERNS).is_err());
+    }
+
+    #[test]
+    fn test_missing() {
+        assert_eq!(missing("target/\n", PATTERNS), [".atomic/"]);
+        assert_eq!(missing(".atomic-old/\n#.atomic/\n", PATTERNS), [".atomic/"]);
+        for ignored in [
+            ".atomic",
+            "/.atomic/",
+            "**/.atomic",
+            ".atomic/*",
+            " .atomic/** ",
+        ] {
+            assert!(
+                missing(&format!("target/\n{}\n", ignored), PATTERNS).is_empty(),
+                "{}",
+                ignored
+            );
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let gitignore = dir.path().join(".gitignore");
+        fs::write(&gitignore, "target/\n").unwrap();
+
+        // noted once, then left alone
+        check(&atomic, dir.path(), false);
+        assert!(state_path(&atomic).is_file());
+        assert_eq!(fs::read_to_string(&gitignore).unwrap(), "target/\n");
+        check(&atomic, dir.path(), false);
+
+        check(&atomic, dir.path(), true);
+        assert_eq!(
+            fs::read_to_string(&gitignore).unwrap(),
+            format!("target/\n\n{}\n.atomic/\n{}\n", BEGIN, END)
+        );
+        assert!(!manage(&gitignore).unwrap());
+    }
+
+    #[test]
+    fn test_auto_commit_leaves_state_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[("a.txt", "a\n")]);
+
+        assert!(manage(&dir.path().join(".gitignore")).unwrap());
+        // state files, without the .gitignore atomic usually puts next to them
+        let state = dir.path().join(STATE_DIR);
+        fs::create_dir_all(state.join("artifacts/1-build/build")).unwrap();
+        fs::write(state.join("last_run.toml"), "version = 1\n").unwrap();
+        fs::write(state.join("artifacts/1-build/build/report.txt"), "ok\n").unwrap();
+        fs::write(dir.path().join("a.txt"), "b\n").unwrap();
+
+        let options = CommitOptions {
+            command: Some("build"),
+            ..Default::default()
+        };
+        let commit = commit_changes(&repo, "atomic: build", &options)
+            .unwrap()
+            .unwrap();
+        let tree = repo.find_commit(commit.id).unwrap().tree().unwrap();
+        let mut paths = Vec::new();
+        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            paths.push(format!("{}{}", root, entry.name().unwrap()));
+            git2::TreeWalkResult::Ok
+        })
+        .unwrap();
+        assert_eq!(paths, [".gitignore", "a.txt"]);
+    }
+}
@@ -0,0 +1,637 @@
+//! `atomic --init`, and the offer to run it when a command is asked for before atomic.toml exists.
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, ErrorKind, IsTerminal, Write},
+    path::{Component, Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use git2::{build::RepoBuilder, FetchOptions};
+use toml::Value;
+
+use crate::config::AtomicConfig;
+use crate::schema::{error_messages, validate_toml_schema};
+use crate::share::{Change, ImportPlan};
+use crate::toml::find_config;
+use crate::yaml;
+use crate::{AtomicError, Result};
+
+/// A starting atomic.toml for a kind of project.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Template {
+    pub name: &'static str,
+    pub text: &'static str,
+}
+
+const RUST: Template = Template {
+    name: "Rust",
+    text: include_str!("../template/rust.toml"),
+};
+
+const GENERIC: Template = Template {
+    name: "generic",
+    text: include_str!("../template/example.toml"),
+};
+
+/// What `--init --from` did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FromRepo {
+    /// No template was named, so these are the ones the repository has.
+    Listed(Vec<String>),
+    /// The named template was written to the project.
+    Written(String),
+}
+
+/// `--init --from <url>#<name>`: writes the template `name` from the git repository at `url` as
+/// the project's config, converted to YAML when `atomic` is atomic.yaml. Without `#<name>`, lists
+/// the templates instead.
+///
+/// A template is `<name>.toml` or `<name>/atomic.toml` at the top of the repository. It's checked
+/// against the schema before anything is written. The repository is cloned shallowly into a
+/// temporary directory, which is removed again however this ends.
+///
+/// # Errors
+/// Returns an error if the repository can't be cloned, has no such template, the template isn't
+/// a valid config, or it can't be written.
+pub fn init_from(atomic: &Path, from: &str) -> Result<FromRepo> {
+    init_from_in(atomic, from, &env::temp_dir())
+}
+
+/// [`init_from`], cloning into a directory under `scratch`.
+fn init_from_in(atomic: &Path, from: &str, scratch: &Path) -> Result<FromRepo> {
+    let (url, name) = match from.rsplit_once('#') {
+        Some((url, name)) => (url, Some(name).filter(|name| !name.is_empty())),
+        None => (from, None),
+    };
+    let clone = TempClone::fetch(url, scratch)?;
+    let templates = list_templates(&clone.dir);
+    let Some(name) = name else {
+        return Ok(FromRepo::Listed(templates));
+    };
+
+    let file = find_template(&clone.dir, name).ok_or_else(|| {
+        AtomicError::Generic(format!(
+            "{} has no template '{}'; it has: {}",
+            url,
+            name,
+            match templates.is_empty() {
+                true => "none".to_string(),
+                false => templates.join(", "),
+            }
+        ))
+    })?;
+    let source = format!("{}#{}", url, name);
+    let text = fs::read_to_string(&file)
+        .map_err(|err| AtomicError::Generic(format!("Unable to read {}: {}", source, err)))?;
+    let value: Value =
+        ::toml::from_str(&text).map_err(|err| AtomicError::TomlParse(source.clone(), err))?;
+    let errors = error_messages(&validate_toml_schema(&value));
+    if !errors.is_empty() {
+        return Err(AtomicError::Validation(
+            errors
+                .into_iter()
+                .map(|error| format!("{}: {}", source, error))
+                .collect(),
+        ));
+    }
+    let text = match yaml::is_yaml(atomic) {
+        true => yaml::to_string(&value)?,
+        false => text,
+    };
+    write_new(atomic, &text)?;
+    Ok(FromRepo::Written(name.to_string()))
+}
+
+/// A shallow clone in a temporary directory, removed when dropped.
+struct TempClone {
+    dir: PathBuf,
+}
+
+impl TempClone {
+    fn fetch(url: &str, scratch: &Path) -> Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.subsec_nanos());
+        let clone = Self {
+            dir: scratch.join(format!("atomic-template-{}-{}", process::id(), nanos)),
+        };
+        let mut fetch = FetchOptions::new();
+        fetch.depth(1);
+        RepoBuilder::new()
+            .fetch_options(fetch)
+            .clone(url, &clone.dir)
+            .map_err(|err| {
+                AtomicError::Generic(format!("Couldn't clone {}: {}", url, err.message()))
+            })?;
+        Ok(clone)
+    }
+}
+
+impl Drop for TempClone {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The templates at the top of a repository checked out in `dir`, sorted.
+fn list_templates(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut templates: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let path = entry.path();
+            if name.starts_with('.') {
+                return None;
+            }
+            match name.strip_suffix(".toml") {
+                Some(stem) if path.is_file() => Some(stem.to_string()),
+                None if path.join("atomic.toml").is_file() => Some(name),
+                _ => None,
+            }
+        })
+        .collect();
+    templates.sort();
+    templates
+}
+
+/// Where the template `name` is in `dir`, if it's there. Names that would leave `dir` aren't.
+fn find_template(dir: &Path, name: &str) -> Option<PathBuf> {
+    let inside = Path::new(name)
+        .components()
+        .all(|part| matches!(part, Component::Normal(_)));
+    if !inside {
+        return None;
+    }
+    [
+        dir.join(format!("{}.toml", name)),
+        dir.join(name).join("atomic.toml"),
+    ]
+    .into_iter()
+    .find(|file| file.is_file())
+}
+
+/// The starting global config that `--edit-config --global` creates, all commented out.
+pub const GLOBAL_TEMPLATE: &str = include_str!("../template/global.toml");
+
+/// Picks the template for the project in `dir`: Rust when it has a Cargo.toml, generic otherwise.
+pub fn detect_template(dir: &Path) -> Template {
+    if dir.join("Cargo.toml").is_file() {
+        RUST
+    } else {
+        GENERIC
+    }
+}
+
+/// The built-in template called `name`, `rust` or `generic`, in any case.
+pub fn template_named(name: &str) -> Option<Template> {
+    [RUST, GENERIC]
+        .into_iter()
+        .find(|template| template.name.eq_ignore_ascii_case(name))
+}
+
+/// What `--init --merge` adds to an existing atomic.toml.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateMerge {
+    /// `section.key` of each command the template adds.
+    pub added: Vec<String>,
+    /// `section.key` of each command the project already defines differently, and keeps.
+    pub skipped: Vec<String>,
+    /// The config with the additions, or as it was when there are none.
+    pub text: String,
+}
+
+impl TemplateMerge {
+    /// One line per command added or skipped.
+    pub fn summary(&self) -> String {
+        let added = self.added.iter().map(|name| format!("  + {} (new)", name));
+        let skipped = self
+            .skipped
+            .iter()
+            .map(|name| format!("  = {} (differs; keeping local)", name));
+        added.chain(skipped).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// Merges `template` into the config text `local`: commands the template has and `local`
+/// doesn't are added, and every key already there is left as it is, comments and all.
+///
+/// # Errors
+/// Returns an error if `local` doesn't parse or one of its sections isn't a table.
+pub fn merge_template(local: &str, template: Template) -> Result<TemplateMerge> {
+    let plan = ImportPlan::new(local, template.text, false)?;
+    let names = |change: Change| -> Vec<String> {
+        plan.entries
+            .iter()
+            .filter(|entry| entry.change == change)
+            .map(|entry| format!("{}.{}", entry.section, entry.key))
+            .collect()
+    };
+    let added = names(Change::Add);
+    let text = match added.is_empty() {
+        true => local.to_string(),
+        false => plan.apply(local)?,
+    };
+    Ok(TemplateMerge {
+        skipped: names(Change::Keep),
+        added,
+        text,
+    })
+}
+
+/// Writes the template for the project around `atomic` to it, converted to YAML when `atomic` is
+/// atomic.yaml or atomic.yml. The conversion drops the template's comments.
+///
+/// # Errors
+/// Returns an error if `atomic`, or another config next to it, already exists, or `atomic` can't
+/// be written.
+pub fn write_template(atomic: &Path) -> Result<Template> {
+    let dir = atomic.parent().unwrap_or(Path::new(""));
+    let template = detect_template(dir);
+    let text = if yaml::is_yaml(atomic) {
+        yaml::to_string(&parse_template(atomic, template)?)?
+    } else {
+        template.text.to_string()
+    };
+    write_new(atomic, &text)?;
+    Ok(template)
+}
+
+/// Writes `text` as the project's new config at `atomic`.
+///
+/// # Errors
+/// Returns an error if `atomic`, or another config next to it, already exists, or `atomic` can't
+/// be written.
+fn write_new(atomic: &Path, text: &str) -> Result<()> {
+    let dir = atomic.parent().unwrap_or(Path::new(""));
+    let existing = find_config(dir)?;
+    if existing.is_file() && existing != atomic {
+        return Err(AtomicError::Generic(format!(
+            "{} already exists",
+            existing.display()
+        )));
+    }
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(atomic)
+        .and_then(|mut file| file.write_all(text.as_bytes()));
+    match written {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => Err(AtomicError::Generic(format!(
+            "{} already exists",
+            atomic.display()
+        ))),
+        Err(err) => Err(AtomicError::Generic(format!(
+            "Failed to write {}: {}",
+            atomic.display(),
+            err
+        ))),
+    }
+}
+
+/// Writes [`GLOBAL_TEMPLATE`] to `global`, and the directories above it, unless it exists.
+///
+/// Returns whether it was created.
+///
+/// # Errors
+/// Returns an error if the file or its directory can't be written.
+pub fn write_global_template(global: &Path) -> Result<bool> {
+    let failed = |err: io::Error| {
+        AtomicError::Generic(format!("Failed to write {}: {}", global.display(), err))
+    };
+    if let Some(dir) = global.parent() {
+        fs::create_dir_all(dir).map_err(failed)?;
+    }
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(global)
+        .and_then(|mut file| file.write_all(GLOBAL_TEMPLATE.as_bytes()));
+    match written {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => Ok(false),
+        Err(err) => Err(failed(err)),
+    }
+}
+
+/// Explains atomic.toml and offers to create it before running `cmd` for the first time.
+///
+/// Returns whether `cmd` can go ahead: the file was created and defines it.
+///
+/// # Errors
+/// Returns an error if the answer can't be read or atomic.toml can't be written.
+pub fn offer_init(
+    atomic: &Path,
+    cmd: &str,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<bool> {
+    let template = detect_template(atomic.parent().unwrap_or(Path::new("")));
+    let asked = write!(
+        output,
+        "{} not found.\n\
+         atomic.toml lists the commands atomic runs, and commits the changes after.\n\
+         Create one from the {} template now? [y/N] ",
+        atomic.display(),
+        template.name
+    )
+    .and_then(|()| output.flush());
+    let mut answer = String::new();
+    asked
+        .and_then(|()| input.read_line(&mut answer))
+        .map_err(|err| AtomicError::Generic(format!("Failed to read answer: {}", err)))?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        let _ = writeln!(output, "Run `atomic --init` when you're ready.");
+        return Ok(false);
+    }
+
+    let template = write_template(atomic)?;
+    let _ = writeln!(
+        output,
+        "Created {} from the {} template.",
+        atomic.display(),
+        template.name
+    );
+    if AtomicConfig::from_value(parse_template(atomic, template)?)?
+        .command(cmd)
+        .is_none()
+    {
+        let _ = writeln!(
+            output,
+            "'{}' isn't in it yet; add it and run `atomic {}` again.",
+            cmd, cmd
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+fn parse_template(atomic: &Path, template: Template) -> Result<Value> {
+    ::toml::from_str(template.text)
+        .map_err(|err| AtomicError::TomlParse(atomic.display().to_string(), err))
+}
+
+/// Whether someone is at the terminal to answer [`offer_init`].
+pub fn can_offer() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{run_command, RunOptions};
+    use crate::test_support::init_repo;
+
+    #[test]
+    fn test_detect_template() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(detect_template(dir.path()).name, "generic");
+        fs::write(dir.path().join("Cargo.toml"), "[package]\n").unwrap();
+        assert_eq!(detect_template(dir.path()).name, "Rust");
+    }
+
+    #[test]
+    fn test_template_named() {
+        assert_eq!(template_named("rust"), Some(RUST));
+        assert_eq!(template_named("Generic"), Some(GENERIC));
+        assert_eq!(template_named("python"), None);
+    }
+
+    #[test]
+    fn test_merge_into_empty_config() {
+        let merge = merge_template("", RUST).unwrap();
+        assert!(merge.skipped.is_empty());
+        assert_eq!(merge.added.len(), 9);
+        assert_eq!(merge.added[0], "custom.chain");
+        let merged: Value = ::toml::from_str(&merge.text).unwrap();
+        assert_eq!(
+            merged,
+            parse_template(Path::new("atomic.toml"), RUST).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merge_partial_overlap() {
+        let merge =
+            merge_template(include_str!("../fixtures/init/partial.before.toml"), RUST).unwrap();
+        assert_eq!(
+            merge.added,
+            [
+                "custom.chain",
+                "custom.clippy",
+                "custom.clippy_max",
+                "custom.doc",
+                "custom.test-all",
+                "default.run"
+            ]
+        );
+        assert_eq!(merge.skipped, ["default.build"]);
+        assert_eq!(
+            merge.text,
+            include_str!("../fixtures/init/partial.after.toml")
+        );
+        assert_eq!(
+            merge.summary(),
+            "  + custom.chain (new)\n  + custom.clippy (new)\n  + custom.clippy_max (new)\n  \
+             + custom.doc (new)\n  + custom.test-all (new)\n  + default.run (new)\n  \
+             = default.build (differs; keeping local)"
+        );
+    }
+
+    #[test]
+    fn test_merge_full_overlap() {
+        let merge = merge_template(RUST.text, RUST).unwrap();
+        assert!(merge.added.is_empty());
+        assert!(merge.skipped.is_empty());
+        assert_eq!(merge.text, RUST.text);
+
+        // merging what it just added changes nothing more
+        let partial = include_str!("../fixtures/init/partial.after.toml");
+        let again = merge_template(partial, RUST).unwrap();
+        assert!(again.added.is_empty());
+        assert_eq!(again.skipped, ["default.build"]);
+        assert_eq!(again.text, partial);
+    }
+
+    #[test]
+    fn test_write_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        assert_eq!(write_template(&atomic).unwrap().name, "generic");
+        assert_eq!(fs::read_to_string(&atomic).unwrap(), GENERIC.text);
+        assert!(write_template(&atomic).is_err());
+    }
+
+    #[test]
+    fn test_write_global_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let global = dir.path().join("config/atomic/config.toml");
+        assert!(write_global_template(&global).unwrap());
+        assert_eq!(fs::read_to_string(&global).unwrap(), GLOBAL_TEMPLATE);
+        let value: Value = ::toml::from_str(GLOBAL_TEMPLATE).unwrap();
+        assert!(crate::schema::validate_toml_schema(&value).is_empty());
+
+        // an existing one is left alone
+        fs::write(&global, "[custom]\nhi = \"echo hi\"\n").unwrap();
+        assert!(!write_global_template(&global).unwrap());
+        assert!(fs::read_to_string(&global).unwrap().contains("echo hi"));
+    }
+
+    /// A repository of shared templates, committed, and its `file://` URL.
+    fn template_repo() -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("rust-service.toml"),
+            "# blessed\n[default]\nbuild = \"cargo build\"\n",
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("web")).unwrap();
+        fs::write(
+            dir.path().join("web/atomic.toml"),
+            "[custom]\nserve = \"npm start\"\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("broken.toml"), "[default]\nbuild = 4\n").unwrap();
+        fs::write(dir.path().join("README.md"), "templates\n").unwrap();
+        init_repo(dir.path(), &[]);
+        let url = format!("file://{}", dir.path().display());
+        (dir, url)
+    }
+
+    #[test]
+    fn test_init_from_repo() {
+        let (_templates, url) = template_repo();
+        let project = tempfile::tempdir().unwrap();
+        let scratch = tempfile::tempdir().unwrap();
+        let atomic = project.path().join("atomic.toml");
+        let init = |atomic: &Path, from: &str| init_from_in(atomic, from, scratch.path());
+
+        assert_eq!(
+            init(&atomic, &url),
+            Ok(FromRepo::Listed(vec![
+                "broken".to_string(),
+                "rust-service".to_string(),
+                "web".to_string()
+            ]))
+        );
+        assert!(!atomic.exists());
+
+        assert_eq!(
+            init(&atomic, &format!("{}#rust-service", url)),
+            Ok(FromRepo::Written("rust-service".to_string()))
+        );
+        // written as it is, comments and all
+        assert!(fs::read_to_string(&atomic)
+            .unwrap()
+            .starts_with("# blessed\n"));
+        let err = init(&atomic, &format!("{}#web", url)).unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+
+        let yaml = project.path().join("web/atomic.yaml");
+        fs::create_dir(project.path().join("web")).unwrap();
+        assert_eq!(
+            init(&yaml, &format!("{}#web", url)),
+            Ok(FromRepo::Written("web".to_string()))
+        );
+        assert_eq!(
+            crate::toml::get_toml_content(&yaml).unwrap()["custom"]["serve"].as_str(),
+            Some("npm start")
+        );
+
+        // the clone is gone however it ends
+        assert_eq!(fs::read_dir(scratch.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_init_from_repo_errors() {
+        let (_templates, url) = template_repo();
+        let project = tempfile::tempdir().unwrap();
+        let scratch = tempfile::tempdir().unwrap();
+        let atomic = project.path().join("atomic.toml");
+        let init = |from: &str| init_from_in(&atomic, from, scratch.path()).unwrap_err();
+
+        let Err(AtomicError::Validation(errors)) =
+            init_from_in(&atomic, &format!("{}#broken", url), scratch.path())
+        else {
+            panic!("broken.toml doesn't pass the schema");
+        };
+        assert!(errors[0].starts_with(&format!("{}#broken: default.build:", url)));
+
+        let err = init(&format!("{}#python", url)).to_string();
+        assert!(
+            err.ends_with("has no template 'python'; it has: broken, rust-service, web"),
+            "{}",
+            err
+        );
+        assert!(init(&format!("{}#../README", url))
+            .to_string()
+            .contains("has no template"));
+
+        let missing = format!("file://{}/nope", scratch.path().display());
+        let err = init(&missing).to_string();
+        assert!(
+            err.contains(&format!("Couldn't clone {}: ", missing)),
+            "{}",
+            err
+        );
+        assert!(!atomic.exists());
+        assert_eq!(fs::read_dir(scratch.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_write_yaml_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.yaml");
+        assert_eq!(write_template(&atomic).unwrap().name, "generic");
+        assert_eq!(
+            crate::toml::get_toml_content(&atomic).unwrap(),
+            ::toml::from_str::<Value>(GENERIC.text).unwrap()
+        );
+        assert!(write_template(&dir.path().join("atomic.toml")).is_err());
+    }
+
+    #[test]
+    fn test_offer_init_then_run() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "[package]\n").unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let options = RunOptions {
+            no_commit: true,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        assert_eq!(
+            offer_init(&atomic, "build", &mut "n\n".as_bytes(), &mut output),
+            Ok(false)
+        );
+        assert!(!atomic.exists());
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("[y/N] Run `atomic --init` when you're ready.\n"));
+
+        let mut output = Vec::new();
+        assert_eq!(
+            offer_init(&atomic, "build", &mut "y\n".as_bytes(), &mut output),
+            Ok(true)
+        );
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("from the Rust template"));
+        // the template's `build` runs in the same invocation
+        assert_eq!(run_command("build", &atomic, &options), Ok(0));
+
+        fs::remove_file(&atomic).unwrap();
+        assert_eq!(
+            offer_init(&atomic, "deploy", &mut "y\n".as_bytes(), &mut Vec::new()),
+            Ok(false)
+        );
+        assert!(atomic.exists());
+    }
+}
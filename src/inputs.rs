@@ -0,0 +1,332 @@
+//! `inputs = ["proto/**/*.proto"]`: skipping a command whose input files haven't changed since
+//! it last succeeded.
+//!
+//! The matched files are hashed by path and content, and the hash of each command's last
+//! successful run is kept in `.atomic/input_hashes.toml`. A run whose hash matches is skipped,
+//! unless `--force` is given or one of its `outputs` has gone missing.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use git2::{ObjectType, Oid};
+use serde::{Deserialize, Serialize};
+
+use crate::last_run::{state_dir, STATE_DIR};
+use crate::{AtomicError, Result};
+
+const FILE: &str = "input_hashes.toml";
+/// Version written to new files; bump it when older atomics would misread them.
+const VERSION: u32 = 1;
+
+/// Directories never searched for inputs.
+const SKIPPED_DIRS: &[&str] = &[".git", STATE_DIR];
+
+/// Whether `path`, relative and with `/` separators, matches the glob `pattern`.
+///
+/// `*` matches within one path segment, `?` one character of it, and a `**` segment any number
+/// of segments, none included.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    let path: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match_segments(&pattern, &path)
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(&"**"), _) => {
+            match_segments(&pattern[1..], path)
+                || (!path.is_empty() && match_segments(pattern, &path[1..]))
+        }
+        (Some(segment), Some(name)) => {
+            match_segment(segment.as_bytes(), name.as_bytes())
+                && match_segments(&pattern[1..], &path[1..])
+        }
+        _ => false,
+    }
+}
+
+fn match_segment(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            match_segment(&pattern[1..], name)
+                || (!name.is_empty() && match_segment(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => match_segment(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) => p == n && match_segment(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// The files under `root` matching any of `patterns`, relative to it with `/` separators,
+/// sorted and without repeats.
+///
+/// Only the directory before a pattern's first wildcard is searched, so `proto/**/*.proto`
+/// doesn't walk the whole project.
+///
+/// # Errors
+/// Returns an error if a directory can't be read.
+pub fn expand(root: &Path, patterns: &[String]) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let pattern = pattern.trim_start_matches("./").replace('\\', "/");
+        let fixed: Vec<&str> = pattern
+            .split('/')
+            .take_while(|segment| !segment.contains(['*', '?']))
+            .collect();
+        let base = fixed.join("/");
+        let start = root.join(&base);
+        if fixed.len() == pattern.split('/').count() {
+            // no wildcard: the path itself
+            if start.is_file() {
+                files.push(base);
+            }
+            continue;
+        }
+        walk(&start, &base, &mut |path| {
+            if glob_match(&pattern, path) {
+                files.push(path.to_string());
+            }
+        })?;
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Calls `found` with every file below `dir`, named `prefix/...`.
+fn walk(dir: &Path, prefix: &str, found: &mut dyn FnMut(&str)) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(read_error(dir, err)),
+    };
+    for entry in entries {
+        let entry = entry.map_err(|err| read_error(dir, err))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = match prefix {
+            "" => name.clone(),
+            prefix => format!("{}/{}", prefix, name),
+        };
+        let kind = entry.file_type().map_err(|err| read_error(dir, err))?;
+        if kind.is_dir() && !SKIPPED_DIRS.contains(&name.as_str()) {
+            walk(&entry.path(), &path, found)?;
+        } else if kind.is_file() {
+            found(&path);
+        }
+    }
+    Ok(())
+}
+
+fn read_error(dir: &Path, err: std::io::Error) -> AtomicError {
+    AtomicError::Generic(format!("Failed to read {}: {}", dir.display(), err))
+}
+
+/// A hash of `files`, relative to `root`: their paths and contents, in order, so adding,
+/// removing, renaming or editing any of them changes it.
+///
+/// # Errors
+/// Returns an error if a file can't be read.
+pub fn hash(root: &Path, files: &[String]) -> Result<String> {
+    let mut listing = String::new();
+    for file in files {
+        let blob = Oid::hash_file(ObjectType::Blob, root.join(file))?;
+        listing.push_str(&format!("{} {}\n", blob, file));
+    }
+    Ok(Oid::hash_object(ObjectType::Blob, listing.as_bytes())?.to_string())
+}
+
+/// The input hash of each command's last successful run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputHashes {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub commands: BTreeMap<String, String>,
+}
+
+/// Where the input hashes of the project around `atomic` are kept.
+pub fn path(atomic: &Path) -> PathBuf {
+    atomic
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(STATE_DIR)
+        .join(FILE)
+}
+
+/// The saved hashes, or none when the file is missing or can't be read.
+pub fn load(atomic: &Path) -> InputHashes {
+    fs::read_to_string(path(atomic))
+        .ok()
+        .and_then(|text| ::toml::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+/// Records `hash` as the inputs of the last successful run of `command`.
+///
+/// # Errors
+/// Returns an error if the state directory or file can't be written.
+pub fn record(atomic: &Path, command: &str, hash: &str) -> Result<()> {
+    let mut hashes = load(atomic);
+    hashes.version = VERSION;
+    hashes
+        .commands
+        .insert(command.to_string(), hash.to_string());
+    let file = path(atomic);
+    let text = ::toml::to_string(&hashes).map_err(|err| {
+        AtomicError::Generic(format!("Failed to serialize input hashes: {}", err))
+    })?;
+    state_dir(atomic, None)
+        .and_then(|_| fs::write(&file, text))
+        .map_err(|err| AtomicError::Generic(format!("Failed to write {}: {}", file.display(), err)))
+}
+
+/// What to do about a command with `inputs`, with the hash of its inputs to [`record`] once it
+/// succeeds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Freshness {
+    /// Nothing changed since the last successful run.
+    UpToDate(String),
+    Stale(String),
+}
+
+impl Freshness {
+    pub fn hash(&self) -> &str {
+        match self {
+            Freshness::UpToDate(hash) | Freshness::Stale(hash) => hash,
+        }
+    }
+}
+
+/// Compares the current `inputs` of `command` with its last successful run. `outputs` that no
+/// longer match any file make it stale too.
+///
+/// # Errors
+/// Returns an error if the files can't be read.
+pub fn check(
+    atomic: &Path,
+    command: &str,
+    inputs: &[String],
+    outputs: &[String],
+) -> Result<Freshness> {
+    let root = atomic.parent().unwrap_or(Path::new(""));
+    let current = hash(root, &expand(root, inputs)?)?;
+    if load(atomic).commands.get(command) != Some(&current) {
+        return Ok(Freshness::Stale(current));
+    }
+    for output in outputs {
+        if expand(root, std::slice::from_ref(output))?.is_empty() {
+            return Ok(Freshness::Stale(current));
+        }
+    }
+    Ok(Freshness::UpToDate(current))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("proto/**/*.proto", "proto/a.proto"));
+        assert!(glob_match("proto/**/*.proto", "proto/v1/deep/b.proto"));
+        assert!(!glob_match("proto/**/*.proto", "proto/a.proto.bak"));
+        assert!(!glob_match("proto/*.proto", "proto/v1/b.proto"));
+        assert!(glob_match("**", "any/thing.rs"));
+        assert!(glob_match("src/?.rs", "src/a.rs"));
+        assert!(!glob_match("src/?.rs", "src/ab.rs"));
+        assert!(glob_match("codegen.toml", "codegen.toml"));
+        assert!(glob_match("*.toml", "codegen.toml"));
+        assert!(!glob_match("*.toml", "sub/codegen.toml"));
+    }
+
+    #[test]
+    fn test_expand() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for file in [
+            "proto/a.proto",
+            "proto/v1/b.proto",
+            "proto/readme.md",
+            "codegen.toml",
+            ".git/x.proto",
+        ] {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), file).unwrap();
+        }
+        let patterns = |patterns: &[&str]| -> Vec<String> {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            expand(root, &patterns).unwrap()
+        };
+        assert_eq!(
+            patterns(&["proto/**/*.proto", "./codegen.toml", "proto/*.proto"]),
+            ["codegen.toml", "proto/a.proto", "proto/v1/b.proto"]
+        );
+        assert_eq!(
+            patterns(&["**/*.proto"]),
+            ["proto/a.proto", "proto/v1/b.proto"]
+        );
+        assert!(patterns(&["missing.toml", "nowhere/**"]).is_empty());
+    }
+
+    #[test]
+    fn test_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        let files = ["a.txt".to_string(), "b.txt".to_string()];
+        let before = hash(root, &files).unwrap();
+        assert_eq!(hash(root, &files).unwrap(), before);
+
+        fs::write(root.join("b.txt"), "changed").unwrap();
+        assert_ne!(hash(root, &files).unwrap(), before);
+        // which files are hashed counts, not only what's in them
+        assert_ne!(
+            hash(root, &files[..1]).unwrap(),
+            hash(root, &files).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_check_and_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(dir.path().join("schema.proto"), "v1").unwrap();
+        let inputs = ["*.proto".to_string()];
+        let outputs = ["gen/*.rs".to_string()];
+
+        let first = check(&atomic, "codegen", &inputs, &[]).unwrap();
+        assert!(matches!(first, Freshness::Stale(_)));
+        let hash = first.hash().to_string();
+        record(&atomic, "codegen", &hash).unwrap();
+        assert_eq!(
+            check(&atomic, "codegen", &inputs, &[]).unwrap(),
+            Freshness::UpToDate(hash.clone())
+        );
+        assert!(dir.path().join(".atomic/.gitignore").exists());
+
+        // a missing output means running again
+        assert!(matches!(
+            check(&atomic, "codegen", &inputs, &outputs).unwrap(),
+            Freshness::Stale(_)
+        ));
+        fs::create_dir(dir.path().join("gen")).unwrap();
+        fs::write(dir.path().join("gen/schema.rs"), "").unwrap();
+        assert_eq!(
+            check(&atomic, "codegen", &inputs, &outputs).unwrap(),
+            Freshness::UpToDate(hash.clone())
+        );
+
+        fs::write(dir.path().join("schema.proto"), "v2").unwrap();
+        assert!(matches!(
+            check(&atomic, "codegen", &inputs, &outputs).unwrap(),
+            Freshness::Stale(_)
+        ));
+        assert_eq!(load(&atomic).commands.len(), 1);
+    }
+}
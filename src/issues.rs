@@ -0,0 +1,528 @@
+//! `[atomic.issues]`: looking up the title of the issue a branch is named after, for commands'
+//! `ATOMIC_ISSUE_TITLE`.
+//!
+//! GitHub issues are looked up in the repository of the `origin` remote unless `repo` names
+//! another, Jira ones as `<project>-<number>` under `base_url`. Titles are kept in
+//! `.atomic/issue_cache.toml` for `cache_ttl`, so runs in a row ask the tracker once. Nothing here
+//! fails a run: without a network, a token the tracker accepts or an answer that makes sense, the
+//! title is empty and one warning says why.
+
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use git2::Repository;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+
+use crate::config::{IssueProvider, IssueSettings};
+use crate::last_run::{state_dir, STATE_DIR};
+use crate::stats::now;
+use crate::{AtomicError, Result};
+
+const FILE: &str = "issue_cache.toml";
+/// Version written to new files; bump it when older atomics would misread them.
+const VERSION: u32 = 1;
+/// How long a title is kept when `cache_ttl` isn't set.
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+const TIMEOUT: Duration = Duration::from_secs(5);
+const GITHUB_API: &str = "https://api.github.com";
+
+thread_local! {
+    static WARNED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Answers the GET requests a lookup makes, so tests can do without a network.
+pub trait Http {
+    /// The body of a successful answer to a GET of `url` with `headers`.
+    ///
+    /// # Errors
+    /// Returns an error if the request fails or the answer isn't a success.
+    fn get(&self, url: &str, headers: &[(&str, String)]) -> Result<String>;
+}
+
+/// [`Http`] over the network.
+pub struct Ureq;
+
+impl Http for Ureq {
+    fn get(&self, url: &str, headers: &[(&str, String)]) -> Result<String> {
+        // GitHub rejects requests without a user agent
+        let mut request = ureq::get(url)
+            .set("User-Agent", concat!("atomic/", env!("CARGO_PKG_VERSION")))
+            .timeout(TIMEOUT);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        request
+            .call()
+            .map_err(|err| AtomicError::Generic(format!("Failed to reach {}: {}", url, err)))?
+            .into_string()
+            .map_err(|err| {
+                AtomicError::Generic(format!("Failed to read reply of {}: {}", url, err))
+            })
+    }
+}
+
+/// Where an issue's title is asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tracker {
+    /// `repo` is `owner/name`.
+    GitHub {
+        api: String,
+        repo: String,
+    },
+    Jira {
+        base_url: String,
+        project: String,
+    },
+}
+
+impl Tracker {
+    /// The tracker `settings` describe. `origin` is the URL of the `origin` remote, where a GitHub
+    /// repository comes from when `repo` isn't set.
+    ///
+    /// # Errors
+    /// Returns an error if a key the provider needs is missing.
+    pub fn new(settings: &IssueSettings, origin: Option<&str>) -> Result<Self> {
+        let base_url = settings
+            .base_url
+            .as_deref()
+            .map(|url| url.trim_end_matches('/').to_string());
+        match settings.provider {
+            IssueProvider::Github => {
+                let repo = match &settings.repo {
+                    Some(repo) => repo.clone(),
+                    None => origin.and_then(github_repo).ok_or(AtomicError::Static(
+                        "no GitHub repository: the origin remote isn't one, and `repo` isn't set",
+                    ))?,
+                };
+                Ok(Tracker::GitHub {
+                    api: base_url.unwrap_or_else(|| GITHUB_API.to_string()),
+                    repo,
+                })
+            }
+            IssueProvider::Jira => match (base_url, &settings.project) {
+                (Some(base_url), Some(project)) => Ok(Tracker::Jira {
+                    base_url,
+                    project: project.clone(),
+                }),
+                _ => Err(AtomicError::Static(
+                    "the jira provider needs `base_url` and `project`",
+                )),
+            },
+        }
+    }
+
+    /// What the title of `issue` is cached under, so trackers and repositories don't mix.
+    pub fn key(&self, issue: u64) -> String {
+        match self {
+            Tracker::GitHub { repo, .. } => format!("github:{}#{}", repo, issue),
+            Tracker::Jira { base_url, project } => {
+                format!("jira:{}/{}-{}", base_url, project, issue)
+            }
+        }
+    }
+
+    /// The API URL describing `issue`.
+    pub fn url(&self, issue: u64) -> String {
+        match self {
+            Tracker::GitHub { api, repo } => format!("{}/repos/{}/issues/{}", api, repo, issue),
+            Tracker::Jira { base_url, project } => format!(
+                "{}/rest/api/2/issue/{}-{}?fields=summary",
+                base_url, project, issue
+            ),
+        }
+    }
+
+    /// The variable the token is read from when `token_env` isn't set.
+    pub fn default_token_env(&self) -> &'static str {
+        match self {
+            Tracker::GitHub { .. } => "GITHUB_TOKEN",
+            Tracker::Jira { .. } => "JIRA_TOKEN",
+        }
+    }
+
+    /// The headers of a request, with `token` when there is one; public GitHub repositories answer
+    /// without.
+    pub fn headers(&self, token: Option<&str>) -> Vec<(&'static str, String)> {
+        let accept = match self {
+            Tracker::GitHub { .. } => "application/vnd.github+json",
+            Tracker::Jira { .. } => "application/json",
+        };
+        let mut headers = vec![("Accept", accept.to_string())];
+        if let Some(token) = token {
+            headers.push(("Authorization", format!("Bearer {}", token)));
+        }
+        headers
+    }
+
+    /// The title in the answer to [`url`](Self::url): GitHub's `title`, Jira's `fields.summary`.
+    ///
+    /// # Errors
+    /// Returns an error if `body` isn't JSON or has no title.
+    pub fn parse_title(&self, body: &str) -> Result<String> {
+        let json: Json = serde_json::from_str(body)
+            .map_err(|err| AtomicError::Generic(format!("Invalid reply: {}", err)))?;
+        let title = match self {
+            Tracker::GitHub { .. } => json.get("title"),
+            Tracker::Jira { .. } => json.get("fields").and_then(|fields| fields.get("summary")),
+        };
+        title
+            .and_then(Json::as_str)
+            .map(|title| title.trim().to_string())
+            .ok_or(AtomicError::Static("the reply has no title"))
+    }
+}
+
+/// `owner/name` of a GitHub remote URL, such as `git@github.com:owner/name.git` or
+/// `https://github.com/owner/name`.
+pub fn github_repo(url: &str) -> Option<String> {
+    let (host, path) = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/')?,
+        None => url.split_once(':')?,
+    };
+    if !host.ends_with("github.com") {
+        return None;
+    }
+    let parts: Vec<&str> = path
+        .trim_end_matches('/')
+        .trim_end_matches(".git")
+        .split('/')
+        .collect();
+    match parts[..] {
+        [owner, name] if !owner.is_empty() && !name.is_empty() => {
+            Some(format!("{}/{}", owner, name))
+        }
+        _ => None,
+    }
+}
+
+/// Titles looked up before, by [`Tracker::key`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IssueCache {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub issues: BTreeMap<String, CachedTitle>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedTitle {
+    pub title: String,
+    /// When it was fetched, in seconds since the epoch.
+    pub fetched: u64,
+}
+
+/// Where the titles of the project around `atomic` are cached.
+pub fn path(atomic: &Path) -> PathBuf {
+    atomic
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(STATE_DIR)
+        .join(FILE)
+}
+
+/// The cached titles, or none when the file is missing or can't be read.
+pub fn load(atomic: &Path) -> IssueCache {
+    fs::read_to_string(path(atomic))
+        .ok()
+        .and_then(|text| ::toml::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn save(atomic: &Path, cache: &IssueCache) -> Result<()> {
+    let file = path(atomic);
+    let text = ::toml::to_string(cache)
+        .map_err(|err| AtomicError::Generic(format!("Failed to serialize issue cache: {}", err)))?;
+    state_dir(atomic, None)
+        .and_then(|_| fs::write(&file, text))
+        .map_err(|err| AtomicError::Generic(format!("Failed to write {}: {}", file.display(), err)))
+}
+
+/// The title of `issue`: cached when it was fetched less than `cache_ttl` before `now`, otherwise
+/// asked of the tracker through `http` and cached.
+///
+/// # Errors
+/// Returns an error if the settings are incomplete, the request fails or the answer has no title.
+pub fn fetch_title(
+    atomic: &Path,
+    settings: &IssueSettings,
+    origin: Option<&str>,
+    issue: u64,
+    http: &dyn Http,
+    now: u64,
+) -> Result<String> {
+    let tracker = Tracker::new(settings, origin)?;
+    let ttl = match &settings.cache_ttl {
+        Some(ttl) => ttl.to_duration()?,
+        None => DEFAULT_TTL,
+    };
+    let key = tracker.key(issue);
+    let mut cache = load(atomic);
+    if let Some(cached) = cache.issues.get(&key) {
+        if now.saturating_sub(cached.fetched) < ttl.as_secs() {
+            return Ok(cached.title.clone());
+        }
+    }
+
+    let token_env = settings
+        .token_env
+        .as_deref()
+        .unwrap_or(tracker.default_token_env());
+    let token = env::var(token_env).ok().filter(|token| !token.is_empty());
+    let body = http.get(&tracker.url(issue), &tracker.headers(token.as_deref()))?;
+    let title = tracker.parse_title(&body)?;
+
+    cache.version = VERSION;
+    cache.issues.insert(
+        key,
+        CachedTitle {
+            title: title.clone(),
+            fetched: now,
+        },
+    );
+    save(atomic, &cache)?;
+    Ok(title)
+}
+
+/// [`fetch_title`], or an empty title after a warning when it fails. Only the first failure of a
+/// run warns, so a chain of steps doesn't repeat it.
+pub fn title(
+    atomic: &Path,
+    settings: &IssueSettings,
+    origin: Option<&str>,
+    issue: u64,
+    http: &dyn Http,
+    now: u64,
+) -> String {
+    fetch_title(atomic, settings, origin, issue, http, now).unwrap_or_else(|err| {
+        if !WARNED.with(|warned| warned.replace(true)) {
+            eprintln!(
+                "warning: couldn't look up the title of issue {}: {} (ATOMIC_ISSUE_TITLE is empty)",
+                issue, err
+            );
+        }
+        String::new()
+    })
+}
+
+/// `ATOMIC_ISSUE_TITLE` for a command run with `env`, from [`crate::command::atomic_env`]: the
+/// title of its `ATOMIC_ISSUE`, empty when the branch names none.
+pub fn title_var(
+    atomic: &Path,
+    settings: &IssueSettings,
+    env: &[(String, String)],
+) -> (String, String) {
+    let issue = env
+        .iter()
+        .find(|(key, _)| key == "ATOMIC_ISSUE")
+        .and_then(|(_, issue)| issue.parse().ok());
+    let title = match issue {
+        Some(issue) => {
+            let origin = Repository::discover(crate::command::env_root(env))
+                .ok()
+                .and_then(|repo| {
+                    let remote = repo.find_remote("origin").ok()?;
+                    remote.url().map(str::to_string)
+                });
+            title(atomic, settings, origin.as_deref(), issue, &Ureq, now())
+        }
+        None => String::new(),
+    };
+    ("ATOMIC_ISSUE_TITLE".to_string(), title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DurationSpec;
+    use std::cell::RefCell;
+
+    const GITHUB_ISSUE: &str = include_str!("../fixtures/issues/github.json");
+    const JIRA_ISSUE: &str = include_str!("../fixtures/issues/jira.json");
+
+    /// A URL with its headers.
+    type Request = (String, Vec<(String, String)>);
+
+    /// Answers every request with `reply`, recording the requests.
+    struct Mock {
+        reply: Result<String>,
+        requests: RefCell<Vec<Request>>,
+    }
+
+    impl Mock {
+        fn new(reply: Result<String>) -> Self {
+            Mock {
+                reply,
+                requests: RefCell::default(),
+            }
+        }
+    }
+
+    impl Http for Mock {
+        fn get(&self, url: &str, headers: &[(&str, String)]) -> Result<String> {
+            let headers = headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect();
+            self.requests.borrow_mut().push((url.to_string(), headers));
+            match &self.reply {
+                Ok(body) => Ok(body.clone()),
+                Err(err) => Err(AtomicError::Generic(err.to_string())),
+            }
+        }
+    }
+
+    fn github() -> IssueSettings {
+        IssueSettings {
+            provider: IssueProvider::Github,
+            repo: None,
+            base_url: None,
+            project: None,
+            token_env: Some("ATOMIC_TEST_ISSUES_NO_TOKEN".to_string()),
+            cache_ttl: None,
+        }
+    }
+
+    fn jira() -> IssueSettings {
+        IssueSettings {
+            provider: IssueProvider::Jira,
+            base_url: Some("https://acme.atlassian.net/".to_string()),
+            project: Some("ABC".to_string()),
+            ..github()
+        }
+    }
+
+    #[test]
+    fn test_github_repo() {
+        for url in [
+            "git@github.com:ExtremelyRyan/atomic.git",
+            "https://github.com/ExtremelyRyan/atomic",
+            "https://github.com/ExtremelyRyan/atomic.git/",
+            "ssh://git@github.com/ExtremelyRyan/atomic.git",
+        ] {
+            assert_eq!(
+                github_repo(url).as_deref(),
+                Some("ExtremelyRyan/atomic"),
+                "{}",
+                url
+            );
+        }
+        assert_eq!(github_repo("https://gitlab.com/a/b.git"), None);
+        assert_eq!(github_repo("https://github.com/a"), None);
+        assert_eq!(github_repo("/srv/git/atomic.git"), None);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let origin = Some("git@github.com:ExtremelyRyan/atomic.git");
+        let tracker = Tracker::new(&github(), origin).unwrap();
+        assert_eq!(
+            tracker.url(144),
+            "https://api.github.com/repos/ExtremelyRyan/atomic/issues/144"
+        );
+        assert_eq!(tracker.key(144), "github:ExtremelyRyan/atomic#144");
+        let other = IssueSettings {
+            repo: Some("me/fork".to_string()),
+            ..github()
+        };
+        assert_eq!(
+            Tracker::new(&other, None).unwrap().url(1),
+            "https://api.github.com/repos/me/fork/issues/1"
+        );
+        assert!(Tracker::new(&github(), Some("https://gitlab.com/a/b")).is_err());
+
+        let tracker = Tracker::new(&jira(), None).unwrap();
+        assert_eq!(
+            tracker.url(144),
+            "https://acme.atlassian.net/rest/api/2/issue/ABC-144?fields=summary"
+        );
+        assert_eq!(tracker.default_token_env(), "JIRA_TOKEN");
+        let incomplete = IssueSettings {
+            project: None,
+            ..jira()
+        };
+        assert!(Tracker::new(&incomplete, None).is_err());
+    }
+
+    #[test]
+    fn test_headers() {
+        let tracker = Tracker::new(&jira(), None).unwrap();
+        assert_eq!(
+            tracker.headers(None),
+            [("Accept", "application/json".to_string())]
+        );
+        assert_eq!(
+            tracker.headers(Some("secret"))[1],
+            ("Authorization", "Bearer secret".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_title() {
+        let github = Tracker::new(&github(), Some("git@github.com:a/b.git")).unwrap();
+        assert_eq!(
+            github.parse_title(GITHUB_ISSUE).unwrap(),
+            "Directly integrate issue trackers"
+        );
+        let jira = Tracker::new(&jira(), None).unwrap();
+        assert_eq!(
+            jira.parse_title(JIRA_ISSUE).unwrap(),
+            "Fix login timeout on slow networks"
+        );
+        // each provider's answer is missing what the other looks for
+        assert!(github.parse_title(JIRA_ISSUE).is_err());
+        assert!(jira.parse_title(GITHUB_ISSUE).is_err());
+        assert!(github.parse_title("<html>rate limited</html>").is_err());
+    }
+
+    #[test]
+    fn test_title_is_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let origin = Some("https://github.com/a/b");
+        let http = Mock::new(Ok(GITHUB_ISSUE.to_string()));
+        let title = |now| fetch_title(&atomic, &github(), origin, 7, &http, now).unwrap();
+
+        assert_eq!(title(1000), "Directly integrate issue trackers");
+        assert_eq!(title(1000 + 3599), "Directly integrate issue trackers");
+        assert_eq!(http.requests.borrow().len(), 1);
+        assert_eq!(
+            http.requests.borrow()[0].0,
+            "https://api.github.com/repos/a/b/issues/7"
+        );
+        assert_eq!(load(&atomic).issues["github:a/b#7"].fetched, 1000);
+
+        // stale after an hour, or whatever cache_ttl says
+        title(1000 + 3600);
+        assert_eq!(http.requests.borrow().len(), 2);
+        let short = IssueSettings {
+            cache_ttl: Some(DurationSpec::Text("10s".to_string())),
+            ..github()
+        };
+        fetch_title(&atomic, &short, origin, 7, &http, 4600 + 10).unwrap();
+        assert_eq!(http.requests.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_title_degrades_to_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let origin = Some("https://github.com/a/b");
+        let down = Mock::new(Err(AtomicError::Static("connection refused")));
+        assert_eq!(title(&atomic, &github(), origin, 7, &down, 0), "");
+        assert!(WARNED.with(Cell::get));
+        // nothing was cached, so the next run asks again
+        assert!(load(&atomic).issues.is_empty());
+
+        let garbage = Mock::new(Ok("{}".to_string()));
+        assert_eq!(title(&atomic, &github(), origin, 7, &garbage, 0), "");
+        assert_eq!(title(&atomic, &jira(), None, 7, &garbage, 0), "");
+        assert_eq!(title(&atomic, &github(), None, 7, &garbage, 0), "");
+        assert_eq!(garbage.requests.borrow().len(), 2);
+    }
+}
@@ -0,0 +1,245 @@
+//! `--junit <file>`: the steps of a run as JUnit XML, for CI test reports.
+//!
+//! Steps are recorded as they finish, the same way [`crate::trace`] records spans: nothing is
+//! kept until [`enable`] is called on the current thread.
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::command::StepOutcome;
+
+/// One step of the run, as a JUnit testcase.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestCase {
+    /// The command name, or the shell command for a step that isn't one.
+    pub name: String,
+    /// The section the command came from, or `shell`.
+    pub classname: String,
+    pub time: Duration,
+    pub result: CaseResult,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaseResult {
+    Passed,
+    Failed {
+        code: i32,
+        attempts: u32,
+        /// Failed in a step marked `ignore_failure`, so the run went on as if it passed.
+        ignored: bool,
+        /// The last [`OUTPUT_LINES`] lines of its stdout.
+        output: Option<String>,
+    },
+    /// Never started, because something it needed failed.
+    Skipped,
+}
+
+impl CaseResult {
+    pub fn from_outcome(outcome: &StepOutcome) -> Self {
+        if outcome.success() {
+            CaseResult::Passed
+        } else {
+            CaseResult::Failed {
+                code: outcome.code,
+                attempts: outcome.attempts,
+                ignored: outcome.ignored,
+                output: outcome.output.clone(),
+            }
+        }
+    }
+}
+
+/// How many lines of a failed step's stdout its testcase carries.
+pub const OUTPUT_LINES: usize = 50;
+
+thread_local! {
+    static CASES: RefCell<Option<Vec<TestCase>>> = const { RefCell::new(None) };
+}
+
+/// Starts recording steps on this thread, discarding anything recorded before.
+pub fn enable() {
+    CASES.with(|cases| *cases.borrow_mut() = Some(Vec::new()));
+}
+
+/// Whether steps are being recorded on this thread, so their stdout is worth keeping.
+pub fn recording() -> bool {
+    CASES.with(|cases| cases.borrow().is_some())
+}
+
+/// Records a finished or skipped step. A no-op unless recording is on.
+pub fn record(name: &str, classname: &str, time: Duration, result: CaseResult) {
+    CASES.with(|cases| {
+        if let Some(cases) = cases.borrow_mut().as_mut() {
+            cases.push(TestCase {
+                name: name.to_string(),
+                classname: classname.to_string(),
+                time,
+                result,
+            });
+        }
+    });
+}
+
+/// Stops recording and returns every step recorded since [`enable`].
+pub fn finish() -> Vec<TestCase> {
+    CASES
+        .with(|cases| cases.borrow_mut().take())
+        .unwrap_or_default()
+}
+
+/// Renders `cases` as a JUnit document with a single testsuite called `suite`.
+///
+/// Failed steps carry their exit code and the end of their stdout; stderr goes straight to the
+/// terminal, so the CI log is where to read it.
+pub fn render(suite: &str, cases: &[TestCase]) -> String {
+    let failures = cases
+        .iter()
+        .filter(|case| matches!(case.result, CaseResult::Failed { ignored: false, .. }))
+        .count();
+    let skipped = cases
+        .iter()
+        .filter(|case| case.result == CaseResult::Skipped)
+        .count();
+    let total: Duration = cases.iter().map(|case| case.time).sum();
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+    out.push_str(&format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\" time=\"{:.3}\">\n",
+        escape(suite),
+        cases.len(),
+        failures,
+        skipped,
+        total.as_secs_f64()
+    ));
+    for case in cases {
+        let open = format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape(&case.name),
+            escape(&case.classname),
+            case.time.as_secs_f64()
+        );
+        match &case.result {
+            CaseResult::Passed => out.push_str(&format!("{}/>\n", open)),
+            CaseResult::Skipped => out.push_str(&format!("{}>\n      <skipped/>\n", open)),
+            CaseResult::Failed {
+                code,
+                attempts,
+                ignored,
+                output,
+            } => {
+                let mut detail = match attempts {
+                    1 => format!("exit code {}", code),
+                    _ => format!("exit code {} after {} attempts", code, attempts),
+                };
+                if let Some(output) = output {
+                    detail = format!("{}\n{}", detail, escape(output));
+                }
+                let element = if *ignored {
+                    format!("<system-out>failed (ignored): {}</system-out>", detail)
+                } else {
+                    format!(
+                        "<failure message=\"exit code {}\">{}</failure>",
+                        code, detail
+                    )
+                };
+                out.push_str(&format!("{}>\n      {}\n", open, element));
+            }
+        }
+        if case.result != CaseResult::Passed {
+            out.push_str("    </testcase>\n");
+        }
+    }
+    out.push_str("  </testsuite>\n</testsuites>\n");
+    out
+}
+
+/// Escapes the five XML special characters, for text and attribute values alike.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(name: &str, classname: &str, millis: u64, result: CaseResult) -> TestCase {
+        TestCase {
+            name: name.to_string(),
+            classname: classname.to_string(),
+            time: Duration::from_millis(millis),
+            result,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let cases = [
+            case("fmt", "default", 120, CaseResult::Passed),
+            case(
+                "clippy",
+                "custom",
+                1500,
+                CaseResult::Failed {
+                    code: 101,
+                    attempts: 2,
+                    ignored: false,
+                    output: Some("error: unused <T> & \"x\"\n1 error".to_string()),
+                },
+            ),
+            case(
+                "echo \"a\" && test 1 < 2",
+                "shell",
+                5,
+                CaseResult::Failed {
+                    code: 1,
+                    attempts: 1,
+                    ignored: true,
+                    output: None,
+                },
+            ),
+            case("deploy", "custom", 0, CaseResult::Skipped),
+        ];
+        assert_eq!(
+            render("atomic ci & <release>", &cases),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="atomic ci &amp; &lt;release&gt;" tests="4" failures="1" errors="0" skipped="1" time="1.625">
+    <testcase name="fmt" classname="default" time="0.120"/>
+    <testcase name="clippy" classname="custom" time="1.500">
+      <failure message="exit code 101">exit code 101 after 2 attempts
+error: unused &lt;T&gt; &amp; &quot;x&quot;
+1 error</failure>
+    </testcase>
+    <testcase name="echo &quot;a&quot; &amp;&amp; test 1 &lt; 2" classname="shell" time="0.005">
+      <system-out>failed (ignored): exit code 1</system-out>
+    </testcase>
+    <testcase name="deploy" classname="custom" time="0.000">
+      <skipped/>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+
+    #[test]
+    fn test_recorder() {
+        record("before", "custom", Duration::ZERO, CaseResult::Passed);
+        enable();
+        record("build", "default", Duration::ZERO, CaseResult::Passed);
+        assert_eq!(finish().len(), 1);
+        assert!(finish().is_empty());
+        assert_eq!(escape("it's"), "it&apos;s");
+    }
+}
@@ -0,0 +1,163 @@
+//! `atomic --again`: the last command run in a project, kept in `.atomic/last_run.toml`.
+//!
+//! The file is rewritten after every run and holds the arguments atomic was started with, so
+//! repeating it is parsing them again.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AtomicError, Result};
+
+/// Directory next to atomic.toml for atomic's own state. It ignores itself, so auto-commits
+/// don't pick it up.
+pub const STATE_DIR: &str = ".atomic";
+const FILE: &str = "last_run.toml";
+
+/// Version written to new files; bump it when older atomics would misread them.
+pub const VERSION: u32 = 1;
+/// Oldest version this atomic can still repeat.
+const MIN_VERSION: u32 = 1;
+
+/// What the last run was. Keys this atomic doesn't know are ignored, so newer files still load.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastRun {
+    #[serde(default)]
+    pub version: u32,
+    /// The arguments after the program name, with the profile and a command picked from the
+    /// menu filled in.
+    pub args: Vec<String>,
+    /// `--stdin`: the command came from stdin, which isn't kept, so there's nothing to repeat.
+    #[serde(default)]
+    pub stdin: bool,
+}
+
+impl LastRun {
+    pub fn new(args: Vec<String>, stdin: bool) -> Self {
+        Self {
+            version: VERSION,
+            args,
+            stdin,
+        }
+    }
+
+    /// The arguments to run again.
+    ///
+    /// # Errors
+    /// Returns an error if the run can't be repeated.
+    pub fn repeatable_args(&self) -> Result<&[String]> {
+        if self.stdin {
+            return Err(AtomicError::Generic(
+                "the last run read its command from --stdin, which isn't kept; --again can't repeat it"
+                    .to_string(),
+            ));
+        }
+        Ok(&self.args)
+    }
+}
+
+/// Where the last run of the project around `atomic` is kept.
+pub fn path(atomic: &Path) -> PathBuf {
+    atomic
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(STATE_DIR)
+        .join(FILE)
+}
+
+/// Creates [`STATE_DIR`] next to `atomic`, and `sub` inside it when given, returning the path.
+///
+/// # Errors
+/// Returns an error if a directory or the `.gitignore` can't be written.
+pub fn state_dir(atomic: &Path, sub: Option<&str>) -> io::Result<PathBuf> {
+    let root = atomic.parent().unwrap_or(Path::new("")).join(STATE_DIR);
+    let dir = match sub {
+        Some(sub) => root.join(sub),
+        None => root.clone(),
+    };
+    fs::create_dir_all(&dir)?;
+    let ignore = root.join(".gitignore");
+    if !ignore.exists() {
+        fs::write(ignore, "*\n")?;
+    }
+    Ok(dir)
+}
+
+/// Writes `run` as the last run of the project around `atomic`.
+///
+/// # Errors
+/// Returns an error if the state directory or file can't be written.
+pub fn save(atomic: &Path, run: &LastRun) -> Result<()> {
+    let file = path(atomic);
+    let text = ::toml::to_string(run)
+        .map_err(|err| AtomicError::Generic(format!("Failed to serialize last run: {}", err)))?;
+    state_dir(atomic, None)
+        .and_then(|_| fs::write(&file, text))
+        .map_err(|err| AtomicError::Generic(format!("Failed to write {}: {}", file.display(), err)))
+}
+
+/// Reads the last run of the project around `atomic`.
+///
+/// # Errors
+/// Returns an error if nothing has run there yet, or the file can't be read or is too old.
+pub fn load(atomic: &Path) -> Result<LastRun> {
+    let file = path(atomic);
+    let text = fs::read_to_string(&file).map_err(|_| AtomicError::NoLastRun)?;
+    let run: LastRun = ::toml::from_str(&text)
+        .map_err(|err| AtomicError::TomlParse(file.display().to_string(), err))?;
+    if run.version < MIN_VERSION {
+        return Err(AtomicError::Generic(format!(
+            "{} was written by an older atomic (version {}, this one reads {} and up); \
+             run a command once to record it again",
+            file.display(),
+            run.version,
+            MIN_VERSION
+        )));
+    }
+    Ok(run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        assert!(load(&atomic).is_err());
+
+        let run = LastRun::new(vec!["--profile".into(), "ci".into(), "build".into()], false);
+        save(&atomic, &run).unwrap();
+        assert_eq!(load(&atomic), Ok(run.clone()));
+        assert_eq!(
+            fs::read_to_string(dir.path().join(".atomic/.gitignore")).unwrap(),
+            "*\n"
+        );
+        assert_eq!(run.repeatable_args().unwrap(), ["--profile", "ci", "build"]);
+        assert!(LastRun::new(Vec::new(), true).repeatable_args().is_err());
+    }
+
+    #[test]
+    fn test_load_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::create_dir(dir.path().join(STATE_DIR)).unwrap();
+
+        fs::write(
+            path(&atomic),
+            "version = 2\nargs = [\"build\"]\nstarted = 1700000000\n",
+        )
+        .unwrap();
+        assert_eq!(load(&atomic).unwrap().args, ["build"]);
+
+        fs::write(path(&atomic), "args = [\"build\"]\n").unwrap();
+        assert!(load(&atomic)
+            .unwrap_err()
+            .to_string()
+            .contains("written by an older atomic"));
+    }
+}
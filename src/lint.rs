@@ -0,0 +1,573 @@
+//! `atomic --lint`: warnings about command strings that parse fine but don't do what they seem
+//! to, such as a `cd` step whose directory is gone by the next step.
+//!
+//! Each rule is a function over one command, listed in [`RULES`]. A command opts out of every
+//! rule with `lint = false`, and `[atomic.lints]` turns single rules off for the whole file.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::config::{AtomicConfig, CommandSpec, ConfirmSpec, Steps, QUALIFIERS};
+
+/// Names of the rules in [`RULES`], in the same order, for the `[atomic.lints]` schema.
+pub const RULE_NAMES: &[&str] = &[
+    "windows_vars",
+    "cd_in_chain",
+    "shell_chain",
+    "rm_rf",
+    "backslash_paths",
+    "missing_script",
+];
+
+/// One rule: a name for `[atomic.lints]`, and the check itself.
+pub struct Rule {
+    pub name: &'static str,
+    pub check: fn(&Target) -> Vec<Lint>,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "windows_vars",
+        check: windows_vars,
+    },
+    Rule {
+        name: "cd_in_chain",
+        check: cd_in_chain,
+    },
+    Rule {
+        name: "shell_chain",
+        check: shell_chain,
+    },
+    Rule {
+        name: "rm_rf",
+        check: rm_rf,
+    },
+    Rule {
+        name: "backslash_paths",
+        check: backslash_paths,
+    },
+    Rule {
+        name: "missing_script",
+        check: missing_script,
+    },
+];
+
+/// A warning about one command string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    pub rule: &'static str,
+    /// TOML path of the string, such as `custom.deploy.command[1]`.
+    pub path: String,
+    pub message: String,
+    pub help: String,
+}
+
+impl Lint {
+    fn new(path: &str, message: impl Into<String>, help: impl Into<String>) -> Self {
+        Self {
+            rule: "",
+            path: path.to_string(),
+            message: message.into(),
+            help: help.into(),
+        }
+    }
+}
+
+/// `custom.deploy.command[1]: ... [cd_in_chain]`, then the suggestion on its own line.
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} [{}]\n  help: {}",
+            self.path, self.message, self.rule, self.help
+        )
+    }
+}
+
+/// The command a rule looks at, and what it needs to know around it.
+pub struct Target<'a> {
+    /// TOML path of the command, such as `custom.deploy` or `build` for a top-level one.
+    pub path: String,
+    pub spec: &'a CommandSpec,
+    pub config: &'a AtomicConfig,
+    /// The directory holding atomic.toml, which commands run in.
+    pub root: &'a Path,
+}
+
+impl Target<'_> {
+    /// Path of the command's steps: the command itself, or its `command` key.
+    fn steps_path(&self) -> String {
+        match self.spec {
+            CommandSpec::Plain(_) => self.path.clone(),
+            CommandSpec::Detailed(_) => format!("{}.command", self.path),
+        }
+    }
+
+    /// Every shell string the command runs, with its TOML path; chain steps naming other
+    /// commands are left out, since those get linted on their own.
+    fn shell_steps(&self) -> Vec<(String, &str)> {
+        let path = self.steps_path();
+        match self.spec.steps() {
+            Steps::Shell(command) => vec![(path, command.as_str())],
+            Steps::Chain(steps) => steps
+                .iter()
+                .enumerate()
+                .filter_map(|(index, step)| {
+                    let (run, _) = step.resolve(self.config);
+                    let named = self.config.command(run).is_some();
+                    (!named).then(|| (format!("{}[{}]", path, index), run))
+                })
+                .collect(),
+        }
+    }
+
+    fn runs_on(&self, platform: &str) -> bool {
+        self.spec
+            .os()
+            .is_some_and(|os| os.platforms().contains(&platform))
+    }
+
+    fn only_on_windows(&self) -> bool {
+        self.spec
+            .os()
+            .is_some_and(|os| os.platforms().iter().all(|p| *p == "windows"))
+    }
+}
+
+/// Lints every command in `config`, whose atomic.toml is in `root`, in lookup order.
+pub fn lint(config: &AtomicConfig, root: &Path) -> Vec<Lint> {
+    let disabled = config.atomic.lints.as_ref();
+    let mut lints = Vec::new();
+    for section in QUALIFIERS {
+        let commands = match *section {
+            "custom" => &config.custom,
+            "default" => &config.default,
+            _ => &config.root,
+        };
+        for (name, spec) in commands {
+            if spec.detail().and_then(|detail| detail.lint) == Some(false) {
+                continue;
+            }
+            let target = Target {
+                path: match *section {
+                    "root" => name.clone(),
+                    section => format!("{}.{}", section, name),
+                },
+                spec,
+                config,
+                root,
+            };
+            for rule in RULES {
+                if disabled.and_then(|lints| lints.get(rule.name)) == Some(&false) {
+                    continue;
+                }
+                lints.extend((rule.check)(&target).into_iter().map(|lint| Lint {
+                    rule: rule.name,
+                    ..lint
+                }));
+            }
+        }
+    }
+    lints
+}
+
+/// The words of a shell string, or `None` when it doesn't split, such as with an open quote.
+fn words(command: &str) -> Option<Vec<String>> {
+    shell_words::split(command).ok()
+}
+
+/// `$VAR` and `${VAR}` mean nothing to cmd.exe, which wants `%VAR%`.
+fn windows_vars(target: &Target) -> Vec<Lint> {
+    if !target.runs_on("windows") {
+        return Vec::new();
+    }
+    target
+        .shell_steps()
+        .into_iter()
+        .filter_map(|(path, command)| {
+            let var = unix_var(command)?;
+            Some(Lint::new(
+                &path,
+                format!("`${}` isn't expanded on Windows", var),
+                format!(
+                    "write it as `%{}%` in a command with `os = \"windows\"`",
+                    var
+                ),
+            ))
+        })
+        .collect()
+}
+
+/// The name of the first `$VAR` or `${VAR}` in `command`.
+fn unix_var(command: &str) -> Option<&str> {
+    let mut rest = command;
+    while let Some(at) = rest.find('$') {
+        rest = &rest[at + 1..];
+        let name = rest.strip_prefix('{').unwrap_or(rest);
+        let len = name
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(name.len());
+        if len > 0 && !name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Some(&name[..len]);
+        }
+    }
+    None
+}
+
+/// A `cd` that is a whole step on its own, whose directory the next step never sees.
+fn cd_in_chain(target: &Target) -> Vec<Lint> {
+    let steps = target.shell_steps();
+    let chain = matches!(target.spec.steps(), Steps::Chain(_));
+    steps
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (path, command))| {
+            let words = words(command)?;
+            if words.first().map(String::as_str) != Some("cd") || words.len() > 2 {
+                return None;
+            }
+            let help = match steps.get(index + 1) {
+                Some((_, next)) if chain => format!(
+                    "join it with the step that needs it: `{} && {}`",
+                    command.trim(),
+                    next.trim()
+                ),
+                _ if chain => {
+                    "remove it, or join it with a step that runs in that directory".to_string()
+                }
+                _ => format!(
+                    "follow it with the command to run there: `{} && ...`",
+                    command.trim()
+                ),
+            };
+            let message = match chain {
+                true => "each step runs in its own shell, so this `cd` doesn't reach the next step",
+                false => "a `cd` on its own changes nothing once the command ends",
+            };
+            Some(Lint::new(path, message, help))
+        })
+        .collect()
+}
+
+/// `a && b` or `a; b` in one string, which a chain shows, times and retries step by step.
+fn shell_chain(target: &Target) -> Vec<Lint> {
+    target
+        .shell_steps()
+        .into_iter()
+        .filter_map(|(path, command)| {
+            let words = words(command)?;
+            // `cd dir && ...` has to stay in one shell
+            if words.first().map(String::as_str) == Some("cd") {
+                return None;
+            }
+            let joined = words
+                .iter()
+                .any(|word| word == "&&" || word == ";" || word.ends_with(';'));
+            joined.then(|| {
+                Lint::new(
+                    &path,
+                    "several commands joined in one string",
+                    "split them into a chain, e.g. `command = [\"cargo fmt\", \"cargo test\"]`, \
+                     which runs them in order and stops at the first failure",
+                )
+            })
+        })
+        .collect()
+}
+
+/// `rm -rf` in a command that runs without asking first.
+fn rm_rf(target: &Target) -> Vec<Lint> {
+    let confirmed = target
+        .spec
+        .detail()
+        .and_then(|detail| detail.confirm.as_ref())
+        .is_some_and(|confirm| *confirm != ConfirmSpec::Flag(false));
+    if confirmed {
+        return Vec::new();
+    }
+    target
+        .shell_steps()
+        .into_iter()
+        .filter(|(_, command)| words(command).is_some_and(|words| removes_recursively(&words)))
+        .map(|(path, _)| {
+            Lint::new(
+                &path,
+                "`rm -rf` runs without asking",
+                "add `confirm = true` to the command table so it asks first",
+            )
+        })
+        .collect()
+}
+
+/// Whether `words` hold an `rm` with both the recursive and force flags.
+fn removes_recursively(words: &[String]) -> bool {
+    words.iter().enumerate().any(|(index, word)| {
+        if word != "rm" {
+            return false;
+        }
+        let flags: Vec<&String> = words[index + 1..]
+            .iter()
+            .take_while(|word| word.starts_with('-'))
+            .collect();
+        let has = |short: char, long: &str| {
+            flags.iter().any(|flag| match flag.strip_prefix("--") {
+                Some(name) => name == long,
+                None => flag.contains(short) || flag.contains(short.to_ascii_uppercase()),
+            })
+        };
+        has('r', "recursive") && has('f', "force")
+    })
+}
+
+/// `scripts\build.sh`: `sh` takes the backslash as an escape, so the path falls apart off
+/// Windows.
+fn backslash_paths(target: &Target) -> Vec<Lint> {
+    if target.only_on_windows() {
+        return Vec::new();
+    }
+    target
+        .shell_steps()
+        .into_iter()
+        .filter_map(|(path, command)| {
+            let word = command
+                .split_whitespace()
+                .find(|word| is_windows_path(word))?;
+            Some(Lint::new(
+                &path,
+                format!(
+                    "`{}` uses backslashes, which unix shells take as escapes",
+                    word
+                ),
+                format!(
+                    "write `{}`; Windows takes forward slashes too, or set `os = \"windows\"`",
+                    word.replace('\\', "/")
+                ),
+            ))
+        })
+        .collect()
+}
+
+/// A word with a backslash between two parts of a path, like `src\main.rs` or `.\build.bat`.
+fn is_windows_path(word: &str) -> bool {
+    let part = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || "._-".contains(c));
+    word.match_indices('\\')
+        .any(|(at, _)| part(word[..at].chars().next_back()) && part(word[at + 1..].chars().next()))
+}
+
+/// Programs that take a script as their first argument.
+const INTERPRETERS: &[&str] = &[
+    "sh", "bash", "zsh", "python", "python3", "node", "ruby", "perl", "pwsh",
+];
+
+/// `./scripts/release.sh`, or `bash scripts/release.sh`, where that file isn't in the project.
+fn missing_script(target: &Target) -> Vec<Lint> {
+    target
+        .shell_steps()
+        .into_iter()
+        .filter_map(|(path, command)| {
+            let script = script_of(&words(command)?)?;
+            if target.root.join(&script).exists() {
+                return None;
+            }
+            Some(Lint::new(
+                &path,
+                format!("`{}` doesn't exist", script),
+                "check the path; commands run in the directory holding atomic.toml",
+            ))
+        })
+        .collect()
+}
+
+/// The relative script path a command runs, if it runs one.
+fn script_of(words: &[String]) -> Option<String> {
+    let first = words.first()?;
+    let script = match INTERPRETERS.contains(&first.as_str()) {
+        true => words[1..].iter().find(|word| !word.starts_with('-'))?,
+        false => first,
+    };
+    let relative = script.starts_with("./") || script.starts_with("../");
+    let path_like = relative || (INTERPRETERS.contains(&first.as_str()) && script.contains('.'));
+    // variables and matrix placeholders are only known at run time
+    let dynamic = script.contains(['$', '{', '%', '*']);
+    (path_like && !dynamic && !Path::new(script).is_absolute()).then(|| script.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Lints `text`, as an atomic.toml in `root`, with only `rule`.
+    fn check(rule: fn(&Target) -> Vec<Lint>, text: &str, root: &Path) -> Vec<String> {
+        let config = AtomicConfig::from_value(::toml::from_str(text).unwrap()).unwrap();
+        let mut lints = Vec::new();
+        for (name, spec) in config
+            .custom
+            .iter()
+            .chain(&config.default)
+            .chain(&config.root)
+        {
+            let target = Target {
+                path: name.clone(),
+                spec,
+                config: &config,
+                root,
+            };
+            lints.extend(rule(&target).into_iter().map(|lint| lint.path));
+        }
+        lints
+    }
+
+    fn lint_paths(rule: fn(&Target) -> Vec<Lint>, text: &str) -> Vec<String> {
+        check(rule, text, Path::new("."))
+    }
+
+    #[test]
+    fn test_rule_names() {
+        let names: Vec<&str> = RULES.iter().map(|rule| rule.name).collect();
+        assert_eq!(names, RULE_NAMES);
+    }
+
+    #[test]
+    fn test_windows_vars() {
+        let text = r#"
+            [custom.win]
+            command = ["echo $HOME", "echo %USERPROFILE%", "echo ${CARGO_HOME}/bin"]
+            os = ["windows", "unix"]
+            [custom.unix]
+            command = "echo $HOME"
+            os = "unix"
+            [custom.price]
+            command = "echo $5"
+            os = "windows"
+        "#;
+        assert_eq!(
+            lint_paths(windows_vars, text),
+            ["win.command[0]", "win.command[2]"]
+        );
+        assert_eq!(unix_var("echo ${CARGO_HOME}/bin"), Some("CARGO_HOME"));
+        assert_eq!(unix_var("echo $5 $"), None);
+    }
+
+    #[test]
+    fn test_cd_in_chain() {
+        let text = r#"
+            [custom]
+            web = ["cd web", "npm run build"]
+            joined = ["cd web && npm run build"]
+            alone = "cd web"
+            fine = "cd web && npm test"
+            named = ["go-web", "npm run build"]
+            go-web = "cd web && ls"
+            [custom.table]
+            command = [{ run = "cd docs" }, "mdbook build"]
+        "#;
+        assert_eq!(
+            lint_paths(cd_in_chain, text),
+            ["alone", "table.command[0]", "web[0]"]
+        );
+
+        let config = AtomicConfig::from_value(::toml::from_str(text).unwrap()).unwrap();
+        let target = Target {
+            path: "custom.web".to_string(),
+            spec: config.command("web").unwrap(),
+            config: &config,
+            root: Path::new("."),
+        };
+        assert_eq!(
+            cd_in_chain(&target)[0].help,
+            "join it with the step that needs it: `cd web && npm run build`"
+        );
+    }
+
+    #[test]
+    fn test_shell_chain() {
+        let text = r#"
+            [custom]
+            both = "cargo fmt && cargo test"
+            semi = "cargo fmt; cargo test"
+            quoted = "echo 'a && b'"
+            web = "cd web && npm test"
+            chain = ["cargo fmt", "cargo build && cargo test"]
+        "#;
+        assert_eq!(lint_paths(shell_chain, text), ["both", "chain[1]", "semi"]);
+    }
+
+    #[test]
+    fn test_rm_rf() {
+        let text = r#"
+            [custom]
+            clean = "rm -rf target"
+            split = "rm -r -f target"
+            long = "rm --recursive --force target"
+            gentle = "rm -r target"
+            [custom.guarded]
+            command = "rm -rf target"
+            confirm = true
+            [custom.unguarded]
+            command = "rm -Rf target"
+            confirm = false
+        "#;
+        assert_eq!(
+            lint_paths(rm_rf, text),
+            ["clean", "long", "split", "unguarded.command"]
+        );
+    }
+
+    #[test]
+    fn test_backslash_paths() {
+        let text = r#"
+            [custom]
+            script = 'scripts\build.sh'
+            escaped = 'echo \"hi\"'
+            regex = 'grep "a\|b" notes.txt'
+            [custom.win]
+            command = '.\build.bat'
+            os = "windows"
+        "#;
+        assert_eq!(lint_paths(backslash_paths, text), ["script"]);
+    }
+
+    #[test]
+    fn test_missing_script() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("scripts")).unwrap();
+        fs::write(dir.path().join("scripts/release.sh"), "").unwrap();
+        let text = r#"
+            [custom]
+            there = "./scripts/release.sh"
+            gone = "./scripts/deploy.sh --prod"
+            bash = "bash -e scripts/gone.sh"
+            python = "python3 tools/gen.py"
+            inline = "python3 -c 'print(1)'"
+            matrix = "./scripts/{target}.sh"
+            cargo = "cargo build"
+        "#;
+        assert_eq!(
+            check(missing_script, text, dir.path()),
+            ["bash", "gone", "python"]
+        );
+    }
+
+    #[test]
+    fn test_suppressed() {
+        let text = r#"
+            [atomic.lints]
+            shell_chain = false
+            [custom]
+            both = "cargo fmt && rm -rf target"
+            [custom.quiet]
+            command = "rm -rf target"
+            lint = false
+        "#;
+        let config = AtomicConfig::from_value(::toml::from_str(text).unwrap()).unwrap();
+        let lints = lint(&config, Path::new("."));
+        assert_eq!(lints.len(), 1);
+        assert_eq!(
+            lints[0].to_string(),
+            "custom.both: `rm -rf` runs without asking [rm_rf]\n  \
+             help: add `confirm = true` to the command table so it asks first"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! `atomic --list`: the commands in atomic.toml, grouped by section and optionally filtered.
+
+use std::path::PathBuf;
+
+use crate::config::{AtomicConfig, Steps};
+use crate::toml::Provenance;
+
+/// Sections in the order `--list` shows them, as [`AtomicConfig::section_of`] names them.
+pub const LIST_SECTIONS: &[&str] = &["custom", "default", "root"];
+
+/// Whether a command runs one shell command or a chain of steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Shell,
+    Chain,
+}
+
+/// One command as `--list` shows it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListEntry {
+    pub section: &'static str,
+    pub name: String,
+    pub desc: Option<String>,
+    pub kind: EntryKind,
+    /// The file it came from, when several atomic.toml files are layered.
+    pub file: Option<PathBuf>,
+    /// Whether it came from the global config.
+    pub global: bool,
+    /// How often it has run, for `--list --stats`.
+    pub usage: Option<String>,
+    /// The platforms it is limited to, from `os`.
+    pub only_on: Option<String>,
+}
+
+impl ListEntry {
+    /// Whether `filter` picks this command out; see [`matches_filter`].
+    pub fn matches(&self, filter: &str) -> bool {
+        matches_filter(filter, &self.name, self.desc.as_deref())
+    }
+}
+
+/// Every command in `config`, grouped by section in [`LIST_SECTIONS`] order.
+pub fn entries(config: &AtomicConfig, provenance: &Provenance) -> Vec<ListEntry> {
+    let layered = provenance.files.len() > 1;
+    let mut entries: Vec<ListEntry> = config
+        .command_names()
+        .into_iter()
+        .filter_map(|name| {
+            let spec = config.command(&name)?;
+            let origin = provenance.command(&name);
+            let global = origin.is_some_and(|origin| provenance.is_global(origin));
+            Some(ListEntry {
+                section: config.section_of(&name)?,
+                desc: spec.desc().map(str::to_string),
+                kind: match spec.steps() {
+                    Steps::Shell(_) => EntryKind::Shell,
+                    Steps::Chain(_) => EntryKind::Chain,
+                },
+                file: origin
+                    .filter(|_| layered && !global)
+                    .map(|origin| origin.file.clone()),
+                global,
+                usage: None,
+                only_on: spec.os().map(|os| os.describe()),
+                name,
+            })
+        })
+        .collect();
+    entries.sort_by_key(|entry| LIST_SECTIONS.iter().position(|s| *s == entry.section));
+    entries
+}
+
+/// Whether `filter` picks out the command `name` described as `desc`.
+///
+/// It matches when it appears in either, ignoring case, or when its letters appear in `name` in
+/// order, so `cltst` finds `clippy-tests`.
+pub fn matches_filter(filter: &str, name: &str, desc: Option<&str>) -> bool {
+    let filter = filter.to_lowercase();
+    let name = name.to_lowercase();
+    if name.contains(&filter) || desc.is_some_and(|desc| desc.to_lowercase().contains(&filter)) {
+        return true;
+    }
+    let mut letters = name.chars();
+    filter
+        .chars()
+        .all(|wanted| letters.any(|letter| letter == wanted))
+}
+
+/// Renders `entries` one per line under a header for each section, with names padded to line up.
+///
+/// Each line has the command's `desc`, `(chain)` for a chain, the file it came from when layered
+/// or `(global)`, the platforms it is limited to, and its usage with `--stats`.
+pub fn render(entries: &[ListEntry]) -> String {
+    let width = entries
+        .iter()
+        .map(|entry| entry.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    let mut section = None;
+    for entry in entries {
+        if section != Some(entry.section) {
+            section = Some(entry.section);
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&match entry.section {
+                "root" => "top level\n".to_string(),
+                other => format!("[{}]\n", other),
+            });
+        }
+        let mut line = format!("  {:width$}", entry.name);
+        if let Some(desc) = &entry.desc {
+            line.push_str(&format!("  {}", desc));
+        }
+        if entry.kind == EntryKind::Chain {
+            line.push_str("  (chain)");
+        }
+        if let Some(file) = &entry.file {
+            line.push_str(&format!("  {}", file.display()));
+        }
+        if entry.global {
+            line.push_str("  (global)");
+        }
+        if let Some(platforms) = &entry.only_on {
+            line.push_str(&format!("  ({} only)", platforms));
+        }
+        if let Some(usage) = &entry.usage {
+            line.push_str(&format!("  [{}]", usage));
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_filter() {
+        assert!(matches_filter("test", "test", None));
+        assert!(matches_filter("TEST", "unit-tests", None));
+        assert!(matches_filter("deps", "fetch", Some("download Deps")));
+        assert!(matches_filter("cltst", "clippy-tests", None));
+        assert!(!matches_filter("tsetc", "clippy-tests", None));
+        assert!(!matches_filter("deps", "fetch", None));
+        // descriptions only match as a whole
+        assert!(!matches_filter("dwd", "fetch", Some("download deps")));
+        assert!(matches_filter("", "anything", None));
+    }
+
+    #[test]
+    fn test_entries_and_render() {
+        let config = AtomicConfig::from_value(
+            ::toml::from_str(
+                r#"
+                lint = "cargo clippy"
+
+                [default]
+                test = "cargo test"
+
+                [custom]
+                ci = ["lint", "test"]
+
+                [custom.open]
+                command = "open ."
+                desc = "open the project"
+                os = "macos"
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let entries = entries(&config, &Provenance::default());
+        let listed: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.section, entry.name.as_str(), entry.kind))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("custom", "ci", EntryKind::Chain),
+                ("custom", "open", EntryKind::Shell),
+                ("default", "test", EntryKind::Shell),
+                ("root", "lint", EntryKind::Shell),
+            ]
+        );
+        assert_eq!(
+            render(&entries),
+            "[custom]\n  ci    (chain)\n  open  open the project  (macos only)\n\n\
+             [default]\n  test\n\ntop level\n  lint\n"
+        );
+
+        let open: Vec<_> = entries
+            .into_iter()
+            .filter(|e| e.matches("project"))
+            .collect();
+        assert_eq!(
+            render(&open),
+            "[custom]\n  open  open the project  (macos only)\n"
+        );
+
+        let global = ListEntry {
+            section: "custom",
+            name: "scratch".to_string(),
+            desc: None,
+            kind: EntryKind::Shell,
+            file: None,
+            global: true,
+            usage: Some("3 runs, last used 2 days ago".to_string()),
+            only_on: None,
+        };
+        assert_eq!(
+            render(&[global]),
+            "[custom]\n  scratch  (global)  [3 runs, last used 2 days ago]\n"
+        );
+    }
+}
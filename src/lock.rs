@@ -0,0 +1,205 @@
+//! `lock = true`: one run of a command at a time, across terminals.
+//!
+//! The lock is an OS file lock on `.atomic/locks/<command>.lock`, so it goes away with the process
+//! holding it. The file also says who holds it, for the message shown to whoever has to wait; it
+//! is emptied on release, so a holder still named in an unlocked file exited without releasing.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::last_run::state_dir;
+use crate::{AtomicError, Result};
+
+/// Subdirectory of [`crate::last_run::STATE_DIR`] holding the lock files.
+const LOCK_DIR: &str = "locks";
+/// How often a waiting run checks whether the lock is free yet.
+const POLL: Duration = Duration::from_millis(100);
+
+/// Who holds a lock, as written into its file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Holder {
+    pub pid: u32,
+    /// When the holder took the lock, in seconds since the Unix epoch.
+    pub started: u64,
+}
+
+impl Holder {
+    fn current() -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        Self {
+            pid: process::id(),
+            started,
+        }
+    }
+
+    /// `pid 4242, running for 35s`.
+    fn describe(&self) -> String {
+        let now = Holder::current().started;
+        format!(
+            "pid {}, running for {}s",
+            self.pid,
+            now.saturating_sub(self.started)
+        )
+    }
+}
+
+/// A held lock, released when dropped.
+#[derive(Debug)]
+pub struct CommandLock {
+    file: File,
+}
+
+impl Drop for CommandLock {
+    fn drop(&mut self) {
+        // an empty file is how the next run knows this one let go properly
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Where the lock for `command` in the project around `atomic` lives.
+pub fn lock_path(atomic: &Path, command: &str) -> PathBuf {
+    atomic
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(crate::last_run::STATE_DIR)
+        .join(LOCK_DIR)
+        .join(format!("{}.lock", command))
+}
+
+/// Takes the lock for `command`, waiting up to `wait` while another run holds it, or without a
+/// limit when `wait` is `None`.
+///
+/// # Errors
+/// Returns an error naming the holder if the lock is still held when the wait is over, or if
+/// the lock file can't be opened.
+pub fn acquire(atomic: &Path, command: &str, wait: Option<Duration>) -> Result<CommandLock> {
+    let path = lock_path(atomic, command);
+    let io_error = |err: std::io::Error| {
+        AtomicError::Generic(format!("Failed to lock {}: {}", path.display(), err))
+    };
+    state_dir(atomic, Some(LOCK_DIR)).map_err(io_error)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(io_error)?;
+
+    let started = Instant::now();
+    let mut told = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(err)) => return Err(io_error(err)),
+        }
+        let holder = read_holder(&mut file).map(|holder| holder.describe());
+        if wait.is_some_and(|wait| started.elapsed() >= wait) {
+            return Err(AtomicError::Locked(command.to_string(), holder));
+        }
+        if !told {
+            let limit = wait.map_or(String::new(), |wait| {
+                format!(" for up to {}s", wait.as_secs())
+            });
+            let holder = holder.map_or(String::new(), |holder| format!(" ({})", holder));
+            eprintln!(
+                "'{}' is already running{}; waiting{}",
+                command, holder, limit
+            );
+            told = true;
+        }
+        thread::sleep(POLL);
+    }
+
+    if let Some(stale) = read_holder(&mut file) {
+        eprintln!(
+            "breaking a stale lock on '{}' left by pid {}, which is no longer running",
+            command, stale.pid
+        );
+    }
+    let holder = ::toml::to_string(&Holder::current())
+        .map_err(|err| AtomicError::Generic(format!("Failed to write lock holder: {}", err)))?;
+    file.set_len(0)
+        .and_then(|()| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.write_all(holder.as_bytes()))
+        .map_err(io_error)?;
+    Ok(CommandLock { file })
+}
+
+/// The holder named in a lock file, if it names one.
+fn read_holder(file: &mut File) -> Option<Holder> {
+    let mut text = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut text).ok()?;
+    ::toml::from_str(&text).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn holder(atomic: &Path) -> Holder {
+        ::toml::from_str(&fs::read_to_string(lock_path(atomic, "migrate")).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_acquire_waits_or_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+
+        let held = acquire(&atomic, "migrate", Some(Duration::ZERO)).unwrap();
+        assert_eq!(holder(&atomic).pid, process::id());
+
+        let err = acquire(&atomic, "migrate", Some(Duration::ZERO))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(&format!(
+                "'migrate' is already running (pid {}",
+                process::id()
+            )),
+            "{}",
+            err
+        );
+        // other commands have their own lock
+        drop(acquire(&atomic, "seed", Some(Duration::ZERO)).unwrap());
+
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(held);
+        });
+        let waited = Instant::now();
+        let second = acquire(&atomic, "migrate", Some(Duration::from_secs(10))).unwrap();
+        assert!(waited.elapsed() >= Duration::from_millis(200));
+        release.join().unwrap();
+        drop(second);
+        assert_eq!(
+            fs::read_to_string(lock_path(&atomic, "migrate")).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_stale_lock_is_broken() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        let path = lock_path(&atomic, "migrate");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "pid = 999999\nstarted = 1\n").unwrap();
+
+        let _lock = acquire(&atomic, "migrate", Some(Duration::ZERO)).unwrap();
+        assert_eq!(holder(&atomic).pid, process::id());
+    }
+}
@@ -1,8 +1,50 @@
 //! Main execution point
 
+mod artifacts;
+mod cargo_json;
+mod ci;
+mod clean_env;
 mod cli;
+mod command;
+mod config;
+mod config_changes;
+mod container;
+mod context;
+mod doctor;
+mod envfile;
+mod errors;
+mod events;
+mod exit;
 mod git;
+mod gitignore;
+mod init;
+mod inputs;
+mod issues;
+mod junit;
+mod last_run;
+mod lint;
+mod list;
+mod lock;
+mod migrate;
+mod nesting;
+mod pager;
+mod plan;
+mod progress;
+mod repos;
+mod schema;
+mod share;
+mod sign;
+mod stats;
+mod template;
+#[cfg(test)]
+mod test_support;
+mod tmpdir;
 mod toml;
+mod trace;
+mod util;
+mod verify;
+mod version;
+mod yaml;
 
 fn main() {
     cli::start_cli();
@@ -13,11 +55,81 @@ pub(crate) type Result<T> = core::result::Result<T, AtomicError>;
 
 #[derive(thiserror::Error, Debug, PartialEq)]
 pub enum AtomicError {
-    #[error("Generic error: {0}")]
+    #[error("{0}")]
     Generic(String),
-    #[error("Static error: {0}")]
+    #[error("{0}")]
     Static(&'static str),
 
+    #[error("{0} not found. Run `atomic --init` to create one.")]
+    ConfigNotFound(String),
+    #[error("found {}; keep one of them so it's clear which config atomic should use", .0.join(" and "))]
+    AmbiguousConfig(Vec<String>),
+    #[error("Profile '{0}' not found in atomic.toml")]
+    ProfileNotFound(String),
+    #[error("Invalid command from --stdin: {0}")]
+    InvalidStdinCommand(String),
+    #[error("Command '{0}' not found in atomic.toml.{}", util::did_you_mean(.1))]
+    CommandNotFound(String, Vec<String>),
+    #[error("Failed to parse {0}\n{1}")]
+    TomlParse(String, ::toml::de::Error),
+    #[error("Failed to parse {0}\n{1}")]
+    YamlParse(String, String),
+    #[error(
+        "git {0} is not set, so atomic can't auto-commit.\nSet it with `git config --global {0} \"{1}\"`, \
+         or add `commit_author = \"Name <email>\"` under [atomic] in atomic.toml."
+    )]
+    MissingGitIdentity(&'static str, &'static str),
+    #[error("Invalid commit_author '{0}': expected \"Name <email>\"")]
+    InvalidCommitAuthor(String),
+    #[error("Invalid [atomic] setting in atomic.toml\n{0}")]
+    InvalidSetting(::toml::de::Error),
+    #[error(
+        "auto-commit stopped: these files are over max_commit_file_size ({0}):\n  {}\n\
+         Track them with Git LFS, add them to .gitignore, or set `large_files = \"skip\"` under \
+         [atomic] to commit everything else.",
+        .1.join("\n  ")
+    )]
+    LargeFiles(String, Vec<String>),
+    #[error("{0}:{1}: {2}")]
+    EnvFile(String, usize, String),
+    #[error(
+        "couldn't sign the auto-commit, which commit.gpgsign asks for: {0}\n\
+         Fix the signing setup, or pass `--no-sign` to commit unsigned."
+    )]
+    Signing(String),
+    #[error("'{0}' aborted by user")]
+    Aborted(String),
+    #[error("Chain step '{0}' must name a single command, not a chain")]
+    NestedChain(String),
+    #[error("`needs` forms a cycle: {}", .0.join(" -> "))]
+    NeedsCycle(Vec<String>),
+    #[error(
+        "'{0}' needs '{1}', which is not defined in atomic.toml.{}",
+        util::did_you_mean(.2)
+    )]
+    UnknownNeed(String, String, Vec<String>),
+    #[error("'{0}' needs confirmation, which CI mode never asks for. Pass --yes to run it.")]
+    ConfirmInCi(String),
+    #[error(
+        "'{0}' is already running{}; try again once it finishes",
+        .1.as_ref().map_or(String::new(), |holder| format!(" ({})", holder))
+    )]
+    Locked(String, Option<String>),
+    #[error(
+        "atomic is running {0} levels deep inside its own commands; stopping in case they loop"
+    )]
+    TooDeep(u32),
+    #[error("nothing to run again: no command has been run in this project yet")]
+    NoLastRun,
+    #[error(
+        ".gitignore has a '{}' line without a '{}' after it",
+        gitignore::BEGIN,
+        gitignore::END
+    )]
+    UnclosedGitignoreBlock,
+    #[error("atomic.toml is invalid:\n  {}", .0.join("\n  "))]
+    Validation(Vec<String>),
+
     #[error(transparent)]
     ParseInt(#[from] std::num::ParseIntError),
 
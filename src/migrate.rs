@@ -0,0 +1,403 @@
+//! `atomic --migrate-config`: upgrades an atomic.toml written for an older atomic to the form the
+//! current one recommends.
+//!
+//! [`MIGRATIONS`] is kept in order; a change to the format appends one to it. Each migration
+//! edits the `toml_edit` document in place, so comments and layout survive, and describes what
+//! it changed.
+
+use std::fmt;
+use std::path::Path;
+
+use git2::Patch;
+use toml_edit::{DocumentMut, InlineTable, Item, Key, Table, TableLike, Value};
+
+use crate::schema::{COMMAND_TABLE_RULES, PROFILE_SECTION, SECTIONS, SETTINGS_RULES};
+use crate::share::parse_document;
+use crate::{AtomicError, Result};
+
+/// One step from an older format to a newer one.
+pub struct Migration {
+    /// Its place in [`MIGRATIONS`], counting from 1.
+    pub version: u32,
+    pub name: &'static str,
+    /// Edits the document, returning a line for each change made or thing left alone.
+    pub apply: fn(&mut DocumentMut) -> Vec<String>,
+}
+
+/// Every migration, oldest first.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "top-level commands",
+        apply: move_root_commands,
+    },
+    Migration {
+        version: 2,
+        name: "key spellings",
+        apply: snake_case_keys,
+    },
+    Migration {
+        version: 3,
+        name: "desc stubs",
+        apply: add_desc_stubs,
+    },
+];
+
+/// Something a migration changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub version: u32,
+    pub migration: &'static str,
+    pub description: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}. {}: {}",
+            self.version, self.migration, self.description
+        )
+    }
+}
+
+/// `text` with every migration applied, and what each did. It comes back unchanged when it's
+/// already current, though there may still be notes on what was left alone.
+///
+/// # Errors
+/// Returns an error if `text` isn't valid TOML.
+pub fn migrate(text: &str) -> Result<(String, Vec<Change>)> {
+    let mut doc = parse_document("atomic.toml", text)?;
+    let mut changes = Vec::new();
+    for migration in MIGRATIONS {
+        changes.extend(
+            (migration.apply)(&mut doc)
+                .into_iter()
+                .map(|description| Change {
+                    version: migration.version,
+                    migration: migration.name,
+                    description,
+                }),
+        );
+    }
+    Ok((doc.to_string(), changes))
+}
+
+/// A unified diff from `old` to `new`, both called `name`.
+///
+/// # Errors
+/// Returns an error if git can't compute the diff.
+pub fn diff(old: &str, new: &str, name: &str) -> Result<String> {
+    let name = Path::new(name);
+    let mut patch =
+        Patch::from_buffers(old.as_bytes(), Some(name), new.as_bytes(), Some(name), None)?;
+    let buf = patch.to_buf()?;
+    buf.as_str()
+        .map(str::to_string)
+        .ok_or(AtomicError::Static("the diff isn't valid UTF-8"))
+}
+
+/// Commands written above every section move into `[default]`, which is where `atomic --init`
+/// puts them.
+fn move_root_commands(doc: &mut DocumentMut) -> Vec<String> {
+    let sections: Vec<&str> = SECTIONS
+        .iter()
+        .map(|section| section.name)
+        .chain([PROFILE_SECTION])
+        .collect();
+    let root: Vec<String> = doc
+        .iter()
+        .filter(|(key, item)| item.is_value() && !sections.contains(key))
+        .map(|(key, _)| key.to_string())
+        .collect();
+    if root.is_empty() {
+        return Vec::new();
+    }
+
+    let mut changes = Vec::new();
+    let mut moved = Vec::new();
+    for name in root {
+        let taken = doc
+            .get("default")
+            .and_then(Item::as_table_like)
+            .is_some_and(|default| default.contains_key(&name));
+        if taken {
+            changes.push(format!(
+                "kept top-level `{}`: [default] already has a command with that name",
+                name
+            ));
+            continue;
+        }
+        if let Some(entry) = doc.as_table_mut().remove_entry(&name) {
+            moved.push(entry);
+        }
+    }
+    if moved.is_empty() {
+        return changes;
+    }
+
+    // comments above the first command that are separated from it by a blank line head the
+    // file rather than the command, so they stay at the top
+    let header = split_header(&mut moved[0].0);
+    if !doc.contains_key("default") {
+        let mut default = Table::new();
+        if !doc.is_empty() {
+            default.decor_mut().set_prefix("\n");
+        }
+        doc.insert("default", Item::Table(default));
+    }
+    if let Some(header) = header {
+        let first = doc
+            .iter_mut()
+            .filter_map(|(_, item)| item.as_table_mut())
+            .min_by_key(|table| table.position().unwrap_or(usize::MAX));
+        if let Some(first) = first {
+            let prefix = first
+                .decor()
+                .prefix()
+                .and_then(|p| p.as_str())
+                .unwrap_or("");
+            let prefix = format!(
+                "{}\n\n{}",
+                header.trim_end_matches('\n'),
+                prefix.trim_start_matches('\n')
+            );
+            first.decor_mut().set_prefix(prefix);
+        }
+    }
+    let Some(default) = doc.get_mut("default").and_then(Item::as_table_mut) else {
+        return changes;
+    };
+    for (key, item) in moved {
+        changes.push(format!("moved top-level `{}` under [default]", key.get()));
+        default.insert_formatted(&key, item);
+    }
+    changes
+}
+
+/// Takes the part of `key`'s leading comments that comes before its last blank line.
+fn split_header(key: &mut Key) -> Option<String> {
+    let prefix = key.leaf_decor().prefix()?.as_str()?.to_string();
+    let at = prefix.rfind("\n\n")? + 2;
+    let mut decor = key.leaf_decor().clone();
+    decor.set_prefix(prefix[at..].to_string());
+    *key = key.clone().with_leaf_decor(decor);
+    Some(prefix[..at].to_string())
+}
+
+/// `retry-delay` and other kebab-case spellings of `[atomic]` settings and command table keys
+/// become the snake_case ones atomic reads.
+fn snake_case_keys(doc: &mut DocumentMut) -> Vec<String> {
+    let mut changes = Vec::new();
+    if let Some(settings) = doc.get_mut("atomic").and_then(Item::as_table_like_mut) {
+        let known: Vec<&str> = SETTINGS_RULES.iter().map(|rule| rule.key).collect();
+        for (old, new) in rename_keys(settings, &known) {
+            changes.push(format!("renamed `{}` to `{}` in [atomic]", old, new));
+        }
+    }
+    let known: Vec<&str> = COMMAND_TABLE_RULES.iter().map(|rule| rule.key).collect();
+    for section in ["default", "custom"] {
+        let Some(commands) = doc.get_mut(section).and_then(Item::as_table_like_mut) else {
+            continue;
+        };
+        for (name, command) in commands.iter_mut() {
+            let Some(table) = command.as_table_like_mut() else {
+                continue;
+            };
+            for (old, new) in rename_keys(table, &known) {
+                changes.push(format!(
+                    "renamed `{}` to `{}` in [{}.{}]",
+                    old,
+                    new,
+                    section,
+                    name.get()
+                ));
+            }
+        }
+    }
+    changes
+}
+
+/// Renames the kebab-case keys of `table` whose snake_case spelling is `known` and not already
+/// there, keeping the order of the keys and their comments. Returns the old and new names.
+fn rename_keys(table: &mut dyn TableLike, known: &[&str]) -> Vec<(String, String)> {
+    let renames: Vec<(String, String)> = table
+        .iter()
+        .map(|(key, _)| (key.to_string(), key.replace('-', "_")))
+        .filter(|(old, new)| {
+            old != new && known.contains(&new.as_str()) && !table.contains_key(new)
+        })
+        .collect();
+    if renames.is_empty() {
+        return renames;
+    }
+
+    let names: Vec<String> = table.iter().map(|(key, _)| key.to_string()).collect();
+    let mut entries = Vec::new();
+    for name in names {
+        let key = table.key(&name).cloned();
+        if let (Some(key), Some(item)) = (key, table.remove(&name)) {
+            entries.push((key, item));
+        }
+    }
+    for (key, item) in entries {
+        let key = match renames.iter().find(|(old, _)| old == key.get()) {
+            Some((_, new)) => Key::new(new.as_str()).with_leaf_decor(key.leaf_decor().clone()),
+            None => key,
+        };
+        table.entry_format(&key).or_insert(item);
+    }
+    renames
+}
+
+/// `[custom]` commands given as a bare string become `{ command = "...", desc = "" }`, so
+/// there's a place to say what they do for `atomic --list`.
+fn add_desc_stubs(doc: &mut DocumentMut) -> Vec<String> {
+    let Some(custom) = doc.get_mut("custom").and_then(Item::as_table_like_mut) else {
+        return Vec::new();
+    };
+    let mut changes = Vec::new();
+    for (name, item) in custom.iter_mut() {
+        let Some(Value::String(command)) = item.as_value() else {
+            continue;
+        };
+        let mut command = command.clone();
+        let decor = std::mem::take(command.decor_mut());
+        let mut table = InlineTable::new();
+        table.insert("command", Value::String(command));
+        table.insert("desc", Value::from(""));
+        table.fmt();
+        *table.decor_mut() = decor;
+        *item = Item::Value(Value::InlineTable(table));
+        changes.push(format!("added a desc stub to [custom] `{}`", name.get()));
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(migration: fn(&mut DocumentMut) -> Vec<String>, text: &str) -> (String, Vec<String>) {
+        let mut doc = parse_document("atomic.toml", text).unwrap();
+        let changes = migration(&mut doc);
+        (doc.to_string(), changes)
+    }
+
+    #[test]
+    fn test_move_root_commands() {
+        let (migrated, changes) = apply(
+            move_root_commands,
+            include_str!("../fixtures/migrate/root_commands.before.toml"),
+        );
+        assert_eq!(
+            migrated,
+            include_str!("../fixtures/migrate/root_commands.after.toml")
+        );
+        assert_eq!(
+            changes,
+            [
+                "kept top-level `build`: [default] already has a command with that name",
+                "moved top-level `lint` under [default]",
+                "moved top-level `ci` under [default]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_move_root_commands_without_default() {
+        let (migrated, changes) = apply(move_root_commands, "fmt = \"cargo fmt\"\n");
+        assert_eq!(migrated, "[default]\nfmt = \"cargo fmt\"\n");
+        assert_eq!(changes, ["moved top-level `fmt` under [default]"]);
+
+        let (migrated, changes) = apply(
+            move_root_commands,
+            "# my commands\n\n# formats\nfmt = \"cargo fmt\"\n\n[atomic]\ngit = false\n",
+        );
+        assert_eq!(
+            migrated,
+            "# my commands\n\n[atomic]\ngit = false\n\n[default]\n# formats\nfmt = \"cargo fmt\"\n"
+        );
+        assert_eq!(changes, ["moved top-level `fmt` under [default]"]);
+    }
+
+    #[test]
+    fn test_snake_case_keys() {
+        let (migrated, changes) = apply(
+            snake_case_keys,
+            include_str!("../fixtures/migrate/key_spellings.before.toml"),
+        );
+        assert_eq!(
+            migrated,
+            include_str!("../fixtures/migrate/key_spellings.after.toml")
+        );
+        assert_eq!(
+            changes,
+            [
+                "renamed `auto-commit` to `auto_commit` in [atomic]",
+                "renamed `run-git-hooks` to `run_git_hooks` in [atomic]",
+                "renamed `retry-delay` to `retry_delay` in [custom.deploy]",
+                "renamed `capture-output` to `capture_output` in [custom.notes]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_desc_stubs() {
+        let (migrated, changes) = apply(
+            add_desc_stubs,
+            include_str!("../fixtures/migrate/desc_stubs.before.toml"),
+        );
+        assert_eq!(
+            migrated,
+            include_str!("../fixtures/migrate/desc_stubs.after.toml")
+        );
+        assert_eq!(
+            changes,
+            [
+                "added a desc stub to [custom] `check`",
+                "added a desc stub to [custom] `doc`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_migrate_is_a_no_op_when_current() {
+        let current = include_str!("../fixtures/migrate/desc_stubs.after.toml");
+        let (migrated, changes) = migrate(current).unwrap();
+        assert_eq!(migrated, current);
+        assert!(changes.is_empty());
+
+        let (migrated, changes) = migrate("fmt = \"cargo fmt\"\n").unwrap();
+        assert_eq!(
+            changes.iter().map(Change::to_string).collect::<Vec<_>>(),
+            ["1. top-level commands: moved top-level `fmt` under [default]"]
+        );
+        // a second run finds nothing left to do
+        assert!(migrate(&migrated).unwrap().1.is_empty());
+
+        // what's left alone is still mentioned, without changing anything
+        let kept = include_str!("../fixtures/migrate/root_commands.after.toml");
+        let (migrated, changes) = migrate(kept).unwrap();
+        assert_eq!(migrated, kept);
+        assert_eq!(changes.len(), 1);
+    }
+
+    #[test]
+    fn test_versions_are_in_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, i + 1, "{}", migration.name);
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let diff = diff("a = 1\nb = 2\n", "a = 1\nb = 3\n", "atomic.toml").unwrap();
+        assert!(
+            diff.contains("--- a/atomic.toml\n+++ b/atomic.toml\n"),
+            "{}",
+            diff
+        );
+        assert!(diff.contains("-b = 2\n+b = 3\n"), "{}", diff);
+    }
+}
@@ -0,0 +1,77 @@
+//! Notices atomic being started by a command that another atomic is running.
+//!
+//! Such a nested run still runs its command, but leaves the auto-commit and the `[atomic]` hooks
+//! to the outer one unless `--allow-nested-commit` is given.
+
+use std::process;
+
+use crate::{AtomicError, Result};
+
+/// Set on every command atomic runs, to the process id of that atomic.
+pub const ACTIVE_VAR: &str = "ATOMIC_ACTIVE";
+/// Set on every command atomic runs, to how many atomics it is running under.
+pub const DEPTH_VAR: &str = "ATOMIC_DEPTH";
+/// Nesting this deep is taken for a loop, and refused.
+pub const MAX_DEPTH: u32 = 5;
+
+/// How many atomics this one is running under.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Nesting {
+    pub depth: u32,
+}
+
+impl Nesting {
+    /// Reads the depth the outer atomic left in the environment; `var` looks up variables.
+    ///
+    /// # Errors
+    /// Returns an error once the depth reaches [`MAX_DEPTH`].
+    pub fn detect(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let depth = match var(DEPTH_VAR) {
+            Some(depth) => depth.trim().parse().unwrap_or(1),
+            None if var(ACTIVE_VAR).is_some() => 1,
+            None => 0,
+        };
+        if depth >= MAX_DEPTH {
+            return Err(AtomicError::TooDeep(depth));
+        }
+        Ok(Self { depth })
+    }
+
+    pub fn is_nested(self) -> bool {
+        self.depth > 0
+    }
+
+    /// The variables that tell an atomic started by one of our commands that it is nested.
+    pub fn child_env(self) -> [(String, String); 2] {
+        [
+            (ACTIVE_VAR.to_string(), process::id().to_string()),
+            (DEPTH_VAR.to_string(), (self.depth + 1).to_string()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::env;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Nesting::detect(env(&[])), Ok(Nesting { depth: 0 }));
+        assert_eq!(
+            Nesting::detect(env(&[("ATOMIC_ACTIVE", "42")])),
+            Ok(Nesting { depth: 1 })
+        );
+        assert_eq!(
+            Nesting::detect(env(&[("ATOMIC_ACTIVE", "42"), ("ATOMIC_DEPTH", "3")])),
+            Ok(Nesting { depth: 3 })
+        );
+        assert!(Nesting::detect(env(&[("ATOMIC_DEPTH", "4")])).is_ok());
+        assert!(Nesting::detect(env(&[("ATOMIC_DEPTH", "5")])).is_err());
+        assert!(!Nesting::default().is_nested());
+
+        let [(_, pid), (_, depth)] = Nesting { depth: 2 }.child_env();
+        assert_eq!(pid, process::id().to_string());
+        assert_eq!(depth, "3");
+    }
+}
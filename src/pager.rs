@@ -0,0 +1,200 @@
+//! Paging the long output of `--list`, `--explain`, `--log`, `--diff` and `--snapshots` when it
+//! doesn't fit on the terminal, through `$PAGER` or `less -RFX` (`more` on Windows).
+//!
+//! `--no-pager`, `NO_PAGER` or `PAGER=cat` print it as it is, as does output that isn't going to
+//! a terminal.
+
+use std::{
+    cell::Cell,
+    env,
+    io::{self, ErrorKind, IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+/// The pager used when `PAGER` isn't set.
+const DEFAULT_PAGER: &str = if cfg!(windows) { "more" } else { "less -RFX" };
+
+/// The terminal height assumed when it can't be found out.
+const DEFAULT_HEIGHT: usize = 24;
+
+thread_local! {
+    static DISABLED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// `--no-pager`: print everything as it is.
+pub fn set_disabled(disabled: bool) {
+    DISABLED.with(|cell| cell.set(disabled));
+}
+
+/// Whether `lines` lines of output go through a pager: only on a `terminal` of `height` (or
+/// [`DEFAULT_HEIGHT`]) they don't fit on, with a line left for the prompt, and unless
+/// `--no-pager` or a non-empty `NO_PAGER` says otherwise. `var` looks up environment variables.
+pub fn should_page(
+    lines: usize,
+    terminal: bool,
+    height: Option<usize>,
+    no_pager: bool,
+    var: impl Fn(&str) -> Option<String>,
+) -> bool {
+    if no_pager || !terminal || var("NO_PAGER").is_some_and(|value| !value.is_empty()) {
+        return false;
+    }
+    lines >= height.unwrap_or(DEFAULT_HEIGHT)
+}
+
+/// The program and arguments of `PAGER`, or of [`DEFAULT_PAGER`] when it's unset. `None` when
+/// it's set empty or to `cat`, which mean not to page.
+pub fn pager_command(var: impl Fn(&str) -> Option<String>) -> Option<Vec<String>> {
+    let pager = var("PAGER").unwrap_or_else(|| DEFAULT_PAGER.to_string());
+    let words = shell_words::split(&pager).ok()?;
+    match words.first().map(String::as_str) {
+        None | Some("cat") => None,
+        Some(_) => Some(words),
+    }
+}
+
+/// The height of the terminal: `LINES`, or what `stty size` says of the controlling terminal.
+fn terminal_height() -> Option<usize> {
+    if let Some(lines) = env::var("LINES").ok().and_then(|lines| lines.parse().ok()) {
+        return Some(lines);
+    }
+    #[cfg(unix)]
+    {
+        let tty = std::fs::File::open("/dev/tty").ok()?;
+        let output = Command::new("stty")
+            .arg("size")
+            .stdin(tty)
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// Prints `text` to stdout, through the pager when [`should_page`] says so. A pager that can't be
+/// started means printing it directly; a reader that goes away early, like a pager quit before
+/// the end or `| head`, isn't an error.
+pub fn print_paged(text: &str) {
+    let var = |key: &str| env::var(key).ok();
+    let page = should_page(
+        text.lines().count(),
+        io::stdout().is_terminal(),
+        terminal_height(),
+        DISABLED.with(Cell::get),
+        var,
+    );
+    if page {
+        if let Some(pager) = pager_command(var) {
+            if page_through(&pager, text) {
+                return;
+            }
+        }
+    }
+    if let Err(err) = write_all(&mut io::stdout(), text) {
+        eprintln!("Error writing output: {}", err);
+    }
+}
+
+/// Writes `text` to `out`, taking a closed pipe as the reader having seen enough.
+fn write_all(out: &mut impl Write, text: &str) -> io::Result<()> {
+    match out.write_all(text.as_bytes()).and_then(|_| out.flush()) {
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+/// Runs `pager` with `text` on its stdin and waits for it to quit. Returns whether it started.
+fn page_through(pager: &[String], text: &str) -> bool {
+    let mut command = Command::new(&pager[0]);
+    command.args(&pager[1..]).stdin(Stdio::piped());
+    // as git does, so a bare `PAGER=less` quits on short output and keeps colours
+    if env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    let Ok(mut child) = command.spawn() else {
+        return false;
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = write_all(&mut stdin, text);
+    }
+    let _ = child.wait();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::env;
+
+    #[test]
+    fn test_should_page() {
+        assert!(should_page(50, true, Some(40), false, env(&[])));
+        assert!(should_page(40, true, Some(40), false, env(&[])));
+        assert!(!should_page(39, true, Some(40), false, env(&[])));
+        // unknown height
+        assert!(should_page(30, true, None, false, env(&[])));
+        assert!(!should_page(10, true, None, false, env(&[])));
+        // not a terminal, --no-pager, NO_PAGER
+        assert!(!should_page(500, false, Some(40), false, env(&[])));
+        assert!(!should_page(500, true, Some(40), true, env(&[])));
+        assert!(!should_page(
+            500,
+            true,
+            Some(40),
+            false,
+            env(&[("NO_PAGER", "1")])
+        ));
+        assert!(should_page(
+            500,
+            true,
+            Some(40),
+            false,
+            env(&[("NO_PAGER", "")])
+        ));
+    }
+
+    #[test]
+    fn test_pager_command() {
+        let default = pager_command(env(&[])).unwrap();
+        assert_eq!(default[0], if cfg!(windows) { "more" } else { "less" });
+        assert_eq!(
+            pager_command(env(&[("PAGER", "less -S '+G'")])).unwrap(),
+            ["less", "-S", "+G"]
+        );
+        assert_eq!(pager_command(env(&[("PAGER", "")])), None);
+        assert_eq!(pager_command(env(&[("PAGER", "cat")])), None);
+        assert_eq!(pager_command(env(&[("PAGER", "less 'unclosed")])), None);
+    }
+
+    #[test]
+    fn test_write_all_ignores_broken_pipe() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        assert!(write_all(&mut Closed, "text").is_ok());
+        let mut out = Vec::new();
+        write_all(&mut out, "text").unwrap();
+        assert_eq!(out, b"text");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pager_quitting_early() {
+        // `true` exits without reading a line of it
+        let text = "line\n".repeat(200_000);
+        assert!(page_through(&["true".to_string()], &text));
+        assert!(!page_through(&["atomic-no-such-pager".to_string()], &text));
+    }
+}
@@ -0,0 +1,745 @@
+//! What running a command would do, worked out without running anything.
+//!
+//! `atomic --explain <cmd>` renders a [`ResolvedPlan`] as an annotated tree, and `--dry-run` as
+//! the list of shell commands it would run, so both describe the same resolution.
+
+use std::path::{Path, PathBuf};
+
+use crate::clean_env;
+use crate::command::{
+    atomic_env, auto_commit_message, command_not_found, env_root, git_on, matrix_combinations,
+    no_commit_reason, plan_matrix, plan_step, resolve_needs, skips_hooks, PlannedStep, RetryPolicy,
+    RunOptions,
+};
+use crate::config::{
+    section_label, AtomicConfig, ChainStep, CommandSpec, ConfirmSpec, Steps, TmpDirMode,
+};
+use crate::envfile;
+use crate::toml::{apply_profile, load_layered, Provenance};
+use crate::Result;
+
+/// The flag of a chain step whose failure doesn't stop the chain.
+const IGNORE_FAILURE: &str = "ignore failure";
+
+/// Everything a run of one command would do, in order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedPlan {
+    pub command: String,
+    pub profile: Option<String>,
+    /// Where the steps run: the directory holding atomic.toml.
+    pub cwd: PathBuf,
+    /// The variables exported to every step; see [`atomic_env`]. Those from env files show
+    /// [`envfile::HIDDEN`] instead of their value.
+    pub env: Vec<(String, String)>,
+    /// Whether the command sets `clean_env`, so `env` starts with the variables it keeps and
+    /// nothing else is passed on.
+    pub clean_env: bool,
+    /// `pre_run`, what the command needs, the command itself, then `post_run`.
+    pub nodes: Vec<PlanNode>,
+    /// Why the run wouldn't auto-commit, or `None` when it would.
+    pub no_commit: Option<&'static str>,
+    /// The auto-commit's subject; see [`auto_commit_message`].
+    pub commit_message: String,
+    /// The command's `commit_branch`, if it sets one.
+    pub commit_branch: Option<String>,
+}
+
+/// One command, hook or chain step of a [`ResolvedPlan`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanNode {
+    /// The command name, hook key, or chain step as written.
+    pub name: String,
+    pub kind: NodeKind,
+    /// Where a named command was found: its section, and its file when configs are layered.
+    pub found_in: Option<(&'static str, Option<PathBuf>)>,
+    /// Settings that change how it runs, such as `retries: 2` or `container: rust:1`.
+    pub flags: Vec<String>,
+    pub action: Action,
+}
+
+/// Why a [`PlanNode`] is part of the plan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    /// `[atomic] pre_run` or `post_run`.
+    Hook,
+    /// A command the requested one `needs`.
+    Need,
+    /// The requested command.
+    Command,
+    /// A step of a chain, at this position counting from 1.
+    Step(usize),
+}
+
+/// What a [`PlanNode`] would do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Runs this shell command.
+    Shell(String),
+    /// Runs these chain steps in order.
+    Chain(Vec<PlanNode>),
+    /// Nothing: it only runs on these platforms.
+    Skipped(String),
+    /// Fails with this message before running anything.
+    Error(String),
+}
+
+/// Works out what `atomic <cmd>` would do with `options`, without running anything.
+///
+/// # Errors
+/// Returns an error if atomic.toml can't be loaded, or doesn't define `cmd`; the latter suggests
+/// close matches.
+pub fn resolve(cmd: &str, atomic: &Path, options: &RunOptions) -> Result<ResolvedPlan> {
+    let (value, provenance) = load_layered(atomic, true)?;
+    let config = AtomicConfig::from_value(apply_profile(value, options.profile.as_deref())?)?;
+    let Some(spec) = config.command(cmd) else {
+        return Err(command_not_found(cmd, &config));
+    };
+    let planner = Planner {
+        config: &config,
+        provenance: &provenance,
+    };
+
+    let hooks = !skips_hooks(options);
+    let hook = |key: &str, steps: &Option<Steps>| {
+        let steps = steps.as_ref().filter(|_| hooks)?;
+        Some(PlanNode {
+            name: key.to_string(),
+            kind: NodeKind::Hook,
+            found_in: None,
+            flags: Vec::new(),
+            action: planner.action(steps, &RetryPolicy::default()),
+        })
+    };
+
+    let mut nodes = Vec::new();
+    nodes.extend(hook("pre_run", &config.atomic.pre_run));
+    match resolve_needs(&config, cmd) {
+        Ok(needs) => nodes.extend(
+            needs
+                .iter()
+                .map(|(name, spec)| planner.command(name, spec, NodeKind::Need)),
+        ),
+        Err(err) => nodes.push(PlanNode {
+            name: "needs".to_string(),
+            kind: NodeKind::Need,
+            found_in: None,
+            flags: Vec::new(),
+            action: Action::Error(err.to_string()),
+        }),
+    }
+    nodes.push(planner.command(cmd, spec, NodeKind::Command));
+    nodes.extend(hook("post_run", &config.atomic.post_run));
+
+    let git = git_on(&config.atomic, options);
+    let atomic_vars = atomic_env(atomic, options.profile.as_deref(), options.nesting, git);
+    let cwd = env_root(&atomic_vars);
+    let clean_env = spec.clean_env();
+    let mut env = match clean_env {
+        true => clean_env::inherited(spec.env_allow()),
+        false => Vec::new(),
+    };
+    // values from env files may be secrets, so only their names are shown
+    env.extend(
+        envfile::load(&cwd, config.atomic.env_files.as_deref())?
+            .into_iter()
+            .map(|(key, _)| (key, envfile::HIDDEN.to_string())),
+    );
+    env.extend(atomic_vars);
+    Ok(ResolvedPlan {
+        command: cmd.to_string(),
+        profile: options.profile.clone(),
+        cwd,
+        env,
+        clean_env,
+        nodes,
+        no_commit: no_commit_reason(&config.atomic, options),
+        commit_message: auto_commit_message(cmd, spec),
+        commit_branch: spec.commit_branch().map(str::to_string),
+    })
+}
+
+/// Builds the [`PlanNode`]s of one config.
+struct Planner<'a> {
+    config: &'a AtomicConfig,
+    provenance: &'a Provenance,
+}
+
+impl Planner<'_> {
+    /// The node for the command `name`.
+    fn command(&self, name: &str, spec: &CommandSpec, kind: NodeKind) -> PlanNode {
+        let mut flags: Vec<String> = self.shadows(name).into_iter().collect();
+        let mut policy = Ok(RetryPolicy::default());
+        if let Some(detail) = spec.detail() {
+            policy = RetryPolicy::from_command(detail);
+            if let Ok(policy) = &policy {
+                flags.extend(retries(policy));
+            }
+            flags.extend(
+                detail
+                    .container
+                    .as_ref()
+                    .map(|image| format!("container: {}", image)),
+            );
+            match &detail.confirm {
+                Some(ConfirmSpec::Flag(true)) => flags.push("confirm".to_string()),
+                Some(ConfirmSpec::Prompt(_)) => flags.push("confirm: type the name".to_string()),
+                _ => {}
+            }
+            if spec.captures_output() {
+                flags.push("capture_output".to_string());
+            }
+            if spec.cargo_json() {
+                flags.push("cargo_json".to_string());
+            }
+            flags.extend(spec.env_file().map(|file| format!("env_file: {}", file)));
+            match spec.env_allow() {
+                _ if !spec.clean_env() => {}
+                [] => flags.push("clean_env".to_string()),
+                allowed => flags.push(format!("clean_env, allowing {}", allowed.join(", "))),
+            }
+            match spec.tmpdir() {
+                TmpDirMode::None => {}
+                TmpDirMode::PerStep => flags.push("tmpdir".to_string()),
+                TmpDirMode::Shared => flags.push("tmpdir: shared".to_string()),
+            }
+            if detail.lock == Some(true) {
+                flags.push("lock".to_string());
+            }
+            flags.extend(detail.commit.map(|commit| format!("commit: {}", commit)));
+            if !detail.needs.is_empty() {
+                flags.push(format!("needs: {}", detail.needs.join(", ")));
+            }
+            if let Ok(combinations) = spec.matrix().map(matrix_combinations).transpose() {
+                flags.extend(combinations.map(|c| format!("matrix: {} combinations", c.len())));
+            }
+            if spec.fail_fast() {
+                flags.push("fail_fast".to_string());
+            }
+            if let Some(inputs) = spec.inputs() {
+                flags.push(format!("inputs: {}", inputs.join(", ")));
+            }
+        }
+        if let Some(os) = spec.os().filter(|_| spec.runs_here()) {
+            flags.push(format!("os: {}", os.describe()));
+        }
+
+        let action = match (policy, spec.os()) {
+            (_, Some(os)) if !spec.runs_here() => Action::Skipped(os.describe()),
+            (Ok(policy), _) => match spec.matrix() {
+                Some(matrix) => match plan_matrix(self.config, name, spec, matrix, &policy) {
+                    Ok(steps) => Action::Chain(
+                        steps
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, step)| self.planned(i + 1, step))
+                            .collect(),
+                    ),
+                    Err(err) => Action::Error(err.to_string()),
+                },
+                None => self.action(spec.steps(), &policy),
+            },
+            (Err(err), _) => Action::Error(err.to_string()),
+        };
+        PlanNode {
+            name: name.to_string(),
+            kind,
+            found_in: self.found_in(name),
+            flags,
+            action,
+        }
+    }
+
+    /// What `steps` run with `policy`, with each step of a chain resolved.
+    fn action(&self, steps: &Steps, policy: &RetryPolicy) -> Action {
+        match steps {
+            Steps::Shell(cmd) => Action::Shell(cmd.clone()),
+            Steps::Chain(chain) => Action::Chain(
+                chain
+                    .iter()
+                    .enumerate()
+                    .map(|(i, chain_step)| self.step(i + 1, chain_step, policy))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The node for a chain step, resolved the way [`plan_step`] resolves it for a run.
+    fn step(&self, position: usize, chain_step: &ChainStep, policy: &RetryPolicy) -> PlanNode {
+        let kind = NodeKind::Step(position);
+        let step = match plan_step(self.config, chain_step, policy) {
+            Ok(step) => step,
+            Err(err) => {
+                let (name, _) = chain_step.resolve(self.config);
+                return PlanNode {
+                    name: name.to_string(),
+                    kind,
+                    found_in: self.found_in(name),
+                    flags: Vec::new(),
+                    action: Action::Error(err.to_string()),
+                };
+            }
+        };
+        self.planned(position, step)
+    }
+
+    /// The node for a resolved step of a chain or matrix.
+    fn planned(&self, position: usize, step: PlannedStep) -> PlanNode {
+        let kind = NodeKind::Step(position);
+        let mut flags: Vec<String> = step.matrix.iter().cloned().collect();
+        if step.ignore_failure {
+            flags.push(IGNORE_FAILURE.to_string());
+        }
+        if step.named {
+            flags.extend(self.shadows(&step.name));
+            flags.extend(retries(&step.policy));
+            flags.extend(step.container.map(|image| format!("container: {}", image)));
+            if step.capture_output {
+                flags.push("capture_output".to_string());
+            }
+            if step.cargo_json {
+                flags.push("cargo_json".to_string());
+            }
+            flags.extend(step.env_file.map(|file| format!("env_file: {}", file)));
+            if step.tmpdir {
+                flags.push("tmpdir".to_string());
+            }
+        }
+        PlanNode {
+            found_in: self.found_in(&step.name).filter(|_| step.named),
+            action: match step.only_on {
+                Some(platforms) => Action::Skipped(platforms),
+                None => Action::Shell(step.command),
+            },
+            name: step.name,
+            kind,
+            flags,
+        }
+    }
+
+    /// `shadows [default]`, when other sections define the unqualified `name` too.
+    fn shadows(&self, name: &str) -> Option<String> {
+        let shadowed = self.config.shadowed(name);
+        let labels: Vec<String> = shadowed.iter().map(|s| section_label(s)).collect();
+        (!labels.is_empty()).then(|| format!("shadows {}", labels.join(" and ")))
+    }
+
+    fn found_in(&self, name: &str) -> Option<(&'static str, Option<PathBuf>)> {
+        let section = self.config.section_of(name)?;
+        let file = self
+            .provenance
+            .command(name)
+            .filter(|origin| self.provenance.files.len() > 1 || self.provenance.is_global(origin))
+            .map(|origin| origin.file.clone());
+        Some((section, file))
+    }
+}
+
+/// `retries: 2, 1s apart`, for a policy that retries at all.
+fn retries(policy: &RetryPolicy) -> Option<String> {
+    let delay = policy.delay.as_millis();
+    let delay = match delay % 1000 {
+        0 => format!("{}s", delay / 1000),
+        _ => format!("{}ms", delay),
+    };
+    (policy.retries > 0).then(|| format!("retries: {}, {} apart", policy.retries, delay))
+}
+
+impl ResolvedPlan {
+    /// Whether every step would at least start: nothing in the plan is an [`Action::Error`].
+    pub fn is_runnable(&self) -> bool {
+        fn runnable(node: &PlanNode) -> bool {
+            match &node.action {
+                Action::Error(_) => false,
+                Action::Chain(steps) => steps.iter().all(runnable),
+                Action::Shell(_) | Action::Skipped(_) => true,
+            }
+        }
+        self.nodes.iter().all(runnable)
+    }
+
+    /// The tree printed by `atomic --explain`.
+    pub fn render_tree(&self) -> String {
+        let mut root = Branch::new(match &self.profile {
+            Some(profile) => format!("atomic {}  (profile {})", self.command, profile),
+            None => format!("atomic {}", self.command),
+        });
+        root.children.extend(self.nodes.iter().map(node_branch));
+        root.children
+            .push(Branch::new(format!("cwd: {}", self.cwd.display())));
+        let mut env = Branch::new(match self.clean_env {
+            true => "env  (clean_env: nothing else is passed on)".to_string(),
+            false => "env".to_string(),
+        });
+        env.children.extend(
+            self.env
+                .iter()
+                .map(|(key, value)| Branch::new(format!("{}={}", key, value))),
+        );
+        root.children.push(env);
+        root.children.push(Branch::new(self.commit_line()));
+
+        let mut out = format!("{}\n", root.text);
+        draw(&root.children, "", &mut out);
+        out
+    }
+
+    /// The list printed by `atomic --dry-run`: one line per shell command, in the order they'd
+    /// run, then whether the run would auto-commit.
+    pub fn render_steps(&self) -> String {
+        let mut out = String::new();
+        for node in &self.nodes {
+            let label = match node.kind {
+                NodeKind::Need => format!("{} (needed)", node.name),
+                _ => node.name.clone(),
+            };
+            steps_lines(&label, &node.action, &mut out);
+        }
+        if self.clean_env {
+            let names: Vec<&str> = self.env.iter().map(|(key, _)| key.as_str()).collect();
+            out.push_str(&format!("env: only {}\n", names.join(", ")));
+        }
+        out.push_str(&self.commit_line());
+        out.push('\n');
+        out
+    }
+
+    fn commit_line(&self) -> String {
+        match self.no_commit {
+            None => match &self.commit_branch {
+                Some(branch) => format!("auto-commit: \"{}\" onto {}", self.commit_message, branch),
+                None => format!("auto-commit: \"{}\"", self.commit_message),
+            },
+            Some(reason) => format!("auto-commit: off ({})", reason),
+        }
+    }
+}
+
+/// A line of the `--explain` tree and the lines under it.
+struct Branch {
+    text: String,
+    children: Vec<Branch>,
+}
+
+impl Branch {
+    fn new(text: String) -> Self {
+        Self {
+            text,
+            children: Vec::new(),
+        }
+    }
+}
+
+/// The `--explain` branch for `node`.
+fn node_branch(node: &PlanNode) -> Branch {
+    // a chain step that isn't a command name runs as written, so it fits on one line
+    if let (NodeKind::Step(position), None, Action::Shell(cmd)) =
+        (node.kind, &node.found_in, &node.action)
+    {
+        let mut text = format!("{}. $ {}", position, cmd);
+        push_flags(&mut text, &node.flags);
+        return Branch::new(text);
+    }
+
+    let mut text = match node.kind {
+        NodeKind::Hook => format!("{}  [atomic]", node.name),
+        NodeKind::Need => format!("needs {}", node.name),
+        NodeKind::Command => node.name.clone(),
+        NodeKind::Step(position) => format!("{}. {}", position, node.name),
+    };
+    if let Some((section, file)) = &node.found_in {
+        match *section {
+            "root" => text.push_str("  top level"),
+            section => text.push_str(&format!("  [{}]", section)),
+        }
+        if let Some(file) = file {
+            text.push_str(&format!(" in {}", file.display()));
+        }
+    }
+    push_flags(&mut text, &node.flags);
+
+    let mut branch = Branch::new(text);
+    match &node.action {
+        Action::Shell(cmd) => branch.children.push(Branch::new(format!("$ {}", cmd))),
+        Action::Chain(steps) => branch.children.extend(steps.iter().map(node_branch)),
+        Action::Skipped(platforms) => branch
+            .children
+            .push(Branch::new(format!("skipped: only runs on {}", platforms))),
+        Action::Error(err) => branch.children.push(Branch::new(format!("error: {}", err))),
+    }
+    branch
+}
+
+fn push_flags(text: &mut String, flags: &[String]) {
+    if !flags.is_empty() {
+        text.push_str(&format!("  ({})", flags.join("; ")));
+    }
+}
+
+/// Draws `branches` under a line, each child indented below its parent.
+fn draw(branches: &[Branch], indent: &str, out: &mut String) {
+    for (i, branch) in branches.iter().enumerate() {
+        let last = i + 1 == branches.len();
+        let (joint, below) = if last {
+            ("└─ ", "   ")
+        } else {
+            ("├─ ", "│  ")
+        };
+        out.push_str(&format!("{}{}{}\n", indent, joint, branch.text));
+        draw(&branch.children, &format!("{}{}", indent, below), out);
+    }
+}
+
+/// Adds the `--dry-run` lines for `action`, labelled with where it comes from.
+fn steps_lines(label: &str, action: &Action, out: &mut String) {
+    match action {
+        Action::Shell(cmd) => out.push_str(&format!("{}: {}\n", label, cmd)),
+        Action::Skipped(platforms) => {
+            out.push_str(&format!("{}: skipped, only runs on {}\n", label, platforms))
+        }
+        Action::Error(err) => out.push_str(&format!("{}: error: {}\n", label, err)),
+        Action::Chain(steps) => {
+            for step in steps {
+                let NodeKind::Step(position) = step.kind else {
+                    continue;
+                };
+                let mut label = match &step.found_in {
+                    Some(_) => format!("{} [{}/{}] {}", label, position, steps.len(), step.name),
+                    None => format!("{} [{}/{}]", label, position, steps.len()),
+                };
+                if step.flags.iter().any(|flag| flag == IGNORE_FAILURE) {
+                    label.push_str(&format!(" ({})", IGNORE_FAILURE));
+                }
+                steps_lines(&label, &step.action, out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AtomicError;
+    use std::fs;
+
+    const FIXTURE: &str = r#"
+        lint = "cargo clippy"
+
+        [atomic]
+        pre_run = "cargo fmt --check"
+        post_run = ["./notify.sh", "-lint"]
+
+        [default]
+        build = "cargo build"
+        ci = ["lint", "build"]
+
+        [custom.deploy]
+        command = ["lint", "./scripts/upload.sh", "-docs", "ci", { run = "open-report", ignore_failure = true }]
+        needs = ["build"]
+        retries = 2
+        retry_delay = "1s"
+        container = "rust:1"
+        confirm = true
+        lock = true
+
+        [custom.docs]
+        command = "cargo doc"
+        capture_output = "commit_body"
+
+        [custom.open-report]
+        command = "open report.html"
+        os = "windows"
+
+        [custom.features]
+        command = ["lint", "cargo test --features {flag}"]
+        matrix = { flag = ["foo", "foo,bar"] }
+        fail_fast = true
+    "#;
+
+    fn resolved(cmd: &str, options: &RunOptions) -> (tempfile::TempDir, Result<ResolvedPlan>) {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(&atomic, FIXTURE).unwrap();
+        let plan = resolve(cmd, &atomic, options);
+        (dir, plan)
+    }
+
+    /// `text` with the temporary directory written as `<dir>`, so it can be compared.
+    fn anonymized(dir: &tempfile::TempDir, text: &str) -> String {
+        let dir = fs::canonicalize(dir.path()).unwrap();
+        text.replace(&dir.display().to_string(), "<dir>")
+    }
+
+    #[test]
+    fn test_render_tree() {
+        let options = RunOptions {
+            no_git: true,
+            ..Default::default()
+        };
+        let (dir, plan) = resolved("deploy", &options);
+        let plan = plan.unwrap();
+        assert!(!plan.is_runnable(), "a chain step naming a chain can't run");
+        let expected = "\
+atomic deploy
+├─ pre_run  [atomic]
+│  └─ $ cargo fmt --check
+├─ needs build  [default]
+│  └─ $ cargo build
+├─ deploy  [custom]  (retries: 2, 1s apart; container: rust:1; confirm; lock; needs: build)
+│  ├─ 1. lint  top level  (retries: 2, 1s apart)
+│  │  └─ $ cargo clippy
+│  ├─ 2. $ ./scripts/upload.sh
+│  ├─ 3. docs  [custom]  (ignore failure; capture_output)
+│  │  └─ $ cargo doc
+│  ├─ 4. ci  [default]
+│  │  └─ error: Chain step 'ci' must name a single command, not a chain
+│  └─ 5. open-report  [custom]  (ignore failure)
+│     └─ skipped: only runs on windows
+├─ post_run  [atomic]
+│  ├─ 1. $ ./notify.sh
+│  └─ 2. lint  top level  (ignore failure)
+│     └─ $ cargo clippy
+├─ cwd: <dir>
+├─ env
+│  ├─ ATOMIC_CONFIG_PATH=<dir>/atomic.toml
+│  ├─ ATOMIC_PROJECT_ROOT=<dir>
+│  ├─ ATOMIC_BRANCH=
+│  ├─ ATOMIC_ISSUE=
+│  ├─ ATOMIC_ACTIVE=<pid>
+│  └─ ATOMIC_DEPTH=1
+└─ auto-commit: off (--no-git)
+";
+        let rendered = anonymized(&dir, &plan.render_tree())
+            .replace(&format!("={}\n", std::process::id()), "=<pid>\n");
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_env_file_values_hidden() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(dir.path().join(".env"), "SECRET=hunter2\n").unwrap();
+        fs::write(
+            &atomic,
+            "[custom.seed]\ncommand = \"./seed.sh\"\nenv_file = \".env.seed\"\n",
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_git: true,
+            ..Default::default()
+        };
+
+        let plan = resolve("seed", &atomic, &options).unwrap();
+        assert_eq!(plan.env[0], ("SECRET".to_string(), "<set>".to_string()));
+        let tree = plan.render_tree();
+        assert!(
+            tree.contains("├─ seed  [custom]  (env_file: .env.seed)"),
+            "{}",
+            tree
+        );
+        assert!(tree.contains("SECRET=<set>"), "{}", tree);
+        assert!(!tree.contains("hunter2"), "{}", tree);
+    }
+
+    #[test]
+    fn test_clean_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(
+            &atomic,
+            "[custom.build]\ncommand = \"cargo build\"\nclean_env = true\nenv_allow = [\"CARGO_HOME\"]\n",
+        )
+        .unwrap();
+        let options = RunOptions {
+            no_git: true,
+            ..Default::default()
+        };
+
+        let plan = resolve("build", &atomic, &options).unwrap();
+        assert!(plan.clean_env);
+        assert!(plan.env.iter().any(|(key, _)| key == "PATH"));
+        let tree = plan.render_tree();
+        assert!(
+            tree.contains("├─ build  [custom]  (clean_env, allowing CARGO_HOME)"),
+            "{}",
+            tree
+        );
+        assert!(tree.contains("├─ env  (clean_env: nothing else is passed on)"));
+        let steps = plan.render_steps();
+        let line = steps
+            .lines()
+            .find(|line| line.starts_with("env: only "))
+            .unwrap();
+        assert!(line.contains("PATH, "), "{}", steps);
+        assert!(line.ends_with(", ATOMIC_DEPTH"), "{}", steps);
+    }
+
+    #[test]
+    fn test_render_steps() {
+        let (dir, plan) = resolved("deploy", &RunOptions::default());
+        let plan = plan.unwrap();
+        assert_eq!(
+            anonymized(&dir, &plan.render_steps()),
+            "\
+pre_run: cargo fmt --check
+build (needed): cargo build
+deploy [1/5] lint: cargo clippy
+deploy [2/5]: ./scripts/upload.sh
+deploy [3/5] docs (ignore failure): cargo doc
+deploy [4/5] ci: error: Chain step 'ci' must name a single command, not a chain
+deploy [5/5] open-report (ignore failure): skipped, only runs on windows
+post_run [1/2]: ./notify.sh
+post_run [2/2] lint (ignore failure): cargo clippy
+auto-commit: \"atomic: deploy\"
+"
+        );
+
+        let (_dir, plan) = resolved("lint", &RunOptions::default());
+        assert!(plan.unwrap().is_runnable());
+    }
+
+    #[test]
+    fn test_matrix() {
+        let options = RunOptions {
+            no_git: true,
+            ..Default::default()
+        };
+        let (_dir, plan) = resolved("features", &options);
+        let plan = plan.unwrap();
+        let tree = plan.render_tree();
+        assert!(
+            tree.contains(
+                "\
+├─ features  [custom]  (matrix: 2 combinations; fail_fast)
+│  ├─ 1. lint  top level  (flag=foo)
+│  │  └─ $ cargo clippy
+│  ├─ 2. $ cargo test --features foo  (flag=foo)
+│  ├─ 3. lint  top level  (flag=foo,bar)
+│  │  └─ $ cargo clippy
+│  └─ 4. $ cargo test --features foo,bar  (flag=foo,bar)
+"
+            ),
+            "{}",
+            tree
+        );
+        let steps = plan.render_steps();
+        assert!(
+            steps.contains(
+                "features [1/4] lint: cargo clippy\nfeatures [2/4]: cargo test --features foo\n"
+            ),
+            "{}",
+            steps
+        );
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let (_dir, plan) = resolved("deplyo", &RunOptions::default());
+        assert_eq!(
+            plan,
+            Err(AtomicError::CommandNotFound(
+                "deplyo".to_string(),
+                vec!["deploy".to_string()]
+            ))
+        );
+    }
+}
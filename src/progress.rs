@@ -0,0 +1,159 @@
+//! The elapsed-time ticker shown under a chain step while it runs on a terminal, and the count
+//! of files an auto-commit has staged so far.
+
+use std::{
+    io::{self, IsTerminal, Write},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::ci::Interactivity;
+
+/// How often the ticker redraws.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Redraws `  … 12s` on the current stderr line until dropped, then clears it.
+///
+/// Inert unless stderr is a terminal and atomic isn't in CI mode, so logs never see it.
+pub struct Ticker {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Ticker {
+    pub fn start(interactivity: Interactivity) -> Self {
+        if interactivity.is_ci() || !io::stderr().is_terminal() {
+            return Self {
+                stop: None,
+                thread: None,
+            };
+        }
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let started = Instant::now();
+            let mut width = 0;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(TICK) {
+                let label = elapsed_label(started.elapsed());
+                width = width.max(label.chars().count());
+                eprint!("\r{}", label);
+                let _ = io::stderr().flush();
+            }
+            if width > 0 {
+                eprint!("\r{}\r", " ".repeat(width));
+                let _ = io::stderr().flush();
+            }
+        });
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        // hanging up the channel wakes the thread, which clears its line and exits
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// How long staging runs before [`StageProgress`] shows up, so small commits print nothing.
+const STAGE_DELAY: Duration = Duration::from_secs(1);
+/// How often [`StageProgress`] redraws at most.
+const STAGE_REDRAW: Duration = Duration::from_millis(100);
+
+/// `  staging: 12345 files (3s)` on the current stderr line while an auto-commit stages a large
+/// work tree, cleared when dropped.
+///
+/// Drawn from the staging loop itself rather than a thread, once staging has taken a second, and
+/// only when stderr is a terminal.
+pub struct StageProgress {
+    started: Instant,
+    drawn: Option<Instant>,
+    width: usize,
+    enabled: bool,
+}
+
+impl StageProgress {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            drawn: None,
+            width: 0,
+            enabled: io::stderr().is_terminal(),
+        }
+    }
+
+    /// Notes that `count` files are staged so far.
+    pub fn staged(&mut self, count: usize) {
+        let elapsed = self.started.elapsed();
+        if !self.enabled
+            || elapsed < STAGE_DELAY
+            || self
+                .drawn
+                .is_some_and(|drawn| drawn.elapsed() < STAGE_REDRAW)
+        {
+            return;
+        }
+        let label = stage_label(count, elapsed);
+        self.width = self.width.max(label.chars().count());
+        eprint!("\r{}", label);
+        let _ = io::stderr().flush();
+        self.drawn = Some(Instant::now());
+    }
+}
+
+impl Drop for StageProgress {
+    fn drop(&mut self) {
+        if self.width > 0 {
+            eprint!("\r{}\r", " ".repeat(self.width));
+            let _ = io::stderr().flush();
+        }
+    }
+}
+
+fn stage_label(count: usize, elapsed: Duration) -> String {
+    let files = match count {
+        1 => "file",
+        _ => "files",
+    };
+    format!("  staging: {} {} ({}s)", count, files, elapsed.as_secs())
+}
+
+fn elapsed_label(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    if secs < 60 {
+        format!("  … {}s", secs)
+    } else {
+        format!("  … {}m {:02}s", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elapsed_label() {
+        assert_eq!(elapsed_label(Duration::from_millis(3400)), "  … 3s");
+        assert_eq!(elapsed_label(Duration::from_secs(59)), "  … 59s");
+        assert_eq!(elapsed_label(Duration::from_secs(65)), "  … 1m 05s");
+    }
+
+    #[test]
+    fn test_stage_label() {
+        assert_eq!(
+            stage_label(12345, Duration::from_millis(3400)),
+            "  staging: 12345 files (3s)"
+        );
+        assert_eq!(
+            stage_label(1, Duration::from_secs(1)),
+            "  staging: 1 file (1s)"
+        );
+    }
+}
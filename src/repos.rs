@@ -0,0 +1,374 @@
+//! `--repos <file>` and `--all-repos`: one command run in several repositories, one after the
+//! other or `--parallel-repos N` at a time, then a table of how each went.
+//!
+//! Each repository runs with its own atomic.toml, as `--repo` would. One without the command is
+//! skipped rather than failed, so a list can mix projects that only some commands apply to.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::config::AtomicConfig;
+use crate::stats::format_elapsed;
+use crate::toml::load_toml;
+use crate::{AtomicError, Result};
+
+/// How the command went in one repository.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed,
+    /// The command ran and exited with this code.
+    Failed(i32),
+    /// The command didn't run, for this reason.
+    Skipped(String),
+    /// atomic couldn't run the command there.
+    Error(String),
+}
+
+impl Outcome {
+    pub fn from_result(result: Result<i32>) -> Self {
+        match result {
+            Ok(0) => Outcome::Passed,
+            Ok(code) => Outcome::Failed(code),
+            Err(err) => Outcome::Error(err.to_string()),
+        }
+    }
+
+    fn failed(&self) -> bool {
+        matches!(self, Outcome::Failed(_) | Outcome::Error(_))
+    }
+}
+
+/// One row of the summary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepoRun {
+    pub repo: PathBuf,
+    pub outcome: Outcome,
+    pub duration: Duration,
+}
+
+/// The repositories listed in `file`, one per line, relative to the file. Blank lines and lines
+/// starting with `#` are left out.
+///
+/// # Errors
+/// Returns an error if the file can't be read or lists nothing.
+pub fn read_list(file: &Path) -> Result<Vec<PathBuf>> {
+    let text = fs::read_to_string(file).map_err(|err| {
+        AtomicError::Generic(format!("Failed to read {}: {}", file.display(), err))
+    })?;
+    let base = file.parent().unwrap_or(Path::new(""));
+    let repos: Vec<PathBuf> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line))
+        .collect();
+    if repos.is_empty() {
+        return Err(AtomicError::Generic(format!(
+            "{} lists no repositories",
+            file.display()
+        )));
+    }
+    Ok(repos)
+}
+
+/// The repositories `workspace_repos` lists under `[atomic]` in `atomic`, relative to it.
+///
+/// # Errors
+/// Returns an error if the config can't be read, or doesn't set `workspace_repos`.
+pub fn from_setting(atomic: &Path, profile: Option<&str>) -> Result<Vec<PathBuf>> {
+    let config = AtomicConfig::from_value(load_toml(atomic, profile)?)?;
+    let base = atomic.parent().unwrap_or(Path::new(""));
+    match config.atomic.workspace_repos {
+        Some(repos) if !repos.is_empty() => Ok(repos.iter().map(|repo| base.join(repo)).collect()),
+        _ => Err(AtomicError::Generic(format!(
+            "--all-repos needs `workspace_repos` under [atomic] in {}",
+            atomic.display()
+        ))),
+    }
+}
+
+/// The config to run `cmd` with, given where the repository's config was looked for. Without a
+/// config, or one that doesn't define `cmd`, the repository is [`Outcome::Skipped`]; a config
+/// that can't be found or read for another reason is an [`Outcome::Error`].
+pub fn runnable(
+    atomic: Result<PathBuf>,
+    cmd: &str,
+    profile: Option<&str>,
+) -> std::result::Result<PathBuf, Outcome> {
+    let atomic = match atomic {
+        Ok(atomic) if atomic.is_file() => atomic,
+        Ok(_) | Err(AtomicError::ConfigNotFound(_)) => {
+            return Err(Outcome::Skipped("no atomic.toml".to_string()))
+        }
+        Err(err) => return Err(Outcome::Error(err.to_string())),
+    };
+    let config = load_toml(&atomic, profile)
+        .and_then(AtomicConfig::from_value)
+        .map_err(|err| Outcome::Error(err.to_string()))?;
+    match config.command(cmd) {
+        Some(_) => Ok(atomic),
+        None => Err(Outcome::Skipped(format!("no '{}' command", cmd))),
+    }
+}
+
+/// Runs `run` in each of `repos` in turn, with a header before each.
+pub fn run_each(repos: &[PathBuf], mut run: impl FnMut(&Path) -> Outcome) -> Vec<RepoRun> {
+    repos
+        .iter()
+        .map(|repo| {
+            eprintln!("==> {}", repo.display());
+            let started = Instant::now();
+            let outcome = run(repo);
+            if let Outcome::Skipped(reason) = &outcome {
+                eprintln!("skipped: {}", reason);
+            }
+            RepoRun {
+                repo: repo.clone(),
+                outcome,
+                duration: started.elapsed(),
+            }
+        })
+        .collect()
+}
+
+/// Runs `run` in up to `jobs` of `repos` at once. `run` returns its outcome and the output it
+/// kept, which is printed in one piece once it's done so repositories don't interleave.
+pub fn run_parallel(
+    repos: &[PathBuf],
+    jobs: usize,
+    run: impl Fn(&Path) -> (Outcome, Vec<u8>) + Sync,
+) -> Vec<RepoRun> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; repos.len()]);
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, repos.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(repo) = repos.get(i) else {
+                    break;
+                };
+                let started = Instant::now();
+                let (outcome, output) = run(repo);
+                let run = RepoRun {
+                    repo: repo.clone(),
+                    outcome,
+                    duration: started.elapsed(),
+                };
+                // the lock also keeps one repository's output together
+                let mut results = results.lock().unwrap_or_else(|err| err.into_inner());
+                eprintln!("==> {}", repo.display());
+                eprint!("{}", String::from_utf8_lossy(&output));
+                if let Outcome::Skipped(reason) = &run.outcome {
+                    eprintln!("skipped: {}", reason);
+                }
+                results[i] = Some(run);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap_or_else(|err| err.into_inner())
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// The table printed at the end: each repository with how it went and how long it took, then
+/// the totals.
+pub fn render_summary(runs: &[RepoRun]) -> String {
+    let rows: Vec<[String; 4]> = runs
+        .iter()
+        .map(|run| {
+            let (result, note) = match &run.outcome {
+                Outcome::Passed => ("passed".to_string(), String::new()),
+                Outcome::Failed(code) => ("failed".to_string(), format!("exit code {}", code)),
+                Outcome::Skipped(reason) => ("skipped".to_string(), reason.clone()),
+                Outcome::Error(err) => ("error".to_string(), first_line(err).to_string()),
+            };
+            let time = match run.outcome {
+                Outcome::Skipped(_) => "-".to_string(),
+                _ => format_elapsed(run.duration),
+            };
+            [run.repo.display().to_string(), result, time, note]
+        })
+        .collect();
+    let header = ["repo", "result", "time", ""].map(str::to_string);
+    let mut widths = header.clone().map(|cell| cell.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut out = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = format!(
+            "{:<w0$}  {:<w1$}  {:>w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    let count = |what: fn(&Outcome) -> bool| runs.iter().filter(|run| what(&run.outcome)).count();
+    out.push_str(&format!(
+        "{} repo{}: {} passed, {} failed, {} skipped\n",
+        runs.len(),
+        if runs.len() == 1 { "" } else { "s" },
+        count(|outcome| *outcome == Outcome::Passed),
+        count(Outcome::failed),
+        count(|outcome| matches!(outcome, Outcome::Skipped(_))),
+    ));
+    out
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or_default()
+}
+
+/// 1 when the command failed in any repository, or couldn't run there; skipped ones don't count.
+pub fn exit_code(runs: &[RepoRun]) -> i32 {
+    i32::from(runs.iter().any(|run| run.outcome.failed()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two repositories with different commands, and a directory that isn't a project.
+    fn workspace() -> (tempfile::TempDir, Vec<PathBuf>) {
+        let dir = tempfile::tempdir().unwrap();
+        let repos: Vec<PathBuf> = ["svc-a", "svc-b", "docs"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for repo in &repos {
+            fs::create_dir(repo).unwrap();
+        }
+        fs::write(
+            repos[0].join("atomic.toml"),
+            "[custom]\nbuild = \"true\"\ntest = \"true\"\n",
+        )
+        .unwrap();
+        fs::write(
+            repos[1].join("atomic.toml"),
+            "[custom]\nbuild = \"exit 3\"\n",
+        )
+        .unwrap();
+        (dir, repos)
+    }
+
+    fn find(repo: &Path) -> Result<PathBuf> {
+        let atomic = repo.join("atomic.toml");
+        match atomic.is_file() {
+            true => Ok(atomic),
+            false => Err(AtomicError::ConfigNotFound(atomic.display().to_string())),
+        }
+    }
+
+    /// Runs `cmd` the way the CLI does, with `sh` standing in for `run_command`.
+    fn run(repo: &Path, cmd: &str) -> Outcome {
+        let atomic = match runnable(find(repo), cmd, None) {
+            Ok(atomic) => atomic,
+            Err(outcome) => return outcome,
+        };
+        let config = AtomicConfig::from_value(load_toml(&atomic, None).unwrap()).unwrap();
+        let crate::config::Steps::Shell(shell) = config.command(cmd).unwrap().steps() else {
+            unreachable!()
+        };
+        let status = std::process::Command::new("sh")
+            .args(["-c", shell])
+            .status()
+            .unwrap();
+        Outcome::from_result(Ok(status.code().unwrap_or(1)))
+    }
+
+    #[test]
+    fn test_read_list() {
+        let (dir, _) = workspace();
+        let list = dir.path().join("repos.txt");
+        fs::write(&list, "# services\nsvc-a\n\n  svc-b  \n").unwrap();
+        assert_eq!(
+            read_list(&list).unwrap(),
+            [dir.path().join("svc-a"), dir.path().join("svc-b")]
+        );
+        fs::write(&list, "# nothing yet\n").unwrap();
+        assert!(read_list(&list).is_err());
+    }
+
+    #[test]
+    fn test_run_each_skips_and_fails() {
+        let (_dir, repos) = workspace();
+
+        let runs = run_each(&repos, |repo| run(repo, "build"));
+        let outcomes: Vec<_> = runs.iter().map(|run| run.outcome.clone()).collect();
+        assert_eq!(
+            outcomes,
+            [
+                Outcome::Passed,
+                Outcome::Failed(3),
+                Outcome::Skipped("no atomic.toml".to_string())
+            ]
+        );
+        assert_eq!(exit_code(&runs), 1);
+
+        // a repository without the command is skipped, not failed
+        let runs = run_each(&repos[..2], |repo| run(repo, "test"));
+        assert_eq!(
+            runs[1].outcome,
+            Outcome::Skipped("no 'test' command".to_string())
+        );
+        assert_eq!(exit_code(&runs), 0);
+
+        let parallel = run_parallel(&repos, 2, |repo| (run(repo, "build"), Vec::new()));
+        let order: Vec<_> = parallel.iter().map(|run| run.repo.clone()).collect();
+        assert_eq!(order, repos, "results stay in list order");
+        assert_eq!(parallel[1].outcome, Outcome::Failed(3));
+    }
+
+    #[test]
+    fn test_render_summary() {
+        let run = |repo: &str, outcome: Outcome, ms: u64| RepoRun {
+            repo: PathBuf::from(repo),
+            outcome,
+            duration: Duration::from_millis(ms),
+        };
+        let runs = [
+            run("../svc-a", Outcome::Passed, 1200),
+            run("../svc-b", Outcome::Failed(2), 300),
+            run(
+                "../docs",
+                Outcome::Skipped("no 'build' command".to_string()),
+                0,
+            ),
+            run(
+                "../gone",
+                Outcome::Error("--repo ../gone: not found\nmore".to_string()),
+                0,
+            ),
+        ];
+        assert_eq!(
+            render_summary(&runs),
+            "\
+repo      result   time
+../svc-a  passed   1.2s
+../svc-b  failed   0.3s  exit code 2
+../docs   skipped     -  no 'build' command
+../gone   error    0.0s  --repo ../gone: not found
+4 repos: 1 passed, 2 failed, 1 skipped
+"
+        );
+    }
+}
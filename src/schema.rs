@@ -0,0 +1,1311 @@
+//! Describes the atomic.toml format.
+//!
+//! The rules below are the single source of truth: [`validate_toml_schema`] checks a parsed
+//! config against them, and [`json_schema`] turns them into a JSON Schema for editors.
+
+use std::fmt;
+
+use serde_json::{json, Map as JsonMap, Value as Json};
+use toml::Value;
+
+use crate::config::MAX_MATRIX;
+use crate::lint::RULE_NAMES;
+use crate::util::{parse_duration, parse_size, PLATFORMS};
+
+/// The type a key's value must have.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    String,
+    Boolean,
+    /// An integer within the inclusive range.
+    Integer {
+        min: i64,
+        max: i64,
+    },
+    /// `"500ms"`, `"5s"`, `"2m"`, or a whole number of seconds.
+    Duration,
+    /// `"500KB"`, `"10MB"`, `"1GB"`, or a whole number of bytes.
+    Size,
+    /// A shell string, or a chain of strings naming commands or shell commands, and
+    /// `{ run = "..." }` tables.
+    Command,
+    /// One of a fixed set of strings.
+    OneOf(&'static [&'static str]),
+    /// One of a fixed set of strings, or a non-empty array of them.
+    AnyOf(&'static [&'static str]),
+    /// An array of strings, such as command names.
+    StringArray,
+    /// A boolean, or a string in its place.
+    BooleanOrString,
+    /// A boolean, or one of a fixed set of strings.
+    BooleanOrOneOf(&'static [&'static str]),
+    /// A table whose values are arrays of strings.
+    StringArrayTable,
+    /// A table of booleans, keyed by some of a fixed set of names.
+    BooleanTable(&'static [&'static str]),
+    /// A table with a fixed set of keys of its own.
+    Table(&'static [KeyRule]),
+}
+
+/// A key allowed inside a table with a fixed set of keys.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyRule {
+    pub key: &'static str,
+    pub kind: Kind,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+/// What a top-level section holds.
+#[derive(Debug, Clone, Copy)]
+pub enum SectionKind {
+    /// A fixed set of setting keys.
+    Settings(&'static [KeyRule]),
+    /// Any key naming a command, written as a string, an array, or a command table.
+    Commands,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SectionRule {
+    pub name: &'static str,
+    pub kind: SectionKind,
+    pub description: &'static str,
+}
+
+/// Keys of a `[custom.<name>]` command table.
+pub const COMMAND_TABLE_RULES: &[KeyRule] = &[
+    KeyRule {
+        key: "command",
+        kind: Kind::Command,
+        required: true,
+        description: "Shell command, or chain of commands and command names, to run.",
+    },
+    KeyRule {
+        key: "desc",
+        kind: Kind::String,
+        required: false,
+        description: "One-line summary shown next to the step when a chain runs this command.",
+    },
+    KeyRule {
+        key: "retries",
+        kind: Kind::Integer { min: 0, max: 10 },
+        required: false,
+        description: "Times to re-run the command after a non-zero exit.",
+    },
+    KeyRule {
+        key: "retry_delay",
+        kind: Kind::Duration,
+        required: false,
+        description: "Wait between retries, e.g. \"500ms\", \"5s\", \"2m\" or a number of seconds.",
+    },
+    KeyRule {
+        key: "commit",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Whether a successful run is auto-committed.",
+    },
+    KeyRule {
+        key: "container",
+        kind: Kind::String,
+        required: false,
+        description: "Image to run the steps in with docker or podman, e.g. \"rust:1.78\".",
+    },
+    KeyRule {
+        key: "needs",
+        kind: Kind::StringArray,
+        required: false,
+        description: "Commands to run first, each at most once, e.g. [\"build\", \"test\"].",
+    },
+    KeyRule {
+        key: "confirm",
+        kind: Kind::BooleanOrString,
+        required: false,
+        description: "Ask before running: true for y/N, or a prompt after which the command's name must be typed.",
+    },
+    KeyRule {
+        key: "os",
+        kind: Kind::AnyOf(PLATFORMS),
+        required: false,
+        description: "Only run on these platforms, and skip the command elsewhere. \"unix\" includes macOS.",
+    },
+    KeyRule {
+        key: "capture_output",
+        kind: Kind::OneOf(&["commit_body", "none"]),
+        required: false,
+        description: "\"commit_body\" adds the command's stdout (up to 8 KB) to the auto-commit message, under `--- output ---`.",
+    },
+    KeyRule {
+        key: "lock",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Only let one run of this command happen at a time; others wait for it, or fail with --no-wait.",
+    },
+    KeyRule {
+        key: "lock_wait",
+        kind: Kind::Duration,
+        required: false,
+        description: "With lock, how long to wait for another run to finish before giving up. Waits as long as it takes by default.",
+    },
+    KeyRule {
+        key: "cargo_json",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Run cargo with --message-format=json, show its diagnostics as usual, and sum them up at the end, e.g. `2 errors (E0308, E0502), 5 warnings across 3 crates`.",
+    },
+    KeyRule {
+        key: "tmpdir",
+        kind: Kind::BooleanOrOneOf(&["shared"]),
+        required: false,
+        description: "Give each step a new scratch directory in ATOMIC_TMPDIR, removed when it finishes. \"shared\" gives a chain's steps one directory between them.",
+    },
+    KeyRule {
+        key: "matrix",
+        kind: Kind::StringArrayTable,
+        required: false,
+        description: "Run the command, or each step of its chain, once for every combination of these values, e.g. { flag = [\"foo\", \"bar\"] }, with `{flag}` in the command replaced by the value. At most 32 combinations.",
+    },
+    KeyRule {
+        key: "fail_fast",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Stop a matrix at the first combination that fails, instead of running the rest and reporting every failure. Chains always stop at the first step that fails.",
+    },
+    KeyRule {
+        key: "inputs",
+        kind: Kind::StringArray,
+        required: false,
+        description: "Files the command reads, as globs relative to atomic.toml such as \"proto/**/*.proto\". The command is skipped when none of them changed since it last succeeded; --force runs it anyway.",
+    },
+    KeyRule {
+        key: "outputs",
+        kind: Kind::StringArray,
+        required: false,
+        description: "With inputs, files the command writes, as globs. It runs again when one of them matches nothing.",
+    },
+    KeyRule {
+        key: "artifacts",
+        kind: Kind::StringArray,
+        required: false,
+        description: "Files to keep once the command finishes, passed or failed, as globs such as \"coverage/*.lcov\". They are copied to .atomic/artifacts/<run>/<step>/, or under --artifacts-dir.",
+    },
+    KeyRule {
+        key: "lint",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Set to false to leave this command out of `atomic --lint`.",
+    },
+    KeyRule {
+        key: "env_file",
+        kind: Kind::String,
+        required: false,
+        description: "A .env file, relative to atomic.toml, whose variables this command gets on top of `env_files`. It must exist.",
+    },
+    KeyRule {
+        key: "clean_env",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Start the command with an empty environment, keeping only PATH, HOME, TERM, LANG (and what Windows needs), atomic's variables, `env_files` and `env_file`.",
+    },
+    KeyRule {
+        key: "env_allow",
+        kind: Kind::StringArray,
+        required: false,
+        description: "With clean_env, more variables to keep from atomic's environment, such as \"CARGO_HOME\".",
+    },
+    KeyRule {
+        key: "commit_message",
+        kind: Kind::String,
+        required: false,
+        description: "Subject of the command's auto-commits, instead of \"atomic: <name>\".",
+    },
+    KeyRule {
+        key: "commit_branch",
+        kind: Kind::String,
+        required: false,
+        description: "Branch the command's auto-commits go onto, created from HEAD if missing, leaving the checkout alone.",
+    },
+];
+
+/// Keys of a `{ run = "..." }` table inside a chain.
+pub const CHAIN_STEP_RULES: &[KeyRule] = &[
+    KeyRule {
+        key: "run",
+        kind: Kind::String,
+        required: true,
+        description: "Command name or shell command, taken as written: a leading `-` is not special here.",
+    },
+    KeyRule {
+        key: "ignore_failure",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Keep the run successful when this step fails. `\"-name\"` is shorthand for a named command.",
+    },
+];
+
+/// Keys of the `[atomic]` settings table.
+pub const SETTINGS_RULES: &[KeyRule] = &[
+    KeyRule {
+        key: "auto_commit",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Commit the work tree after a command succeeds. Defaults to true.",
+    },
+    KeyRule {
+        key: "base_branch",
+        kind: Kind::String,
+        required: false,
+        description: "Branch `atomic --log` stops at. Defaults to what origin/HEAD points at, then main, master, trunk or develop.",
+    },
+    KeyRule {
+        key: "commit_author",
+        kind: Kind::String,
+        required: false,
+        description: "\"Name <email>\" to commit as when git has no user.name / user.email, e.g. in CI.",
+    },
+    KeyRule {
+        key: "container_runtime",
+        kind: Kind::String,
+        required: false,
+        description: "Program that runs `container` steps. Defaults to docker, then podman.",
+    },
+    KeyRule {
+        key: "autocommit_preexisting",
+        kind: Kind::OneOf(&["include", "exclude", "ask"]),
+        required: false,
+        description: "What to do with files that already had changes before the command ran. Defaults to ask.",
+    },
+    KeyRule {
+        key: "run_git_hooks",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Run the repository's pre-commit and commit-msg hooks before each auto-commit. Defaults to false.",
+    },
+    KeyRule {
+        key: "pre_run",
+        kind: Kind::Command,
+        required: false,
+        description: "Runs before every command; when it fails, nothing else does.",
+    },
+    KeyRule {
+        key: "post_run",
+        kind: Kind::Command,
+        required: false,
+        description: "Runs after every command, with ATOMIC_RESULT set to \"success\" or \"failure\".",
+    },
+    KeyRule {
+        key: "root_only",
+        kind: Kind::Boolean,
+        required: false,
+        description: "In the repository's top-level atomic.toml: don't layer atomic.toml files from subdirectories over this one; each is used on its own.",
+    },
+    KeyRule {
+        key: "no_picker",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Show help instead of a numbered command menu when `atomic` runs with no arguments in a terminal.",
+    },
+    KeyRule {
+        key: "git",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Set to false to only run commands: no auto-commit, empty ATOMIC_BRANCH and ATOMIC_ISSUE, and git flags refused. Defaults to true.",
+    },
+    KeyRule {
+        key: "max_commit_file_size",
+        kind: Kind::Size,
+        required: false,
+        description: "Largest file an auto-commit takes, e.g. \"10MB\". Files tracked by Git LFS are exempt. Defaults to 10MB.",
+    },
+    KeyRule {
+        key: "large_files",
+        kind: Kind::OneOf(&["abort", "skip"]),
+        required: false,
+        description: "What an auto-commit does with files over max_commit_file_size: \"abort\" it (the default), or \"skip\" them and commit the rest.",
+    },
+    KeyRule {
+        key: "stats",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Count each command's runs and time in .atomic/stats.toml, for `atomic --stats`. On by default.",
+    },
+    KeyRule {
+        key: "event_socket",
+        kind: Kind::String,
+        required: false,
+        description: "Unix socket (named pipe on Windows) to send progress events to as JSON lines, as `--events` does.",
+    },
+    KeyRule {
+        key: "keep_tmp_on_failure",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Keep the ATOMIC_TMPDIR of a step that failed, and print where it is.",
+    },
+    KeyRule {
+        key: "change_notice",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Print a one-line summary of the commands added, modified and removed when atomic.toml changed since the last run. On by default.",
+    },
+    KeyRule {
+        key: "env_files",
+        kind: Kind::StringArray,
+        required: false,
+        description: "Files of KEY=VALUE lines, relative to atomic.toml, whose variables every command gets; later files win. Each must exist. Defaults to .env and .env.local, when they exist.",
+    },
+    KeyRule {
+        key: "workspace_repos",
+        kind: Kind::StringArray,
+        required: false,
+        description: "Repositories, relative to atomic.toml, that `atomic --all-repos <cmd>` runs the command in, each with its own atomic.toml.",
+    },
+    KeyRule {
+        key: "artifact_max_size",
+        kind: Kind::Size,
+        required: false,
+        description: "Largest file a command's artifacts take, e.g. \"20MB\"; bigger ones are skipped with a notice. Defaults to 100MB.",
+    },
+    KeyRule {
+        key: "manage_gitignore",
+        kind: Kind::Boolean,
+        required: false,
+        description: "Keep a block in the repository's .gitignore that ignores .atomic/, where atomic keeps its state. Otherwise atomic only says, once, when .gitignore doesn't ignore it.",
+    },
+    KeyRule {
+        key: "lints",
+        kind: Kind::BooleanTable(RULE_NAMES),
+        required: false,
+        description: "Rules of `atomic --lint` to turn off for every command, e.g. { shell_chain = false }.",
+    },
+    KeyRule {
+        key: "issues",
+        kind: Kind::Table(ISSUES_RULES),
+        required: false,
+        description: "Where commands' ATOMIC_ISSUE_TITLE is looked up: the title of the issue named by the branch.",
+    },
+];
+
+/// Keys allowed in `[atomic.issues]`.
+pub const ISSUES_RULES: &[KeyRule] = &[
+    KeyRule {
+        key: "provider",
+        kind: Kind::OneOf(&["github", "jira"]),
+        required: true,
+        description: "The issue tracker.",
+    },
+    KeyRule {
+        key: "repo",
+        kind: Kind::String,
+        required: false,
+        description: "GitHub repository as owner/name; defaults to the origin remote's.",
+    },
+    KeyRule {
+        key: "base_url",
+        kind: Kind::String,
+        required: false,
+        description: "Jira site such as https://acme.atlassian.net (required for jira), or a GitHub Enterprise API URL.",
+    },
+    KeyRule {
+        key: "project",
+        kind: Kind::String,
+        required: false,
+        description: "Jira project key, so issue 144 is ABC-144 (required for jira).",
+    },
+    KeyRule {
+        key: "token_env",
+        kind: Kind::String,
+        required: false,
+        description: "Environment variable holding the API token; defaults to GITHUB_TOKEN or JIRA_TOKEN.",
+    },
+    KeyRule {
+        key: "cache_ttl",
+        kind: Kind::Duration,
+        required: false,
+        description: "How long a fetched title is reused from .atomic/issue_cache.toml; defaults to 60m.",
+    },
+];
+
+pub const SECTIONS: &[SectionRule] = &[
+    SectionRule {
+        name: "atomic",
+        kind: SectionKind::Settings(SETTINGS_RULES),
+        description: "Settings for atomic itself.",
+    },
+    SectionRule {
+        name: "default",
+        kind: SectionKind::Commands,
+        description: "The build, test and run commands most projects have.",
+    },
+    SectionRule {
+        name: "custom",
+        kind: SectionKind::Commands,
+        description: "Any other commands you want auto-committed.",
+    },
+];
+
+/// Name of the table holding `[profile.<name>]` overlays.
+pub const PROFILE_SECTION: &str = "profile";
+
+/// How much a problem found by [`validate_toml_schema`] matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Left out of the config, and reported, but commands still run; e.g. a key a newer atomic
+    /// knows.
+    Warning,
+    /// Stops every command until it's fixed.
+    Error,
+}
+
+/// A problem with a config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The TOML path of the offending key, or empty for the whole file.
+    pub path: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(path: &str, message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, path, message)
+    }
+
+    pub fn warning(path: &str, message: impl Into<String>) -> Self {
+        Self::new(Severity::Warning, path, message)
+    }
+
+    fn new(severity: Severity, path: &str, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            path: path.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// The same problem, found in `file`, one of several merged configs.
+    pub fn in_file(self, file: &str) -> Self {
+        let path = match self.path.is_empty() {
+            true => file.to_string(),
+            false => format!("{}: {}", file, self.path),
+        };
+        Self { path, ..self }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+/// The messages of the diagnostics that stop a run, for [`crate::AtomicError::Validation`].
+pub fn error_messages(diagnostics: &[Diagnostic]) -> Vec<String> {
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .map(Diagnostic::to_string)
+        .collect()
+}
+
+/// Removes the sections [`validate_toml_schema`] warns are unknown, at the top level and in
+/// profiles, so the rest of the config can still be used.
+pub fn drop_unknown_sections(config: &mut Value) {
+    let Some(root) = config.as_table_mut() else {
+        return;
+    };
+    let unknown = |key: &str, value: &Value| {
+        value.is_table() && key != PROFILE_SECTION && !SECTIONS.iter().any(|s| s.name == key)
+    };
+    root.retain(|key, value| !unknown(key, value));
+    if let Some(Value::Table(profiles)) = root.get_mut(PROFILE_SECTION) {
+        for profile in profiles
+            .iter_mut()
+            .filter_map(|(_, profile)| profile.as_table_mut())
+        {
+            profile.retain(|key, value| key == PROFILE_SECTION || !unknown(key, value));
+        }
+    }
+}
+
+/// Checks a parsed atomic.toml against the rules, returning one diagnostic per problem.
+///
+/// Unknown sections and keys are warnings, so a key added for a newer atomic doesn't stop
+/// anyone's commands; wrong types, missing keys and values that can't work are errors. Profiles
+/// are checked as partial configs, since they are merged over the base file.
+pub fn validate_toml_schema(config: &Value) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+    let Some(root) = config.as_table() else {
+        errors.push(Diagnostic::error("", "atomic.toml must be a table"));
+        return errors;
+    };
+
+    for (key, value) in root {
+        if key == PROFILE_SECTION {
+            match value.as_table() {
+                Some(profiles) => {
+                    for (name, profile) in profiles {
+                        validate_profile(
+                            &format!("{}.{}", PROFILE_SECTION, name),
+                            profile,
+                            &mut errors,
+                        );
+                    }
+                }
+                None => errors.push(Diagnostic::error(key, "expected a table of profiles")),
+            }
+        } else {
+            validate_entry("", key, value, &mut errors);
+        }
+    }
+    errors
+}
+
+fn validate_profile(path: &str, config: &Value, errors: &mut Vec<Diagnostic>) {
+    match config.as_table() {
+        Some(table) => {
+            for (key, value) in table {
+                validate_entry(path, key, value, errors);
+            }
+        }
+        None => errors.push(Diagnostic::error(path, "expected a table")),
+    }
+}
+
+/// Validates one top-level key: a known section, or a root-level command.
+fn validate_entry(prefix: &str, key: &str, value: &Value, errors: &mut Vec<Diagnostic>) {
+    let path = join_path(prefix, key);
+    match SECTIONS.iter().find(|section| section.name == key) {
+        Some(section) => match (section.kind, value.as_table()) {
+            (SectionKind::Settings(rules), Some(table)) => {
+                validate_keys(&path, table, rules, errors)
+            }
+            (SectionKind::Commands, Some(table)) => {
+                for (name, command) in table {
+                    validate_command(&join_path(&path, name), command, errors);
+                }
+            }
+            (_, None) => errors.push(Diagnostic::error(&path, "expected a table")),
+        },
+        None if value.is_table() => errors.push(Diagnostic::warning(&path, "unknown section")),
+        None => validate_command(&path, value, errors),
+    }
+}
+
+/// Validates a command entry: a string, an array of strings, or a command table.
+fn validate_command(path: &str, value: &Value, errors: &mut Vec<Diagnostic>) {
+    let type_errors = errors.len();
+    match value {
+        Value::Table(table) => validate_keys(path, table, COMMAND_TABLE_RULES, errors),
+        Value::Integer(_) | Value::Float(_) | Value::Boolean(_) | Value::Datetime(_) => errors
+            .push(Diagnostic::error(
+                path,
+                "commands must be a string, an array of strings or a table; \
+                 settings belong under [atomic]",
+            )),
+        other => {
+            if let Some(message) = check_kind(Kind::Command, other) {
+                errors.push(Diagnostic::error(path, message));
+            }
+        }
+    }
+    if errors[type_errors..]
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+    {
+        return;
+    }
+
+    let name = path.rsplit('.').next().unwrap_or(path);
+    for check in COMMAND_CHECKS {
+        let target = match value {
+            Value::Table(table) => table.get(check.key),
+            // a bare string or array is the command itself
+            _ if check.key == "command" => Some(value),
+            _ => None,
+        };
+        if let Some(message) = target.and_then(|target| (check.check)(name, target)) {
+            let key_path = match value {
+                Value::Table(_) => join_path(path, check.key),
+                _ => path.to_string(),
+            };
+            errors.push(Diagnostic::error(&key_path, message));
+        }
+    }
+}
+
+/// A rule about a command key's value that its type alone can't express.
+struct ValueCheck {
+    key: &'static str,
+    /// Gets the command's name and the key's value, already of the right type.
+    check: fn(&str, &Value) -> Option<&'static str>,
+}
+
+/// Checked on every command once its types are right; messages name the key's TOML path.
+const COMMAND_CHECKS: &[ValueCheck] = &[
+    ValueCheck {
+        key: "command",
+        check: |_, command| match command {
+            Value::Array(steps) if steps.is_empty() => Some("chain has no steps"),
+            _ => None,
+        },
+    },
+    ValueCheck {
+        key: "container",
+        check: |_, image| {
+            (image.as_str().map(str::trim) == Some("")).then_some("container image is empty")
+        },
+    },
+    ValueCheck {
+        key: "matrix",
+        check: |_, matrix| {
+            let values = matrix.as_table()?.values().filter_map(Value::as_array);
+            let mut combinations: usize = 1;
+            for values in values {
+                if values.is_empty() {
+                    return Some("matrix values can't be empty");
+                }
+                combinations = combinations.saturating_mul(values.len());
+            }
+            (combinations > MAX_MATRIX).then_some("matrix has more than 32 combinations")
+        },
+    },
+    ValueCheck {
+        key: "commit_branch",
+        check: |_, branch| {
+            let branch = branch.as_str()?;
+            (!git2::Branch::name_is_valid(branch).unwrap_or(false))
+                .then_some("not a valid branch name")
+        },
+    },
+    ValueCheck {
+        key: "needs",
+        check: |name, needs| {
+            needs
+                .as_array()
+                .is_some_and(|needs| needs.iter().any(|need| need.as_str() == Some(name)))
+                .then_some("a command can't need itself")
+        },
+    },
+];
+
+fn validate_keys(
+    path: &str,
+    table: &toml::map::Map<String, Value>,
+    rules: &[KeyRule],
+    errors: &mut Vec<Diagnostic>,
+) {
+    for rule in rules.iter().filter(|rule| rule.required) {
+        if !table.contains_key(rule.key) {
+            errors.push(Diagnostic::error(
+                path,
+                format!("missing required key `{}`", rule.key),
+            ));
+        }
+    }
+
+    for (key, value) in table {
+        let key_path = join_path(path, key);
+        match rules.iter().find(|rule| rule.key == key) {
+            Some(KeyRule {
+                kind: Kind::Table(rules),
+                ..
+            }) if value.is_table() => {
+                validate_keys(&key_path, value.as_table().unwrap(), rules, errors);
+            }
+            Some(rule) => {
+                if let Some(message) = check_kind(rule.kind, value) {
+                    errors.push(Diagnostic::error(&key_path, message));
+                }
+            }
+            None => errors.push(Diagnostic::warning(&key_path, "unknown key")),
+        }
+    }
+}
+
+/// Returns a description of the problem when `value` doesn't match `kind`.
+fn check_kind(kind: Kind, value: &Value) -> Option<String> {
+    match (kind, value) {
+        (Kind::String, Value::String(_)) | (Kind::Boolean, Value::Boolean(_)) => None,
+        (Kind::Integer { min, max }, Value::Integer(n)) if (min..=max).contains(n) => None,
+        (Kind::Duration, Value::String(s)) if parse_duration(s).is_ok() => None,
+        (Kind::Duration, Value::Integer(n)) if *n >= 0 => None,
+        (Kind::Size, Value::String(s)) if parse_size(s).is_ok() => None,
+        (Kind::Size, Value::Integer(n)) if *n >= 0 => None,
+        (Kind::Command, Value::String(_)) => None,
+        (Kind::Command, Value::Array(steps)) if steps.iter().all(is_chain_step) => None,
+        (Kind::OneOf(choices) | Kind::AnyOf(choices), Value::String(s))
+            if choices.contains(&s.as_str()) =>
+        {
+            None
+        }
+        (Kind::AnyOf(choices), Value::Array(items))
+            if !items.is_empty()
+                && items
+                    .iter()
+                    .all(|item| item.as_str().is_some_and(|s| choices.contains(&s))) =>
+        {
+            None
+        }
+        (Kind::StringArray, Value::Array(items)) if items.iter().all(Value::is_str) => None,
+        (Kind::BooleanOrString, Value::Boolean(_) | Value::String(_)) => None,
+        (Kind::BooleanOrOneOf(_), Value::Boolean(_)) => None,
+        (Kind::BooleanOrOneOf(choices), Value::String(s)) if choices.contains(&s.as_str()) => None,
+        (Kind::StringArrayTable, Value::Table(table))
+            if table.values().all(|values| {
+                values
+                    .as_array()
+                    .is_some_and(|values| values.iter().all(Value::is_str))
+            }) =>
+        {
+            None
+        }
+        (Kind::BooleanTable(names), Value::Table(table))
+            if table
+                .iter()
+                .all(|(name, value)| names.contains(&name.as_str()) && value.is_bool()) =>
+        {
+            None
+        }
+        (kind, _) => Some(format!("expected {}", describe(kind))),
+    }
+}
+
+/// A string, or a `{ run = "..." }` table with only the keys in [`CHAIN_STEP_RULES`].
+fn is_chain_step(step: &Value) -> bool {
+    match step {
+        Value::String(_) => true,
+        Value::Table(table) => {
+            let mut errors = Vec::new();
+            validate_keys("", table, CHAIN_STEP_RULES, &mut errors);
+            errors.is_empty()
+        }
+        _ => false,
+    }
+}
+
+fn describe(kind: Kind) -> String {
+    match kind {
+        Kind::String => "a string".to_string(),
+        Kind::Boolean => "a boolean".to_string(),
+        Kind::Integer { min, max } => format!("an integer between {} and {}", min, max),
+        Kind::Duration => "a duration such as \"5s\" or a number of seconds".to_string(),
+        Kind::Size => "a size such as \"10MB\" or a number of bytes".to_string(),
+        Kind::Command => "a string or an array of strings and { run = \"...\" } tables".to_string(),
+        Kind::OneOf(choices) => format!("one of \"{}\"", choices.join("\", \"")),
+        Kind::AnyOf(choices) => {
+            format!("one of \"{}\", or an array of them", choices.join("\", \""))
+        }
+        Kind::StringArray => "an array of strings".to_string(),
+        Kind::BooleanOrString => "a boolean or a string".to_string(),
+        Kind::BooleanOrOneOf(choices) => {
+            format!("a boolean or one of \"{}\"", choices.join("\", \""))
+        }
+        Kind::StringArrayTable => "a table of string arrays".to_string(),
+        Kind::BooleanTable(names) => {
+            format!("a table of booleans for \"{}\"", names.join("\", \""))
+        }
+        Kind::Table(rules) => format!(
+            "a table of \"{}\"",
+            rules
+                .iter()
+                .map(|rule| rule.key)
+                .collect::<Vec<_>>()
+                .join("\", \"")
+        ),
+    }
+}
+
+fn join_path(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+/// Builds a JSON Schema (draft 07) for atomic.toml from the same rules the validator uses.
+pub fn json_schema() -> Json {
+    let mut properties = JsonMap::new();
+    for section in SECTIONS {
+        properties.insert(
+            section.name.to_string(),
+            json!({ "$ref": format!("#/definitions/{}", section.name) }),
+        );
+    }
+    let profile_properties = properties.clone();
+    properties.insert(
+        PROFILE_SECTION.to_string(),
+        json!({
+            "description": "Named overlays merged over the rest of the file with `atomic --profile <name>`.",
+            "type": "object",
+            "additionalProperties": {
+                "type": "object",
+                "properties": profile_properties,
+                "additionalProperties": { "$ref": "#/definitions/command" }
+            }
+        }),
+    );
+
+    let mut definitions = JsonMap::new();
+    for section in SECTIONS {
+        definitions.insert(section.name.to_string(), section_schema(section));
+    }
+    definitions.insert("command".to_string(), command_schema());
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "atomic.toml",
+        "description": "Configuration for atomic, the command runner that auto-commits your work.",
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": { "$ref": "#/definitions/command" },
+        "definitions": definitions
+    })
+}
+
+fn section_schema(section: &SectionRule) -> Json {
+    match section.kind {
+        SectionKind::Settings(rules) => {
+            let mut schema = keys_schema(rules);
+            schema["description"] = json!(section.description);
+            schema
+        }
+        SectionKind::Commands => json!({
+            "description": section.description,
+            "type": "object",
+            "additionalProperties": { "$ref": "#/definitions/command" }
+        }),
+    }
+}
+
+fn command_schema() -> Json {
+    let mut table = keys_schema(COMMAND_TABLE_RULES);
+    table["description"] = json!("A command with extra options.");
+
+    let mut schema = kind_schema(Kind::Command);
+    if let Some(any_of) = schema["anyOf"].as_array_mut() {
+        any_of.push(table);
+    }
+    schema
+}
+
+fn keys_schema(rules: &[KeyRule]) -> Json {
+    let mut properties = JsonMap::new();
+    for rule in rules {
+        let mut schema = kind_schema(rule.kind);
+        schema["description"] = json!(rule.description);
+        properties.insert(rule.key.to_string(), schema);
+    }
+    let required: Vec<&str> = rules
+        .iter()
+        .filter(|rule| rule.required)
+        .map(|rule| rule.key)
+        .collect();
+
+    let mut schema = json!({
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false
+    });
+    if !required.is_empty() {
+        schema["required"] = json!(required);
+    }
+    schema
+}
+
+fn kind_schema(kind: Kind) -> Json {
+    match kind {
+        Kind::String => json!({ "type": "string" }),
+        Kind::Boolean => json!({ "type": "boolean" }),
+        Kind::Integer { min, max } => json!({ "type": "integer", "minimum": min, "maximum": max }),
+        Kind::Duration => json!({
+            "anyOf": [
+                { "type": "string", "pattern": "^\\s*\\d+\\s*(ms|s|m)?\\s*$" },
+                { "type": "integer", "minimum": 0 }
+            ]
+        }),
+        Kind::Size => json!({
+            "anyOf": [
+                { "type": "string", "pattern": "^\\s*\\d+\\s*([bB]|[kKmMgG][bB])?\\s*$" },
+                { "type": "integer", "minimum": 0 }
+            ]
+        }),
+        Kind::Command => json!({
+            "anyOf": [
+                { "type": "string" },
+                {
+                    "type": "array",
+                    "items": { "anyOf": [{ "type": "string" }, keys_schema(CHAIN_STEP_RULES)] },
+                    "minItems": 1
+                }
+            ]
+        }),
+        Kind::OneOf(choices) => json!({ "type": "string", "enum": choices }),
+        Kind::AnyOf(choices) => json!({
+            "anyOf": [
+                { "type": "string", "enum": choices },
+                { "type": "array", "items": { "type": "string", "enum": choices }, "minItems": 1 }
+            ]
+        }),
+        Kind::StringArray => json!({ "type": "array", "items": { "type": "string" } }),
+        Kind::BooleanOrString => json!({ "type": ["boolean", "string"] }),
+        Kind::BooleanOrOneOf(choices) => json!({
+            "anyOf": [{ "type": "boolean" }, { "type": "string", "enum": choices }]
+        }),
+        Kind::StringArrayTable => json!({
+            "type": "object",
+            "additionalProperties": { "type": "array", "items": { "type": "string" }, "minItems": 1 }
+        }),
+        Kind::BooleanTable(names) => {
+            let properties: JsonMap<String, Json> = names
+                .iter()
+                .map(|name| (name.to_string(), json!({ "type": "boolean" })))
+                .collect();
+            json!({ "type": "object", "properties": properties, "additionalProperties": false })
+        }
+        Kind::Table(rules) => keys_schema(rules),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(s: &str) -> Vec<String> {
+        validate_toml_schema(&toml::from_str(s).unwrap())
+            .iter()
+            .map(Diagnostic::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_configs() {
+        assert!(validate(include_str!("../atomic.toml")).is_empty());
+        assert!(validate(include_str!("../template/rust.toml")).is_empty());
+        assert!(validate(include_str!("../template/example.toml")).is_empty());
+        assert!(validate(
+            r#"
+            build = "cargo build"
+
+            [atomic]
+            auto_commit = false
+            base_branch = "master"
+
+            [custom.fetch]
+            command = ["cargo fetch", "check"]
+            retries = 3
+            retry_delay = "5s"
+
+            [profile.ci.custom]
+            test = "cargo test --locked"
+            [profile.ci.atomic]
+            auto_commit = false
+            "#
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_invalid_types() {
+        assert_eq!(
+            validate(
+                r#"
+                [default]
+                jobs = 4
+                mixed = ["cargo fmt", 1]
+                [custom]
+                verbose = true
+                [atomic]
+                auto_commit = "yes"
+                autocommit_preexisting = "sometimes"
+                "#
+            ),
+            vec![
+                "atomic.auto_commit: expected a boolean",
+                "atomic.autocommit_preexisting: expected one of \"include\", \"exclude\", \"ask\"",
+                "custom.verbose: commands must be a string, an array of strings or a table; \
+                 settings belong under [atomic]",
+                "default.jobs: commands must be a string, an array of strings or a table; \
+                 settings belong under [atomic]",
+                "default.mixed: expected a string or an array of strings and { run = \"...\" } tables",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chain_steps() {
+        assert!(validate(
+            r#"
+            [custom]
+            ci = ["-docs", { run = "docs", ignore_failure = true }, { run = "cargo test" }]
+            [atomic]
+            post_run = [{ run = "notify", ignore_failure = true }]
+            "#
+        )
+        .is_empty());
+
+        let expected = "expected a string or an array of strings and { run = \"...\" } tables";
+        for step in [
+            r#"{ ignore_failure = true }"#,
+            r#"{ run = "docs", ignore_failure = "yes" }"#,
+            r#"{ run = "docs", retries = 2 }"#,
+            r#"["docs"]"#,
+        ] {
+            assert_eq!(
+                validate(&format!("[default]\nci = [{}]\n", step)),
+                vec![format!("default.ci: {}", expected)],
+                "{}",
+                step
+            );
+        }
+    }
+
+    #[test]
+    fn test_command_tables() {
+        assert_eq!(
+            validate(
+                r#"
+                [custom.fetch]
+                retries = 11
+                retry_delay = "soon"
+                colour = "red"
+                "#
+            ),
+            vec![
+                "custom.fetch: missing required key `command`",
+                "custom.fetch.colour: unknown key",
+                "custom.fetch.retries: expected an integer between 0 and 10",
+                "custom.fetch.retry_delay: expected a duration such as \"5s\" or a number of seconds",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_os_platforms() {
+        assert!(validate("[custom.open]\ncommand = \"open .\"\nos = \"macos\"\n").is_empty());
+        assert!(
+            validate("[custom.open]\ncommand = \"open .\"\nos = [\"unix\", \"windows\"]\n")
+                .is_empty()
+        );
+        let expected =
+            "custom.open.os: expected one of \"windows\", \"unix\", \"macos\", or an array of them";
+        for os in ["\"linux\"", "[]", "[\"macos\", \"beos\"]", "true"] {
+            assert_eq!(
+                validate(&format!(
+                    "[custom.open]\ncommand = \"open .\"\nos = {}\n",
+                    os
+                )),
+                vec![expected],
+                "os = {}",
+                os
+            );
+        }
+    }
+
+    #[test]
+    fn test_sizes() {
+        for size in ["\"10MB\"", "\"500 kb\"", "1048576"] {
+            let config = format!(
+                "[atomic]\nmax_commit_file_size = {}\nlarge_files = \"skip\"\n",
+                size
+            );
+            assert!(validate(&config).is_empty(), "{}", size);
+        }
+        assert_eq!(
+            validate("[atomic]\nmax_commit_file_size = \"10TB\"\nlarge_files = \"warn\"\n"),
+            vec![
+                "atomic.large_files: expected one of \"abort\", \"skip\"",
+                "atomic.max_commit_file_size: expected a size such as \"10MB\" or a number of bytes",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_tables() {
+        let config = "[atomic.issues]\nprovider = \"jira\"\nbase_url = \"https://acme.atlassian.net\"\nproject = \"ABC\"\ncache_ttl = \"10m\"\n";
+        assert!(validate(config).is_empty());
+        assert_eq!(
+            validate("[atomic.issues]\nprovider = \"gitlab\"\ncache_ttl = \"1d\"\ntoken = \"x\"\n"),
+            [
+                "atomic.issues.cache_ttl: expected a duration such as \"5s\" or a number of seconds",
+                "atomic.issues.provider: expected one of \"github\", \"jira\"",
+                "atomic.issues.token: unknown key",
+            ]
+        );
+        assert_eq!(
+            validate("[atomic.issues]\nrepo = \"a/b\"\n"),
+            ["atomic.issues: missing required key `provider`"]
+        );
+        assert!(validate("[atomic]\nissues = \"github\"\n")[0]
+            .starts_with("atomic.issues: expected a table of \"provider\", \"repo\""));
+    }
+
+    #[test]
+    fn test_value_checks() {
+        assert_eq!(
+            validate("[custom.fmt]\ncommand = \"cargo fmt\"\ncommit_branch = \"bad..name\"\n"),
+            vec!["custom.fmt.commit_branch: not a valid branch name"]
+        );
+        assert!(validate(
+            "[custom.fmt]\ncommand = \"cargo fmt\"\ncommit_branch = \"atomic/fmt\"\ncommit_message = \"style: fmt\"\n"
+        )
+        .is_empty());
+        assert_eq!(
+            validate("[custom.ci]\ncommand = []\n"),
+            vec!["custom.ci.command: chain has no steps"]
+        );
+        assert_eq!(
+            validate("[default]\nci = []\n"),
+            vec!["default.ci: chain has no steps"]
+        );
+        assert_eq!(
+            validate("[custom.test]\ncommand = \"cargo test\"\ncontainer = \"\"\n"),
+            vec!["custom.test.container: container image is empty"]
+        );
+        assert_eq!(
+            validate("[profile.ci.custom.test]\ncommand = \"cargo test\"\nneeds = [\"test\"]\n"),
+            vec!["profile.ci.custom.test.needs: a command can't need itself"]
+        );
+        assert_eq!(
+            validate("[custom.docs]\ncommand = \"mdbook build\"\ntmpdir = \"per-run\"\n"),
+            vec!["custom.docs.tmpdir: expected a boolean or one of \"shared\""]
+        );
+        assert!(
+            validate("[custom.docs]\ncommand = \"mdbook build\"\ntmpdir = \"shared\"\n").is_empty()
+        );
+        let matrix = |matrix: &str| {
+            validate(&format!(
+                "[custom.test]\ncommand = \"cargo test --features {{flag}}\"\nmatrix = {}\n",
+                matrix
+            ))
+        };
+        assert!(matrix("{ flag = [\"foo\", \"bar\", \"foo,bar\"] }").is_empty());
+        assert_eq!(
+            matrix("{ flag = \"foo\" }"),
+            vec!["custom.test.matrix: expected a table of string arrays"]
+        );
+        assert_eq!(
+            matrix("{ flag = [] }"),
+            vec!["custom.test.matrix: matrix values can't be empty"]
+        );
+        assert_eq!(
+            matrix("{ a = [\"1\", \"2\", \"3\", \"4\", \"5\", \"6\"], b = [\"1\", \"2\", \"3\", \"4\", \"5\", \"6\"] }"),
+            vec!["custom.test.matrix: matrix has more than 32 combinations"]
+        );
+        // type errors come first and aren't repeated by value checks
+        assert_eq!(
+            validate("[custom.ci]\ncommand = []\nretries = 11\n"),
+            vec!["custom.ci.retries: expected an integer between 0 and 10"]
+        );
+    }
+
+    #[test]
+    fn test_unknown_sections_and_profiles() {
+        assert_eq!(
+            validate(
+                r#"
+                [plugins]
+                a = "b"
+                [profile.ci.atomic]
+                auto_commit = 1
+                "#
+            ),
+            vec![
+                "plugins: unknown section",
+                "profile.ci.atomic.auto_commit: expected a boolean",
+            ]
+        );
+        assert_eq!(
+            validate("profile = 1"),
+            vec!["profile: expected a table of profiles"]
+        );
+    }
+
+    #[test]
+    fn test_severities() {
+        let config: Value = toml::from_str(
+            r#"
+            [atomic]
+            auto_commit = "yes"
+            future_setting = 1
+            [custom.fetch]
+            command = "cargo fetch"
+            colour = "red"
+            [plugins]
+            a = "b"
+            [profile.ci.plugins]
+            a = "b"
+            "#,
+        )
+        .unwrap();
+        let diagnostics = validate_toml_schema(&config);
+        let severities: Vec<(&str, Severity)> = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.path.as_str(), diagnostic.severity))
+            .collect();
+        assert_eq!(
+            severities,
+            [
+                ("atomic.auto_commit", Severity::Error),
+                ("atomic.future_setting", Severity::Warning),
+                ("custom.fetch.colour", Severity::Warning),
+                ("plugins", Severity::Warning),
+                ("profile.ci.plugins", Severity::Warning),
+            ]
+        );
+        assert_eq!(
+            error_messages(&diagnostics),
+            ["atomic.auto_commit: expected a boolean"]
+        );
+        assert_eq!(
+            Diagnostic::warning("plugins", "unknown section")
+                .in_file("api/atomic.toml")
+                .to_string(),
+            "api/atomic.toml: plugins: unknown section"
+        );
+        assert_eq!(
+            Diagnostic::error("", "atomic.toml must be a table")
+                .in_file("api/atomic.toml")
+                .to_string(),
+            "api/atomic.toml: atomic.toml must be a table"
+        );
+
+        // an unknown key doesn't hide the value checks of its command
+        assert_eq!(
+            validate("[custom.ci]\ncommand = []\ncolour = \"red\"\n"),
+            [
+                "custom.ci.colour: unknown key",
+                "custom.ci.command: chain has no steps"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_drop_unknown_sections() {
+        let mut config: Value = toml::from_str(
+            "gen = \"make\"\n[plugins]\na = 1\n[custom]\nx = \"y\"\n\
+             [profile.ci.plugins]\na = 1\n[profile.ci.custom]\nx = \"z\"\n",
+        )
+        .unwrap();
+        drop_unknown_sections(&mut config);
+        assert_eq!(
+            config,
+            toml::from_str::<Value>(
+                "gen = \"make\"\n[custom]\nx = \"y\"\n[profile.ci.custom]\nx = \"z\"\n"
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_json_schema_snapshot() {
+        let rendered = serde_json::to_string_pretty(&json_schema()).unwrap() + "\n";
+        assert_eq!(
+            rendered,
+            include_str!("../schema/atomic.schema.json"),
+            "schema/atomic.schema.json is out of date, regenerate it with `atomic --schema`"
+        );
+    }
+}
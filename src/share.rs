@@ -0,0 +1,338 @@
+//! `atomic --export` and `--import`: moving command sets between projects' atomic.toml files.
+//!
+//! Edits go through `toml_edit`, so the comments and layout of the atomic.toml being imported
+//! into survive.
+
+use toml::Value;
+use toml_edit::{DocumentMut, Item, Table};
+
+use crate::schema::{error_messages, validate_toml_schema};
+use crate::{AtomicError, Result};
+
+/// Sections an export holds; `--only` picks from these.
+pub const EXPORT_SECTIONS: &[&str] = &["default", "custom"];
+
+/// Renders `sections` of an atomic.toml as a standalone file, under a comment header naming the
+/// atomic version and `date`.
+///
+/// # Errors
+/// Returns an error if `atomic_toml` doesn't parse or has none of the sections.
+pub fn export_commands(atomic_toml: &str, sections: &[&str], date: &str) -> Result<String> {
+    let doc = parse_document("atomic.toml", atomic_toml)?;
+
+    let mut export = DocumentMut::new();
+    for section in sections {
+        if let Some(item) = doc.get(section) {
+            export.insert(section, item.clone());
+        }
+    }
+    if export.is_empty() {
+        return Err(AtomicError::Generic(format!(
+            "atomic.toml has no [{}] commands to export",
+            sections.join("] or [")
+        )));
+    }
+
+    Ok(format!(
+        "# atomic command export\n# atomic_version = \"{}\"\n# exported = \"{}\"\n\n{}",
+        env!("CARGO_PKG_VERSION"),
+        date,
+        export.to_string().trim_start()
+    ))
+}
+
+/// What importing a single command does to the local atomic.toml.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    /// Not defined locally yet.
+    Add,
+    /// Defined locally and replaced, because of `--overwrite`.
+    Overwrite,
+    /// Defined locally with something else, and left alone.
+    Keep,
+    /// Already defined exactly like this.
+    Same,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportEntry {
+    pub section: String,
+    pub key: String,
+    pub change: Change,
+}
+
+/// The result of comparing an export file with the local atomic.toml.
+#[derive(Debug)]
+pub struct ImportPlan {
+    incoming: DocumentMut,
+    pub entries: Vec<ImportEntry>,
+}
+
+impl ImportPlan {
+    /// Checks `incoming` against the schema and works out what merging it into `local` changes.
+    ///
+    /// Local commands are kept unless `overwrite` is set.
+    ///
+    /// # Errors
+    /// Returns an error if either file doesn't parse, `incoming` fails validation, or it holds
+    /// anything but [`EXPORT_SECTIONS`].
+    pub fn new(local: &str, incoming: &str, overwrite: bool) -> Result<Self> {
+        let parsed: Value = toml::from_str(incoming)
+            .map_err(|err| AtomicError::TomlParse("the import file".to_string(), err))?;
+        let errors = error_messages(&validate_toml_schema(&parsed));
+        if !errors.is_empty() {
+            return Err(AtomicError::Validation(errors));
+        }
+        let local_parsed: Value = toml::from_str(local)
+            .map_err(|err| AtomicError::TomlParse("atomic.toml".to_string(), err))?;
+
+        let mut entries = Vec::new();
+        for (section, commands) in parsed.as_table().into_iter().flatten() {
+            if !EXPORT_SECTIONS.contains(&section.as_str()) {
+                return Err(AtomicError::Generic(format!(
+                    "[{}] can't be imported; only [{}] can",
+                    section,
+                    EXPORT_SECTIONS.join("] and [")
+                )));
+            }
+            for (key, value) in commands.as_table().into_iter().flatten() {
+                let change = match local_parsed.get(section).and_then(|s| s.get(key)) {
+                    None => Change::Add,
+                    Some(existing) if existing == value => Change::Same,
+                    Some(_) if overwrite => Change::Overwrite,
+                    Some(_) => Change::Keep,
+                };
+                entries.push(ImportEntry {
+                    section: section.clone(),
+                    key: key.clone(),
+                    change,
+                });
+            }
+        }
+
+        Ok(Self {
+            incoming: parse_document("the import file", incoming)?,
+            entries,
+        })
+    }
+
+    /// Whether applying the plan would change atomic.toml at all.
+    pub fn has_changes(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| matches!(entry.change, Change::Add | Change::Overwrite))
+    }
+
+    /// One line per command that is new, replaced or kept, for showing before asking.
+    pub fn summary(&self) -> String {
+        let mut lines = Vec::new();
+        for entry in &self.entries {
+            let name = format!("{}.{}", entry.section, entry.key);
+            match entry.change {
+                Change::Add => lines.push(format!("  + {} (new)", name)),
+                Change::Overwrite => lines.push(format!("  ~ {} (overwritten)", name)),
+                Change::Keep => lines.push(format!(
+                    "  = {} (differs; keeping local, pass --overwrite to replace)",
+                    name
+                )),
+                Change::Same => {}
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Merges the new and overwritten commands into `local`, returning the updated file.
+    ///
+    /// # Errors
+    /// Returns an error if `local` doesn't parse or one of its sections isn't a table.
+    pub fn apply(&self, local: &str) -> Result<String> {
+        let mut doc = parse_document("atomic.toml", local)?;
+        let next_position = max_position(doc.as_table()).map_or(0, |p| p + 1);
+
+        for entry in &self.entries {
+            if !matches!(entry.change, Change::Add | Change::Overwrite) {
+                continue;
+            }
+            let section = doc.entry(&entry.section).or_insert_with(|| {
+                let mut table = Table::new();
+                table.set_implicit(true);
+                table.set_position(next_position);
+                Item::Table(table)
+            });
+            let Some(section) = section.as_table_mut() else {
+                return Err(AtomicError::Generic(format!(
+                    "[{}] in atomic.toml must be a table to import into",
+                    entry.section
+                )));
+            };
+
+            // new subtables go where they replace one, or after the rest of their section
+            let position = match section.get(&entry.key).and_then(Item::as_table) {
+                Some(existing) => existing.position(),
+                None => max_position(section),
+            };
+            let mut item = self.incoming[entry.section.as_str()][entry.key.as_str()].clone();
+            if let Some(position) = position {
+                set_position(&mut item, position);
+            }
+            section.insert(&entry.key, item);
+        }
+
+        Ok(doc.to_string())
+    }
+}
+
+pub fn parse_document(name: &str, text: &str) -> Result<DocumentMut> {
+    text.parse()
+        .map_err(|err| AtomicError::Generic(format!("Failed to parse {}\n{}", name, err)))
+}
+
+/// The highest position of `table` or any table nested in it.
+fn max_position(table: &Table) -> Option<usize> {
+    table
+        .iter()
+        .filter_map(|(_, item)| item.as_table().and_then(max_position))
+        .chain(table.position())
+        .max()
+}
+
+/// Places a table, and the tables nested in it, at `position` in the document.
+fn set_position(item: &mut Item, position: usize) {
+    if let Item::Table(table) = item {
+        table.set_position(position);
+        for (_, child) in table.iter_mut() {
+            set_position(child, position);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHARED: &str = r#"# team commands
+[default]
+check = "cargo check"
+
+[custom]
+fmt = "cargo fmt --all"
+lint = "cargo clippy -- -D warnings"
+
+[custom.deploy]
+command = "./deploy.sh"
+retries = 2
+
+[atomic]
+auto_commit = false
+"#;
+
+    const LOCAL: &str = r#"# my project
+[custom]
+# keep this one strict
+lint = "cargo clippy --all-targets"
+
+[custom.release]
+command = "cargo publish"   # manual for now
+
+[atomic]
+base_branch = "develop"
+"#;
+
+    fn change(plan: &ImportPlan, key: &str) -> Change {
+        plan.entries
+            .iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.change)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_export_commands() {
+        let export = export_commands(SHARED, &["custom"], "2024-05-01").unwrap();
+        assert!(export.starts_with(&format!(
+            "# atomic command export\n# atomic_version = \"{}\"\n# exported = \"2024-05-01\"\n",
+            env!("CARGO_PKG_VERSION")
+        )));
+        let parsed: Value = toml::from_str(&export).unwrap();
+        let sections: Vec<_> = parsed.as_table().unwrap().keys().collect();
+        assert_eq!(sections, ["custom"]);
+        assert_eq!(parsed["custom"]["deploy"]["retries"].as_integer(), Some(2));
+
+        let both: Value =
+            toml::from_str(&export_commands(SHARED, EXPORT_SECTIONS, "2024-05-01").unwrap())
+                .unwrap();
+        assert_eq!(both.as_table().unwrap().len(), 2);
+
+        assert!(export_commands(LOCAL, &["default"], "2024-05-01").is_err());
+    }
+
+    #[test]
+    fn test_import_round_trip() {
+        let export = export_commands(SHARED, EXPORT_SECTIONS, "2024-05-01").unwrap();
+
+        let plan = ImportPlan::new(LOCAL, &export, false).unwrap();
+        assert_eq!(change(&plan, "check"), Change::Add);
+        assert_eq!(change(&plan, "fmt"), Change::Add);
+        assert_eq!(change(&plan, "lint"), Change::Keep);
+        assert_eq!(change(&plan, "deploy"), Change::Add);
+        assert!(plan.has_changes());
+        assert_eq!(
+            plan.summary(),
+            "  + custom.deploy (new)\n  + custom.fmt (new)\n  \
+             = custom.lint (differs; keeping local, pass --overwrite to replace)\n  \
+             + default.check (new)"
+        );
+
+        let merged = plan.apply(LOCAL).unwrap();
+        // local comments and settings survive
+        assert!(merged.starts_with("# my project\n[custom]\n# keep this one strict\n"));
+        assert!(merged.contains("command = \"cargo publish\"   # manual for now"));
+        let parsed: Value = toml::from_str(&merged).unwrap();
+        assert_eq!(
+            parsed["custom"]["lint"].as_str(),
+            Some("cargo clippy --all-targets")
+        );
+        assert_eq!(parsed["custom"]["fmt"].as_str(), Some("cargo fmt --all"));
+        assert_eq!(parsed["custom"]["deploy"]["retries"].as_integer(), Some(2));
+        assert_eq!(parsed["default"]["check"].as_str(), Some("cargo check"));
+        assert_eq!(parsed["atomic"]["base_branch"].as_str(), Some("develop"));
+        assert!(validate_toml_schema(&parsed).is_empty());
+        // [custom.deploy] lands with the rest of [custom], before [atomic]
+        assert!(merged.find("[custom.deploy]") < merged.find("[atomic]"));
+
+        // importing again changes nothing
+        let again = ImportPlan::new(&merged, &export, false).unwrap();
+        assert!(!again.has_changes());
+    }
+
+    #[test]
+    fn test_import_overwrite() {
+        let export = export_commands(SHARED, &["custom"], "2024-05-01").unwrap();
+        let plan = ImportPlan::new(LOCAL, &export, true).unwrap();
+        assert_eq!(change(&plan, "lint"), Change::Overwrite);
+
+        let parsed: Value = toml::from_str(&plan.apply(LOCAL).unwrap()).unwrap();
+        assert_eq!(
+            parsed["custom"]["lint"].as_str(),
+            Some("cargo clippy -- -D warnings")
+        );
+        assert_eq!(
+            parsed["custom"]["release"]["command"].as_str(),
+            Some("cargo publish")
+        );
+    }
+
+    #[test]
+    fn test_import_rejects_invalid_files() {
+        assert!(matches!(
+            ImportPlan::new(LOCAL, "[custom.deploy]\nretries = 2\n", false),
+            Err(AtomicError::Validation(_))
+        ));
+        assert_eq!(
+            ImportPlan::new(LOCAL, "[atomic]\nauto_commit = false\n", false).map(|_| ()),
+            Err(AtomicError::Generic(
+                "[atomic] can't be imported; only [default] and [custom] can".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Signed auto-commits, for repositories whose branch protection only takes signed commits.
+//!
+//! libgit2 doesn't sign by itself, so this follows git: when `commit.gpgsign` is set, the commit
+//! is written out as a buffer, signed by the program `gpg.format` picks (`gpg`, `ssh-keygen -Y
+//! sign` or `gpgsm`) with `user.signingkey`, and stored with the signature in its header.
+
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use crate::{AtomicError, Result};
+
+/// `gpg.format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    OpenPgp,
+    Ssh,
+    X509,
+}
+
+/// How to sign commits, as set in git config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signer {
+    pub format: Format,
+    /// `user.signingkey`; OpenPGP and X.509 fall back to the committer's identity without it.
+    pub key: Option<String>,
+    /// `gpg.program`, `gpg.ssh.program` or `gpg.x509.program`.
+    pub program: String,
+}
+
+impl Signer {
+    /// The signer `config` asks for, or `None` when `commit.gpgsign` isn't set.
+    ///
+    /// # Errors
+    /// Returns [`AtomicError::Signing`] for an unknown `gpg.format`, or SSH signing without a key.
+    pub fn from_config(config: &git2::Config) -> Result<Option<Self>> {
+        if !config.get_bool("commit.gpgsign").unwrap_or(false) {
+            return Ok(None);
+        }
+        let format = match config.get_string("gpg.format").ok().as_deref() {
+            None | Some("openpgp") => Format::OpenPgp,
+            Some("ssh") => Format::Ssh,
+            Some("x509") => Format::X509,
+            Some(other) => {
+                return Err(AtomicError::Signing(format!(
+                    "unknown gpg.format '{}'",
+                    other
+                )))
+            }
+        };
+        let (key, default) = match format {
+            Format::OpenPgp => ("gpg.openpgp.program", "gpg"),
+            Format::Ssh => ("gpg.ssh.program", "ssh-keygen"),
+            Format::X509 => ("gpg.x509.program", "gpgsm"),
+        };
+        let program = config
+            .get_string(key)
+            .or_else(|_| match format {
+                // the name git used before there were other formats
+                Format::OpenPgp => config.get_string("gpg.program"),
+                _ => Err(git2::Error::from_str("unset")),
+            })
+            .unwrap_or_else(|_| default.to_string());
+        let key = config
+            .get_string("user.signingkey")
+            .ok()
+            .filter(|key| !key.is_empty());
+        if format == Format::Ssh && key.is_none() {
+            return Err(AtomicError::Signing(
+                "gpg.format is ssh, but user.signingkey is not set".to_string(),
+            ));
+        }
+        Ok(Some(Self {
+            format,
+            key,
+            program,
+        }))
+    }
+
+    /// Signs the commit `buffer`, returning the armored signature.
+    ///
+    /// # Errors
+    /// Returns [`AtomicError::Signing`] if the program can't be started, fails, or prints no
+    /// signature.
+    pub fn sign(&self, buffer: &[u8]) -> Result<String> {
+        let mut command = Command::new(&self.program);
+        // an SSH key given inline has to be written out for ssh-keygen
+        let mut inline_key = None;
+        match self.format {
+            Format::OpenPgp | Format::X509 => {
+                command.arg("--status-fd=2").arg("-bsa");
+                if let Some(key) = &self.key {
+                    command.arg("-u").arg(key);
+                }
+            }
+            Format::Ssh => {
+                let key = self.key.as_deref().unwrap_or_default();
+                let file = match key.strip_prefix("key::") {
+                    Some(public) => {
+                        let file = inline_key_file(public)?;
+                        inline_key = Some(file.clone());
+                        file
+                    }
+                    None => expand_home(key),
+                };
+                command.args(["-Y", "sign", "-n", "git", "-f"]).arg(file);
+                if inline_key.is_some() {
+                    // the private half is in ssh-agent
+                    command.arg("-U");
+                }
+            }
+        }
+        let output = run(command, buffer);
+        if let Some(file) = inline_key {
+            let _ = fs::remove_file(file);
+        }
+        let output = output.map_err(|err| {
+            AtomicError::Signing(format!("couldn't run {}: {}", self.program, err))
+        })?;
+        let signature = String::from_utf8_lossy(&output.stdout).into_owned();
+        if !output.status.success() || signature.trim().is_empty() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = stderr
+                .lines()
+                .filter(|line| !line.starts_with("[GNUPG:]"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(AtomicError::Signing(format!(
+                "{} failed{}",
+                self.program,
+                match reason.trim() {
+                    "" => String::new(),
+                    reason => format!(": {}", reason),
+                }
+            )));
+        }
+        Ok(signature)
+    }
+}
+
+/// Runs `command` with `input` on its stdin, collecting its output.
+fn run(mut command: Command, input: &[u8]) -> std::io::Result<std::process::Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // write from another thread so a program that answers while still reading can't deadlock
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    // a program that exits without reading everything is reported through its status instead
+    let _ = writer.join();
+    Ok(output)
+}
+
+fn inline_key_file(public: &str) -> Result<PathBuf> {
+    let file = env::temp_dir().join(format!("atomic-signing-key-{}.pub", std::process::id()));
+    fs::write(&file, format!("{}\n", public.trim())).map_err(|err| {
+        AtomicError::Signing(format!(
+            "couldn't write the public key for ssh-keygen: {}",
+            err
+        ))
+    })?;
+    Ok(file)
+}
+
+/// `~/.ssh/id_ed25519` with the home directory filled in, as git does for `user.signingkey`.
+fn expand_home(key: &str) -> PathBuf {
+    match (key.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(entries: &[(&str, &str)]) -> (tempfile::TempDir, git2::Config) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = git2::Config::open(&dir.path().join("config")).unwrap();
+        for (key, value) in entries {
+            config.set_str(key, value).unwrap();
+        }
+        (dir, config)
+    }
+
+    #[test]
+    fn test_from_config() {
+        let signer = |entries: &[(&str, &str)]| Signer::from_config(&config(entries).1);
+
+        assert_eq!(signer(&[("user.signingkey", "ABCD")]), Ok(None));
+        assert_eq!(signer(&[("commit.gpgsign", "false")]), Ok(None));
+        assert_eq!(
+            signer(&[("commit.gpgsign", "true")]),
+            Ok(Some(Signer {
+                format: Format::OpenPgp,
+                key: None,
+                program: "gpg".to_string(),
+            }))
+        );
+        assert_eq!(
+            signer(&[
+                ("commit.gpgsign", "true"),
+                ("gpg.program", "gpg2"),
+                ("user.signingkey", "ABCD"),
+            ]),
+            Ok(Some(Signer {
+                format: Format::OpenPgp,
+                key: Some("ABCD".to_string()),
+                program: "gpg2".to_string(),
+            }))
+        );
+        assert_eq!(
+            signer(&[
+                ("commit.gpgsign", "true"),
+                ("gpg.format", "ssh"),
+                ("user.signingkey", "~/.ssh/id_ed25519.pub"),
+            ]),
+            Ok(Some(Signer {
+                format: Format::Ssh,
+                key: Some("~/.ssh/id_ed25519.pub".to_string()),
+                program: "ssh-keygen".to_string(),
+            }))
+        );
+        assert!(signer(&[("commit.gpgsign", "true"), ("gpg.format", "ssh")]).is_err());
+        let unknown = signer(&[("commit.gpgsign", "true"), ("gpg.format", "pgp")]);
+        assert!(unknown
+            .unwrap_err()
+            .to_string()
+            .contains("unknown gpg.format 'pgp'"));
+    }
+
+    #[test]
+    fn test_signing_program_fails() {
+        let signer = Signer {
+            format: Format::OpenPgp,
+            key: None,
+            program: "atomic-no-such-gpg".to_string(),
+        };
+        let err = signer.sign(b"tree 0\n").unwrap_err().to_string();
+        assert!(err.contains("couldn't run atomic-no-such-gpg"), "{}", err);
+        assert!(err.contains("--no-sign"), "{}", err);
+    }
+}
@@ -0,0 +1,325 @@
+//! `atomic --stats`: how often each command runs, kept in `.atomic/stats.toml`.
+//!
+//! Every run adds to the file under a lock, re-reading it first, so runs from several terminals
+//! all count. It's a nicety, so a file that can't be read starts over instead of failing a run.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::git::relative_time;
+use crate::last_run::{state_dir, STATE_DIR};
+use crate::{AtomicError, Result};
+
+const FILE: &str = "stats.toml";
+/// Held while the file is read and written again.
+const LOCK_FILE: &str = "stats.lock";
+/// Version written to new files; bump it when older atomics would misread them.
+const VERSION: u32 = 1;
+
+/// Usage of every command that has run in a project.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub commands: BTreeMap<String, CommandStats>,
+}
+
+/// Usage of one command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CommandStats {
+    pub runs: u64,
+    /// When it last ran, in seconds since the Unix epoch.
+    pub last_used: u64,
+    /// Time spent in all its runs together, in milliseconds.
+    pub total_ms: u64,
+}
+
+impl CommandStats {
+    pub fn total(&self) -> Duration {
+        Duration::from_millis(self.total_ms)
+    }
+
+    /// `2 hours ago`, as of `now`.
+    pub fn last_used_ago(&self, now: u64) -> String {
+        relative_time(now.saturating_sub(self.last_used) as i64)
+    }
+}
+
+/// `12 runs, last used 2 hours ago`, or `never run`, for `--list --stats`.
+pub fn describe(stats: Option<&CommandStats>, now: u64) -> String {
+    match stats {
+        Some(stats) => format!(
+            "{} run{}, last used {}",
+            stats.runs,
+            if stats.runs == 1 { "" } else { "s" },
+            stats.last_used_ago(now)
+        ),
+        None => "never run".to_string(),
+    }
+}
+
+/// The `--stats` table: every command that has run, most used first.
+pub fn render(stats: &Stats, now: u64) -> String {
+    let rows: Vec<[String; 4]> = stats
+        .by_runs()
+        .into_iter()
+        .map(|(name, stats)| {
+            [
+                name.to_string(),
+                stats.runs.to_string(),
+                stats.last_used_ago(now),
+                format_elapsed(stats.total()),
+            ]
+        })
+        .collect();
+    let header = ["command", "runs", "last used", "total time"].map(str::to_string);
+    let mut widths = header.clone().map(|cell| cell.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut out = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = format!(
+            "{:<w0$}  {:>w1$}  {:<w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+impl Stats {
+    /// Counts a run of `name` that took `elapsed` and finished at `now`.
+    pub fn add(&mut self, name: &str, elapsed: Duration, now: u64) {
+        let stats = self.commands.entry(name.to_string()).or_default();
+        stats.runs += 1;
+        stats.last_used = stats.last_used.max(now);
+        stats.total_ms += u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+    }
+
+    /// Commands from most to least used, most recent first among equals.
+    pub fn by_runs(&self) -> Vec<(&str, &CommandStats)> {
+        let mut commands: Vec<(&str, &CommandStats)> = self
+            .commands
+            .iter()
+            .map(|(name, stats)| (name.as_str(), stats))
+            .collect();
+        commands.sort_by(|a, b| {
+            (b.1.runs, b.1.last_used)
+                .cmp(&(a.1.runs, a.1.last_used))
+                .then(a.0.cmp(b.0))
+        });
+        commands
+    }
+}
+
+/// Where the stats of the project around `atomic` are kept.
+pub fn path(atomic: &Path) -> PathBuf {
+    atomic
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(STATE_DIR)
+        .join(FILE)
+}
+
+/// Reads the stats of the project around `atomic`: empty when nothing has run yet, and when the
+/// file can't be read, after a warning.
+pub fn load(atomic: &Path) -> Stats {
+    let file = path(atomic);
+    let Ok(text) = fs::read_to_string(&file) else {
+        return Stats::default();
+    };
+    match ::toml::from_str(&text) {
+        Ok(stats) => stats,
+        Err(err) => {
+            eprintln!(
+                "warning: {} is unreadable, so usage stats start over: {}",
+                file.display(),
+                err
+            );
+            Stats::default()
+        }
+    }
+}
+
+/// Writes `stats` as the stats of the project around `atomic`.
+///
+/// # Errors
+/// Returns an error if the state directory or file can't be written.
+pub fn save(atomic: &Path, stats: &Stats) -> Result<()> {
+    let file = path(atomic);
+    let stats = Stats {
+        version: VERSION,
+        ..stats.clone()
+    };
+    let text = ::toml::to_string(&stats)
+        .map_err(|err| AtomicError::Generic(format!("Failed to serialize stats: {}", err)))?;
+    state_dir(atomic, None)
+        .and_then(|_| fs::write(&file, text))
+        .map_err(|err| AtomicError::Generic(format!("Failed to write {}: {}", file.display(), err)))
+}
+
+/// Re-reads the stats, applies `change` and writes them back, holding a lock throughout so
+/// concurrent runs don't lose each other's updates.
+///
+/// # Errors
+/// Returns an error if the lock can't be taken or the file can't be written.
+pub fn update(atomic: &Path, change: impl FnOnce(&mut Stats)) -> Result<()> {
+    let lock_path = path(atomic).with_file_name(LOCK_FILE);
+    let io_error = |err: std::io::Error| {
+        AtomicError::Generic(format!("Failed to lock {}: {}", lock_path.display(), err))
+    };
+    state_dir(atomic, None).map_err(io_error)?;
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .map_err(io_error)?;
+    lock.lock().map_err(io_error)?;
+
+    let mut stats = load(atomic);
+    change(&mut stats);
+    save(atomic, &stats)
+}
+
+/// Counts a run of `name` that took `elapsed`.
+///
+/// # Errors
+/// See [`update`].
+pub fn record(atomic: &Path, name: &str, elapsed: Duration) -> Result<()> {
+    let now = now();
+    update(atomic, |stats| stats.add(name, elapsed, now))
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Forgets every recorded run.
+///
+/// # Errors
+/// See [`update`].
+pub fn reset(atomic: &Path) -> Result<()> {
+    update(atomic, |stats| *stats = Stats::default())
+}
+
+/// `1h 5m`, `3m 12s` or `4.2s`.
+pub fn format_elapsed(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    match seconds {
+        0..=59 => format!("{:.1}s", elapsed.as_secs_f64()),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_add_and_sort() {
+        let mut stats = Stats::default();
+        stats.add("build", Duration::from_millis(1500), 100);
+        stats.add("test", Duration::from_secs(2), 50);
+        stats.add("test", Duration::from_secs(3), 300);
+        stats.add("lint", Duration::ZERO, 200);
+
+        let test = stats.commands["test"];
+        assert_eq!(test.runs, 2);
+        assert_eq!(test.last_used, 300);
+        assert_eq!(test.total(), Duration::from_secs(5));
+        let order: Vec<&str> = stats.by_runs().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(order, ["test", "lint", "build"]);
+
+        assert_eq!(format_elapsed(Duration::from_millis(4200)), "4.2s");
+        assert_eq!(format_elapsed(Duration::from_secs(192)), "3m 12s");
+        assert_eq!(format_elapsed(Duration::from_secs(3900)), "1h 5m");
+
+        assert_eq!(
+            render(&stats, 300 + 7200),
+            "command  runs  last used    total time\n\
+             test        2  2 hours ago  5.0s\n\
+             lint        1  2 hours ago  0.0s\n\
+             build       1  2 hours ago  1.5s\n"
+        );
+        assert_eq!(
+            describe(stats.commands.get("lint"), 200 + 180),
+            "1 run, last used 3 minutes ago"
+        );
+        assert_eq!(describe(None, 0), "never run");
+    }
+
+    #[test]
+    fn test_save_load_and_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        assert_eq!(load(&atomic), Stats::default());
+
+        record(&atomic, "build", Duration::from_secs(1)).unwrap();
+        let stats = load(&atomic);
+        assert_eq!(stats.version, VERSION);
+        assert_eq!(stats.commands["build"].runs, 1);
+        assert!(stats.commands["build"].last_used > 0);
+
+        fs::write(path(&atomic), "commands = 4\n[[[").unwrap();
+        assert_eq!(load(&atomic), Stats::default());
+        record(&atomic, "build", Duration::from_secs(1)).unwrap();
+        assert_eq!(load(&atomic).commands["build"].runs, 1);
+
+        reset(&atomic).unwrap();
+        assert!(load(&atomic).commands.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_updates_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        // an entry written by another atomic before these runs
+        let mut before = Stats::default();
+        before.add("deploy", Duration::from_secs(60), 1);
+        save(&atomic, &before).unwrap();
+
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let atomic = atomic.clone();
+                thread::spawn(move || {
+                    let name = if i % 2 == 0 { "build" } else { "test" };
+                    for _ in 0..10 {
+                        record(&atomic, name, Duration::from_millis(10)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let stats = load(&atomic);
+        assert_eq!(stats.commands["build"].runs, 40);
+        assert_eq!(stats.commands["test"].runs, 40);
+        assert_eq!(stats.commands["test"].total_ms, 400);
+        assert_eq!(stats.commands["deploy"].runs, 1);
+    }
+}
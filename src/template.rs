@@ -0,0 +1,372 @@
+//! `atomic --template <show|diff|update> <name>`: the built-in templates, compared with
+//! atomic.toml, and kept up to date when saved locally.
+//!
+//! A copy saved as `templates/<name>.toml` next to the global config takes the place of the
+//! bundled template of that name for `show` and `diff`. `update` rewrites it from the bundled one.
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use toml::{Table, Value};
+
+use crate::init::Template;
+use crate::{AtomicError, Result};
+
+/// What `--template` can do.
+pub const ACTIONS: &[&str] = &["show", "diff", "update"];
+
+/// Directory next to the global config that saved templates are kept in.
+const DIR: &str = "templates";
+
+/// Where the saved copy of `template` is kept, next to the global config at `global`.
+pub fn saved_path(global: &Path, template: Template) -> PathBuf {
+    global
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(DIR)
+        .join(format!("{}.toml", template.name.to_lowercase()))
+}
+
+/// The text of the saved copy at `saved`, or `None` when there isn't one.
+///
+/// # Errors
+/// Returns an error if the file exists but can't be read.
+pub fn read_saved(saved: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(saved) {
+        Ok(text) => Ok(Some(text)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(AtomicError::Generic(format!(
+            "Unable to read {}: {}",
+            saved.display(),
+            err
+        ))),
+    }
+}
+
+/// Parses a template, or atomic.toml, called `name` in errors.
+///
+/// # Errors
+/// Returns [`AtomicError::TomlParse`] if `text` isn't TOML.
+pub fn parse(name: &str, text: &str) -> Result<Table> {
+    ::toml::from_str(text).map_err(|err| AtomicError::TomlParse(name.to_string(), err))
+}
+
+/// How a key differs between two configs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyChange {
+    /// Only in the new config.
+    Added(Value),
+    /// Only in the old config.
+    Removed(Value),
+    Changed {
+        old: Value,
+        new: Value,
+    },
+}
+
+/// A key that differs between two configs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyDiff {
+    /// The top-level table the key is in, or empty for a key at the top level.
+    pub section: String,
+    /// The key within the section, dotted when it's in a table below it, e.g. `build.cmd`.
+    pub key: String,
+    pub change: KeyChange,
+}
+
+/// Compares two parsed configs key by key, grouped by section, so comments, formatting and
+/// order don't count.
+///
+/// Tables on both sides are compared key by key; anything else, arrays included, is compared
+/// whole. The result is sorted by section, with the top level first, then by key.
+pub fn structural_diff(old: &Table, new: &Table) -> Vec<KeyDiff> {
+    let mut diffs = Vec::new();
+    // keys that are a table on neither side are the top level's own
+    let plain = |key: &str| {
+        ![old, new]
+            .iter()
+            .any(|table| table.get(key).is_some_and(Value::is_table))
+    };
+    diff_tables(
+        "",
+        "",
+        &top_level(old, plain),
+        &top_level(new, plain),
+        &mut diffs,
+    );
+
+    let sections: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for section in sections {
+        match (old.get(section), new.get(section)) {
+            (Some(Value::Table(old)), Some(Value::Table(new))) => {
+                diff_tables(section, "", old, new, &mut diffs)
+            }
+            // a section on one side only: each of its keys is added or removed
+            (Some(Value::Table(old)), None) => {
+                diff_tables(section, "", old, &Table::new(), &mut diffs)
+            }
+            (None, Some(Value::Table(new))) => {
+                diff_tables(section, "", &Table::new(), new, &mut diffs)
+            }
+            // a section on one side and a plain key on the other
+            (Some(old), Some(new)) if old.is_table() || new.is_table() => diffs.push(KeyDiff {
+                section: String::new(),
+                key: section.clone(),
+                change: KeyChange::Changed {
+                    old: old.clone(),
+                    new: new.clone(),
+                },
+            }),
+            _ => {}
+        }
+    }
+    diffs.sort_by(|a, b| (&a.section, &a.key).cmp(&(&b.section, &b.key)));
+    diffs
+}
+
+fn top_level(table: &Table, keep: impl Fn(&str) -> bool) -> Table {
+    table
+        .iter()
+        .filter(|(key, _)| keep(key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+fn diff_tables(section: &str, prefix: &str, old: &Table, new: &Table, diffs: &mut Vec<KeyDiff>) {
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for key in keys {
+        let path = format!("{}{}", prefix, key);
+        let change = match (old.get(key), new.get(key)) {
+            (Some(old), Some(new)) if old == new => continue,
+            (Some(Value::Table(old)), Some(Value::Table(new))) => {
+                diff_tables(section, &format!("{}.", path), old, new, diffs);
+                continue;
+            }
+            (Some(old), Some(new)) => KeyChange::Changed {
+                old: old.clone(),
+                new: new.clone(),
+            },
+            (Some(old), None) => KeyChange::Removed(old.clone()),
+            (None, Some(new)) => KeyChange::Added(new.clone()),
+            (None, None) => continue,
+        };
+        diffs.push(KeyDiff {
+            section: section.to_string(),
+            key: path,
+            change,
+        });
+    }
+}
+
+/// `diffs` under a `[section]` header each: `+ key = value` for added keys, `- key = value` for
+/// removed ones, and `~ key = old -> new` for changed ones.
+pub fn render(diffs: &[KeyDiff]) -> String {
+    let mut out = String::new();
+    let mut section = None;
+    for diff in diffs {
+        if section != Some(&diff.section) {
+            match diff.section.as_str() {
+                "" => out.push_str("(top level)\n"),
+                name => out.push_str(&format!("[{}]\n", name)),
+            }
+            section = Some(&diff.section);
+        }
+        let line = match &diff.change {
+            KeyChange::Added(value) => format!("  + {} = {}", diff.key, value),
+            KeyChange::Removed(value) => format!("  - {} = {}", diff.key, value),
+            KeyChange::Changed { old, new } => format!("  ~ {} = {} -> {}", diff.key, old, new),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init::template_named;
+
+    fn table(text: &str) -> Table {
+        parse("test.toml", text).unwrap()
+    }
+
+    fn added(section: &str, key: &str, value: Value) -> KeyDiff {
+        KeyDiff {
+            section: section.to_string(),
+            key: key.to_string(),
+            change: KeyChange::Added(value),
+        }
+    }
+
+    fn removed(section: &str, key: &str, value: Value) -> KeyDiff {
+        KeyDiff {
+            section: section.to_string(),
+            key: key.to_string(),
+            change: KeyChange::Removed(value),
+        }
+    }
+
+    fn changed(section: &str, key: &str, old: Value, new: Value) -> KeyDiff {
+        KeyDiff {
+            section: section.to_string(),
+            key: key.to_string(),
+            change: KeyChange::Changed { old, new },
+        }
+    }
+
+    fn string(text: &str) -> Value {
+        Value::String(text.to_string())
+    }
+
+    #[test]
+    fn test_identical_configs() {
+        let text = "[default]\nfmt = \"cargo fmt\"\n\n[custom.build]\ncmd = \"cargo build\"\n";
+        assert!(structural_diff(&table(text), &table(text)).is_empty());
+        // comments, layout and order don't count
+        let reordered = "# build things\n[custom]\nbuild = { cmd = \"cargo build\" }\n\n\
+                         [default]\nfmt   =   \"cargo fmt\" # format\n";
+        assert!(structural_diff(&table(text), &table(reordered)).is_empty());
+    }
+
+    #[test]
+    fn test_scalar_keys() {
+        let old = table("[default]\nfmt = \"cargo fmt\"\nmine = \"echo mine\"\n");
+        let new = table(
+            "[default]\nfmt = \"cargo fmt --all\"\ncheck = \"cargo check\"\n\n\
+             [atomic]\nauto_commit = false\n",
+        );
+        assert_eq!(
+            structural_diff(&old, &new),
+            [
+                added("atomic", "auto_commit", Value::Boolean(false)),
+                added("default", "check", string("cargo check")),
+                changed(
+                    "default",
+                    "fmt",
+                    string("cargo fmt"),
+                    string("cargo fmt --all")
+                ),
+                removed("default", "mine", string("echo mine")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_tables_are_compared_by_key() {
+        let old = table(
+            "[custom.build]\ncmd = \"cargo build\"\ndesc = \"build it\"\n\n\
+             [profile.ci.custom]\nlint = \"cargo clippy\"\n",
+        );
+        let new = table(
+            "[custom.build]\ncmd = \"cargo build --release\"\ndesc = \"build it\"\n\n\
+             [profile.ci.custom]\nlint = \"cargo clippy\"\ntest = \"cargo test\"\n",
+        );
+        assert_eq!(
+            structural_diff(&old, &new),
+            [
+                changed(
+                    "custom",
+                    "build.cmd",
+                    string("cargo build"),
+                    string("cargo build --release")
+                ),
+                added("profile", "ci.custom.test", string("cargo test")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_values_of_different_kinds() {
+        let old = table("[custom]\nall = \"make\"\nci = [\"fmt\", \"test\"]\n");
+        let new = table("[custom]\nall = [\"fmt\"]\nci = [\"fmt\", \"lint\", \"test\"]\n");
+        let diffs = structural_diff(&old, &new);
+        assert_eq!(diffs.len(), 2);
+        // arrays are compared whole
+        assert_eq!(diffs[1].key, "ci");
+        assert!(matches!(diffs[0].change, KeyChange::Changed { .. }));
+
+        // a command that's a table on one side and a string on the other is one change
+        let detailed = table("[custom.build]\ncmd = \"cargo build\"\n");
+        let plain = table("[custom]\nbuild = \"cargo build\"\n");
+        let diffs = structural_diff(&plain, &detailed);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].key, "build");
+    }
+
+    #[test]
+    fn test_top_level_keys_and_whole_sections() {
+        let old = table("gen = \"make gen\"\n\n[default]\nfmt = \"cargo fmt\"\n");
+        let new = table("gen = \"make all\"\n\n[custom]\nlint = \"cargo clippy\"\n");
+        assert_eq!(
+            structural_diff(&old, &new),
+            [
+                changed("", "gen", string("make gen"), string("make all")),
+                added("custom", "lint", string("cargo clippy")),
+                removed("default", "fmt", string("cargo fmt")),
+            ]
+        );
+
+        // a section on one side is a plain key on the other
+        let keyed = table("default = \"odd\"\n");
+        let sectioned = table("[default]\nfmt = \"cargo fmt\"\n");
+        let diffs = structural_diff(&keyed, &sectioned);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(
+            (diffs[0].section.as_str(), diffs[0].key.as_str()),
+            ("", "default")
+        );
+        assert!(matches!(diffs[0].change, KeyChange::Changed { .. }));
+    }
+
+    #[test]
+    fn test_render() {
+        let old = table("gen = \"make\"\n\n[default]\nfmt = \"cargo fmt\"\nmine = \"x\"\n");
+        let new = table(
+            "gen = \"make all\"\n\n[default]\nfmt = \"cargo fmt --all\"\n\n\
+             [custom]\nci = [\"fmt\", \"test\"]\n",
+        );
+        assert_eq!(
+            render(&structural_diff(&old, &new)),
+            "(top level)\n  ~ gen = \"make\" -> \"make all\"\n\
+             [custom]\n  + ci = [\"fmt\", \"test\"]\n\
+             [default]\n  ~ fmt = \"cargo fmt\" -> \"cargo fmt --all\"\n  - mine = \"x\"\n"
+        );
+        assert_eq!(render(&[]), "");
+    }
+
+    #[test]
+    fn test_bundled_templates_against_themselves() {
+        for name in ["rust", "generic"] {
+            let template = template_named(name).unwrap();
+            let parsed = parse(name, template.text).unwrap();
+            assert!(structural_diff(&parsed, &parsed).is_empty());
+            // from nothing, every key is added
+            assert!(structural_diff(&Table::new(), &parsed)
+                .iter()
+                .all(|diff| matches!(diff.change, KeyChange::Added(_))));
+        }
+    }
+
+    #[test]
+    fn test_saved_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let global = dir.path().join("atomic/config.toml");
+        let rust = template_named("rust").unwrap();
+        let saved = saved_path(&global, rust);
+        assert_eq!(saved, dir.path().join("atomic/templates/rust.toml"));
+        assert_eq!(read_saved(&saved), Ok(None));
+
+        fs::create_dir_all(saved.parent().unwrap()).unwrap();
+        fs::write(&saved, "[default]\nfmt = \"cargo fmt\"\n").unwrap();
+        assert_eq!(
+            read_saved(&saved),
+            Ok(Some("[default]\nfmt = \"cargo fmt\"\n".to_string()))
+        );
+        assert!(parse("rust.toml", "[default\n").is_err());
+    }
+}
@@ -0,0 +1,41 @@
+//! Helpers shared by the unit tests of several modules.
+
+use std::{fs, path::Path};
+
+use git2::Repository;
+
+/// A stand-in for the process environment holding only `vars`, for functions that take a
+/// `var` lookup instead of reading the environment themselves.
+pub fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+    move |key| {
+        vars.iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.to_string())
+    }
+}
+
+/// Creates a repository in `dir` with a configured user and an initial commit holding `files`,
+/// along with whatever `dir` already has.
+pub fn init_repo(dir: &Path, files: &[(&str, &str)]) -> Repository {
+    let repo = Repository::init(dir).unwrap();
+    {
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Atomic Tester").unwrap();
+        config.set_str("user.email", "tester@example.com").unwrap();
+    }
+    for (name, contents) in files {
+        fs::write(dir.join(name), contents).unwrap();
+    }
+
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+        .unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = repo.signature().unwrap();
+    repo.commit(Some("HEAD"), &sig, &sig, "initial", &tree, &[])
+        .unwrap();
+    drop(tree);
+    repo
+}
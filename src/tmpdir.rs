@@ -0,0 +1,161 @@
+//! `tmpdir = true`: a scratch directory made for a step, passed to it as `ATOMIC_TMPDIR`, and
+//! removed once the step is done.
+//!
+//! The directories live in `.atomic/tmp/` rather than the system temp directory, so container
+//! steps see them under the mounted project root too.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::last_run::state_dir;
+use crate::{AtomicError, Result};
+
+/// The variable holding the directory.
+pub const ENV_VAR: &str = "ATOMIC_TMPDIR";
+
+/// Tells directories made by one atomic apart.
+static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// A step's scratch directory, removed by [`TmpDir::finish`].
+#[derive(Debug)]
+pub struct TmpDir {
+    path: PathBuf,
+}
+
+impl TmpDir {
+    /// Makes a new, empty directory in `.atomic/tmp/` next to `atomic`, named after `step`.
+    ///
+    /// # Errors
+    /// Returns an error if the directory can't be created.
+    pub fn create(atomic: &Path, step: &str) -> Result<Self> {
+        let io_error = |err: io::Error| {
+            AtomicError::Generic(format!("Failed to create a tmpdir for '{}': {}", step, err))
+        };
+        let parent = state_dir(atomic, Some("tmp")).map_err(io_error)?;
+        let slug: String = step
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .take(32)
+            .collect();
+        loop {
+            let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = parent.join(format!("{}-{}-{}", slug, process::id(), n));
+            match fs::create_dir(&path) {
+                Ok(()) => {
+                    let path = fs::canonicalize(&path).unwrap_or(path);
+                    return Ok(Self { path });
+                }
+                // left behind by an earlier atomic with the same pid
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(io_error(err)),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes the directory, or leaves it in place and says where when `keep` is set.
+    pub fn finish(self, step: &str, keep: bool) {
+        if keep {
+            eprintln!("kept {} of '{}': {}", ENV_VAR, step, self.path.display());
+        } else if let Err(err) = remove(&self.path) {
+            eprintln!(
+                "warning: couldn't remove {} of '{}' ({}): {}",
+                ENV_VAR,
+                step,
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Removes `dir` and everything in it, first making read-only entries writable when that's
+/// what stands in the way.
+pub fn remove(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            make_writable(dir);
+            fs::remove_dir_all(dir)
+        }
+        result => result,
+    }
+}
+
+/// Best-effort: gives the owner write access to `path` and everything under it.
+fn make_writable(path: &Path) {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return;
+    };
+    if metadata.file_type().is_symlink() {
+        return;
+    }
+    let mut permissions = metadata.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // directories also need to be listable to be emptied
+        let extra = if metadata.is_dir() { 0o700 } else { 0o200 };
+        permissions.set_mode(permissions.mode() | extra);
+    }
+    #[cfg(not(unix))]
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    let _ = fs::set_permissions(path, permissions);
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+            make_writable(&entry.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_and_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+
+        let first = TmpDir::create(&atomic, "gen docs").unwrap();
+        let second = TmpDir::create(&atomic, "gen docs").unwrap();
+        assert_ne!(first.path(), second.path());
+        assert!(first.path().is_dir());
+        let name = first.path().file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("gen-docs-"), "{}", name);
+        assert!(first
+            .path()
+            .starts_with(fs::canonicalize(dir.path().join(".atomic/tmp")).unwrap()));
+
+        let kept = second.path().to_path_buf();
+        second.finish("gen docs", true);
+        assert!(kept.is_dir());
+        let removed = first.path().to_path_buf();
+        fs::write(removed.join("scratch.txt"), "x").unwrap();
+        first.finish("gen docs", false);
+        assert!(!removed.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remove_read_only_leftovers() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let locked = dir.path().join("out/locked");
+        fs::create_dir_all(&locked).unwrap();
+        fs::write(locked.join("data.bin"), "x").unwrap();
+        fs::set_permissions(locked.join("data.bin"), fs::Permissions::from_mode(0o444)).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+
+        remove(&dir.path().join("out")).unwrap();
+        assert!(!dir.path().join("out").exists());
+    }
+}
@@ -1,82 +1,429 @@
-use std::{fs::read_to_string, path::Path};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    env,
+    ffi::OsString,
+    fs::read_to_string,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
 use toml::Value;
 
-pub fn find_key_in_tables(parsed_toml: Value, key: &str) -> Option<(String, Option<Value>)> {
-    // Directly check if the key exists at the root level
-    if let Some(table) = parsed_toml.as_table() {
-        if table.contains_key(key) {
-            return Some((key.to_string(), table.get(key).cloned()));
-        }
+use crate::config::{split_qualifier, Settings, QUALIFIERS};
+use crate::schema::{
+    drop_unknown_sections, error_messages, validate_toml_schema, Diagnostic, Severity, SECTIONS,
+};
+use crate::yaml;
+use crate::{AtomicError, Result};
 
-        // Search nested tables, e.g., "custom"
-        for (k, v) in table {
-            if let Value::Table(inner_table) = v {
-                if inner_table.contains_key(key) {
-                    return Some((k.clone(), inner_table.get(key).cloned()));
-                }
-            }
-        }
+/// File names atomic reads its config from, in order of preference.
+pub const CONFIG_NAMES: &[&str] = &["atomic.toml", "atomic.yaml", "atomic.yml"];
 
-        // Explicitly check "custom" table if it exists
-        if let Some(Value::Table(custom_table)) = table.get("custom") {
-            if let Some(value) = custom_table.get(key) {
-                return Some(("custom".to_string(), Some(value.clone())));
-            }
-        }
+/// The config file in `dir`: atomic.toml, or atomic.yaml / atomic.yml. With none of them, the
+/// atomic.toml `--init` would create.
+///
+/// # Errors
+/// Returns an error when more than one exists, since it isn't clear which one is meant.
+pub fn find_config(dir: &Path) -> Result<PathBuf> {
+    let found: Vec<PathBuf> = CONFIG_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|file| file.is_file())
+        .collect();
+    match found.as_slice() {
+        [] => Ok(dir.join(CONFIG_NAMES[0])),
+        [file] => Ok(file.clone()),
+        files => Err(AtomicError::AmbiguousConfig(
+            files
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect(),
+        )),
     }
+}
 
-    None
+/// Reads and parses the atomic file, as YAML when it is atomic.yaml or atomic.yml.
+///
+/// # Errors
+/// A missing file is reported as [`AtomicError::ConfigNotFound`], and a syntax error as
+/// [`AtomicError::TomlParse`], which renders the offending line with a caret under the problem,
+/// or [`AtomicError::YamlParse`].
+pub fn get_toml_content<P>(atomic: P) -> Result<Value>
+where
+    P: AsRef<Path>,
+{
+    let atomic = atomic.as_ref();
+    let contents = read_toml_text(atomic)?;
+    if yaml::is_yaml(atomic) {
+        return yaml::from_str(atomic, &contents);
+    }
+    toml::from_str(&contents)
+        .map_err(|err| AtomicError::TomlParse(atomic.display().to_string(), err))
 }
 
-pub fn get_toml_content<P>(atomic: P) -> Option<Value>
+/// Reads the atomic file as text, for edits that keep its comments and formatting.
+pub fn read_toml_text<P>(atomic: P) -> Result<String>
 where
     P: AsRef<Path>,
 {
-    let contents = read_to_string(atomic.as_ref()).expect("Unable to read atomic file");
-    toml::from_str(&contents).ok()
+    let atomic = atomic.as_ref();
+    read_to_string(atomic).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => AtomicError::ConfigNotFound(atomic.display().to_string()),
+        _ => AtomicError::Generic(format!("Unable to read {}: {}", atomic.display(), err)),
+    })
 }
 
-/// Parses a TOML file and returns a vector of all the keys present in it.
-/// # Arguments
+/// Reads the atomic file, layered over the ones above it, and overlays the requested profile on
+/// top.
 ///
-/// * `filename` - A string slice that holds the path to the TOML file.
+/// The `[profile]` table is always stripped from the returned value so profiles never show up as
+/// commands, whether or not one is active.
+pub fn load_toml<P>(atomic: P, profile: Option<&str>) -> Result<Value>
+where
+    P: AsRef<Path>,
+{
+    apply_profile(load_layered(atomic.as_ref(), false)?.0, profile)
+}
+
+/// Like [`load_toml`], but first checks each file against the schema. Warnings are printed,
+/// once per invocation, and the run goes on; see [`set_quiet`].
 ///
-/// # Returns
-/// A vector of strings containing all the keys found in the TOML file.
+/// # Errors
+/// Returns [`AtomicError::Validation`] listing every error found.
+pub fn load_and_validate_toml<P>(atomic: P, profile: Option<&str>) -> Result<Value>
+where
+    P: AsRef<Path>,
+{
+    apply_profile(load_layered(atomic.as_ref(), true)?.0, profile)
+}
+
+/// The atomic.toml files that make up the config at `atomic`, nearest first.
+///
+/// Inside a git work tree that is `atomic` itself and the config in every directory above it, up
+/// to the top of the work tree, skipping the ones that don't exist. A directory's config is the
+/// first of [`CONFIG_NAMES`] it has, so TOML and YAML layers mix. When the top-level file sets
+/// `[atomic] root_only = true`, only the nearest one is used.
+pub fn config_files(atomic: &Path) -> Vec<PathBuf> {
+    let dir = atomic.parent().unwrap_or(Path::new(""));
+    let absolute = match env::current_dir() {
+        Ok(cwd) => cwd.join(dir),
+        Err(_) => dir.to_path_buf(),
+    };
+    // how many directories up the top of the work tree is, if there is one
+    let levels = absolute
+        .ancestors()
+        .position(|ancestor| ancestor.join(".git").exists())
+        .unwrap_or(0);
+
+    let mut files = Vec::new();
+    let mut up = dir.to_path_buf();
+    for level in 0..=levels {
+        // the nearest is the file atomic was pointed at, whatever it's called
+        let file = match level {
+            0 => Some(atomic.to_path_buf()).filter(|file| file.is_file()),
+            _ => CONFIG_NAMES
+                .iter()
+                .map(|name| up.join(name))
+                .find(|file| file.is_file()),
+        };
+        files.extend(file);
+        up.push("..");
+    }
+
+    let root_only = files.len() > 1
+        && files
+            .last()
+            .and_then(|top| get_toml_content(top).ok())
+            .and_then(|top| Settings::from_config(&top).ok()?.root_only)
+            == Some(true);
+    if root_only {
+        files.truncate(1);
+    }
+    files
+}
+
+/// Sections the global config may set. Everything else is specific to a project.
+pub const GLOBAL_SECTIONS: &[&str] = &["atomic", "custom"];
+
+/// The machine-wide config under the platform's config directory, merged beneath every project:
+/// `$XDG_CONFIG_HOME/atomic/config.toml` (or `~/.config/...`) on Linux,
+/// `~/Library/Application Support/atomic/config.toml` on macOS and `%APPDATA%\atomic\config.toml`
+/// on Windows. `None` when there's no home directory to put it in.
+pub fn global_config() -> Option<PathBuf> {
+    config_dir(|key| env::var_os(key)).map(|dir| dir.join("atomic").join("config.toml"))
+}
+
+/// The platform's config directory, looked up with `var`.
+fn config_dir(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    let absolute = |key: &str| var(key).map(PathBuf::from).filter(|dir| dir.is_absolute());
+    if cfg!(windows) {
+        absolute("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        absolute("HOME").map(|home| home.join("Library/Application Support"))
+    } else {
+        absolute("XDG_CONFIG_HOME").or_else(|| absolute("HOME").map(|home| home.join(".config")))
+    }
+}
+
+/// Which file each command and setting in a layered config came from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Provenance {
+    /// Every project file that was merged, nearest first.
+    pub files: Vec<PathBuf>,
+    /// The global config, when there is one.
+    pub global: Option<PathBuf>,
+    entries: BTreeMap<String, Origin>,
+}
+
+/// Where one entry of a layered config was defined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    /// The nearest file defining it, whose value won.
+    pub file: PathBuf,
+    /// Files further up that defined it too, nearest first.
+    pub overrides: Vec<PathBuf>,
+}
+
+impl Provenance {
+    /// Records the entries of `contents`, read from `file`, over everything recorded so far.
+    fn record(&mut self, file: &Path, contents: &Value) {
+        let Some(table) = contents.as_table() else {
+            return;
+        };
+        for (key, value) in table {
+            let section = SECTIONS.iter().any(|section| section.name == key);
+            let names: Vec<String> = match value.as_table() {
+                Some(entries) if section => entries
+                    .keys()
+                    .map(|name| format!("{}.{}", key, name))
+                    .collect(),
+                _ if key == "profile" => Vec::new(),
+                _ => vec![key.clone()],
+            };
+            for name in names {
+                let origin = self.entries.entry(name).or_insert_with(|| Origin {
+                    file: file.to_path_buf(),
+                    overrides: Vec::new(),
+                });
+                if origin.file != file {
+                    let shadowed = std::mem::replace(&mut origin.file, file.to_path_buf());
+                    origin.overrides.insert(0, shadowed);
+                }
+            }
+        }
+    }
+
+    /// Whether `origin` is the global config.
+    pub fn is_global(&self, origin: &Origin) -> bool {
+        self.global.as_ref() == Some(&origin.file)
+    }
+
+    /// Where the command `name` came from, following the lookup order of
+    /// [`AtomicConfig::command`](crate::config::AtomicConfig::command).
+    pub fn command(&self, name: &str) -> Option<&Origin> {
+        let key = |section: &str, name: &str| match section {
+            "root" => name.to_string(),
+            section => format!("{}.{}", section, name),
+        };
+        match split_qualifier(name) {
+            Some((section, name)) => self.entries.get(&key(section, name)),
+            None => QUALIFIERS
+                .iter()
+                .find_map(|section| self.entries.get(&key(section, name))),
+        }
+    }
+
+    /// Entries defined by more than one project file. Overriding the global config is what it's
+    /// for, so that alone isn't a conflict.
+    pub fn conflicts(&self) -> impl Iterator<Item = (&str, &Origin)> {
+        self.entries
+            .iter()
+            .filter(|(_, origin)| {
+                origin
+                    .overrides
+                    .iter()
+                    .any(|file| Some(file) != self.global.as_ref())
+            })
+            .map(|(name, origin)| (name.as_str(), origin))
+    }
+}
+
+/// Reads every file from [`config_files`] and deep-merges them over the [`global_config`], the
+/// nearest winning, the same way profiles are applied. With `validate`, each file is checked
+/// against the schema first.
 ///
 /// # Errors
-/// This function returns an empty vector if it encounters any errors while reading or parsing the TOML file.
-pub fn get_toml_keys(contents: Value) -> Vec<String> {
-    let mut keys = Vec::new();
-    collect_keys("", &contents, &mut keys);
-    keys
+/// Returns [`AtomicError::ConfigNotFound`] for `atomic` when there are no project files, and
+/// [`AtomicError::Validation`] listing every problem, prefixed with the file when several are
+/// merged.
+pub fn load_layered(atomic: &Path, validate: bool) -> Result<(Value, Provenance)> {
+    let global = global_config().filter(|global| global.is_file());
+    load_layers(atomic, global.as_deref(), validate)
 }
 
-/// Recursively collects all keys present in a TOML value.
+/// [`load_layered`] with the global config at `global`.
+fn load_layers(
+    atomic: &Path,
+    global: Option<&Path>,
+    validate: bool,
+) -> Result<(Value, Provenance)> {
+    let (merged, provenance, diagnostics) = merge_layers(atomic, global, validate)?;
+    let errors = error_messages(&diagnostics);
+    if !errors.is_empty() {
+        return Err(AtomicError::Validation(errors));
+    }
+    warn(&diagnostics);
+    Ok((merged, provenance))
+}
+
+/// `atomic --check`: every problem with the configs that make up the one at `atomic`, warnings
+/// included, without printing any.
 ///
-/// This function is used internally by `get_toml_keys` to traverse the TOML structure recursively
-/// and collect all keys into the provided vector.
+/// # Errors
+/// Returns an error if there's no config or one of them doesn't parse.
+pub fn check_config(atomic: &Path) -> Result<Vec<Diagnostic>> {
+    let global = global_config().filter(|global| global.is_file());
+    Ok(merge_layers(atomic, global.as_deref(), true)?.2)
+}
+
+thread_local! {
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+    /// Warnings already printed, so loading the config again for the same run doesn't repeat them.
+    static WARNED: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// `--quiet`: stops printing warnings about the config from now on.
+pub fn set_quiet(quiet: bool) {
+    QUIET.with(|current| current.set(quiet));
+}
+
+/// Prints the warnings of `diagnostics` that haven't been printed yet.
+fn warn(diagnostics: &[Diagnostic]) {
+    let quiet = QUIET.with(Cell::get);
+    let warnings = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Warning);
+    for diagnostic in warnings {
+        let line = diagnostic.to_string();
+        let new = WARNED.with(|warned| warned.borrow_mut().insert(line.clone()));
+        if new && !quiet {
+            eprintln!("warning: {} (ignored)", line);
+        }
+    }
+}
+
+/// The configs that make up the one at `atomic`, merged, with what [`validate_toml_schema`]
+/// found in each when `validate` is set. Unknown sections are left out of the result.
+fn merge_layers(
+    atomic: &Path,
+    global: Option<&Path>,
+    validate: bool,
+) -> Result<(Value, Provenance, Vec<Diagnostic>)> {
+    let files = config_files(atomic);
+    if files.is_empty() {
+        return Err(AtomicError::ConfigNotFound(atomic.display().to_string()));
+    }
+
+    let mut merged = Value::Table(Default::default());
+    let mut provenance = Provenance::default();
+    let mut errors = Vec::new();
+    if let Some(global) = global {
+        let mut contents = get_toml_content(global)?;
+        if let Some(table) = contents.as_table_mut() {
+            // project sections are dropped, and only reported when validating
+            table.retain(|key, _| {
+                let kept = GLOBAL_SECTIONS.contains(&key);
+                if validate && !kept {
+                    errors.push(
+                        Diagnostic::warning(
+                            key,
+                            format!(
+                                "the global config only sets [{}]; put it in a project's atomic.toml",
+                                GLOBAL_SECTIONS.join("] and [")
+                            ),
+                        )
+                        .in_file(&global.display().to_string()),
+                    );
+                }
+                kept
+            });
+        }
+        if validate {
+            errors.extend(
+                validate_toml_schema(&contents)
+                    .into_iter()
+                    .map(|error| error.in_file(&global.display().to_string())),
+            );
+        }
+        drop_unknown_sections(&mut contents);
+        provenance.record(global, &contents);
+        provenance.global = Some(global.to_path_buf());
+        merge_toml(&mut merged, contents);
+    }
+    for file in files.iter().rev() {
+        let mut contents = get_toml_content(file)?;
+        if validate {
+            errors.extend(validate_toml_schema(&contents).into_iter().map(
+                |error| match files.len() {
+                    1 => error,
+                    _ => error.in_file(&file.display().to_string()),
+                },
+            ));
+        }
+        drop_unknown_sections(&mut contents);
+        provenance.record(file, &contents);
+        merge_toml(&mut merged, contents);
+    }
+    provenance.files = files;
+    Ok((merged, provenance, errors))
+}
+
+/// Deep-merges `[profile.<name>]` over the rest of the config, with profile values winning.
 ///
-/// # Arguments
+/// # Errors
+/// Returns an error if a profile is requested but not defined in the config.
+pub fn apply_profile(mut contents: Value, profile: Option<&str>) -> Result<Value> {
+    let profiles = contents
+        .as_table_mut()
+        .and_then(|table| table.remove("profile"));
+
+    let Some(name) = profile else {
+        return Ok(contents);
+    };
+
+    let overlay = match profiles {
+        Some(Value::Table(mut profiles)) => profiles.remove(name),
+        _ => None,
+    }
+    .ok_or_else(|| AtomicError::ProfileNotFound(name.to_string()))?;
+
+    merge_toml(&mut contents, overlay);
+    Ok(contents)
+}
+
+/// Recursively merges `overlay` into `base`.
 ///
-fn collect_keys(prefix: &str, value: &Value, keys: &mut Vec<String>) {
-    match value {
-        Value::Table(table) => {
-            for (key, val) in table {
-                let new_prefix = if prefix.is_empty() {
-                    key.clone()
-                } else {
-                    key.to_string()
-                };
-                collect_keys(&new_prefix, val, keys);
+/// Tables are merged key by key, while scalars and arrays from `overlay` replace whatever `base`
+/// held, including a table of the same name.
+pub fn merge_toml(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base_table), Value::Table(overlay_table)) => {
+            for (key, value) in overlay_table {
+                match base_table.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base_table.insert(key, value);
+                    }
+                }
             }
         }
-        _ => {
-            keys.push(prefix.to_string());
-        }
+        (base, overlay) => *base = overlay,
     }
 }
 
+/// Table holding atomic's own scalar settings, as opposed to commands.
+pub const SETTINGS_TABLE: &str = "atomic";
+
 pub fn _table_lookup<'a>(value: &'a Value, table_name: &str, key: &str) -> Option<&'a Value> {
     // Check if the value is a table
     if let Value::Table(table) = value {
@@ -88,3 +435,407 @@ pub fn _table_lookup<'a>(value: &'a Value, table_name: &str, key: &str) -> Optio
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn parse(s: &str) -> Value {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_merge_toml_tables_merge_recursively() {
+        let mut base = parse(
+            r#"
+            [custom]
+            check = "cargo check"
+            test = "cargo test"
+            "#,
+        );
+        merge_toml(
+            &mut base,
+            parse(
+                r#"
+                [custom]
+                test = "cargo test --locked"
+                "#,
+            ),
+        );
+
+        assert_eq!(
+            base,
+            parse(
+                r#"
+                [custom]
+                check = "cargo check"
+                test = "cargo test --locked"
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_toml_scalars_and_arrays_replace() {
+        let mut base = parse(
+            r#"
+            [custom]
+            chain = ["check", "clippy", "cargo fmt"]
+            [atomic]
+            auto_commit = true
+            "#,
+        );
+        merge_toml(
+            &mut base,
+            parse(
+                r#"
+                [custom]
+                chain = ["check"]
+                [atomic]
+                auto_commit = false
+                "#,
+            ),
+        );
+
+        assert_eq!(base["custom"]["chain"], parse("a = [\"check\"]")["a"]);
+        assert_eq!(base["atomic"]["auto_commit"], Value::Boolean(false));
+    }
+
+    #[test]
+    fn test_merge_toml_type_mismatch_overlay_wins() {
+        let mut base = parse(
+            r#"
+            [custom.build]
+            command = "cargo build"
+            "#,
+        );
+        merge_toml(
+            &mut base,
+            parse(r#"custom = { build = "cargo build --release" }"#),
+        );
+        assert_eq!(
+            base["custom"]["build"],
+            Value::String("cargo build --release".to_string())
+        );
+
+        let mut base = parse(r#"custom = { build = "cargo build" }"#);
+        merge_toml(
+            &mut base,
+            parse(r#"custom = { build = { command = "make" } }"#),
+        );
+        assert_eq!(base["custom"]["build"]["command"].as_str(), Some("make"));
+    }
+
+    /// A repository with an atomic.toml at the top, in `services` and in `services/api`.
+    fn layered_repo(root: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let api = dir.path().join("services/api");
+        fs::create_dir_all(&api).unwrap();
+        fs::create_dir(dir.path().join(".git")).unwrap();
+        fs::write(dir.path().join("atomic.toml"), root).unwrap();
+        fs::write(
+            dir.path().join("services/atomic.toml"),
+            "[custom]\nlint = \"cargo clippy\"\n",
+        )
+        .unwrap();
+        fs::write(
+            api.join("atomic.toml"),
+            "[default]\nbuild = \"cargo build -p api\"\n[custom]\nserve = \"cargo run\"\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_load_layered() {
+        let dir = layered_repo(
+            "[default]\nbuild = \"cargo build\"\ntest = \"cargo test\"\n[atomic]\nauto_commit = false\n",
+        );
+        let root = dir.path().join("atomic.toml");
+        let services = dir.path().join("services/atomic.toml");
+        let api = dir.path().join("services/api/atomic.toml");
+
+        assert_eq!(
+            config_files(&api),
+            [
+                api.clone(),
+                dir.path().join("services/api/../atomic.toml"),
+                dir.path().join("services/api/../../atomic.toml"),
+            ]
+        );
+        let (merged, provenance) = load_layered(&api, true).unwrap();
+        assert_eq!(
+            merged,
+            parse(
+                r#"
+                [default]
+                build = "cargo build -p api"
+                test = "cargo test"
+                [custom]
+                lint = "cargo clippy"
+                serve = "cargo run"
+                [atomic]
+                auto_commit = false
+                "#
+            )
+        );
+
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap();
+        let build = provenance.command("build").unwrap();
+        assert_eq!(build.file, api);
+        assert_eq!(
+            build
+                .overrides
+                .iter()
+                .map(|f| canonical(f))
+                .collect::<Vec<_>>(),
+            [canonical(&root)]
+        );
+        assert_eq!(
+            canonical(&provenance.command("lint").unwrap().file),
+            canonical(&services)
+        );
+        let conflicts: Vec<&str> = provenance.conflicts().map(|(name, _)| name).collect();
+        assert_eq!(conflicts, ["default.build"]);
+
+        // a missing file in the nearest directory falls back to the ones above
+        fs::remove_file(&api).unwrap();
+        let (merged, _) = load_layered(&api, true).unwrap();
+        assert_eq!(merged["default"]["build"].as_str(), Some("cargo build"));
+    }
+
+    #[test]
+    fn test_find_config_and_yaml_layers() {
+        let dir = layered_repo("[default]\ntest = \"cargo test\"\n");
+        fs::remove_file(dir.path().join("services/atomic.toml")).unwrap();
+        fs::write(
+            dir.path().join("services/atomic.yml"),
+            "custom:\n  lint: cargo clippy\n",
+        )
+        .unwrap();
+        let api = dir.path().join("services/api");
+        assert_eq!(find_config(&api).unwrap(), api.join("atomic.toml"));
+        assert_eq!(
+            config_files(&api.join("atomic.toml")),
+            [
+                api.join("atomic.toml"),
+                dir.path().join("services/api/../atomic.yml"),
+                dir.path().join("services/api/../../atomic.toml"),
+            ]
+        );
+        let merged = load_toml(api.join("atomic.toml"), None).unwrap();
+        assert_eq!(merged["custom"]["lint"].as_str(), Some("cargo clippy"));
+        assert_eq!(merged["default"]["test"].as_str(), Some("cargo test"));
+
+        let services = dir.path().join("services");
+        assert_eq!(find_config(&services).unwrap(), services.join("atomic.yml"));
+        let empty = services.join("empty");
+        fs::create_dir(&empty).unwrap();
+        assert_eq!(find_config(&empty).unwrap(), empty.join("atomic.toml"));
+        fs::write(services.join("atomic.toml"), "").unwrap();
+        let err = find_config(&services).unwrap_err().to_string();
+        assert!(err.contains("atomic.toml and"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_layered_root_only_and_errors() {
+        let dir = layered_repo("[default]\nbuild = \"cargo build\"\n[atomic]\nroot_only = true\n");
+        let api = dir.path().join("services/api/atomic.toml");
+        assert_eq!(config_files(&api), vec![api.clone()]);
+        let (merged, provenance) = load_layered(&api, true).unwrap();
+        assert_eq!(
+            merged["default"]["build"].as_str(),
+            Some("cargo build -p api")
+        );
+        assert!(merged.get("atomic").is_none());
+        assert_eq!(provenance.files, vec![api.clone()]);
+
+        // validation errors name their file once several are merged
+        let dir = layered_repo("[default]\nbuild = 4\n");
+        let api = dir.path().join("services/api/atomic.toml");
+        let Err(AtomicError::Validation(errors)) = load_layered(&api, true) else {
+            panic!("the root file is invalid");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0],
+            format!(
+                "{}: default.build: commands must be a string, an array of strings or a table; \
+                 settings belong under [atomic]",
+                dir.path().join("services/api/../../atomic.toml").display()
+            )
+        );
+
+        // outside a repository only the file itself counts
+        let outside = tempfile::tempdir().unwrap();
+        let atomic = outside.path().join("atomic.toml");
+        assert!(config_files(&atomic).is_empty());
+        assert_eq!(
+            load_layered(&atomic, false),
+            Err(AtomicError::ConfigNotFound(atomic.display().to_string()))
+        );
+    }
+
+    #[test]
+    fn test_config_dir() {
+        let env = |vars: &'static [(&str, &str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| OsString::from(value))
+            }
+        };
+        assert_eq!(config_dir(env(&[])), None);
+        assert_eq!(config_dir(env(&[("HOME", "relative")])), None);
+        if cfg!(all(unix, not(target_os = "macos"))) {
+            assert_eq!(
+                config_dir(env(&[("HOME", "/home/me")])),
+                Some(PathBuf::from("/home/me/.config"))
+            );
+            assert_eq!(
+                config_dir(env(&[("HOME", "/home/me"), ("XDG_CONFIG_HOME", "/cfg")])),
+                Some(PathBuf::from("/cfg"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_layers_over_global() {
+        let dir = layered_repo("[default]\nbuild = \"cargo build\"\n");
+        let global = dir.path().join("global.toml");
+        fs::write(
+            &global,
+            "[atomic]\nrun_git_hooks = true\ncommit_author = \"Me <me@example.com>\"\n\
+             [custom]\nlint = \"my-lint\"\nscratch = \"git stash list\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("atomic.toml"),
+            "[default]\nbuild = \"cargo build\"\n[atomic]\nrun_git_hooks = false\n",
+        )
+        .unwrap();
+        let api = dir.path().join("services/api/atomic.toml");
+
+        let (merged, provenance) = load_layers(&api, Some(&global), true).unwrap();
+        // the project wins, key by key
+        assert_eq!(merged["atomic"]["run_git_hooks"].as_bool(), Some(false));
+        assert_eq!(
+            merged["atomic"]["commit_author"].as_str(),
+            Some("Me <me@example.com>")
+        );
+        assert_eq!(merged["custom"]["lint"].as_str(), Some("cargo clippy"));
+        assert_eq!(merged["custom"]["scratch"].as_str(), Some("git stash list"));
+
+        assert_eq!(provenance.files.len(), 3);
+        assert_eq!(provenance.global.as_deref(), Some(global.as_path()));
+        let scratch = provenance.command("scratch").unwrap();
+        assert!(provenance.is_global(scratch));
+        let lint = provenance.command("lint").unwrap();
+        assert!(!provenance.is_global(lint));
+        assert_eq!(lint.overrides, vec![global.clone()]);
+        // overriding the global config isn't a conflict between project files
+        let conflicts: Vec<&str> = provenance.conflicts().map(|(name, _)| name).collect();
+        assert_eq!(conflicts, ["default.build"]);
+
+        // project sections in the global config are reported, and never merged
+        fs::write(
+            &global,
+            "[default]\ntest = \"make test\"\n[custom]\nscratch = 4\n",
+        )
+        .unwrap();
+        let Err(AtomicError::Validation(errors)) = load_layers(&api, Some(&global), true) else {
+            panic!("the global config sets custom.scratch to a number");
+        };
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(
+            errors[0].contains("global.toml: custom.scratch: commands must be"),
+            "{}",
+            errors[0]
+        );
+        // the stray section is only a warning
+        let diagnostics = merge_layers(&api, Some(&global), true).unwrap().2;
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert!(
+            diagnostics[0].to_string().contains(
+                "global.toml: default: the global config only sets [atomic] and [custom]"
+            ),
+            "{}",
+            diagnostics[0]
+        );
+        fs::write(&global, "[default]\ntest = \"make test\"\n").unwrap();
+        assert!(load_layers(&api, Some(&global), true).is_ok());
+        let (merged, _) = load_layers(&api, Some(&global), false).unwrap();
+        assert!(merged["default"].get("test").is_none());
+
+        // a global config alone isn't a project
+        let outside = tempfile::tempdir().unwrap();
+        let atomic = outside.path().join("atomic.toml");
+        assert_eq!(
+            load_layers(&atomic, Some(&global), false),
+            Err(AtomicError::ConfigNotFound(atomic.display().to_string()))
+        );
+    }
+
+    #[test]
+    fn test_merge_toml_adds_new_sections() {
+        let mut base = parse(r#"default = { build = "cargo build" }"#);
+        merge_toml(&mut base, parse(r#"env = { RUST_LOG = "debug" }"#));
+        assert_eq!(base["env"]["RUST_LOG"].as_str(), Some("debug"));
+        assert_eq!(base["default"]["build"].as_str(), Some("cargo build"));
+    }
+
+    #[test]
+    fn test_get_toml_content_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+
+        assert_eq!(
+            get_toml_content(&atomic),
+            Err(AtomicError::ConfigNotFound(atomic.display().to_string()))
+        );
+        assert!(get_toml_content(&atomic)
+            .unwrap_err()
+            .to_string()
+            .contains("atomic --init"));
+
+        fs::write(
+            &atomic,
+            "# atomic\n\n[default]\nbuild = \"cargo build\"\n\n[custom]\ncheck = cargo check\n",
+        )
+        .unwrap();
+        let message = get_toml_content(&atomic).unwrap_err().to_string();
+        assert!(message.contains("line 7"), "{}", message);
+        assert!(message.contains("check = cargo check"), "{}", message);
+        assert!(message.contains('^'), "{}", message);
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let config = parse(
+            r#"
+            [default]
+            test = "cargo test"
+
+            [profile.ci.default]
+            test = "cargo test --locked"
+            "#,
+        );
+
+        let merged = apply_profile(config.clone(), Some("ci")).unwrap();
+        assert_eq!(
+            merged["default"]["test"].as_str(),
+            Some("cargo test --locked")
+        );
+        assert!(merged.get("profile").is_none());
+
+        let base = apply_profile(config.clone(), None).unwrap();
+        assert_eq!(base["default"]["test"].as_str(), Some("cargo test"));
+        assert!(base.get("profile").is_none());
+
+        assert_eq!(
+            apply_profile(config, Some("release")),
+            Err(AtomicError::ProfileNotFound("release".to_string()))
+        );
+    }
+}
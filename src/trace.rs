@@ -0,0 +1,198 @@
+//! A small span recorder behind `--trace`, for finding out where a slow chain spends its time.
+//!
+//! Spans are RAII guards: [`span`] opens one and dropping it closes it, so nesting follows scope.
+//! Nothing is recorded until [`enable`] is called on the current thread.
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+/// A finished span, stored in the order spans were opened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanRecord {
+    pub name: String,
+    pub depth: usize,
+    /// Offset from when tracing was enabled.
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+struct Recorder {
+    origin: Instant,
+    depth: usize,
+    spans: Vec<SpanRecord>,
+}
+
+thread_local! {
+    static RECORDER: RefCell<Option<Recorder>> = const { RefCell::new(None) };
+}
+
+/// Closes its span when dropped.
+#[must_use = "the span closes as soon as the guard is dropped"]
+pub struct Span {
+    index: Option<usize>,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(index) = self.index else {
+            return;
+        };
+        RECORDER.with(|recorder| {
+            if let Some(recorder) = recorder.borrow_mut().as_mut() {
+                let elapsed = recorder.origin.elapsed();
+                let span = &mut recorder.spans[index];
+                span.duration = elapsed.saturating_sub(span.start);
+                recorder.depth -= 1;
+            }
+        });
+    }
+}
+
+/// Starts recording spans on this thread, discarding anything recorded before.
+pub fn enable() {
+    RECORDER.with(|recorder| {
+        *recorder.borrow_mut() = Some(Recorder {
+            origin: Instant::now(),
+            depth: 0,
+            spans: Vec::new(),
+        });
+    });
+}
+
+/// Opens a span nested under whichever spans are currently open. A no-op unless tracing is on.
+pub fn span(name: impl Into<String>) -> Span {
+    let index = RECORDER.with(|recorder| {
+        recorder.borrow_mut().as_mut().map(|recorder| {
+            recorder.spans.push(SpanRecord {
+                name: name.into(),
+                depth: recorder.depth,
+                start: recorder.origin.elapsed(),
+                duration: Duration::ZERO,
+            });
+            recorder.depth += 1;
+            recorder.spans.len() - 1
+        })
+    });
+    Span { index }
+}
+
+/// Stops recording and returns every span recorded since [`enable`].
+pub fn finish() -> Vec<SpanRecord> {
+    RECORDER
+        .with(|recorder| recorder.borrow_mut().take())
+        .map(|recorder| recorder.spans)
+        .unwrap_or_default()
+}
+
+/// Renders spans as an indented tree with start offsets and durations in milliseconds.
+pub fn render_tree(spans: &[SpanRecord]) -> String {
+    let mut out = format!("{:>10} {:>10}  span\n", "start", "duration");
+    for span in spans {
+        out.push_str(&format!(
+            "{:>8.1}ms {:>8.1}ms  {}{}\n",
+            millis(span.start),
+            millis(span.duration),
+            "  ".repeat(span.depth),
+            span.name
+        ));
+    }
+    out
+}
+
+/// Converts spans to Chrome trace-event JSON, which about://tracing and Perfetto can load.
+pub fn chrome_trace(spans: &[SpanRecord]) -> serde_json::Value {
+    let events: Vec<_> = spans
+        .iter()
+        .map(|span| {
+            json!({
+                "name": span.name,
+                "ph": "X",
+                "ts": span.start.as_micros() as u64,
+                "dur": span.duration.as_micros() as u64,
+                "pid": 1,
+                "tid": 1,
+            })
+        })
+        .collect();
+    json!({ "traceEvents": events })
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(spans: &[SpanRecord]) -> Vec<(usize, &str)> {
+        spans.iter().map(|s| (s.depth, s.name.as_str())).collect()
+    }
+
+    #[test]
+    fn test_spans_nest_by_scope() {
+        // recording is off by default
+        drop(span("ignored"));
+        assert!(finish().is_empty());
+
+        enable();
+        {
+            let _run = span("atomic build");
+            drop(span("load atomic.toml"));
+            {
+                let _step = span("check");
+                drop(span("cargo check"));
+            }
+            drop(span("auto-commit"));
+        }
+        let spans = finish();
+
+        assert_eq!(
+            names(&spans),
+            vec![
+                (0, "atomic build"),
+                (1, "load atomic.toml"),
+                (1, "check"),
+                (2, "cargo check"),
+                (1, "auto-commit"),
+            ]
+        );
+        for child in &spans[1..] {
+            assert!(child.start >= spans[0].start);
+            assert!(child.start + child.duration <= spans[0].start + spans[0].duration);
+        }
+        assert!(finish().is_empty());
+    }
+
+    #[test]
+    fn test_render_tree_and_chrome_trace() {
+        let spans = vec![
+            SpanRecord {
+                name: "atomic build".to_string(),
+                depth: 0,
+                start: Duration::ZERO,
+                duration: Duration::from_millis(12),
+            },
+            SpanRecord {
+                name: "cargo build".to_string(),
+                depth: 1,
+                start: Duration::from_micros(1500),
+                duration: Duration::from_millis(10),
+            },
+        ];
+
+        assert_eq!(
+            render_tree(&spans),
+            "     start   duration  span\n\
+             \x20    0.0ms     12.0ms  atomic build\n\
+             \x20    1.5ms     10.0ms    cargo build\n"
+        );
+
+        let trace = chrome_trace(&spans);
+        assert_eq!(trace["traceEvents"][1]["ts"], 1500);
+        assert_eq!(trace["traceEvents"][1]["dur"], 10000);
+        assert_eq!(trace["traceEvents"][0]["ph"], "X");
+    }
+}
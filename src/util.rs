@@ -0,0 +1,297 @@
+//! Small helpers shared across atomic's modules.
+
+use std::io;
+use std::time::Duration;
+
+use crate::ci::Interactivity;
+use crate::{AtomicError, Result};
+
+/// Parses a human friendly duration such as `"500ms"`, `"5s"`, `"2m"` or a bare number of seconds.
+///
+/// # Errors
+/// Returns an error if the number is missing or invalid, or the unit is not one of `ms`, `s`, `m`.
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    if number.is_empty() {
+        return Err(AtomicError::Generic(format!(
+            "Invalid duration '{}': expected a number such as \"500ms\", \"5s\" or \"2m\"",
+            input
+        )));
+    }
+    let number = number.parse::<u64>()?;
+
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => number
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| {
+                AtomicError::Generic(format!("Invalid duration '{}': too large", input))
+            }),
+        other => Err(AtomicError::Generic(format!(
+            "Invalid duration unit '{}' in '{}': expected ms, s or m",
+            other, input
+        ))),
+    }
+}
+
+/// Parses a file size such as `"10MB"`, `"500 KB"`, `"1GB"` or a bare number of bytes.
+///
+/// Units are powers of 1024 and ignore case.
+///
+/// # Errors
+/// Returns an error if the number is missing or invalid, or the unit is not one of `B`, `KB`,
+/// `MB`, `GB`.
+pub fn parse_size(input: &str) -> Result<u64> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    if number.is_empty() {
+        return Err(AtomicError::Generic(format!(
+            "Invalid size '{}': expected a number such as \"500KB\", \"10MB\" or \"1GB\"",
+            input
+        )));
+    }
+    let number = number.parse::<u64>()?;
+
+    let scale: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        other => {
+            return Err(AtomicError::Generic(format!(
+                "Invalid size unit '{}' in '{}': expected B, KB, MB or GB",
+                other, input
+            )))
+        }
+    };
+    number
+        .checked_mul(scale)
+        .ok_or_else(|| AtomicError::Generic(format!("Invalid size '{}': too large", input)))
+}
+
+/// `300.0 MB`, `12.5 KB` or `512 B`, for messages about file sizes.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Asks a yes/no question on stderr and reads the answer from stdin; anything but yes is no.
+/// In CI mode nobody is there to answer, so the answer is no without reading stdin.
+pub fn confirm(question: &str, interactivity: Interactivity) -> Result<bool> {
+    if interactivity.is_ci() {
+        eprintln!("{} [y/N] n (CI mode doesn't ask)", question);
+        return Ok(false);
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|err| AtomicError::Generic(format!("Failed to read answer: {}", err)))?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Platform names a command's `os` key accepts.
+pub const PLATFORMS: &[&str] = &["windows", "unix", "macos"];
+
+/// Whether atomic is running on one of `platforms`: `"windows"`, `"macos"`, or `"unix"` for
+/// macOS, Linux and the BSDs alike.
+pub fn platform_matches<'a>(platforms: impl IntoIterator<Item = &'a str>) -> bool {
+    platforms
+        .into_iter()
+        .any(|platform| platform_is(platform, std::env::consts::OS, cfg!(unix)))
+}
+
+/// [`platform_matches`] for one platform name, on the OS called `os`.
+fn platform_is(platform: &str, os: &str, unix: bool) -> bool {
+    match platform {
+        "unix" => unix,
+        "windows" | "macos" => platform == os,
+        _ => false,
+    }
+}
+
+include!("date.rs");
+
+/// Most suggestions [`suggest`] returns.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Candidates that look like a typo of `input`, best first, for "did you mean" hints.
+///
+/// Matching ignores case. Candidates that start with `input` rank first, then the rest by
+/// edit distance, which may be at most a third of the input's length (but always at least 1).
+pub fn suggest<'a>(input: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+    let input = input.to_lowercase();
+    let threshold = (input.chars().count() / 3).max(1);
+
+    let mut ranked: Vec<_> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let lower = candidate.to_lowercase();
+            let distance = edit_distance(&input, &lower);
+            let prefix = input.chars().count() >= 2 && lower.starts_with(&input);
+            (prefix || distance <= threshold).then_some((!prefix, distance, candidate))
+        })
+        .collect();
+    ranked.sort();
+    ranked.dedup_by_key(|(_, _, candidate)| *candidate);
+    ranked
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, _, candidate)| candidate)
+        .collect()
+}
+
+/// Formats suggestions as a sentence to append to an error, or nothing when there are none.
+pub fn did_you_mean(suggestions: &[String]) -> String {
+    match suggestions {
+        [] => String::new(),
+        [only] => format!(" Did you mean '{}'?", only),
+        many => format!(" Did you mean one of '{}'?", many.join("', '")),
+    }
+}
+
+/// Levenshtein distance between two strings, counted in chars.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("7"), Ok(Duration::from_secs(7)));
+        assert_eq!(parse_duration(" 3 s "), Ok(Duration::from_secs(3)));
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("5h").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("400000000000000000m").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("500KB"), Ok(500 * 1024));
+        assert_eq!(parse_size("10 mb"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size(" 1GB "), Ok(1 << 30));
+        assert_eq!(parse_size("64B"), Ok(64));
+
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("10TB").is_err());
+        assert!(parse_size("-1MB").is_err());
+        assert!(parse_size("99999999999GB").is_err());
+
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(12 * 1024 + 512), "12.5 KB");
+        assert_eq!(format_size(300 * 1024 * 1024), "300.0 MB");
+        assert_eq!(format_size(3 << 30), "3.0 GB");
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("clippy", "clippy"), 0);
+        assert_eq!(edit_distance("clipy", "clippy"), 1);
+        assert_eq!(edit_distance("tset", "test"), 2);
+        assert_eq!(edit_distance("", "doc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_suggest() {
+        let keys = ["check", "clippy", "clippy_max", "doc", "test-all", "fmt"];
+
+        assert_eq!(suggest("clipy", keys), vec!["clippy"]);
+        assert_eq!(suggest("CHEK", keys), vec!["check"]);
+        // prefix matches rank ahead of closer edits
+        assert_eq!(suggest("clippy_", keys), vec!["clippy_max", "clippy"]);
+        assert_eq!(suggest("test", keys), vec!["test-all"]);
+
+        // "dco" is 2 edits from "doc", over the threshold of 1 for a 3 letter input
+        assert!(suggest("dco", keys).is_empty());
+        // 6 letters allow 2 edits
+        assert_eq!(suggest("cheeks", keys), vec!["check"]);
+        assert!(suggest("deploy-to-production", keys).is_empty());
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_844), (2024, 5, 1));
+    }
+
+    #[test]
+    fn test_did_you_mean() {
+        assert_eq!(did_you_mean(&[]), "");
+        assert_eq!(
+            did_you_mean(&["clippy".to_string()]),
+            " Did you mean 'clippy'?"
+        );
+        assert_eq!(
+            did_you_mean(&["check".to_string(), "clippy".to_string()]),
+            " Did you mean one of 'check', 'clippy'?"
+        );
+    }
+
+    #[test]
+    fn test_confirm_in_ci() {
+        // answered without waiting on stdin
+        let ci = Interactivity::Ci {
+            github_actions: false,
+        };
+        assert_eq!(confirm("Forget every run?", ci), Ok(false));
+    }
+
+    #[test]
+    fn test_platform_is() {
+        assert!(platform_is("macos", "macos", true));
+        assert!(platform_is("unix", "macos", true));
+        assert!(platform_is("unix", "linux", true));
+        assert!(!platform_is("macos", "linux", true));
+        assert!(platform_is("windows", "windows", false));
+        assert!(!platform_is("unix", "windows", false));
+        assert!(!platform_is("linux", "linux", true));
+
+        assert!(platform_matches(PLATFORMS.iter().copied()));
+        assert!(!platform_matches([]));
+    }
+}
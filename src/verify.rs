@@ -0,0 +1,286 @@
+//! `atomic --verify [range]`: checks the provenance trailers of the auto-commits in a range of
+//! history, that the config file each command ran with is the one committed in its tree.
+//!
+//! A config edited but left uncommitted while the command ran shows up as a mismatch, since no
+//! file in the tree has the `Atomic-Config-Hash` the commit recorded.
+
+use git2::{Commit, ErrorCode, ObjectType, Oid, Repository, Tree, TreeWalkMode, TreeWalkResult};
+
+use crate::git::{parse_trailers, COMMAND_TRAILER, CONFIG_HASH_TRAILER};
+use crate::toml::CONFIG_NAMES;
+use crate::Result;
+
+/// What `--verify` found for one auto-commit.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    /// A file in the tree, at this path, is the config the command ran with.
+    Verified(String),
+    /// The tree has a config file, but not the one the command ran with.
+    Mismatch,
+    /// The tree has no config file at all.
+    NoConfig,
+    /// No `Atomic-Config-Hash` trailer, as on auto-commits made before atomic recorded one.
+    NoHash,
+}
+
+impl Status {
+    /// Whether this commit fails `--verify`. Commits without a hash to check don't.
+    pub fn failed(&self) -> bool {
+        matches!(self, Status::Mismatch | Status::NoConfig)
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Status::Verified(path) => format!("{} is the config it ran with", path),
+            Status::Mismatch => {
+                "the config in its tree isn't the one it ran with (uncommitted at the time?)"
+                    .to_string()
+            }
+            Status::NoConfig => "no config file in its tree".to_string(),
+            Status::NoHash => format!("no {} trailer", CONFIG_HASH_TRAILER),
+        }
+    }
+}
+
+/// An auto-commit, with what `--verify` found for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checked {
+    pub id: Oid,
+    /// Its `Atomic-Command` trailer.
+    pub command: String,
+    pub status: Status,
+}
+
+/// The commits of `range`, newest first: `a..b`, or a single revision and everything it reaches.
+/// Without a range, the commits on HEAD since it left `base`, or all of them without one.
+pub fn commits<'r>(
+    repo: &'r Repository,
+    range: Option<&str>,
+    base: Option<&str>,
+) -> Result<Vec<Commit<'r>>> {
+    let mut revwalk = repo.revwalk()?;
+    match range {
+        Some(range) if range.contains("..") => revwalk.push_range(range)?,
+        Some(rev) => revwalk.push(repo.revparse_single(rev)?.peel_to_commit()?.id())?,
+        None => {
+            let head = match repo.head() {
+                Ok(head) => head.peel_to_commit()?,
+                Err(err) if err.code() == ErrorCode::UnbornBranch => return Ok(Vec::new()),
+                Err(err) => return Err(err.into()),
+            };
+            revwalk.push(head.id())?;
+            let base = base.and_then(|base| repo.revparse_single(base).ok()?.peel_to_commit().ok());
+            if let Some(base) = base {
+                let merge_base = repo.merge_base(head.id(), base.id())?;
+                // on the base branch itself there is nothing to cut off
+                if merge_base != head.id() {
+                    revwalk.hide(merge_base)?;
+                }
+            }
+        }
+    }
+    revwalk.map(|oid| Ok(repo.find_commit(oid?)?)).collect()
+}
+
+/// Checks `commit` when it's an auto-commit, one with an `Atomic-Command` trailer.
+pub fn check(commit: &Commit) -> Result<Option<Checked>> {
+    let trailers = parse_trailers(commit.message().unwrap_or_default());
+    let find = |key: &str| {
+        trailers
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| *value)
+    };
+    let Some(command) = find(COMMAND_TRAILER) else {
+        return Ok(None);
+    };
+    let status = match find(CONFIG_HASH_TRAILER) {
+        Some(hash) => find_config(&commit.tree()?, Oid::from_str(hash).ok())?,
+        None => Status::NoHash,
+    };
+    Ok(Some(Checked {
+        id: commit.id(),
+        command: command.to_string(),
+        status,
+    }))
+}
+
+/// Looks through `tree` for a file with the contents `hash` names.
+fn find_config(tree: &Tree, hash: Option<Oid>) -> Result<Status> {
+    let mut found = None;
+    let mut any_config = false;
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        let name = entry.name().unwrap_or_default();
+        let blob = entry.kind() == Some(ObjectType::Blob);
+        if blob && found.is_none() && Some(entry.id()) == hash {
+            found = Some(format!("{}{}", root, name));
+        }
+        any_config |= blob && CONFIG_NAMES.contains(&name);
+        TreeWalkResult::Ok
+    })?;
+    Ok(match (found, any_config) {
+        (Some(path), _) => Status::Verified(path),
+        (None, true) => Status::Mismatch,
+        (None, false) => Status::NoConfig,
+    })
+}
+
+/// Checks every auto-commit in [`commits`], newest first.
+pub fn verify(repo: &Repository, range: Option<&str>, base: Option<&str>) -> Result<Vec<Checked>> {
+    let mut checked = Vec::new();
+    for commit in commits(repo, range, base)? {
+        checked.extend(check(&commit)?);
+    }
+    Ok(checked)
+}
+
+/// The `--verify` report: a line for each commit, then how many were verified.
+pub fn render(checked: &[Checked]) -> String {
+    let mut out: String = checked
+        .iter()
+        .map(|commit| {
+            let label = match commit.status {
+                Status::Verified(_) => "verified",
+                _ => "unverifiable",
+            };
+            format!(
+                "{:<12}  {:.7}  {}: {}\n",
+                label,
+                commit.id.to_string(),
+                commit.command,
+                commit.status.describe()
+            )
+        })
+        .collect();
+    let verified = checked
+        .iter()
+        .filter(|commit| matches!(commit.status, Status::Verified(_)))
+        .count();
+    out.push_str(&format!(
+        "\n{} auto-commit{}: {} verified, {} unverifiable\n",
+        checked.len(),
+        if checked.len() == 1 { "" } else { "s" },
+        verified,
+        checked.len() - verified
+    ));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::git::{config_hash, VERSION_TRAILER};
+    use crate::test_support::init_repo;
+
+    /// Commits every file in the work tree of `repo` with `message`.
+    fn commit_all(repo: &Repository, message: &str) -> Oid {
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.update_all(["*"].iter(), None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = repo.signature().unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<&Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+            .unwrap()
+    }
+
+    fn auto_message(command: &str, hash: &str) -> String {
+        format!(
+            "atomic: {}\n\n{}: 0.1.0\n{}: {}\n{}: {}\n",
+            command, VERSION_TRAILER, COMMAND_TRAILER, command, CONFIG_HASH_TRAILER, hash
+        )
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let atomic = dir.path().join("atomic.toml");
+        fs::write(&atomic, "[custom]\ngen = \"echo gen\"\n").unwrap();
+        let repo = init_repo(dir.path(), &[]);
+        let initial = repo.head().unwrap().target().unwrap();
+
+        // matching: ran with the committed config
+        let hash = config_hash(&atomic).unwrap();
+        fs::write(dir.path().join("gen.txt"), "1\n").unwrap();
+        let matching = commit_all(&repo, &auto_message("gen", &hash));
+
+        // mismatching: ran with an edit that was then reverted before committing
+        fs::write(&atomic, "[custom]\ngen = \"echo changed\"\n").unwrap();
+        let edited = config_hash(&atomic).unwrap();
+        fs::write(&atomic, "[custom]\ngen = \"echo gen\"\n").unwrap();
+        fs::write(dir.path().join("gen.txt"), "2\n").unwrap();
+        let mismatching = commit_all(&repo, &auto_message("gen", &edited));
+
+        // missing: the config is gone from the tree
+        fs::remove_file(&atomic).unwrap();
+        fs::write(dir.path().join("gen.txt"), "3\n").unwrap();
+        let missing = commit_all(&repo, &auto_message("gen", &hash));
+
+        // made before atomic recorded a hash, and a commit by hand
+        fs::write(dir.path().join("gen.txt"), "4\n").unwrap();
+        let old = commit_all(&repo, "atomic: gen\n\nAtomic-Command: gen\n");
+        fs::write(dir.path().join("gen.txt"), "5\n").unwrap();
+        commit_all(&repo, "by hand\n\nSigned-off-by: me\n");
+
+        let checked = verify(&repo, None, None).unwrap();
+        let statuses: Vec<_> = checked
+            .iter()
+            .map(|commit| (commit.id, commit.status.clone()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                (old, Status::NoHash),
+                (missing, Status::NoConfig),
+                (mismatching, Status::Mismatch),
+                (matching, Status::Verified("atomic.toml".to_string())),
+            ]
+        );
+        assert_eq!(checked.iter().filter(|c| c.status.failed()).count(), 2);
+
+        let report = render(&checked);
+        assert!(
+            report.ends_with("\n4 auto-commits: 1 verified, 3 unverifiable\n"),
+            "{}",
+            report
+        );
+        assert!(report.starts_with(&format!("unverifiable  {:.7}  gen: no ", old.to_string())));
+
+        // a range, and a base to stop at
+        let range = format!("{}..{}", initial, mismatching);
+        assert_eq!(verify(&repo, Some(&range), None).unwrap().len(), 2);
+        let base = mismatching.to_string();
+        assert_eq!(verify(&repo, None, Some(&base)).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_verify_nested_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = init_repo(dir.path(), &[]);
+        fs::create_dir(dir.path().join("services")).unwrap();
+        let atomic = dir.path().join("services/atomic.yaml");
+        fs::write(&atomic, "custom:\n  gen: echo gen\n").unwrap();
+        let hash = config_hash(&atomic).unwrap();
+        let commit = commit_all(&repo, &auto_message("gen", &hash));
+
+        let checked = verify(&repo, Some(&commit.to_string()), None).unwrap();
+        assert_eq!(
+            checked[0].status,
+            Status::Verified("services/atomic.yaml".to_string())
+        );
+        assert_eq!(
+            render(&checked),
+            format!(
+                "verified      {:.7}  gen: services/atomic.yaml is the config it ran with\n\n\
+                 1 auto-commit: 1 verified, 0 unverifiable\n",
+                commit.to_string()
+            )
+        );
+    }
+}
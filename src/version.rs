@@ -0,0 +1,184 @@
+//! `atomic --version --verbose` build details and the opt-in `--check-update` lookup.
+
+use std::cmp::Ordering;
+use std::time::Duration;
+
+use semver::Version;
+
+use crate::{AtomicError, Result};
+
+/// The name atomic is published under on crates.io.
+const CRATE_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Give up on crates.io after this long; an update check should never hold anything up.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Version, commit, build date, target and features, one per line.
+pub fn verbose_version() -> String {
+    let features = option_env!("ATOMIC_FEATURES").unwrap_or_default();
+    format!(
+        "atomic {}\ncommit: {}\nbuilt: {}\ntarget: {}\nfeatures: {}",
+        env!("CARGO_PKG_VERSION"),
+        option_env!("ATOMIC_GIT_COMMIT").unwrap_or("unknown"),
+        option_env!("ATOMIC_BUILD_DATE").unwrap_or("unknown"),
+        option_env!("ATOMIC_TARGET").unwrap_or("unknown"),
+        if features.is_empty() {
+            "none"
+        } else {
+            features
+        }
+    )
+}
+
+/// Somewhere to ask for the newest published version of a crate.
+pub trait VersionSource {
+    fn latest_version(&self, crate_name: &str) -> Result<String>;
+}
+
+/// The crates.io API.
+pub struct CratesIo;
+
+impl VersionSource for CratesIo {
+    fn latest_version(&self, crate_name: &str) -> Result<String> {
+        let url = format!("https://crates.io/api/v1/crates/{}", crate_name);
+        let body = ureq::get(&url)
+            // crates.io rejects requests without a user agent
+            .set("User-Agent", concat!("atomic/", env!("CARGO_PKG_VERSION")))
+            .timeout(CHECK_TIMEOUT)
+            .call()
+            .map_err(|err| AtomicError::Generic(format!("Failed to reach crates.io: {}", err)))?
+            .into_string()
+            .map_err(|err| {
+                AtomicError::Generic(format!("Failed to read crates.io reply: {}", err))
+            })?;
+        parse_latest_version(&body)
+    }
+}
+
+/// Pulls the newest stable version out of a crates.io `/api/v1/crates/<name>` response.
+pub fn parse_latest_version(body: &str) -> Result<String> {
+    let json: serde_json::Value = serde_json::from_str(body)
+        .map_err(|err| AtomicError::Generic(format!("Unexpected crates.io reply: {}", err)))?;
+    let krate = &json["crate"];
+    ["max_stable_version", "newest_version", "max_version"]
+        .iter()
+        .find_map(|key| krate[key].as_str())
+        .map(str::to_string)
+        .ok_or(AtomicError::Static(
+            "Unexpected crates.io reply: no version found",
+        ))
+}
+
+/// Compares two semver versions, `current` against `latest`.
+pub fn compare_versions(current: &str, latest: &str) -> Result<Ordering> {
+    let parse = |version: &str| {
+        Version::parse(version.trim())
+            .map_err(|err| AtomicError::Generic(format!("Invalid version '{}': {}", version, err)))
+    };
+    Ok(parse(current)?.cmp(&parse(latest)?))
+}
+
+/// Asks `source` for the newest release and describes how this build compares.
+pub fn check_update(source: &impl VersionSource) -> Result<String> {
+    let current = env!("CARGO_PKG_VERSION");
+    let latest = source.latest_version(CRATE_NAME)?;
+    Ok(update_message(
+        current,
+        &latest,
+        compare_versions(current, &latest)?,
+    ))
+}
+
+fn update_message(current: &str, latest: &str, ordering: Ordering) -> String {
+    match ordering {
+        Ordering::Less => format!(
+            "atomic {} is available (you have {}). Update with `cargo install {}`.",
+            latest, current, CRATE_NAME
+        ),
+        Ordering::Equal => format!("atomic {} is up to date.", current),
+        Ordering::Greater => format!(
+            "atomic {} is newer than the latest release, {}.",
+            current, latest
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers with a fixed version, or fails like an offline machine when there is none.
+    struct Stub(Option<&'static str>);
+
+    impl VersionSource for Stub {
+        fn latest_version(&self, crate_name: &str) -> Result<String> {
+            assert_eq!(crate_name, "cargo-atomic");
+            self.0
+                .map(str::to_string)
+                .ok_or(AtomicError::Static("Failed to reach crates.io: dns error"))
+        }
+    }
+
+    #[test]
+    fn test_parse_latest_version() {
+        let body = r#"{"crate": {"id": "cargo-atomic", "max_version": "0.3.0-beta.1",
+            "max_stable_version": "0.2.1", "newest_version": "0.3.0-beta.1"}}"#;
+        assert_eq!(parse_latest_version(body), Ok("0.2.1".to_string()));
+
+        // crates with only pre-releases have no stable version
+        let body = r#"{"crate": {"max_stable_version": null, "newest_version": "0.1.0-alpha"}}"#;
+        assert_eq!(parse_latest_version(body), Ok("0.1.0-alpha".to_string()));
+
+        assert!(parse_latest_version(r#"{"errors": [{"detail": "Not Found"}]}"#).is_err());
+        assert!(parse_latest_version("<html>").is_err());
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("0.1.0", "0.2.0"), Ok(Ordering::Less));
+        assert_eq!(compare_versions("0.10.0", "0.9.9"), Ok(Ordering::Greater));
+        assert_eq!(compare_versions("1.0.0", "1.0.0"), Ok(Ordering::Equal));
+        assert_eq!(compare_versions("1.0.0-rc.1", "1.0.0"), Ok(Ordering::Less));
+        assert!(compare_versions("1.0", "1.0.0").is_err());
+    }
+
+    #[test]
+    fn test_check_update() {
+        let current = env!("CARGO_PKG_VERSION");
+
+        let message = check_update(&Stub(Some("999.0.0"))).unwrap();
+        assert_eq!(
+            message,
+            format!(
+                "atomic 999.0.0 is available (you have {}). Update with `cargo install cargo-atomic`.",
+                current
+            )
+        );
+        assert_eq!(
+            check_update(&Stub(Some(current))),
+            Ok(format!("atomic {} is up to date.", current))
+        );
+        assert_eq!(
+            check_update(&Stub(Some("0.0.1"))),
+            Ok(format!(
+                "atomic {} is newer than the latest release, 0.0.1.",
+                current
+            ))
+        );
+
+        assert_eq!(
+            check_update(&Stub(None)),
+            Err(AtomicError::Static("Failed to reach crates.io: dns error"))
+        );
+    }
+
+    #[test]
+    fn test_verbose_version() {
+        let lines: Vec<_> = verbose_version().lines().map(str::to_string).collect();
+        assert_eq!(lines[0], format!("atomic {}", env!("CARGO_PKG_VERSION")));
+        assert!(lines[1].starts_with("commit: "));
+        assert!(lines[2].starts_with("built: "));
+        assert!(lines[3].starts_with("target: "));
+        assert_eq!(lines[4], "features: none");
+    }
+}
@@ -0,0 +1,241 @@
+//! atomic.yaml: the same config as atomic.toml, written as YAML.
+//!
+//! YAML is converted into the [`toml::Value`] atomic.toml parses to, so validation and
+//! everything after it never see the difference.
+
+use std::path::Path;
+
+use serde_yaml::Value as Yaml;
+use toml::{map::Map, Value};
+
+use crate::{AtomicError, Result};
+
+/// Whether `file` is read as YAML: it ends in `.yaml` or `.yml`.
+pub fn is_yaml(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|extension| extension == "yaml" || extension == "yml")
+}
+
+/// Parses the YAML `text` of `file`.
+///
+/// Anchors, aliases and `<<` merge keys are resolved first.
+///
+/// # Errors
+/// Returns [`AtomicError::YamlParse`] for invalid YAML, and for what TOML has no room for: keys
+/// that aren't strings, nulls, tags, and integers too big for TOML. The error names the key.
+pub fn from_str(file: &Path, text: &str) -> Result<Value> {
+    let parse_error = |message: String| AtomicError::YamlParse(file.display().to_string(), message);
+    let mut yaml: Yaml = serde_yaml::from_str(text).map_err(|err| parse_error(err.to_string()))?;
+    yaml.apply_merge()
+        .map_err(|err| parse_error(err.to_string()))?;
+    match yaml {
+        // an empty file, like an empty atomic.toml
+        Yaml::Null => Ok(Value::Table(Map::new())),
+        Yaml::Mapping(_) => convert(yaml, "").map_err(parse_error),
+        _ => Err(parse_error(
+            "expected a mapping of sections and commands at the top".to_string(),
+        )),
+    }
+}
+
+/// Renders `value` as YAML, for `atomic --init --format yaml`.
+///
+/// # Errors
+/// Returns an error if `value` holds something YAML can't, which parsed TOML never does.
+pub fn to_string(value: &Value) -> Result<String> {
+    serde_yaml::to_string(value)
+        .map_err(|err| AtomicError::Generic(format!("Failed to render YAML: {}", err)))
+}
+
+/// Converts the YAML found at `path`, a dotted key such as `custom.ci[1]`.
+fn convert(yaml: Yaml, path: &str) -> std::result::Result<Value, String> {
+    let at = |problem: &str| match path {
+        "" => problem.to_string(),
+        path => format!("{}: {}", path, problem),
+    };
+    Ok(match yaml {
+        Yaml::Bool(value) => Value::Boolean(value),
+        Yaml::Number(number) => match (number.as_i64(), number.as_f64()) {
+            (Some(integer), _) => Value::Integer(integer),
+            (None, Some(_)) if number.is_u64() => {
+                return Err(at("integer is too big for atomic's config"))
+            }
+            (None, Some(float)) => Value::Float(float),
+            (None, None) => return Err(at("unsupported number")),
+        },
+        Yaml::String(value) => Value::String(value),
+        Yaml::Sequence(items) => Value::Array(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(i, item)| convert(item, &format!("{}[{}]", path, i)))
+                .collect::<std::result::Result<_, _>>()?,
+        ),
+        Yaml::Mapping(entries) => {
+            let mut table = Map::new();
+            for (key, value) in entries {
+                let Yaml::String(key) = key else {
+                    return Err(at(&format!(
+                        "keys must be strings, found {}",
+                        describe(&key)
+                    )));
+                };
+                let child = match path {
+                    "" => key.clone(),
+                    path => format!("{}.{}", path, key),
+                };
+                table.insert(key, convert(value, &child)?);
+            }
+            Value::Table(table)
+        }
+        Yaml::Null => return Err(at("has no value; TOML has no null")),
+        Yaml::Tagged(tagged) => {
+            return Err(at(&format!("tags such as {} aren't supported", tagged.tag)))
+        }
+    })
+}
+
+/// `a number`, `a list` and so on, for a key that isn't a string.
+fn describe(yaml: &Yaml) -> &'static str {
+    match yaml {
+        Yaml::Null => "null",
+        Yaml::Bool(_) => "a boolean",
+        Yaml::Number(_) => "a number",
+        Yaml::String(_) => "a string",
+        Yaml::Sequence(_) => "a list",
+        Yaml::Mapping(_) => "a mapping",
+        Yaml::Tagged(_) => "a tagged value",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AtomicConfig;
+    use crate::toml::load_and_validate_toml;
+    use std::fs;
+
+    fn parse(text: &str) -> Result<Value> {
+        from_str(Path::new("atomic.yaml"), text)
+    }
+
+    fn parse_error(text: &str) -> String {
+        match parse(text) {
+            Err(AtomicError::YamlParse(_, message)) => message,
+            other => panic!("expected a YAML error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_convert() {
+        let value = parse(
+            r#"
+            base: &base
+              retries: 2
+              retry_delay: 1.5
+            custom:
+              fetch:
+                <<: *base
+                command: cargo fetch
+                confirm: true
+              ci: [fetch, { run: docs, ignore_failure: true }]
+            "#,
+        )
+        .unwrap();
+        let expected: Value = ::toml::from_str(
+            r#"
+            base = { retries = 2, retry_delay = 1.5 }
+            [custom]
+            fetch = { retries = 2, retry_delay = 1.5, command = "cargo fetch", confirm = true }
+            ci = ["fetch", { run = "docs", ignore_failure = true }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+        assert_eq!(parse("").unwrap(), Value::Table(Map::new()));
+    }
+
+    #[test]
+    fn test_convert_errors() {
+        assert_eq!(
+            parse_error("custom:\n  1: cargo build\n"),
+            "custom: keys must be strings, found a number"
+        );
+        assert_eq!(
+            parse_error("custom:\n  ci: [fmt, ~]\n"),
+            "custom.ci[1]: has no value; TOML has no null"
+        );
+        assert_eq!(
+            parse_error("custom:\n  build: !shell cargo build\n"),
+            "custom.build: tags such as !shell aren't supported"
+        );
+        assert_eq!(
+            parse_error("atomic:\n  limit: 18446744073709551615\n"),
+            "atomic.limit: integer is too big for atomic's config"
+        );
+        assert!(parse_error("- build\n").contains("expected a mapping"));
+        assert!(parse("custom: [").is_err());
+    }
+
+    #[test]
+    fn test_same_config_in_both_formats() {
+        let toml_dir = tempfile::tempdir().unwrap();
+        let yaml_dir = tempfile::tempdir().unwrap();
+        let toml = toml_dir.path().join("atomic.toml");
+        let yaml = yaml_dir.path().join("atomic.yml");
+        fs::write(
+            &toml,
+            r#"
+            lint = "cargo clippy"
+            [atomic]
+            auto_commit = false
+            [custom]
+            ci = ["lint", "-docs"]
+            [custom.docs]
+            command = "cargo doc"
+            retries = 2
+            os = ["unix", "windows"]
+            [profile.ci.custom]
+            lint = "cargo clippy -- -D warnings"
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            &yaml,
+            r#"
+            lint: cargo clippy
+            atomic:
+              auto_commit: false
+            custom:
+              ci: [lint, -docs]
+              docs:
+                command: cargo doc
+                retries: 2
+                os: [unix, windows]
+            profile:
+              ci:
+                custom:
+                  lint: cargo clippy -- -D warnings
+            "#,
+        )
+        .unwrap();
+
+        for profile in [None, Some("ci")] {
+            let from_toml = load_and_validate_toml(&toml, profile).unwrap();
+            let from_yaml = load_and_validate_toml(&yaml, profile).unwrap();
+            assert_eq!(from_toml, from_yaml);
+            let from_toml = AtomicConfig::from_value(from_toml).unwrap();
+            let from_yaml = AtomicConfig::from_value(from_yaml).unwrap();
+            for name in ["lint", "ci", "docs"] {
+                assert_eq!(from_toml.command(name), from_yaml.command(name));
+            }
+        }
+
+        fs::write(&toml, "[custom.docs]\nretries = \"2\"\ncolour = 1\n").unwrap();
+        fs::write(&yaml, "custom:\n  docs:\n    retries: '2'\n    colour: 1\n").unwrap();
+        assert_eq!(
+            load_and_validate_toml(&toml, None),
+            load_and_validate_toml(&yaml, None)
+        );
+    }
+}
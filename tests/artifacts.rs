@@ -0,0 +1,94 @@
+//! Runs fixtures/artifacts/atomic.toml, whose steps write files that do and don't match their
+//! `artifacts`, and checks exactly the matching ones were collected.
+#![cfg(unix)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+/// Runs `atomic args` in `dir`, holding a copy of the fixture.
+fn atomic(dir: &Path, args: &[&str]) -> Output {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/artifacts/atomic.toml");
+    fs::copy(source, dir.join("atomic.toml")).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_atomic"))
+        .args(args)
+        .current_dir(dir)
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE")
+        .env_remove("ATOMIC_DEPTH")
+        .env_remove("ATOMIC_PROFILE")
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir)
+        .output()
+        .unwrap()
+}
+
+/// Every file below `dir`, relative to it.
+fn files(dir: &Path) -> Vec<String> {
+    fn walk(dir: &Path, root: &Path, found: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path: PathBuf = entry.unwrap().path();
+            if path.is_dir() {
+                walk(&path, root, found);
+            } else {
+                let relative = path.strip_prefix(root).unwrap();
+                found.push(relative.to_string_lossy().replace('\\', "/"));
+            }
+        }
+    }
+    let mut found = Vec::new();
+    walk(dir, dir, &mut found);
+    found.sort();
+    found
+}
+
+#[test]
+fn test_artifacts_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("out");
+    let output = atomic(
+        dir.path(),
+        &["--artifacts-dir", out.to_str().unwrap(), "ci"],
+    );
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    // bench failed, and still had its report collected; big.lcov is over artifact_max_size
+    assert_eq!(
+        files(&out),
+        [
+            "bench/target/criterion/fib/report.html",
+            "coverage/coverage/lcov.lcov",
+        ]
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("artifacts: skipping coverage/big.lcov (100 B), over artifact_max_size"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_artifacts_history() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = atomic(dir.path(), &["ci"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let runs = dir.path().join(".atomic/artifacts");
+    let run: Vec<_> = fs::read_dir(&runs).unwrap().collect();
+    assert_eq!(run.len(), 1);
+    let run = run[0].as_ref().unwrap().path();
+    assert!(run.file_name().unwrap().to_str().unwrap().ends_with("-ci"));
+    assert_eq!(
+        files(&run),
+        [
+            "bench/target/criterion/fib/report.html",
+            "coverage/coverage/lcov.lcov",
+        ]
+    );
+
+    let listing = atomic(dir.path(), &["--artifacts"]);
+    let stdout = String::from_utf8_lossy(&listing.stdout);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    assert!(stdout.contains("  ci  2 files, 11 B  "), "{}", stdout);
+}
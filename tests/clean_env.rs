@@ -0,0 +1,33 @@
+//! Runs fixtures/clean_env/atomic.toml with a stray variable in atomic's environment, and checks
+//! only the commands without `clean_env`, or with it in `env_allow`, see it.
+#![cfg(unix)]
+
+use std::{fs, path::Path, process::Command};
+
+/// Runs `atomic cmd` in `dir`, holding a copy of the fixture, and returns what it wrote.
+fn run(dir: &Path, cmd: &str) -> String {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/clean_env/atomic.toml");
+    fs::copy(source, dir.join("atomic.toml")).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_atomic"))
+        .arg(cmd)
+        .current_dir(dir)
+        .env_remove("ATOMIC_DEPTH")
+        .env_remove("ATOMIC_PROFILE")
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir)
+        .env("ATOMIC_TEST_POLLUTION", "stray")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    fs::read_to_string(dir.join("out.txt")).unwrap()
+}
+
+#[test]
+fn test_clean_env() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join(".env"), "FROM_FILE=kept\n").unwrap();
+
+    assert_eq!(run(dir.path(), "dirty"), "stray\n");
+    assert_eq!(run(dir.path(), "clean"), "|kept|1|path\n");
+    assert_eq!(run(dir.path(), "allowed"), "stray\n");
+}
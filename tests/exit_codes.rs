@@ -0,0 +1,64 @@
+//! Runs the atomic binary against the configs in fixtures/exit and checks the code it exits with.
+
+use std::{fs, path::Path, process::Command};
+
+/// Runs `atomic args` in a fresh directory holding `fixture` as its atomic.toml, or no config at
+/// all, away from CI variables and any global config. Returns the exit code.
+fn atomic(fixture: Option<&str>, args: &[&str]) -> i32 {
+    let dir = tempfile::tempdir().unwrap();
+    if let Some(fixture) = fixture {
+        let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/exit")
+            .join(fixture);
+        fs::copy(source, dir.path().join("atomic.toml")).unwrap();
+    }
+    let output = Command::new(env!("CARGO_BIN_EXE_atomic"))
+        .args(args)
+        .current_dir(dir.path())
+        .env_remove("CI")
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE")
+        .env_remove("ATOMIC_DEPTH")
+        .env_remove("ATOMIC_PROFILE")
+        .env("HOME", dir.path())
+        .env("XDG_CONFIG_HOME", dir.path())
+        .output()
+        .unwrap();
+    output.status.code().unwrap()
+}
+
+#[test]
+fn test_success() {
+    assert_eq!(atomic(Some("steps.toml"), &["pass"]), 0);
+    assert_eq!(atomic(None, &["--print-exit-codes"]), 0);
+}
+
+#[test]
+fn test_usage() {
+    assert_eq!(atomic(Some("steps.toml"), &["--no-such-flag"]), 2);
+    assert_eq!(atomic(None, &["build"]), 2);
+}
+
+#[test]
+fn test_validation_failure() {
+    assert_eq!(atomic(Some("invalid.toml"), &["build"]), 3);
+}
+
+#[test]
+fn test_command_not_found() {
+    assert_eq!(atomic(Some("steps.toml"), &["nope"]), 4);
+}
+
+#[test]
+fn test_step_failure() {
+    assert_eq!(atomic(Some("steps.toml"), &["fail"]), 5);
+    assert_eq!(
+        atomic(Some("steps.toml"), &["--passthrough-exit-code", "fail"]),
+        7
+    );
+}
+
+#[test]
+fn test_failure_before_any_step() {
+    assert_eq!(atomic(Some("steps.toml"), &["outer"]), 1);
+}
@@ -0,0 +1,81 @@
+//! Runs a chain with the variables of each CI service set, checking its steps are folded in the
+//! markers of that service and no other.
+
+use std::{fs, path::Path, process::Command};
+
+const CI_VARS: &[&str] = &["CI", "GITHUB_ACTIONS", "GITLAB_CI", "BUILDKITE"];
+
+/// Runs `atomic args` on fixtures/fold/chain.toml in a fresh directory with only `vars` of the CI
+/// variables set, and returns its stdout. Stdout is a pipe, so no terminal header is drawn.
+fn atomic(vars: &[(&str, &str)], args: &[&str]) -> String {
+    let dir = tempfile::tempdir().unwrap();
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/fold/chain.toml");
+    fs::copy(source, dir.path().join("atomic.toml")).unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_atomic"));
+    command
+        .args(args)
+        .current_dir(dir.path())
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE")
+        .env_remove("ATOMIC_DEPTH")
+        .env_remove("ATOMIC_PROFILE")
+        .env("HOME", dir.path())
+        .env("XDG_CONFIG_HOME", dir.path());
+    for var in CI_VARS {
+        command.env_remove(var);
+    }
+    let output = command.envs(vars.iter().copied()).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn test_no_markers_outside_ci() {
+    assert_eq!(atomic(&[], &["build"]), "building\ntesting\n");
+}
+
+#[test]
+fn test_github_actions() {
+    let out = atomic(&[("CI", "true"), ("GITHUB_ACTIONS", "true")], &["build"]);
+    // CI mode adds a summary line after the steps
+    assert!(
+        out.starts_with(
+            "::group::echo building\nbuilding\n::endgroup::\n\
+             ::group::echo testing\ntesting\n::endgroup::\natomic build: "
+        ),
+        "{:?}",
+        out
+    );
+}
+
+#[test]
+fn test_gitlab() {
+    let out = atomic(&[("CI", "true"), ("GITLAB_CI", "true")], &["build"]);
+    assert_eq!(out.matches("\x1b[0Ksection_start:").count(), 2, "{:?}", out);
+    assert_eq!(out.matches("\x1b[0Ksection_end:").count(), 2, "{:?}", out);
+    assert!(out.contains("[collapsed=true]\r\x1b[0Kecho building\nbuilding\n"));
+    assert!(!out.contains("::group::") && !out.contains("--- "));
+}
+
+#[test]
+fn test_buildkite() {
+    let out = atomic(&[("CI", "true"), ("BUILDKITE", "true")], &["build"]);
+    assert!(
+        out.starts_with("--- echo building\nbuilding\n--- echo testing\ntesting\natomic build: "),
+        "{:?}",
+        out
+    );
+}
+
+#[test]
+fn test_no_fold() {
+    let out = atomic(
+        &[("CI", "true"), ("GITHUB_ACTIONS", "true")],
+        &["--no-fold", "build"],
+    );
+    assert!(
+        out.starts_with("building\ntesting\natomic build: "),
+        "{:?}",
+        out
+    );
+}
@@ -0,0 +1,43 @@
+//! Runs fixtures/junit/atomic.toml, whose chain fails halfway, and reads back the `--junit`
+//! report it wrote.
+
+use std::{fs, path::Path, process::Command};
+
+#[test]
+fn test_aborted_chain_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/junit/atomic.toml");
+    fs::copy(source, dir.path().join("atomic.toml")).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_atomic"))
+        .args(["--junit", "report.xml", "ci"])
+        .current_dir(dir.path())
+        .env_remove("CI")
+        .env_remove("ATOMIC_DEPTH")
+        .env_remove("ATOMIC_PROFILE")
+        .env("HOME", dir.path())
+        .env("XDG_CONFIG_HOME", dir.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(5), "{:?}", output);
+
+    let report = fs::read_to_string(dir.path().join("report.xml")).unwrap();
+    assert!(
+        report.contains("tests=\"4\" failures=\"1\" errors=\"0\" skipped=\"2\""),
+        "{}",
+        report
+    );
+    // the steps after the failure never ran
+    for step in ["echo test", "echo docs"] {
+        let case = format!("<testcase name=\"{}\" classname=\"shell\"", step);
+        let rest = &report[report.find(&case).expect(&case)..];
+        assert!(
+            rest.split("</testcase>")
+                .next()
+                .unwrap()
+                .contains("<skipped/>"),
+            "{}",
+            report
+        );
+    }
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("test\n"));
+}
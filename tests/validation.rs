@@ -0,0 +1,84 @@
+//! Runs the atomic binary against the configs in fixtures/validation: warnings are printed once
+//! and don't stop a run, errors do.
+
+use std::{fs, path::Path, process::Command};
+
+/// Runs `atomic args` in a fresh directory holding `fixture` as its atomic.toml, away from CI
+/// variables and any global config. Returns the exit code, stdout and stderr.
+fn atomic(fixture: &str, args: &[&str]) -> (i32, String, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures/validation")
+        .join(fixture);
+    fs::copy(source, dir.path().join("atomic.toml")).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_atomic"))
+        .args(args)
+        .current_dir(dir.path())
+        .env_remove("CI")
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE")
+        .env_remove("ATOMIC_DEPTH")
+        .env_remove("ATOMIC_PROFILE")
+        .env("HOME", dir.path())
+        .env("XDG_CONFIG_HOME", dir.path())
+        .output()
+        .unwrap();
+    (
+        output.status.code().unwrap(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+const WARNINGS: &[&str] = &[
+    "warning: atomic.future_setting: unknown key (ignored)",
+    "warning: custom.second.colour: unknown key (ignored)",
+    "warning: plugins: unknown section (ignored)",
+];
+
+#[test]
+fn test_warnings_dont_stop_a_run() {
+    let (code, stdout, stderr) = atomic("warnings.toml", &["all"]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stdout.contains("second"), "{}", stdout);
+    // once each, though the chain resolves `first` and `second` and `second` needs `first`
+    for warning in WARNINGS {
+        assert_eq!(
+            stderr.matches(warning).count(),
+            1,
+            "{}\n{}",
+            warning,
+            stderr
+        );
+    }
+
+    let (code, _, stderr) = atomic("warnings.toml", &["--quiet", "all"]);
+    assert_eq!(code, 0);
+    assert!(!stderr.contains("warning:"), "{}", stderr);
+}
+
+#[test]
+fn test_check() {
+    let (code, stdout, _) = atomic("warnings.toml", &["--check"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout.lines().count(), WARNINGS.len(), "{}", stdout);
+    assert!(stdout.contains("warning: plugins: unknown section"));
+
+    assert_eq!(atomic("warnings.toml", &["--check", "--strict"]).0, 3);
+    let (code, stdout, _) = atomic("warnings.toml", &["--check", "--quiet"]);
+    assert_eq!((code, stdout.as_str()), (0, ""));
+
+    let (code, stdout, _) = atomic("errors.toml", &["--check"]);
+    assert_eq!(code, 3);
+    assert!(
+        stdout.contains("error: custom.build.retries: expected an integer"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("warning: custom.build.colour: unknown key"),
+        "{}",
+        stdout
+    );
+    assert_eq!(atomic("errors.toml", &["build"]).0, 3);
+}